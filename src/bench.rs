@@ -0,0 +1,48 @@
+//! Simple throughput benchmark of the core, runs a small loop of loads,
+//! stores, ALU operations and branches and reports the MIPS
+
+use std::time::Instant;
+
+use crate::cpu::{ Core, Register };
+
+// NOTE(patrik): Assembled from:
+//   _start:
+//     lui s0, 0x10
+//   outer:
+//     mv t0, s0
+//     li t1, 512
+//   inner:
+//     ld a0, 0(t0)
+//     addi a0, a0, 1
+//     sd a0, 0(t0)
+//     lw a1, 8(t0)
+//     add a1, a1, a0
+//     sw a1, 8(t0)
+//     addi t0, t0, 16
+//     addi t1, t1, -1
+//     bnez t1, inner
+//     j outer
+const PROGRAM: [u8; 38] = [
+    0x41, 0x64, 0xa2, 0x82, 0x13, 0x03, 0x00, 0x20, 0x03, 0xb5, 0x02, 0x00,
+    0x05, 0x05, 0x23, 0xb0, 0xa2, 0x00, 0x83, 0xa5, 0x82, 0x00, 0xaa, 0x95,
+    0x23, 0xa4, 0xb2, 0x00, 0xc1, 0x02, 0x7d, 0x13, 0xe3, 0x14, 0x03, 0xfe,
+    0xf9, 0xbf
+];
+
+/// Load the benchmark program at address 0 and run `instructions`
+/// instructions on `core`
+pub fn run(core: &mut Core, instructions: u64) {
+    for (index, value) in PROGRAM.iter().enumerate() {
+        core.mmu.write_u8(index as u64, *value);
+    }
+
+    core.set_reg(Register::Pc, 0);
+
+    let start = Instant::now();
+    let result = core.run(instructions);
+    let elapsed = start.elapsed();
+
+    let mips = result.retired as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+    println!("Executed {} instructions in {:.3}s: {:.2} MIPS",
+             result.retired, elapsed.as_secs_f64(), mips);
+}
@@ -0,0 +1,189 @@
+//! Core Local Interruptor, provides the machine timer and software
+//! interrupts using the same register layout as the SiFive CLINT
+
+use std::time::Instant;
+
+use crate::device::{ Device, Interrupts };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP_BASE: u64     = 0x0000;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64         = 0xbff8;
+
+/// Number of instructions between reads of the host clock
+const WALL_CLOCK_SYNC_TICKS: u64 = 256;
+
+const MSIP_BIT: u64 = 3;
+const MTIP_BIT: u64 = 7;
+
+/// Where the value of mtime comes from
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TimerSource {
+    /// mtime advances by one every `n` instructions, runs are fully
+    /// deterministic
+    Instructions(u64),
+    /// mtime follows the host clock ticking at the given frequency in Hz
+    WallClock(u64),
+}
+
+pub struct Clint {
+    source: TimerSource,
+
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+
+    mtime: u64,
+    /// Instructions since mtime was last incremented
+    ticks: u64,
+
+    /// Host time and mtime value when the wall clock was last synced
+    start: Instant,
+    start_mtime: u64,
+}
+
+impl Clint {
+    pub fn new(harts: usize, source: TimerSource) -> Self {
+        Self {
+            source,
+
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+
+            mtime: 0,
+            ticks: 0,
+
+            start: Instant::now(),
+            start_mtime: 0,
+        }
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = value;
+        self.start = Instant::now();
+        self.start_mtime = value;
+    }
+
+    /// Read the 64-bit register at `offset` rounded down to 8 bytes, `None`
+    /// if there is no register there
+    fn register(&self, offset: u64) -> Option<u64> {
+        let hart_count = self.msip.len() as u64;
+
+        if offset.wrapping_sub(MSIP_BASE) < hart_count * 4 {
+            // NOTE(patrik): msip is only 32-bit wide so two harts share the
+            // same 64-bit word
+            let hart = ((offset - MSIP_BASE) / 8 * 2) as usize;
+            let low = self.msip[hart] as u64;
+            let high = self.msip.get(hart + 1).copied().unwrap_or(false);
+
+            return Some(low | (high as u64) << 32);
+        }
+
+        if offset.wrapping_sub(MTIMECMP_BASE) < hart_count * 8 {
+            let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+            return Some(self.mtimecmp[hart]);
+        }
+
+        if offset == MTIME {
+            return Some(self.mtime);
+        }
+
+        None
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, size: usize) -> u64 {
+        let value = self.register(offset & !7).unwrap_or(0);
+        let shift = (offset & 7) * 8;
+        let mask = if size == 8 { u64::MAX } else { (1 << (size * 8)) - 1 };
+
+        (value >> shift) & mask
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) {
+        let old = match self.register(offset & !7) {
+            Some(old) => old,
+            None => return,
+        };
+
+        let shift = (offset & 7) * 8;
+        let mask = if size == 8 { u64::MAX } else { (1 << (size * 8)) - 1 };
+        let value = (old & !(mask << shift)) | ((value & mask) << shift);
+
+        let aligned = offset & !7;
+        if aligned == MTIME {
+            self.set_mtime(value);
+        } else if aligned >= MTIMECMP_BASE {
+            let hart = ((aligned - MTIMECMP_BASE) / 8) as usize;
+            self.mtimecmp[hart] = value;
+        } else {
+            let hart = ((aligned - MSIP_BASE) / 8 * 2) as usize;
+            self.msip[hart] = value & 1 != 0;
+            if let Some(msip) = self.msip.get_mut(hart + 1) {
+                *msip = (value >> 32) & 1 != 0;
+            }
+        }
+    }
+
+    fn tick(&mut self, interrupts: &mut Interrupts) {
+        match self.source {
+            TimerSource::Instructions(divider) => {
+                self.ticks += 1;
+                if self.ticks >= divider {
+                    self.ticks = 0;
+                    self.mtime = self.mtime.wrapping_add(1);
+                }
+            },
+
+            TimerSource::WallClock(frequency) => {
+                // NOTE(patrik): Reading the host clock is slow compared to
+                // executing an instruction so only do it once in a while
+                self.ticks += 1;
+                if self.ticks >= WALL_CLOCK_SYNC_TICKS {
+                    self.ticks = 0;
+
+                    let elapsed = self.start.elapsed().as_nanos();
+                    let ticks = elapsed * frequency as u128 / 1_000_000_000;
+                    self.mtime = self.start_mtime.wrapping_add(ticks as u64);
+                }
+            },
+        }
+
+        interrupts.set_time(self.mtime);
+
+        for (hart, (msip, mtimecmp)) in self.msip.iter()
+            .zip(self.mtimecmp.iter()).enumerate()
+        {
+            interrupts.set_pending(hart, MSIP_BIT, *msip);
+            interrupts.set_pending(hart, MTIP_BIT, self.mtime >= *mtimecmp);
+        }
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.list_bool(&self.msip);
+        writer.list_u64(&self.mtimecmp);
+        writer.u64(self.mtime);
+        writer.u64(self.ticks);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let msip = reader.list_bool(Some(self.msip.len()))?;
+        let mtimecmp = reader.list_u64(Some(self.mtimecmp.len()))?;
+        let mtime = reader.u64()?;
+        let ticks = reader.u64()?;
+
+        self.msip = msip;
+        self.mtimecmp = mtimecmp;
+        // NOTE(patrik): With the wall clock as the source mtime continues
+        // from the saved value
+        self.set_mtime(mtime);
+        self.ticks = ticks;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+//! Runner for the riscv-tests and riscv-arch-test suites, every ELF file
+//! given or found in the given directories is run and the result is
+//! reported per test
+//!
+//!     rest-emu test [--references DIR] [--signatures DIR] [--limit N] PATH...
+//!
+//! riscv-tests report the result through HTIF. riscv-arch-test programs
+//! have their signature region between `begin_signature` and
+//! `end_signature` compared against `NAME.reference_output` in the
+//! references directory
+
+use std::fs;
+use std::io::Write;
+use std::panic::{ self, AssertUnwindSafe };
+use std::path::{ Path, PathBuf };
+
+use crate::cpu::{ Core, CoreExit, Register, StopReason };
+use crate::device::Shutdown;
+use crate::clint::TimerSource;
+use crate::elf::Elf;
+use crate::htif::Htif;
+use crate::machine::Machine;
+use crate::uart::Uart;
+use crate::UART_IRQ;
+
+/// Instructions a test gets to run before it's considered hung
+const DEFAULT_LIMIT: u64 = 10_000_000;
+
+enum Outcome {
+    Pass,
+    /// The test reported a failure, riscv-tests give the number of the
+    /// failing test case
+    Fail(u16),
+    Timeout,
+    /// The emulator panicked, usually an unimplemented instruction
+    Panic(String),
+    /// The signature didn't match the reference at the given line
+    Mismatch(usize),
+    NoReference,
+}
+
+struct Options {
+    references: Option<PathBuf>,
+    signatures: Option<PathBuf>,
+    limit: u64,
+}
+
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Read the signature region as 32-bit words, one per line in hex like
+/// the reference files
+fn read_signature(core: &Core, elf: &Elf) -> Option<Vec<String>> {
+    let begin = elf.symbol("begin_signature")?;
+    let end = elf.symbol("end_signature")?;
+
+    let words = (begin..end)
+        .step_by(4)
+        .map(|addr| {
+            let mut word = 0;
+            for index in (0..4).rev() {
+                let byte = core.mmu.peek_u8(addr + index).unwrap_or(0);
+                word = word << 8 | byte as u32;
+            }
+
+            format!("{:08x}", word)
+        })
+        .collect();
+
+    Some(words)
+}
+
+/// Compare the signature against the reference file, a line missing on
+/// either side is a mismatch as well
+fn compare_signature(signature: &[String], reference: &str) -> Outcome {
+    let reference: Vec<&str> = reference.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    for line in 0..signature.len().max(reference.len()) {
+        match (signature.get(line), reference.get(line)) {
+            (Some(word), Some(expected))
+                if word.eq_ignore_ascii_case(expected) => {},
+            _ => return Outcome::Mismatch(line + 1),
+        }
+    }
+
+    Outcome::Pass
+}
+
+fn run_test(path: &Path, elf: &Elf, options: &Options) -> Outcome {
+    let memory_map = [crate::default_memory(Some(elf))];
+    let uart = Uart::new(UART_IRQ, None, Box::new(std::io::sink()));
+    let mut mmu = crate::create_mmu(&memory_map,
+                                    TimerSource::Instructions(1), uart,
+                                    None);
+    elf.load(&mut mmu);
+
+    let htif = elf.symbol("tohost").map(|tohost| {
+        Htif::new(tohost, elf.symbol("fromhost"),
+                  Box::new(std::io::sink()))
+    });
+
+    let mut core = crate::create_core(mmu);
+    core.set_environment_traps(true);
+    core.set_reg(Register::Pc, elf.entry);
+
+    let mut machine = Machine::new(core, htif);
+    let shutdown = match machine.run(options.limit).reason {
+        StopReason::Exit(CoreExit::Shutdown(shutdown)) => shutdown,
+        _ => return Outcome::Timeout,
+    };
+    let core = &machine.core;
+
+    let signature = match read_signature(core, elf) {
+        Some(signature) => signature,
+
+        None => return match shutdown {
+            Shutdown::Pass => Outcome::Pass,
+            Shutdown::Fail(code) => Outcome::Fail(code),
+            Shutdown::Reboot => Outcome::Fail(0),
+        },
+    };
+
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    if let Some(directory) = options.signatures.as_ref() {
+        let path = directory.join(format!("{}.signature", name));
+        let mut contents = signature.join("\n");
+        contents.push('\n');
+        fs::write(&path, contents)
+            .unwrap_or_else(|_| panic!("Failed to write {}", path.display()));
+    }
+
+    let reference = options.references.as_ref()
+        .map(|directory| {
+            directory.join(format!("{}.reference_output", name))
+        })
+        .and_then(|path| fs::read_to_string(path).ok());
+
+    match reference {
+        Some(reference) => compare_signature(&signature, &reference),
+        None => Outcome::NoReference,
+    }
+}
+
+fn is_elf_file(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok() && Elf::is_elf(&magic)
+}
+
+/// Collect the ELF files at `path`, either the file itself or all the ELF
+/// files in the directory sorted by name
+fn find_tests(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        if !is_elf_file(path) {
+            return Vec::new();
+        }
+
+        return vec![path.to_path_buf()];
+    }
+
+    let entries = fs::read_dir(path)
+        .unwrap_or_else(|_| panic!("Failed to read {}", path.display()));
+
+    let mut tests: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_elf_file(path))
+        .collect();
+    tests.sort();
+
+    tests
+}
+
+/// Entry point of the `test` subcommand, returns the process exit code
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let mut options = Options {
+        references: None,
+        signatures: None,
+        limit: DEFAULT_LIMIT,
+    };
+    let mut paths = Vec::new();
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--references" => {
+                let value = args.next()
+                    .expect("--references needs a directory");
+                options.references = Some(PathBuf::from(value));
+            },
+
+            "--signatures" => {
+                let value = args.next()
+                    .expect("--signatures needs a directory");
+                options.signatures = Some(PathBuf::from(value));
+            },
+
+            "--limit" => {
+                let value = args.next()
+                    .expect("--limit needs an instruction count");
+                options.limit = crate::parse_u64(&value);
+            },
+
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    if paths.is_empty() {
+        panic!("test: No tests given");
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for path in paths.iter() {
+        for path in find_tests(path) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+            let data = fs::read(&path)
+                .unwrap_or_else(|_| panic!("Failed to read {}", name));
+
+            // NOTE(patrik): Panics in a test are reported as a failed test
+            // so don't print them as they happen. Anything else, like a
+            // file that can't be read, still panics with a message
+            let default_hook = panic::take_hook();
+            panic::set_hook(Box::new(|_| {}));
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                let elf = Elf::parse(&data);
+                run_test(&path, &elf, &options)
+            }));
+            panic::set_hook(default_hook);
+
+            let outcome = outcome
+                .unwrap_or_else(|payload| {
+                    Outcome::Panic(panic_message(payload.as_ref()))
+                });
+
+            if let Outcome::Pass = outcome {
+                passed += 1;
+            } else {
+                failed += 1;
+            }
+
+            let result = match outcome {
+                Outcome::Pass => "PASS".to_string(),
+                Outcome::Fail(code) => format!("FAIL (test {})", code),
+                Outcome::Timeout => "FAIL (timeout)".to_string(),
+                Outcome::Panic(message) => format!("FAIL (panic: {})",
+                                                   message),
+                Outcome::Mismatch(line) => {
+                    format!("FAIL (signature mismatch at line {})", line)
+                },
+                Outcome::NoReference => "FAIL (no reference)".to_string(),
+            };
+
+            println!("{:<40} {}", name, result);
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    println!();
+    println!("{} passed, {} failed", passed, failed);
+
+    if failed == 0 { 0 } else { 1 }
+}
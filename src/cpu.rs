@@ -1,10 +1,29 @@
+use std::cmp;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
 use crate::instruction::{ Instruction, Type };
 use crate::instruction::{ RType, IType, SType, BType, UType, JType };
-use crate::mmu::Mmu;
+use crate::mmu::{ Mmu, MemoryAccess, AccessKind, WatchKind, PAGE_SIZE };
+use crate::device::Shutdown;
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+use crate::fuzz::Coverage;
+use crate::paging::{ self, Tlb, AccessContext };
+use crate::float::{ self, Float, RoundingMode };
+use crate::csr;
 
 const MAX_REGISTERS: usize = 33;
+const MAX_FLOAT_REGISTERS: usize = 32;
 const MAX_CSR_REGISTERS: usize = 4096;
 
+/// Set in xcause when the trap was caused by an interrupt
+const INTERRUPT_BIT: u64 = 1 << 63;
+
+/// ISA string of the harts for the device tree
+pub const ISA: &str =
+    "rv64imafdc_zicsr_zifencei_zicntr_zihpm_sstc_svadu";
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum PrivilegeLevel {
     User,
@@ -13,6 +32,19 @@ pub enum PrivilegeLevel {
     Machine,
 }
 
+impl From<u64> for PrivilegeLevel {
+    fn from(value: u64) -> Self {
+        match value {
+            0 => PrivilegeLevel::User,
+            1 => PrivilegeLevel::Supervisor,
+            2 => PrivilegeLevel::Reserved,
+            3 => PrivilegeLevel::Machine,
+
+            _ => panic!("Unknown privilege level: {}", value),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Register {
     Zero, // x0
@@ -174,11 +206,157 @@ impl From<u16> for Register {
     }
 }
 
+/// How a hart handles loads and stores that are not naturally aligned,
+/// AMOs and LR/SC always raise an exception when misaligned
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MisalignedPolicy {
+    /// Perform the access as if it was aligned
+    Allow,
+    /// Always raise an address-misaligned exception
+    Trap,
+    /// Only raise an address-misaligned exception when the access crosses
+    /// a page boundary
+    TrapPageCross,
+}
+
+/// Synchronous exceptions, the value is what gets written to xtval
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    StoreAddressMisaligned(u64),
+    /// ECALL executed at the given privilege level
+    EnvironmentCall(PrivilegeLevel),
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    /// Page fault of a store or an AMO
+    StorePageFault(u64),
+    InstructionAccessFault(u64),
+    LoadAccessFault(u64),
+    /// Access fault of a store or an AMO
+    StoreAccessFault(u64),
+}
+
+impl Exception {
+    pub fn cause(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_)       => 1,
+            Exception::IllegalInstruction(_)           => 2,
+            Exception::Breakpoint(_)                   => 3,
+            Exception::LoadAddressMisaligned(_)        => 4,
+            Exception::LoadAccessFault(_)              => 5,
+            Exception::StoreAddressMisaligned(_)       => 6,
+            Exception::StoreAccessFault(_)             => 7,
+
+            Exception::EnvironmentCall(level) => match level {
+                PrivilegeLevel::User       => 8,
+                PrivilegeLevel::Supervisor => 9,
+                PrivilegeLevel::Reserved   => 10,
+                PrivilegeLevel::Machine    => 11,
+            },
+
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_)        => 13,
+            Exception::StorePageFault(_)       => 15,
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(value) => *value,
+            Exception::IllegalInstruction(value)           => *value,
+            Exception::Breakpoint(value)                   => *value,
+            Exception::LoadAddressMisaligned(value)        => *value,
+            Exception::StoreAddressMisaligned(value)       => *value,
+            Exception::EnvironmentCall(_)                  => 0,
+            Exception::InstructionPageFault(value)         => *value,
+            Exception::LoadPageFault(value)                => *value,
+            Exception::StorePageFault(value)               => *value,
+            Exception::InstructionAccessFault(value)       => *value,
+            Exception::LoadAccessFault(value)              => *value,
+            Exception::StoreAccessFault(value)             => *value,
+        }
+    }
+}
+
+/// Interrupts in the order they are taken when several are pending, the
+/// value is the cause code and the bit in mip/mie
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interrupt {
+    MachineExternal    = 11,
+    MachineSoftware    = 3,
+    MachineTimer       = 7,
+    SupervisorExternal = 9,
+    SupervisorSoftware = 1,
+    SupervisorTimer    = 5,
+}
+
+impl Interrupt {
+    const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    pub fn cause(&self) -> u64 {
+        *self as u64
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum CoreExit {
     Success,
     Ecall,
+    /// EBREAK was executed, with EBREAK debugging enabled the PC is left
+    /// pointing at the EBREAK
     Ebreak,
+    /// The semihosting sequence around an EBREAK was executed, the
+    /// operation is in a0 and its parameter in a1. PC is past the EBREAK
+    Semihosting,
+    /// The instruction raised an exception and the core has already
+    /// trapped to the handler
+    Exception(Exception),
+    /// An interrupt was taken before executing the next instruction and
+    /// the core has already trapped to the handler
+    Interrupt(Interrupt),
+    /// A device has asked for the machine to stop, no more instructions
+    /// are executed
+    Shutdown(Shutdown),
+    /// An access hit a watchpoint. Loads and stores have completed while
+    /// for execute watchpoints the instruction has not been executed yet
+    Watchpoint(MemoryAccess),
+}
+
+/// Why `Core::run` returned
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopReason {
+    /// The limit of steps was reached
+    Limit,
+    /// PC is at a breakpoint, the instruction there has not been executed
+    Breakpoint(u64),
+    /// Someone asked for the core to stop through `stop_handle`
+    StopRequested,
+    /// A step returned something the host has to look at, exceptions and
+    /// interrupts only stop the core when `stop_on_exceptions` is set
+    Exit(CoreExit),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RunResult {
+    pub reason: StopReason,
+    /// Steps taken, a step either retires an instruction or takes a trap
+    pub steps: u64,
+    /// Instructions that completed
+    pub retired: u64,
+    /// PC of the last instruction stepped, the one that raised the
+    /// exception when stopping on one
+    pub last_pc: u64,
 }
 
 pub struct CoreStateFunctions {
@@ -215,73 +393,533 @@ impl CoreState {
     }
 }
 
+/// Copy of the architectural state of a core kept in memory, see
+/// `Core::snapshot`
+pub struct CoreSnapshot {
+    registers: [u64; MAX_REGISTERS],
+    float_registers: [u64; MAX_FLOAT_REGISTERS],
+    csr_registers: Box<[u64; MAX_CSR_REGISTERS]>,
+    privilege_level: PrivilegeLevel,
+    reservation: Option<u64>,
+    counters: [u64; csr::COUNTERS],
+}
+
+/// Watchpoint on a range of virtual addresses, see
+/// `Core::add_virtual_watchpoint`
+#[derive(Clone, PartialEq, Debug)]
+pub struct VirtualWatchpoint {
+    pub addr: u64,
+    pub length: u64,
+    pub kind: WatchKind,
+    /// Physical ranges the watchpoint was added to
+    ranges: Vec<(u64, usize)>,
+}
+
+impl VirtualWatchpoint {
+    /// Virtual address of the first watched byte `access` touched, `None`
+    /// if it didn't touch this watchpoint
+    pub fn hit(&self, access: &MemoryAccess) -> Option<u64> {
+        let access_end = access.addr.saturating_add(access.size as u64);
+
+        let mut offset = 0;
+        for (physical, size) in self.ranges.iter() {
+            let start = access.addr.max(*physical);
+            let end = access_end.min(physical + *size as u64);
+            if start < end {
+                return Some(self.addr.wrapping_add(offset + start - physical));
+            }
+
+            offset += *size as u64;
+        }
+
+        None
+    }
+}
+
 pub struct Core {
     registers: [u64; MAX_REGISTERS],
+    /// f0-f31 as raw bits, singles are NaN-boxed
+    float_registers: [u64; MAX_FLOAT_REGISTERS],
     state: CoreState,
 
+    hart_id: usize,
+
+    /// mip bits driven by devices the last time interrupts were checked
+    device_pending: u64,
+    /// Set when something that affects which interrupts can be taken has
+    /// changed since the last check
+    check_interrupts: bool,
+
+    /// Address reserved by the last LR instruction
+    reservation: Option<u64>,
+
+    /// PC of the last execute watchpoint reported, it's not reported again
+    /// when execution resumes at the same instruction
+    execute_watchpoint: Option<u64>,
+
+    /// ECALL and EBREAK trap to the handler of the guest instead of
+    /// exiting to the host
+    environment_traps: bool,
+    /// ECALLs from supervisor mode exit to the host even with environment
+    /// traps, the host is the SBI firmware
+    firmware_ecalls: bool,
+    /// EBREAK stops the core at the EBREAK for a debugger instead of
+    /// trapping, like dcsr.ebreakm
+    ebreak_debug: bool,
+    /// EBREAKs in the semihosting sequence exit to the host, before any of
+    /// the above
+    semihosting: bool,
+
+    /// Print every instruction executed
+    trace: bool,
+
+    /// Edges taken by branches and jumps, only counted when set
+    coverage: Option<Coverage>,
+
+    /// Instructions retired since the core was created
+    retired: u64,
+
+    /// mcycle, minstret and mhpmcounter3-31 indexed by counter, the entry
+    /// of time is unused as it comes from the CLINT
+    counters: [u64; csr::COUNTERS],
+    /// Counters stopped by mcountinhibit
+    counters_inhibited: u64,
+    /// HPM counters with an event selected that aren't inhibited
+    hpm_active: u64,
+    /// Counters written by the instruction being executed, the write is
+    /// done instead of the increment
+    counters_written: u64,
+
+    /// stimecmp when menvcfg.STCE enables it, STIP then follows the timer
+    /// instead of being written by software
+    stimecmp: Option<u64>,
+
+    /// Physical address of the root page table when satp selects Sv39
+    page_table: Option<u64>,
+    tlb: Tlb,
+
+    /// Addresses `run` stops at before executing the instruction there
+    breakpoints: Vec<u64>,
+    /// Set from other threads to make `run` return
+    stop_request: Arc<AtomicBool>,
+    /// `run` returns when an instruction raises an exception
+    stop_on_exceptions: bool,
+
+    misaligned_policy: MisalignedPolicy,
+    /// Instruction address alignment in bytes, 2 when the C extention is
+    /// enabled otherwise 4
+    ialign: u64,
+
     pub mmu: Mmu
 }
 
 impl Core {
     pub fn new(state: CoreState, mmu: Mmu) -> Self {
-        Self {
+        let mut core = Self {
             registers: [0; MAX_REGISTERS],
+            float_registers: [0; MAX_FLOAT_REGISTERS],
             state,
 
+            hart_id: 0,
+
+            device_pending: 0,
+            check_interrupts: true,
+
+            reservation: None,
+
+            execute_watchpoint: None,
+
+            environment_traps: false,
+            firmware_ecalls: false,
+            ebreak_debug: false,
+            semihosting: false,
+
+            trace: false,
+
+            coverage: None,
+
+            retired: 0,
+
+            counters: [0; csr::COUNTERS],
+            counters_inhibited: 0,
+            hpm_active: 0,
+            counters_written: 0,
+
+            stimecmp: None,
+
+            page_table: None,
+            tlb: Tlb::new(),
+
+            breakpoints: Vec::new(),
+            stop_request: Arc::new(AtomicBool::new(false)),
+            stop_on_exceptions: false,
+
+            misaligned_policy: MisalignedPolicy::Allow,
+            ialign: 2,
+
             mmu
+        };
+
+        // NOTE(patrik): misa is read-only so it's set behind the back of
+        // write_csr
+        (core.state.write_csr)(&mut core.state, csr::MISA, csr::MISA_VALUE);
+
+        core
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.retain(|breakpoint| *breakpoint != addr);
+    }
+
+    pub fn is_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    /// Flag that makes `run` return with `StopReason::StopRequested` when
+    /// set, it's cleared when `run` sees it
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop_request.clone()
+    }
+
+    pub fn set_stop_on_exceptions(&mut self, enabled: bool) {
+        self.stop_on_exceptions = enabled;
+    }
+
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn coverage_mut(&mut self) -> Option<&mut Coverage> {
+        self.coverage.as_mut()
+    }
+
+    pub fn set_environment_traps(&mut self, enabled: bool) {
+        self.environment_traps = enabled;
+    }
+
+    pub fn set_firmware_ecalls(&mut self, enabled: bool) {
+        self.firmware_ecalls = enabled;
+    }
+
+    pub fn set_ebreak_debug(&mut self, enabled: bool) {
+        self.ebreak_debug = enabled;
+    }
+
+    pub fn set_semihosting(&mut self, enabled: bool) {
+        self.semihosting = enabled;
+    }
+
+    /// Don't report an execute watchpoint for the instruction at the
+    /// current PC on the next step, used when resuming from a stop
+    pub fn skip_execute_watchpoint(&mut self) {
+        self.execute_watchpoint = Some(self.reg(Register::Pc));
+    }
+
+    /// Save the architectural state of the core, the MMU is saved
+    /// separately
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        writer.list_u64(&self.registers);
+        writer.list_u64(&self.float_registers);
+        writer.list_u64(&self.state.csr_registers);
+        writer.u8(self.state.privilege_level as u8);
+        writer.option_u64(self.reservation);
+        writer.list_u64(&self.counters);
+    }
+
+    /// Read the state written by `save` without touching the core, it's
+    /// applied with `reset_to`
+    pub fn load_snapshot(reader: &mut SnapshotReader)
+        -> Result<CoreSnapshot, String>
+    {
+        let registers = reader.list_u64(Some(MAX_REGISTERS))?;
+        let float_registers = reader.list_u64(Some(MAX_FLOAT_REGISTERS))?;
+        let csr_registers = reader.list_u64(Some(MAX_CSR_REGISTERS))?;
+        let privilege_level = match reader.u8()? {
+            level @ 0..=3 => PrivilegeLevel::from(level as u64),
+            level => {
+                return Err(format!("Snapshot: Bad privilege level: {}",
+                                   level));
+            },
+        };
+        let reservation = reader.option_u64()?;
+        let counters = reader.list_u64(Some(csr::COUNTERS))?;
+
+        let mut snapshot = CoreSnapshot {
+            registers: [0; MAX_REGISTERS],
+            float_registers: [0; MAX_FLOAT_REGISTERS],
+            csr_registers: Box::new([0; MAX_CSR_REGISTERS]),
+            privilege_level,
+            reservation,
+            counters: [0; csr::COUNTERS],
+        };
+        snapshot.registers.copy_from_slice(&registers);
+        snapshot.float_registers.copy_from_slice(&float_registers);
+        snapshot.csr_registers.copy_from_slice(&csr_registers);
+        snapshot.counters.copy_from_slice(&counters);
+
+        Ok(snapshot)
+    }
+
+    /// Take an in-memory copy of the same state as `save`
+    pub fn snapshot(&self) -> CoreSnapshot {
+        CoreSnapshot {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            csr_registers: Box::new(self.state.csr_registers),
+            privilege_level: self.state.privilege_level,
+            reservation: self.reservation,
+            counters: self.counters,
+        }
+    }
+
+    pub fn reset_to(&mut self, snapshot: &CoreSnapshot) {
+        self.registers = snapshot.registers;
+        self.float_registers = snapshot.float_registers;
+        self.state.csr_registers
+            .copy_from_slice(&snapshot.csr_registers[..]);
+        self.state.privilege_level = snapshot.privilege_level;
+        self.reservation = snapshot.reservation;
+        self.counters = snapshot.counters;
+        self.update_counters();
+        self.update_stimecmp();
+        self.update_page_table();
+
+        self.execute_watchpoint = None;
+        self.check_interrupts = true;
+    }
+
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned_policy = policy;
+    }
+
+    /// Set IALIGN in bits, only 16 and 32 are valid
+    pub fn set_ialign(&mut self, ialign: u32) {
+        match ialign {
+            16 => self.ialign = 2,
+            32 => self.ialign = 4,
+
+            _ => panic!("set_ialign: IALIGN must be 16 or 32: {}", ialign),
+        }
+
+        let misa = if self.ialign == 2 {
+            csr::MISA_VALUE
+        } else {
+            csr::MISA_VALUE & !csr::MISA_C
+        };
+        (self.state.write_csr)(&mut self.state, csr::MISA, misa);
+    }
+
+    /// Step the core at most `limit` times, until a breakpoint, a stop
+    /// request or a step the host has to see. The first step never stops
+    /// at a breakpoint so it's possible to continue from one
+    pub fn run(&mut self, limit: u64) -> RunResult {
+        let retired = self.retired;
+
+        let mut result = RunResult {
+            reason: StopReason::Limit,
+            steps: 0,
+            retired: 0,
+            last_pc: self.reg(Register::Pc),
+        };
+
+        while result.steps < limit {
+            let pc = self.reg(Register::Pc);
+            if result.steps != 0 && self.breakpoints.contains(&pc) {
+                result.reason = StopReason::Breakpoint(pc);
+                break;
+            }
+
+            if self.stop_request.load(Ordering::Relaxed) {
+                self.stop_request.store(false, Ordering::Relaxed);
+                result.reason = StopReason::StopRequested;
+                break;
+            }
+
+            result.last_pc = pc;
+            let exit = self.step();
+            result.steps += 1;
+
+            match exit {
+                CoreExit::Success | CoreExit::Interrupt(_) => {},
+                CoreExit::Exception(_) if !self.stop_on_exceptions => {},
+
+                _ => {
+                    result.reason = StopReason::Exit(exit);
+                    break;
+                },
+            }
         }
+
+        result.retired = self.retired - retired;
+
+        result
     }
 
     pub fn step(&mut self) -> CoreExit {
+        self.mmu.tick();
+
+        if let Some(shutdown) = self.mmu.interrupts().shutdown() {
+            return CoreExit::Shutdown(shutdown);
+        }
+
+        // NOTE(patrik): Every step takes one cycle, the increment is done
+        // before the instruction so a write to mcycle replaces it
+        self.counters_written = 0;
+        self.increment_counter(csr::COUNTER_CYCLE);
+
+        if let Some(interrupt) = self.pending_interrupt() {
+            let pc = self.reg(Register::Pc);
+            self.interrupt(interrupt, pc);
+
+            return CoreExit::Interrupt(interrupt);
+        }
+
         let current_pc = self.reg(Register::Pc);
 
-        let inst = self.fetch_u16();
+        let inst = match self.fetch() {
+            Ok(inst) => inst,
+            Err(exception) => {
+                self.trap(exception, current_pc);
+                return CoreExit::Exception(exception);
+            },
+        };
+
+        let resumed = self.execute_watchpoint.take() == Some(current_pc);
+        if let Some(access) = self.mmu.take_watchpoint_hit() {
+            if !resumed {
+                self.set_reg(Register::Pc, current_pc);
+                self.execute_watchpoint = Some(current_pc);
+
+                return CoreExit::Watchpoint(access);
+            }
+        }
+
+        let next_pc = self.reg(Register::Pc);
+        let events = if self.hpm_active != 0 {
+            instruction_events(&inst)
+        } else {
+            0
+        };
+
+        let result = self.execute(inst, current_pc);
+
+        // NOTE(patrik): A load or store that hit a watchpoint has still
+        // been done, unless it raised an exception
+        let watchpoint = self.mmu.take_watchpoint_hit();
+
+        match result {
+            Ok(exit) => {
+                // NOTE(patrik): EBREAK for a debugger leaves PC at the
+                // EBREAK so it never completes
+                if !(self.ebreak_debug && exit == CoreExit::Ebreak) {
+                    self.retired += 1;
+                    self.increment_counter(csr::COUNTER_INSTRET);
+
+                    if events != 0 {
+                        let taken = self.reg(Register::Pc) != next_pc;
+                        self.count_instruction_events(events, taken);
+                    }
+                }
+
+                watchpoint.map(CoreExit::Watchpoint).unwrap_or(exit)
+            },
+            Err(exception) => {
+                self.trap(exception, current_pc);
+                CoreExit::Exception(exception)
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Result<Instruction, Exception> {
+        let pc = self.reg(Register::Pc);
+        if pc & (self.ialign - 1) != 0 {
+            return Err(Exception::InstructionAddressMisaligned(pc));
+        }
+
+        let (inst, addr) = self.fetch_raw(pc)?;
         let is_compressed = (inst & 0b11) != 0b11;
 
+        let (size, raw) = if is_compressed {
+            (2, inst as u16 as u64)
+        } else {
+            (4, inst as u64)
+        };
+        // NOTE(patrik): Like loads and stores the fetch is observed at its
+        // physical address
+        self.mmu.observe(addr, size, raw, AccessKind::Execute);
+
         let inst = if is_compressed {
-            self.set_reg(Register::Pc, self.reg(Register::Pc) + 2);
-            Instruction::decode_compressed(inst)
+            // NOTE(patrik): With IALIGN=32 the C extention is not
+            // available so a compressed encoding is an illegal instruction
+            if self.ialign != 2 {
+                return Err(Exception::IllegalInstruction(inst as u64));
+            }
+
+            self.set_reg(Register::Pc, pc + 2);
+            Instruction::decode_compressed(inst as u16)
         } else {
-            let inst = self.fetch_u32();
-            self.set_reg(Register::Pc, self.reg(Register::Pc) + 4);
+            self.set_reg(Register::Pc, pc + 4);
             Instruction::decode(inst)
         };
 
-        println!("Instruction: {:?}", inst);
+        if self.trace {
+            println!("Instruction: {:?}", inst);
+        }
+
+        Ok(inst)
+    }
 
-        return match inst {
+    fn execute(&mut self, inst: Instruction, current_pc: u64)
+        -> Result<CoreExit, Exception>
+    {
+        match inst {
             Instruction::Lui { rd, imm } => {
                 self.set_reg(rd, imm as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Auipc { rd, imm } => {
                 let value = (imm as i64 as u64).wrapping_add(current_pc);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Jal { rd, imm } => {
                 let target = current_pc.wrapping_add(imm as i64 as u64);
                 let return_addr = self.reg(Register::Pc);
 
+                // NOTE(patrik): rd is only written once the jump can't trap
+                self.jump(target)?;
                 self.set_reg(rd, return_addr);
-                self.set_reg(Register::Pc, target);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Jalr { rd, rs1, imm } => {
                 let target = self.reg(rs1)
-                    .wrapping_add(imm as i64 as u64);
+                    .wrapping_add(imm as i64 as u64) & !1;
 
                 let return_addr = self.reg(Register::Pc);
+                self.jump(target)?;
                 self.set_reg(rd, return_addr);
-                self.set_reg(Register::Pc, target);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Beq { rs1, rs2, imm } => {
@@ -291,10 +929,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 == rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Bne { rs1, rs2, imm } => {
@@ -304,10 +942,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 != rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Blt { rs1, rs2, imm } => {
@@ -317,10 +955,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 < rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Bge { rs1, rs2, imm } => {
@@ -330,10 +968,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 >= rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Bltu { rs1, rs2, imm } => {
@@ -343,10 +981,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 < rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Bgeu { rs1, rs2, imm } => {
@@ -356,80 +994,80 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 >= rs2 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lb { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u8(addr);
+                let value = self.load_u8(addr)?;
                 self.set_reg(rd, value as i8 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lh { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u16(addr);
+                let value = self.load_u16(addr)?;
                 self.set_reg(rd, value as i16 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lw { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u32(addr);
+                let value = self.load_u32(addr)?;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lbu { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u8(addr);
+                let value = self.load_u8(addr)?;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lhu { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u16(addr);
+                let value = self.load_u16(addr)?;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Lwu { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u32(addr);
+                let value = self.load_u32(addr)?;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Ld { rd, rs1, imm } => {
                 let addr = self.reg(rs1)
                     .wrapping_add(imm as i64 as u64);
 
-                let value = self.mmu.read_u64(addr);
+                let value = self.load_u64(addr)?;
                 self.set_reg(rd, value as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sb { rs1, rs2, imm } => {
@@ -437,9 +1075,9 @@ impl Core {
                     .wrapping_add(imm as i64 as u64);
 
                 let value = self.reg(rs2) as u8;
-                self.mmu.write_u8(addr, value);
+                self.store_u8(addr, value)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sh { rs1, rs2, imm } => {
@@ -447,9 +1085,9 @@ impl Core {
                     .wrapping_add(imm as i64 as u64);
 
                 let value = self.reg(rs2) as u16;
-                self.mmu.write_u16(addr, value);
+                self.store_u16(addr, value)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sw { rs1, rs2, imm } => {
@@ -457,9 +1095,9 @@ impl Core {
                     .wrapping_add(imm as i64 as u64);
 
                 let value = self.reg(rs2) as u32;
-                self.mmu.write_u32(addr, value);
+                self.store_u32(addr, value)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sd { rs1, rs2, imm } => {
@@ -467,9 +1105,9 @@ impl Core {
                     .wrapping_add(imm as i64 as u64);
 
                 let value = self.reg(rs2);
-                self.mmu.write_u64(addr, value);
+                self.store_u64(addr, value)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Addi { rd, rs1, imm } => {
@@ -477,7 +1115,7 @@ impl Core {
                     .wrapping_add(imm as i64 as u64);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Slti { rd, rs1, imm } => {
@@ -490,7 +1128,7 @@ impl Core {
                     self.set_reg(rd, 0);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
             Instruction::Sltiu { rd, rs1, imm } => {
                 let rs1 = self.reg(rs1);
@@ -502,7 +1140,7 @@ impl Core {
                     self.set_reg(rd, 0);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Xori { rd, rs1, imm } => {
@@ -512,7 +1150,7 @@ impl Core {
                 let value = rs1 ^ imm;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Ori { rd, rs1, imm } => {
@@ -522,7 +1160,7 @@ impl Core {
                 let value = rs1 | imm;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Andi { rd, rs1, imm } => {
@@ -532,7 +1170,7 @@ impl Core {
                 let value = rs1 & imm;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Slli { rd, rs1, shamt } => {
@@ -541,7 +1179,7 @@ impl Core {
                 let value = rs1 << shamt;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Srli { rd, rs1, shamt } => {
@@ -550,7 +1188,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Srai { rd, rs1, shamt } => {
@@ -559,7 +1197,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Addiw { rd, rs1, imm } => {
@@ -569,7 +1207,7 @@ impl Core {
                 let value = rs1.wrapping_add(imm);
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Slliw { rd, rs1, shamt } => {
@@ -578,7 +1216,7 @@ impl Core {
                 let value = rs1 << shamt;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Srliw { rd, rs1, shamt } => {
@@ -587,7 +1225,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sraiw { rd, rs1, shamt } => {
@@ -596,7 +1234,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Add { rd, rs1, rs2 } => {
@@ -606,7 +1244,7 @@ impl Core {
                 let value = rs1.wrapping_add(rs2);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sub { rd, rs1, rs2 } => {
@@ -616,7 +1254,7 @@ impl Core {
                 let value = rs1.wrapping_sub(rs2);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sll { rd, rs1, rs2 } => {
@@ -628,7 +1266,7 @@ impl Core {
                 let value = rs1 << shamt;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Slt { rd, rs1, rs2 } => {
@@ -641,7 +1279,7 @@ impl Core {
                     self.set_reg(rd, 0);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sltu { rd, rs1, rs2 } => {
@@ -654,7 +1292,7 @@ impl Core {
                     self.set_reg(rd, 0);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Xor { rd, rs1, rs2 } => {
@@ -664,7 +1302,7 @@ impl Core {
                 let value = rs1 ^ rs2;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Srl { rd, rs1, rs2 } => {
@@ -675,7 +1313,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sra { rd, rs1, rs2 } => {
@@ -686,7 +1324,7 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Or { rd, rs1, rs2 } => {
@@ -696,7 +1334,7 @@ impl Core {
                 let value = rs1 | rs2;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::And { rd, rs1, rs2 } => {
@@ -706,7 +1344,7 @@ impl Core {
                 let value = rs1 & rs2;
                 self.set_reg(rd, value as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Mul { rd, rs1, rs2 } => {
@@ -716,7 +1354,7 @@ impl Core {
                 let value = rs1.wrapping_mul(rs2);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Mulh { rd, rs1, rs2 } => {
@@ -726,7 +1364,7 @@ impl Core {
                 let value = rs1.wrapping_mul(rs2);
                 self.set_reg(rd, (value >> 64) as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Mulhsu { rd, rs1, rs2 } => {
@@ -736,7 +1374,7 @@ impl Core {
                 let value = rs1.wrapping_mul(rs2);
                 self.set_reg(rd, (value >> 64) as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Mulhu { rd, rs1, rs2 } => {
@@ -746,7 +1384,7 @@ impl Core {
                 let value = rs1.wrapping_mul(rs2);
                 self.set_reg(rd, (value >> 64) as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Div { rd, rs1, rs2 } => {
@@ -760,7 +1398,7 @@ impl Core {
                     self.set_reg(rd, value as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Divu { rd, rs1, rs2 } => {
@@ -774,7 +1412,7 @@ impl Core {
                     self.set_reg(rd, value);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Rem { rd, rs1, rs2 } => {
@@ -788,7 +1426,7 @@ impl Core {
                     self.set_reg(rd, value as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Remu { rd, rs1, rs2 } => {
@@ -802,7 +1440,7 @@ impl Core {
                     self.set_reg(rd, value);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Addw { rd, rs1, rs2 } => {
@@ -812,7 +1450,7 @@ impl Core {
                 let value = rs1.wrapping_add(rs2);
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Subw { rd, rs1, rs2 } => {
@@ -822,7 +1460,7 @@ impl Core {
                 let value = rs1.wrapping_sub(rs2);
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sllw { rd, rs1, rs2 } => {
@@ -833,7 +1471,7 @@ impl Core {
                 let value = rs1 << shamt;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Srlw { rd, rs1, rs2 } => {
@@ -844,18 +1482,18 @@ impl Core {
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Sraw { rd, rs1, rs2 } => {
-                let rs1 = self.reg(rs1) as u32;
+                let rs1 = self.reg(rs1) as u32 as i32;
                 let rs2 = self.reg(rs2);
                 let shamt = rs2 & 0b11111;
 
                 let value = rs1 >> shamt;
                 self.set_reg(rd, value as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Mulw { rd, rs1, rs2 } => {
@@ -865,7 +1503,7 @@ impl Core {
                 let value = rs1.wrapping_mul(rs2);
                 self.set_reg(rd, value as i32 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Divw { rd, rs1, rs2 } => {
@@ -879,7 +1517,7 @@ impl Core {
                     self.set_reg(rd, value as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Divuw { rd, rs1, rs2 } => {
@@ -893,7 +1531,7 @@ impl Core {
                     self.set_reg(rd, value as i32 as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Remw { rd, rs1, rs2 } => {
@@ -901,13 +1539,13 @@ impl Core {
                 let rs2 = self.reg(rs2) as u32 as i32;
 
                 if rs2 == 0 {
-                    self.set_reg(rd, u32::MAX as i32 as u64);
+                    self.set_reg(rd, rs1 as u64);
                 } else {
                     let value = rs1.wrapping_rem(rs2);
                     self.set_reg(rd, value as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Remuw { rd, rs1, rs2 } => {
@@ -915,21 +1553,119 @@ impl Core {
                 let rs2 = self.reg(rs2) as u32;
 
                 if rs2 == 0 {
-                    self.set_reg(rd, u32::MAX as i32 as u64);
+                    self.set_reg(rd, rs1 as i32 as u64);
                 } else {
                     let value = rs1.wrapping_rem(rs2);
                     self.set_reg(rd, value as i32 as u64);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fence { .. } => Ok(CoreExit::Success),
+
+            // NOTE(patrik): Instructions are fetched from memory every time
+            // so there is nothing to synchronize
+            Instruction::FenceI => Ok(CoreExit::Success),
+
+            // NOTE(patrik): The whole TLB is flushed no matter the address
+            // and address space
+            Instruction::SfenceVma { .. } => {
+                if self.privilege_level() == PrivilegeLevel::User {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+                self.check_virtual_memory_access()?;
+
+                self.tlb.flush();
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Ecall => self.ecall(),
+            Instruction::Ebreak => self.ebreak(current_pc),
+
+            Instruction::Mret => {
+                if self.privilege_level() != PrivilegeLevel::Machine {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+
+                // NOTE(patrik): Jump first, nothing may change if the
+                // target traps
+                let target = self.read_csr(csr::MEPC);
+                self.jump(target)?;
+
+                let status = self.read_csr(csr::MSTATUS);
+                let mpp = (status & csr::MSTATUS_MPP) >>
+                    csr::MSTATUS_MPP_SHIFT;
+                let mpie = (status & csr::MSTATUS_MPIE) != 0;
+
+                let mut status = status &
+                    !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+                status |= csr::MSTATUS_MPIE;
+                if mpie {
+                    status |= csr::MSTATUS_MIE;
+                }
+                // NOTE(patrik): MPRV only stays set when returning to
+                // machine mode
+                if mpp != PrivilegeLevel::Machine as u64 {
+                    status &= !csr::MSTATUS_MPRV;
+                }
+                self.write_csr(csr::MSTATUS, status);
+
+                self.set_privilege_level(PrivilegeLevel::from(mpp));
+                self.reservation = None;
+
+                Ok(CoreExit::Success)
             },
 
-            Instruction::Fence { .. } => CoreExit::Success,
+            Instruction::Sret => {
+                let status = self.read_csr(csr::MSTATUS);
+
+                // NOTE(patrik): mstatus.TSR lets machine mode emulate SRET
+                // for supervisor mode
+                let trapped = match self.privilege_level() {
+                    PrivilegeLevel::Machine => false,
+                    PrivilegeLevel::Supervisor => {
+                        status & csr::MSTATUS_TSR != 0
+                    },
+                    _ => true,
+                };
+                if trapped {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+
+                let target = self.read_csr(csr::SEPC);
+                self.jump(target)?;
+
+                let spp = (status & csr::MSTATUS_SPP) != 0;
+                let spie = (status & csr::MSTATUS_SPIE) != 0;
+
+                let mut status = status & !(csr::MSTATUS_SIE |
+                                            csr::MSTATUS_SPP |
+                                            csr::MSTATUS_MPRV);
+                status |= csr::MSTATUS_SPIE;
+                if spie {
+                    status |= csr::MSTATUS_SIE;
+                }
+                self.write_csr(csr::MSTATUS, status);
+
+                if spp {
+                    self.set_privilege_level(PrivilegeLevel::Supervisor);
+                } else {
+                    self.set_privilege_level(PrivilegeLevel::User);
+                }
+                self.reservation = None;
 
-            Instruction::Ecall => CoreExit::Ecall,
-            Instruction::Ebreak => CoreExit::Ebreak,
+                Ok(CoreExit::Success)
+            },
+
+            // NOTE(patrik): Interrupts are checked before every instruction
+            // so WFI can be a nop
+            Instruction::Wfi => Ok(CoreExit::Success),
 
             Instruction::Csrrw { rd, rs1, csr } => {
+                self.check_csr_access(csr, true)?;
+
                 // NOTE(patrik): Doing this because the spec says that if the
                 // Zero/x0 register is used for rd then don't read the csr
                 // and if we change self.read_csr to have side effect we don't
@@ -944,10 +1680,12 @@ impl Core {
                 let rs1 = self.reg(rs1);
                 self.write_csr(csr, rs1);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Csrrs { rd, rs1, csr } => {
+                self.check_csr_access(csr, rs1 != Register::Zero)?;
+
                 let value = self.read_csr(csr);
                 self.set_reg(rd, value);
 
@@ -958,10 +1696,12 @@ impl Core {
                     self.write_csr(csr, value | rs1);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Csrrc { rd, rs1, csr } => {
+                self.check_csr_access(csr, rs1 != Register::Zero)?;
+
                 let value = self.read_csr(csr);
                 self.set_reg(rd, value);
 
@@ -972,10 +1712,12 @@ impl Core {
                     self.write_csr(csr, value & !rs1);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Csrrwi { rd, uimm, csr } => {
+                self.check_csr_access(csr, true)?;
+
                 // NOTE(patrik): Doing this because the spec says that if the
                 // Zero/x0 register is used for rd then don't read the csr
                 // and if we change self.read_csr to have side effect we don't
@@ -989,10 +1731,12 @@ impl Core {
 
                 self.write_csr(csr, uimm as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Csrrsi { rd, uimm, csr } => {
+                self.check_csr_access(csr, uimm != 0)?;
+
                 let value = self.read_csr(csr);
                 self.set_reg(rd, value);
 
@@ -1002,10 +1746,12 @@ impl Core {
                     self.write_csr(csr, value | uimm);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Csrrci { rd, uimm, csr } => {
+                self.check_csr_access(csr, uimm != 0)?;
+
                 let value = self.read_csr(csr);
                 self.set_reg(rd, value);
 
@@ -1015,240 +1761,587 @@ impl Core {
                     self.write_csr(csr, value & !uimm);
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             // A Extention
 
-            // Lrw      { rd: Register, rs1: Register, aq: bool, rl: bool },
-            // Scw      { rd: Register, rs1: Register, rs2: Register, aq: bool, rl: bool },
+            Instruction::Lrw { rd, rs1, .. } => {
+                let addr = self.reg(rs1);
+                self.check_amo_alignment(addr, 4, false)?;
+
+                let value = self.load(addr, 4)? as u32;
+                self.reservation = Some(addr);
+
+                self.set_reg(rd, value as i32 as i64 as u64);
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Scw { rd, rs1, rs2, .. } => {
+                let rs2 = self.reg(rs2) as u32;
+                let addr = self.reg(rs1);
+                self.check_amo_alignment(addr, 4, true)?;
+
+                if self.reservation.take() == Some(addr) {
+                    self.amo_store_u32(addr, rs2)?;
+                    self.set_reg(rd, 0);
+                } else {
+                    self.set_reg(rd, 1);
+                }
+
+                Ok(CoreExit::Success)
+            },
 
             Instruction::Amoswapw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
-                self.mmu.write_u32(addr, rs2);
+                self.amo_store_u32(addr, rs2)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoaddw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = old.wrapping_add(rs2);
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoxorw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = old ^ rs2;
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoandw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = old & rs2;
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoorw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = old | rs2;
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amominw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = std::cmp::min(old as i32, rs2 as i32);
-                self.mmu.write_u32(addr, value as u32);
+                self.amo_store_u32(addr, value as u32)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amomaxw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = std::cmp::max(old as i32, rs2 as i32);
-                self.mmu.write_u32(addr, value as u32);
+                self.amo_store_u32(addr, value as u32)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amominuw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = std::cmp::min(old, rs2);
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amomaxuw { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2) as u32;
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u32(addr);
+                let old = self.amo_load_u32(addr)?;
 
                 let value = std::cmp::max(old, rs2);
-                self.mmu.write_u32(addr, value);
+                self.amo_store_u32(addr, value)?;
 
                 self.set_reg(rd, old as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Lrd { rd, rs1, .. } => {
+                let addr = self.reg(rs1);
+                self.check_amo_alignment(addr, 8, false)?;
+
+                let value = self.load(addr, 8)?;
+                self.reservation = Some(addr);
+
+                self.set_reg(rd, value);
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Scd { rd, rs1, rs2, .. } => {
+                let rs2 = self.reg(rs2);
+                let addr = self.reg(rs1);
+                self.check_amo_alignment(addr, 8, true)?;
+
+                if self.reservation.take() == Some(addr) {
+                    self.amo_store_u64(addr, rs2)?;
+                    self.set_reg(rd, 0);
+                } else {
+                    self.set_reg(rd, 1);
+                }
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Amoswapd { rd, rs1, rs2, .. } => {
+                let rs2 = self.reg(rs2);
+                let addr = self.reg(rs1);
+                let old = self.amo_load_u64(addr)?;
+
+                self.amo_store_u64(addr, rs2)?;
+
+                self.set_reg(rd, old);
+
+                Ok(CoreExit::Success)
             },
 
-            // Lrd      { rd: Register, rs1: Register, aq: bool, rl: bool},
-            // Scd      { rd: Register, rs1: Register, rs2: Register, aq: bool, rl: bool },
-            // Amoswapd { rd: Register, rs1: Register, rs2: Register, aq: bool, rl: bool },
             Instruction::Amoaddd { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = old.wrapping_add(rs2);
-                self.mmu.write_u64(addr, value);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoxord { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = old ^ rs2;
-                self.mmu.write_u64(addr, value);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoandd { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = old & rs2;
-                self.mmu.write_u64(addr, value);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amoord { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = old | rs2;
-                self.mmu.write_u64(addr, value);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amomind { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = std::cmp::min(old as i64, rs2 as i64);
-                self.mmu.write_u64(addr, value as u64);
+                self.amo_store_u64(addr, value as u64)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amomaxd { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = std::cmp::max(old as i64, rs2 as i64);
-                self.mmu.write_u64(addr, value as u64);
+                self.amo_store_u64(addr, value as u64)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amominud { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
-                let value = std::cmp::max(old, rs2);
-                self.mmu.write_u64(addr, value);
+                let value = std::cmp::min(old, rs2);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Amomaxud { rd, rs1, rs2, .. } => {
                 let rs2 = self.reg(rs2);
                 let addr = self.reg(rs1);
-                let old = self.mmu.read_u64(addr);
+                let old = self.amo_load_u64(addr)?;
 
                 let value = std::cmp::max(old, rs2);
-                self.mmu.write_u64(addr, value);
+                self.amo_store_u64(addr, value)?;
 
                 self.set_reg(rd, old);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
-            // C Extention
+            // F and D Extentions
 
-            Instruction::Hint => CoreExit::Success,
+            Instruction::Flw { rd, rs1, imm } => {
+                self.check_float_enabled()?;
+                let addr = self.reg(rs1)
+                    .wrapping_add(imm as i64 as u64);
+
+                let value = self.load_u32(addr)?;
+                self.set_float_reg(rd, f32::rebox(value as u64));
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fld { rd, rs1, imm } => {
+                self.check_float_enabled()?;
+                let addr = self.reg(rs1)
+                    .wrapping_add(imm as i64 as u64);
+
+                let value = self.load_u64(addr)?;
+                self.set_float_reg(rd, value);
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fsw { rs1, rs2, imm } => {
+                self.check_float_enabled()?;
+                let addr = self.reg(rs1)
+                    .wrapping_add(imm as i64 as u64);
+
+                let value = self.float_reg(rs2) as u32;
+                self.store_u32(addr, value)?;
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fsd { rs1, rs2, imm } => {
+                self.check_float_enabled()?;
+                let addr = self.reg(rs1)
+                    .wrapping_add(imm as i64 as u64);
+
+                let value = self.float_reg(rs2);
+                self.store_u64(addr, value)?;
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fmadds { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f32>(rd, rs1, rs2, rs3, rm, false, false)
+            },
+            Instruction::Fmsubs { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f32>(rd, rs1, rs2, rs3, rm, false, true)
+            },
+            Instruction::Fnmsubs { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f32>(rd, rs1, rs2, rs3, rm, true, false)
+            },
+            Instruction::Fnmadds { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f32>(rd, rs1, rs2, rs3, rm, true, true)
+            },
+
+            Instruction::Fadds { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f32>(rd, rs1, rs2, rm, float::add)
+            },
+            Instruction::Fsubs { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f32>(rd, rs1, rs2, rm, float::sub)
+            },
+            Instruction::Fmuls { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f32>(rd, rs1, rs2, rm, float::mul)
+            },
+            Instruction::Fdivs { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f32>(rd, rs1, rs2, rm, float::div)
+            },
+            Instruction::Fsqrts { rd, rs1, rm } => {
+                self.float_sqrt::<f32>(rd, rs1, rm)
+            },
+
+            Instruction::Fsgnjs { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f32>(rd, rs1, rs2, |_, b| b)
+            },
+            Instruction::Fsgnjns { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f32>(rd, rs1, rs2, |_, b| !b)
+            },
+            Instruction::Fsgnjxs { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f32>(rd, rs1, rs2, |a, b| a ^ b)
+            },
+
+            Instruction::Fmins { rd, rs1, rs2 } => {
+                self.float_min_max::<f32>(rd, rs1, rs2, false)
+            },
+            Instruction::Fmaxs { rd, rs1, rs2 } => {
+                self.float_min_max::<f32>(rd, rs1, rs2, true)
+            },
+
+            Instruction::Feqs { rd, rs1, rs2 } => {
+                self.float_compare::<f32>(rd, rs1, rs2, &[cmp::Ordering::Equal],
+                                          true)
+            },
+            Instruction::Flts { rd, rs1, rs2 } => {
+                self.float_compare::<f32>(rd, rs1, rs2, &[cmp::Ordering::Less],
+                                          false)
+            },
+            Instruction::Fles { rd, rs1, rs2 } => {
+                let accepted = [cmp::Ordering::Less, cmp::Ordering::Equal];
+                self.float_compare::<f32>(rd, rs1, rs2, &accepted, false)
+            },
+
+            Instruction::Fclasss { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = f32::from_register(self.float_reg(rs1));
+                self.set_reg(rd, float::classify(value));
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fcvtws { rd, rs1, rm } => {
+                self.float_to_int::<f32>(rd, rs1, rm, i32::MIN as i128,
+                                         i32::MAX as i128, true)
+            },
+            Instruction::Fcvtwus { rd, rs1, rm } => {
+                self.float_to_int::<f32>(rd, rs1, rm, 0, u32::MAX as i128,
+                                         true)
+            },
+            Instruction::Fcvtls { rd, rs1, rm } => {
+                self.float_to_int::<f32>(rd, rs1, rm, i64::MIN as i128,
+                                         i64::MAX as i128, false)
+            },
+            Instruction::Fcvtlus { rd, rs1, rm } => {
+                self.float_to_int::<f32>(rd, rs1, rm, 0, u64::MAX as i128,
+                                         false)
+            },
+
+            Instruction::Fcvtsw { rd, rs1, rm } => {
+                self.float_from_int::<f32>(rd, rs1, rm,
+                                           |value| value as i32 as i128)
+            },
+            Instruction::Fcvtswu { rd, rs1, rm } => {
+                self.float_from_int::<f32>(rd, rs1, rm,
+                                           |value| value as u32 as i128)
+            },
+            Instruction::Fcvtsl { rd, rs1, rm } => {
+                self.float_from_int::<f32>(rd, rs1, rm,
+                                           |value| value as i64 as i128)
+            },
+            Instruction::Fcvtslu { rd, rs1, rm } => {
+                self.float_from_int::<f32>(rd, rs1, rm, |value| value as i128)
+            },
+
+            Instruction::Fmaddd { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f64>(rd, rs1, rs2, rs3, rm, false, false)
+            },
+            Instruction::Fmsubd { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f64>(rd, rs1, rs2, rs3, rm, false, true)
+            },
+            Instruction::Fnmsubd { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f64>(rd, rs1, rs2, rs3, rm, true, false)
+            },
+            Instruction::Fnmaddd { rd, rs1, rs2, rs3, rm } => {
+                self.float_fused::<f64>(rd, rs1, rs2, rs3, rm, true, true)
+            },
+
+            Instruction::Faddd { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f64>(rd, rs1, rs2, rm, float::add)
+            },
+            Instruction::Fsubd { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f64>(rd, rs1, rs2, rm, float::sub)
+            },
+            Instruction::Fmuld { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f64>(rd, rs1, rs2, rm, float::mul)
+            },
+            Instruction::Fdivd { rd, rs1, rs2, rm } => {
+                self.float_arithmetic::<f64>(rd, rs1, rs2, rm, float::div)
+            },
+            Instruction::Fsqrtd { rd, rs1, rm } => {
+                self.float_sqrt::<f64>(rd, rs1, rm)
+            },
+
+            Instruction::Fsgnjd { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f64>(rd, rs1, rs2, |_, b| b)
+            },
+            Instruction::Fsgnjnd { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f64>(rd, rs1, rs2, |_, b| !b)
+            },
+            Instruction::Fsgnjxd { rd, rs1, rs2 } => {
+                self.float_sign_injection::<f64>(rd, rs1, rs2, |a, b| a ^ b)
+            },
+
+            Instruction::Fmind { rd, rs1, rs2 } => {
+                self.float_min_max::<f64>(rd, rs1, rs2, false)
+            },
+            Instruction::Fmaxd { rd, rs1, rs2 } => {
+                self.float_min_max::<f64>(rd, rs1, rs2, true)
+            },
+
+            Instruction::Feqd { rd, rs1, rs2 } => {
+                self.float_compare::<f64>(rd, rs1, rs2, &[cmp::Ordering::Equal],
+                                          true)
+            },
+            Instruction::Fltd { rd, rs1, rs2 } => {
+                self.float_compare::<f64>(rd, rs1, rs2, &[cmp::Ordering::Less],
+                                          false)
+            },
+            Instruction::Fled { rd, rs1, rs2 } => {
+                let accepted = [cmp::Ordering::Less, cmp::Ordering::Equal];
+                self.float_compare::<f64>(rd, rs1, rs2, &accepted, false)
+            },
+
+            Instruction::Fclassd { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = f64::from_register(self.float_reg(rs1));
+                self.set_reg(rd, float::classify(value));
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fcvtwd { rd, rs1, rm } => {
+                self.float_to_int::<f64>(rd, rs1, rm, i32::MIN as i128,
+                                         i32::MAX as i128, true)
+            },
+            Instruction::Fcvtwud { rd, rs1, rm } => {
+                self.float_to_int::<f64>(rd, rs1, rm, 0, u32::MAX as i128,
+                                         true)
+            },
+            Instruction::Fcvtld { rd, rs1, rm } => {
+                self.float_to_int::<f64>(rd, rs1, rm, i64::MIN as i128,
+                                         i64::MAX as i128, false)
+            },
+            Instruction::Fcvtlud { rd, rs1, rm } => {
+                self.float_to_int::<f64>(rd, rs1, rm, 0, u64::MAX as i128,
+                                         false)
+            },
+
+            Instruction::Fcvtdw { rd, rs1, rm } => {
+                self.float_from_int::<f64>(rd, rs1, rm,
+                                           |value| value as i32 as i128)
+            },
+            Instruction::Fcvtdwu { rd, rs1, rm } => {
+                self.float_from_int::<f64>(rd, rs1, rm,
+                                           |value| value as u32 as i128)
+            },
+            Instruction::Fcvtdl { rd, rs1, rm } => {
+                self.float_from_int::<f64>(rd, rs1, rm,
+                                           |value| value as i64 as i128)
+            },
+            Instruction::Fcvtdlu { rd, rs1, rm } => {
+                self.float_from_int::<f64>(rd, rs1, rm, |value| value as i128)
+            },
+
+            Instruction::Fcvtsd { rd, rs1, rm } => {
+                self.float_convert::<f64, f32>(rd, rs1, rm)
+            },
+            Instruction::Fcvtds { rd, rs1, rm } => {
+                self.float_convert::<f32, f64>(rd, rs1, rm)
+            },
+
+            // NOTE(patrik): The moves copy the bits as they are, singles
+            // are sign extended into the integer register
+            Instruction::Fmvxw { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = self.float_reg(rs1) as u32;
+                self.set_reg(rd, value as i32 as i64 as u64);
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fmvwx { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = self.reg(rs1) as u32;
+                self.set_float_reg(rd, f32::rebox(value as u64));
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fmvxd { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = self.float_reg(rs1);
+                self.set_reg(rd, value);
+
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::Fmvdx { rd, rs1 } => {
+                self.check_float_enabled()?;
+                let value = self.reg(rs1);
+                self.set_float_reg(rd, value);
+
+                Ok(CoreExit::Success)
+            },
+
+            // C Extention
+
+            Instruction::Hint => Ok(CoreExit::Success),
 
             // Quad 0
             Instruction::CAddi4spn { rd, nzuimm } => {
@@ -1258,10 +2351,19 @@ impl Core {
                     .wrapping_add(nzuimm);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
-            // CFld      { rd:  Register, rs1: Register, uimm: u32 },
+            Instruction::CFld { rd, rs1, uimm } => {
+                self.check_float_enabled()?;
+                let uimm = uimm as u64;
+                let addr = self.reg(rs1).wrapping_add(uimm);
+
+                let value = self.load_u64(addr)?;
+                self.set_float_reg(rd, value);
+
+                Ok(CoreExit::Success)
+            },
 
             Instruction::CLw { rd, rs1, uimm } => {
                 let uimm = uimm as u64;
@@ -1269,10 +2371,10 @@ impl Core {
 
                 let addr = rs1.wrapping_add(uimm);
 
-                let value = self.mmu.read_u32(addr);
+                let value = self.load_u32(addr)?;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CLd { rd, rs1, uimm } => {
@@ -1281,13 +2383,23 @@ impl Core {
 
                 let addr = rs1.wrapping_add(uimm);
 
-                let value = self.mmu.read_u64(addr);
+                let value = self.load_u64(addr)?;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::CFsd { rs1, rs2, uimm } => {
+                self.check_float_enabled()?;
+                let uimm = uimm as u64;
+                let addr = self.reg(rs1).wrapping_add(uimm);
+
+                let value = self.float_reg(rs2);
+                self.store_u64(addr, value)?;
+
+                Ok(CoreExit::Success)
             },
 
-            // CFsd      { rs1: Register, rs2: Register, uimm: u32 },
             Instruction::CSw { rs1, rs2, uimm } => {
                 let uimm = uimm as u64;
                 let rs1 = self.reg(rs1);
@@ -1295,9 +2407,9 @@ impl Core {
 
                 let addr = rs1.wrapping_add(uimm);
 
-                self.mmu.write_u32(addr, rs2);
+                self.store_u32(addr, rs2)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CSd { rs1, rs2, uimm } => {
@@ -1307,13 +2419,13 @@ impl Core {
 
                 let addr = rs1.wrapping_add(uimm);
 
-                self.mmu.write_u64(addr, rs2);
+                self.store_u64(addr, rs2)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             // Quad 1
-            Instruction::CNop => CoreExit::Success,
+            Instruction::CNop => Ok(CoreExit::Success),
 
             Instruction::CAddi { reg, nzimm } => {
                 let rs1 = self.reg(reg);
@@ -1321,7 +2433,7 @@ impl Core {
                 let value = rs1.wrapping_add(nzimm as i64 as u64);
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             }
 
             Instruction::CAddiw { reg, imm } => {
@@ -1331,14 +2443,14 @@ impl Core {
                 let value = rs1.wrapping_add(imm);
                 self.set_reg(reg, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CLi { rd, imm } => {
                 let value = imm as i64 as u64;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CAddi16sp { nzimm } => {
@@ -1348,14 +2460,14 @@ impl Core {
                     .wrapping_add(nzimm as i64 as u64);
                 self.set_reg(Register::Sp, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CLui { rd, nzimm } => {
                 let value = nzimm as i64 as u64;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CAndi { reg, imm } => {
@@ -1365,7 +2477,7 @@ impl Core {
                 let value = rs1 & imm;
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CSub { reg, rs2 } => {
@@ -1375,7 +2487,7 @@ impl Core {
                 let value = rs1.wrapping_sub(rs2);
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CXor { reg, rs2 } => {
@@ -1385,7 +2497,7 @@ impl Core {
                 let value = rs1 ^ rs2;
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::COr { reg, rs2 } => {
@@ -1395,7 +2507,7 @@ impl Core {
                 let value = rs1 | rs2;
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CAnd { reg, rs2 } => {
@@ -1405,7 +2517,7 @@ impl Core {
                 let value = rs1 & rs2;
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CSubw { reg, rs2 } => {
@@ -1415,7 +2527,7 @@ impl Core {
                 let value = rs1.wrapping_sub(rs2);
                 self.set_reg(reg, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CAddw { reg, rs2 } => {
@@ -1425,7 +2537,7 @@ impl Core {
                 let value = rs1.wrapping_add(rs2);
                 self.set_reg(reg, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CJ { imm } => {
@@ -1433,9 +2545,9 @@ impl Core {
 
                 let target = current_pc
                     .wrapping_add(imm);
-                self.set_reg(Register::Pc, target);
+                self.jump(target)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CBeqz { rs1, imm } => {
@@ -1444,10 +2556,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 == 0 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CBnez { rs1, imm } => {
@@ -1456,10 +2568,10 @@ impl Core {
                 let target = current_pc.wrapping_add(imm);
 
                 if rs1 != 0 {
-                    self.set_reg(Register::Pc, target);
+                    self.jump(target)?;
                 }
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             // Quad 2
@@ -1469,10 +2581,20 @@ impl Core {
                 let value = rs1 << nzuimm;
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::CFldsp { rd, uimm } => {
+                self.check_float_enabled()?;
+                let uimm = uimm as u64;
+                let addr = self.reg(Register::Sp).wrapping_add(uimm);
+
+                let value = self.load_u64(addr)?;
+                self.set_float_reg(rd, value);
+
+                Ok(CoreExit::Success)
             },
 
-            // CFldsp { rd:  Register, uimm:   u32 },
 
             Instruction::CLwsp { rd, uimm } => {
                 let uimm = uimm as u64;
@@ -1480,10 +2602,10 @@ impl Core {
                 let addr = self.reg(Register::Sp)
                     .wrapping_add(uimm);
 
-                let value = self.mmu.read_u32(addr);
+                let value = self.load_u32(addr)?;
                 self.set_reg(rd, value as i32 as i64 as u64);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CLdsp { rd, uimm } => {
@@ -1492,38 +2614,36 @@ impl Core {
                 let addr = self.reg(Register::Sp)
                     .wrapping_add(uimm);
 
-                let value = self.mmu.read_u64(addr);
+                let value = self.load_u64(addr)?;
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CJr { rs1 } => {
-                let target = self.reg(rs1);
-                self.set_reg(Register::Pc, target);
+                let target = self.reg(rs1) & !1;
+                self.jump(target)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CMv { rd, rs2 } => {
                 let value = self.reg(rs2);
                 self.set_reg(rd, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
-            Instruction::CEbreak => {
-                CoreExit::Ebreak
-            },
+            Instruction::CEbreak => self.ebreak(current_pc),
 
             Instruction::CJalr { rs1 } => {
-                let target = self.reg(rs1);
+                let target = self.reg(rs1) & !1;
                 let return_addr = self.reg(Register::Pc);
 
+                self.jump(target)?;
                 self.set_reg(Register::Ra, return_addr);
-                self.set_reg(Register::Pc, target);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::CAdd { reg, rs2 } => {
@@ -1533,10 +2653,20 @@ impl Core {
                 let value = rs1.wrapping_add(rs2);
                 self.set_reg(reg, value);
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
+            },
+
+            Instruction::CFsdsp { rs2, uimm } => {
+                self.check_float_enabled()?;
+                let uimm = uimm as u64;
+                let addr = self.reg(Register::Sp).wrapping_add(uimm);
+
+                let value = self.float_reg(rs2);
+                self.store_u64(addr, value)?;
+
+                Ok(CoreExit::Success)
             },
 
-            // CFsdsp { rs2: Register, uimm: u32 },
             Instruction::CSwsp { rs2, uimm } => {
                 let rs2 = self.reg(rs2) as u32;
                 let uimm = uimm as u64;
@@ -1544,9 +2674,9 @@ impl Core {
                 let addr = self.reg(Register::Sp)
                     .wrapping_add(uimm);
 
-                self.mmu.write_u32(addr, rs2);
+                self.store_u32(addr, rs2)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             }
 
             Instruction::CSdsp { rs2, uimm } => {
@@ -1556,9 +2686,9 @@ impl Core {
                 let addr = self.reg(Register::Sp)
                     .wrapping_add(uimm);
 
-                self.mmu.write_u64(addr, rs2);
+                self.store_u64(addr, rs2)?;
 
-                CoreExit::Success
+                Ok(CoreExit::Success)
             },
 
             Instruction::Undefined(inst) => {
@@ -1619,10 +2749,7 @@ impl Core {
                        funct3: 0b{:03b} {:#x} at PC: {:#x}",
                        quad, funct3, inst, current_pc);
             }
-
-            _ => unimplemented!("Unimplemented instruction: {:?} at PC: {:#x}",
-                                inst, current_pc),
-        };
+        }
     }
 
     pub fn write_csr(&mut self, csr: u16, value: u64) {
@@ -1631,7 +2758,100 @@ impl Core {
                    csr);
         }
 
+        if let Some(counter) = csr::machine_counter(csr) {
+            self.counters[counter] = value;
+            self.counters_written |= 1 << counter;
+            return;
+        }
+
+        // NOTE(patrik): The user mode counters are read-only, writes from
+        // instructions trap before getting here
+        if csr::user_counter(csr).is_some() {
+            return;
+        }
+
+        // NOTE(patrik): The supervisor views only change the bits they
+        // show of the machine registers
+        let (csr, value) = match csr {
+            csr::SSTATUS => {
+                let mstatus = self.read_csr(csr::MSTATUS);
+                (csr::MSTATUS, (mstatus & !csr::SSTATUS_MASK) |
+                 (value & csr::SSTATUS_MASK))
+            },
+            csr::SIE => {
+                let mask = self.read_csr(csr::MIDELEG);
+                let mie = self.read_csr(csr::MIE);
+                (csr::MIE, (mie & !mask) | (value & mask))
+            },
+            csr::SIP => {
+                let mask = self.read_csr(csr::MIDELEG) & csr::MIP_SSIP;
+                let mip = self.read_csr(csr::MIP);
+                (csr::MIP, (mip & !mask) | (value & mask))
+            },
+            csr::FFLAGS => {
+                let fcsr = self.read_csr(csr::FCSR);
+                let mask = csr::FCSR_FFLAGS_MASK;
+                (csr::FCSR, (fcsr & !mask) | (value & mask))
+            },
+            csr::FRM => {
+                let fcsr = self.read_csr(csr::FCSR);
+                let mask = csr::FCSR_FFLAGS_MASK;
+                (csr::FCSR, (fcsr & mask) |
+                 (value & 0b111) << csr::FCSR_FRM_SHIFT)
+            },
+
+            _ => (csr, value),
+        };
+
+        let value = match csr {
+            // NOTE(patrik): time can't be inhibited so bit 1 is hardwired
+            // to zero
+            csr::MCOUNTINHIBIT => value & 0xffff_fffd,
+            _ if csr::is_hpm_event(csr) => {
+                if csr::is_supported_event(value) { value } else { 0 }
+            },
+            csr::MSTATUS => {
+                // NOTE(patrik): SD summarizes if FS or XS is dirty
+                let dirty = value & csr::MSTATUS_FS == csr::MSTATUS_FS ||
+                    value & csr::MSTATUS_XS == csr::MSTATUS_XS;
+                if dirty {
+                    value | csr::MSTATUS_SD
+                } else {
+                    value & !csr::MSTATUS_SD
+                }
+            },
+            csr::FCSR => value & csr::FCSR_MASK,
+            csr::SATP => {
+                let mode = value >> csr::SATP_MODE_SHIFT;
+                if mode != csr::SATP_MODE_BARE && mode != csr::SATP_MODE_SV39 {
+                    return;
+                }
+
+                value
+            },
+            // NOTE(patrik): misa can't be used to turn extentions off
+            csr::MISA => return,
+            _ => value,
+        };
+
         (self.state.write_csr)(&mut self.state, csr, value);
+        self.check_interrupts = true;
+
+        if csr == csr::MCOUNTINHIBIT || csr::is_hpm_event(csr) {
+            self.update_counters();
+        }
+
+        if csr == csr::STIMECMP || csr == csr::MENVCFG {
+            self.update_stimecmp();
+        }
+
+        if csr == csr::SATP {
+            self.update_page_table();
+        }
+
+        if csr == csr::FCSR {
+            self.set_float_dirty();
+        }
     }
 
     pub fn read_csr(&self, csr: u16) -> u64 {
@@ -1640,63 +2860,1369 @@ impl Core {
                    csr);
         }
 
-        (self.state.read_csr)(&self.state, csr)
+        let counter = csr::user_counter(csr)
+            .or_else(|| csr::machine_counter(csr));
+        if let Some(counter) = counter {
+            if counter == csr::COUNTER_TIME {
+                return self.mmu.interrupts().time();
+            }
+
+            return self.counters[counter];
+        }
+
+        match csr {
+            csr::SSTATUS => self.read_csr(csr::MSTATUS) & csr::SSTATUS_MASK,
+            csr::SIE => self.read_csr(csr::MIE) & self.read_csr(csr::MIDELEG),
+            csr::SIP => self.read_csr(csr::MIP) & self.read_csr(csr::MIDELEG),
+            csr::FFLAGS => self.read_csr(csr::FCSR) & csr::FCSR_FFLAGS_MASK,
+            csr::FRM => self.read_csr(csr::FCSR) >> csr::FCSR_FRM_SHIFT,
+
+            _ => (self.state.read_csr)(&self.state, csr),
+        }
     }
 
-    fn fetch_u16(&mut self) -> u16 {
-        let pc = self.reg(Register::Pc);
-        let result = self.mmu.read_u16(pc);
+    /// Check that an instruction at the current privilege level can access
+    /// `csr`. Bits 9:8 of the address are the lowest privilege level
+    /// allowed and CSRs with bits 11:10 set are read-only. On top of that
+    /// the floating point CSRs need mstatus.FS to be on and satp can't be
+    /// touched from supervisor mode with mstatus.TVM set
+    fn check_csr_access(&self, csr: u16, write: bool)
+        -> Result<(), Exception>
+    {
+        let level = (csr >> 8) & 0b11;
+        let read_only = (csr >> 10) & 0b11 == 0b11;
+        if (self.privilege_level() as u16) < level || (write && read_only) {
+            return Err(Exception::IllegalInstruction(0));
+        }
 
-        result
+        match csr {
+            csr::FFLAGS | csr::FRM | csr::FCSR => self.check_float_enabled(),
+            csr::SATP => self.check_virtual_memory_access(),
+
+            _ => self.check_counter_access(csr),
+        }
     }
 
-    fn fetch_u32(&mut self) -> u32 {
-        let pc = self.reg(Register::Pc);
-        let result = self.mmu.read_u32(pc);
+    /// Check that supervisor mode isn't trapped from managing virtual
+    /// memory by mstatus.TVM
+    fn check_virtual_memory_access(&self) -> Result<(), Exception> {
+        let trapped = self.read_csr(csr::MSTATUS) & csr::MSTATUS_TVM != 0;
+        if self.privilege_level() == PrivilegeLevel::Supervisor && trapped {
+            return Err(Exception::IllegalInstruction(0));
+        }
 
-        result
+        Ok(())
     }
 
-    pub fn set_reg(&mut self, reg: Register, value: u64) {
-        if reg != Register::Zero {
-            self.registers[reg.index()] = value;
+    /// Check that an instruction at the current privilege level can access
+    /// `csr` if it's one of the user mode counters. They're only available
+    /// below machine mode when enabled by mcounteren and scounteren.
+    /// stimecmp also needs menvcfg.STCE and mcounteren.TM
+    fn check_counter_access(&self, csr: u16) -> Result<(), Exception> {
+        if csr == csr::STIMECMP {
+            let time = self.read_csr(csr::MCOUNTEREN) >> csr::COUNTER_TIME;
+            let enabled = match self.privilege_level() {
+                PrivilegeLevel::Machine => true,
+                PrivilegeLevel::Supervisor => {
+                    self.stimecmp.is_some() && time & 1 != 0
+                },
+                _ => false,
+            };
+
+            if !enabled {
+                return Err(Exception::IllegalInstruction(0));
+            }
+
+            return Ok(());
+        }
+
+        let counter = match csr::user_counter(csr) {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+
+        let enabled = match self.privilege_level() {
+            PrivilegeLevel::Machine => u64::MAX,
+            PrivilegeLevel::Supervisor => self.read_csr(csr::MCOUNTEREN),
+            _ => {
+                self.read_csr(csr::MCOUNTEREN) &
+                    self.read_csr(csr::SCOUNTEREN)
+            },
+        };
+
+        // NOTE(patrik): The instruction bits aren't around anymore after
+        // decoding so mtval is zero, which the spec allows
+        if (enabled >> counter) & 1 == 0 {
+            return Err(Exception::IllegalInstruction(0));
         }
+
+        Ok(())
     }
 
-    pub fn reg(&self, reg: Register) -> u64 {
-        self.registers[reg.index()]
+    /// Update the cached stimecmp after it or menvcfg has been written
+    fn update_stimecmp(&mut self) {
+        let enabled = self.read_csr(csr::MENVCFG) & csr::MENVCFG_STCE != 0;
+        self.stimecmp = if enabled {
+            Some(self.read_csr(csr::STIMECMP))
+        } else {
+            None
+        };
+
+        // NOTE(patrik): Force the device driven bits of mip to be updated
+        // as STIP might have changed hands
+        self.device_pending = u64::MAX;
     }
-}
 
-impl std::fmt::Debug for Core {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "z0 {:016x} ra {:016x}  sp {:016x}  gp {:016x}\n",
-               self.reg(Register::Zero), self.reg(Register::Ra),
-               self.reg(Register::Sp), self.reg(Register::Gp))?;
-        write!(f, "tp {:016x} t0 {:016x}  t1 {:016x}  t2 {:016x}\n",
-               self.reg(Register::Tp), self.reg(Register::T0),
-               self.reg(Register::T1), self.reg(Register::T2))?;
-        write!(f, "s0 {:016x} s1 {:016x}  a0 {:016x}  a1 {:016x}\n",
-               self.reg(Register::S0), self.reg(Register::S1),
-               self.reg(Register::A0), self.reg(Register::A1))?;
-        write!(f, "a2 {:016x} a3 {:016x}  a4 {:016x}  a5 {:016x}\n",
-               self.reg(Register::A2), self.reg(Register::A3),
-               self.reg(Register::A4), self.reg(Register::A5))?;
-        write!(f, "a6 {:016x} a7 {:016x}  s2 {:016x}  s3 {:016x}\n",
-               self.reg(Register::A6), self.reg(Register::A7),
-               self.reg(Register::S2), self.reg(Register::S3))?;
-        write!(f, "s4 {:016x} s5 {:016x}  s6 {:016x}  s7 {:016x}\n",
-               self.reg(Register::S4), self.reg(Register::S5),
-               self.reg(Register::S6), self.reg(Register::S7))?;
-        write!(f, "s8 {:016x} s9 {:016x} s10 {:016x} s11 {:016x}\n",
-               self.reg(Register::S8), self.reg(Register::S9),
-               self.reg(Register::S10), self.reg(Register::S11))?;
-        write!(f, "t3 {:016x} t4 {:016x}  t5 {:016x}  t6 {:016x}\n",
-               self.reg(Register::T3), self.reg(Register::T4),
-               self.reg(Register::T5), self.reg(Register::T6))?;
+    /// Update the cached root page table after satp has been written, the
+    /// translations of the old address space are forgotten
+    fn update_page_table(&mut self) {
+        let satp = self.read_csr(csr::SATP);
+        let mode = satp >> csr::SATP_MODE_SHIFT;
+        self.page_table = if mode == csr::SATP_MODE_SV39 {
+            Some((satp & csr::SATP_PPN_MASK) * PAGE_SIZE)
+        } else {
+            None
+        };
 
-        write!(f, "pc {:016x}", self.reg(Register::Pc))?;
+        self.tlb.flush();
+    }
 
-        Ok(())
+    /// Update the cached counter state after mcountinhibit or one of the
+    /// mhpmevent registers has been written
+    fn update_counters(&mut self) {
+        self.counters_inhibited = self.read_csr(csr::MCOUNTINHIBIT);
+
+        let mut active = 0;
+        for counter in csr::COUNTER_HPM3..csr::COUNTERS {
+            if self.read_csr(csr::MCOUNTINHIBIT + counter as u16) != 0 {
+                active |= 1 << counter;
+            }
+        }
+
+        self.hpm_active = active & !self.counters_inhibited;
+    }
+
+    fn increment_counter(&mut self, counter: usize) {
+        let stopped = self.counters_inhibited | self.counters_written;
+        if (stopped >> counter) & 1 == 0 {
+            self.counters[counter] = self.counters[counter].wrapping_add(1);
+        }
+    }
+
+    /// Increment the HPM counters counting one of `events`, a mask of
+    /// `1 << HPM_EVENT_*` bits
+    fn count_events(&mut self, events: u64) {
+        let mut active = self.hpm_active & !self.counters_written;
+        while active != 0 {
+            let counter = active.trailing_zeros() as usize;
+            active &= active - 1;
+
+            let event = self.read_csr(csr::MCOUNTINHIBIT + counter as u16);
+            if (events >> event) & 1 != 0 {
+                self.counters[counter] =
+                    self.counters[counter].wrapping_add(1);
+            }
+        }
+    }
+
+    fn count_instruction_events(&mut self, events: u64, taken: bool) {
+        let taken_branch = 1 << csr::HPM_EVENT_TAKEN_BRANCHES;
+        if taken && events & (1 << csr::HPM_EVENT_BRANCHES) != 0 {
+            self.count_events(events | taken_branch);
+        } else {
+            self.count_events(events);
+        }
+    }
+
+    fn ecall(&self) -> Result<CoreExit, Exception> {
+        let privilege_level = self.privilege_level();
+        let firmware = self.firmware_ecalls &&
+            privilege_level == PrivilegeLevel::Supervisor;
+        if !self.environment_traps || firmware {
+            return Ok(CoreExit::Ecall);
+        }
+
+        Err(Exception::EnvironmentCall(privilege_level))
+    }
+
+    fn ebreak(&mut self, current_pc: u64) -> Result<CoreExit, Exception> {
+        if self.semihosting && self.is_semihosting_call(current_pc) {
+            return Ok(CoreExit::Semihosting);
+        }
+
+        if self.ebreak_debug {
+            self.set_reg(Register::Pc, current_pc);
+            return Ok(CoreExit::Ebreak);
+        }
+
+        if !self.environment_traps {
+            return Ok(CoreExit::Ebreak);
+        }
+
+        Err(Exception::Breakpoint(current_pc))
+    }
+
+    /// Is the EBREAK at `pc` surrounded by `slli zero, zero, 0x1f` and
+    /// `srai zero, zero, 7`. All three have to be uncompressed so a
+    /// C.EBREAK is never a semihosting call
+    fn is_semihosting_call(&self, pc: u64) -> bool {
+        const SEQUENCE: [u32; 3] = [0x01f01013, 0x00100073, 0x40705013];
+
+        let mut bytes = [0; 12];
+        let addr = pc.wrapping_sub(4);
+        if !self.peek_virtual(addr, &mut bytes, AccessKind::Execute) {
+            return false;
+        }
+
+        bytes.chunks(4).zip(SEQUENCE.iter()).all(|(word, expected)| {
+            u32::from_le_bytes([word[0], word[1], word[2], word[3]]) ==
+                *expected
+        })
+    }
+
+    /// Forget every cached address translation, what SFENCE.VMA does
+    /// for the whole address space
+    pub fn flush_tlb(&mut self) {
+        self.tlb.flush();
+    }
+
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        (self.state.read_privilege_level)(&self.state)
+    }
+
+    pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
+        (self.state.write_privilege_level)(&mut self.state, privilege_level);
+        self.check_interrupts = true;
+    }
+
+    /// Take a trap for `exception` raised by the instruction at `epc`,
+    /// delegating it to supervisor mode if medeleg says so
+    pub fn trap(&mut self, exception: Exception, epc: u64) {
+        let cause = exception.cause();
+        let medeleg = self.read_csr(csr::MEDELEG);
+        let delegate = (medeleg >> cause) & 1 == 1;
+
+        self.take_trap(cause, exception.tval(), epc, delegate);
+    }
+
+    /// Take a trap for `interrupt` with `epc` as the address of the next
+    /// instruction to execute, delegating it to supervisor mode if mideleg
+    /// says so
+    pub fn interrupt(&mut self, interrupt: Interrupt, epc: u64) {
+        let cause = interrupt.cause();
+        let mideleg = self.read_csr(csr::MIDELEG);
+        let delegate = (mideleg >> cause) & 1 == 1;
+
+        self.take_trap(cause | INTERRUPT_BIT, 0, epc, delegate);
+    }
+
+    fn take_trap(&mut self, cause: u64, tval: u64, epc: u64,
+                 delegate: bool)
+    {
+        if self.hpm_active != 0 {
+            self.count_events(1 << csr::HPM_EVENT_TRAPS);
+        }
+
+        // NOTE(patrik): A trap handler could otherwise complete an SC
+        // paired with an LR from the code it interrupted
+        self.reservation = None;
+
+        let privilege_level = self.privilege_level();
+
+        // NOTE(patrik): Traps never go to a lower privilege level so
+        // delegation only applies when not running in machine mode
+        let delegate = delegate &&
+            privilege_level != PrivilegeLevel::Machine;
+
+        let status = self.read_csr(csr::MSTATUS);
+
+        let tvec = if delegate {
+            self.write_csr(csr::SEPC, epc);
+            self.write_csr(csr::SCAUSE, cause);
+            self.write_csr(csr::STVAL, tval);
+
+            let sie = (status & csr::MSTATUS_SIE) != 0;
+            let mut status = status & !(csr::MSTATUS_SPIE |
+                                        csr::MSTATUS_SIE |
+                                        csr::MSTATUS_SPP);
+            if sie {
+                status |= csr::MSTATUS_SPIE;
+            }
+            if privilege_level == PrivilegeLevel::Supervisor {
+                status |= csr::MSTATUS_SPP;
+            }
+            self.write_csr(csr::MSTATUS, status);
+
+            self.set_privilege_level(PrivilegeLevel::Supervisor);
+            self.read_csr(csr::STVEC)
+        } else {
+            self.write_csr(csr::MEPC, epc);
+            self.write_csr(csr::MCAUSE, cause);
+            self.write_csr(csr::MTVAL, tval);
+
+            let mie = (status & csr::MSTATUS_MIE) != 0;
+            let mut status = status & !(csr::MSTATUS_MPIE |
+                                        csr::MSTATUS_MIE |
+                                        csr::MSTATUS_MPP);
+            if mie {
+                status |= csr::MSTATUS_MPIE;
+            }
+            status |= (privilege_level as u64) << csr::MSTATUS_MPP_SHIFT;
+            self.write_csr(csr::MSTATUS, status);
+
+            self.set_privilege_level(PrivilegeLevel::Machine);
+            self.read_csr(csr::MTVEC)
+        };
+
+        // NOTE(patrik): In vectored mode interrupts jump to base + 4 * cause
+        let base = tvec & !0b11;
+        let target = if tvec & 0b11 == 1 && cause & INTERRUPT_BIT != 0 {
+            base.wrapping_add((cause & !INTERRUPT_BIT) * 4)
+        } else {
+            base
+        };
+
+        self.set_reg(Register::Pc, target);
+    }
+
+    /// Update the device driven bits of mip and return the highest
+    /// priority interrupt that is both pending and enabled
+    fn pending_interrupt(&mut self) -> Option<Interrupt> {
+        let interrupts = self.mmu.interrupts();
+        let mut device_pending = interrupts.pending(self.hart_id);
+        let mut device_mask = csr::MIP_DEVICE_MASK;
+        if let Some(stimecmp) = self.stimecmp {
+            device_mask |= csr::MIP_STIP;
+            if interrupts.time() >= stimecmp {
+                device_pending |= csr::MIP_STIP;
+            }
+        }
+
+        if device_pending != self.device_pending {
+            self.device_pending = device_pending;
+
+            let mip = self.read_csr(csr::MIP);
+            let mip = (mip & !device_mask) | device_pending;
+            self.write_csr(csr::MIP, mip);
+        }
+
+        // NOTE(patrik): Reading all the CSRs is slow so only do it when one
+        // of them or the privilege level has changed
+        if !self.check_interrupts {
+            return None;
+        }
+        self.check_interrupts = false;
+
+        let pending = self.read_csr(csr::MIP) & self.read_csr(csr::MIE);
+        if pending == 0 {
+            return None;
+        }
+
+        let privilege_level = self.privilege_level();
+        let status = self.read_csr(csr::MSTATUS);
+        let mideleg = self.read_csr(csr::MIDELEG);
+
+        let machine_enabled = privilege_level != PrivilegeLevel::Machine ||
+            (status & csr::MSTATUS_MIE) != 0;
+        let supervisor_enabled = match privilege_level {
+            PrivilegeLevel::User => true,
+            PrivilegeLevel::Supervisor => (status & csr::MSTATUS_SIE) != 0,
+            _ => false,
+        };
+
+        Interrupt::PRIORITY.iter().copied().find(|interrupt| {
+            let bit = 1 << interrupt.cause();
+            if pending & bit == 0 {
+                return false;
+            }
+
+            if mideleg & bit != 0 {
+                supervisor_enabled
+            } else {
+                machine_enabled
+            }
+        })
+    }
+
+    /// Set the PC to the target of a jump or taken branch, raising an
+    /// exception if the target is not IALIGN aligned
+    fn jump(&mut self, target: u64) -> Result<(), Exception> {
+        if target & (self.ialign - 1) != 0 {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+
+        // NOTE(patrik): PC already points after the branch so it tells
+        // the branches apart
+        let from = self.reg(Register::Pc);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(from, target);
+        }
+
+        self.set_reg(Register::Pc, target);
+
+        Ok(())
+    }
+
+    fn is_misaligned(&self, addr: u64, size: u64) -> bool {
+        if addr & (size - 1) == 0 {
+            return false;
+        }
+
+        match self.misaligned_policy {
+            MisalignedPolicy::Allow => false,
+            MisalignedPolicy::Trap => true,
+            MisalignedPolicy::TrapPageCross => {
+                addr / PAGE_SIZE != (addr + size - 1) / PAGE_SIZE
+            }
+        }
+    }
+
+    fn check_load_alignment(&self, addr: u64, size: u64)
+        -> Result<(), Exception>
+    {
+        if self.is_misaligned(addr, size) {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+
+        Ok(())
+    }
+
+    fn check_store_alignment(&self, addr: u64, size: u64)
+        -> Result<(), Exception>
+    {
+        if self.is_misaligned(addr, size) {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+
+        Ok(())
+    }
+
+    /// AMOs and LR/SC ignore the misaligned policy and always fault,
+    /// LR reports a load fault and everything else a store/AMO fault
+    fn check_amo_alignment(&self, addr: u64, size: u64, store: bool)
+        -> Result<(), Exception>
+    {
+        if addr & (size - 1) == 0 {
+            return Ok(());
+        }
+
+        if store {
+            Err(Exception::StoreAddressMisaligned(addr))
+        } else {
+            Err(Exception::LoadAddressMisaligned(addr))
+        }
+    }
+
+    /// What deciding if an access is allowed needs, the access is done
+    /// with the privilege level in MPP when mstatus.MPRV is set. `None`
+    /// when addresses are physical
+    fn access_context(&self, kind: AccessKind) -> Option<AccessContext> {
+        let root = self.page_table?;
+
+        let status = self.read_csr(csr::MSTATUS);
+        let mut privilege_level = self.privilege_level();
+        if kind != AccessKind::Execute &&
+            privilege_level == PrivilegeLevel::Machine &&
+            status & csr::MSTATUS_MPRV != 0
+        {
+            let mpp = (status & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT;
+            privilege_level = PrivilegeLevel::from(mpp);
+        }
+
+        if privilege_level == PrivilegeLevel::Machine {
+            return None;
+        }
+
+        Some(AccessContext {
+            root,
+            user: privilege_level == PrivilegeLevel::User,
+            sum: status & csr::MSTATUS_SUM != 0,
+            mxr: status & csr::MSTATUS_MXR != 0,
+        })
+    }
+
+    /// Translate the virtual address `addr` of an access to a physical
+    /// address
+    fn translate(&mut self, addr: u64, kind: AccessKind)
+        -> Result<u64, Exception>
+    {
+        let context = match self.access_context(kind) {
+            Some(context) => context,
+            None => return Ok(addr),
+        };
+
+        match self.tlb.translate(&mut self.mmu, &context, addr, kind) {
+            Some(translation) => {
+                if translation.miss && self.hpm_active != 0 {
+                    self.count_events(1 << csr::HPM_EVENT_TLB_MISSES);
+                }
+
+                Ok(translation.address)
+            },
+
+            None => Err(match kind {
+                AccessKind::Read => Exception::LoadPageFault(addr),
+                AccessKind::Write => Exception::StorePageFault(addr),
+                AccessKind::Execute => Exception::InstructionPageFault(addr),
+            }),
+        }
+    }
+
+    /// Watch `length` bytes at the virtual address `addr` as the hart sees
+    /// it now. Accesses are observed at their physical address so the
+    /// watchpoint is added to every physical page the range touches,
+    /// `None` if some of it is not mapped
+    pub fn add_virtual_watchpoint(&mut self, addr: u64, length: u64,
+                                  kind: WatchKind)
+        -> Option<VirtualWatchpoint>
+    {
+        let access = if kind == WatchKind::Execute {
+            AccessKind::Execute
+        } else {
+            AccessKind::Read
+        };
+
+        let ranges = self.peek_ranges(addr, length as usize, access)?;
+        for (physical, size) in ranges.iter() {
+            self.mmu.add_watchpoint(*physical, *size as u64, kind);
+        }
+
+        Some(VirtualWatchpoint { addr, length, kind, ranges })
+    }
+
+    /// Remove a watchpoint from the physical pages it was added to, even
+    /// if the mapping changed since
+    pub fn remove_virtual_watchpoint(&mut self,
+                                     watchpoint: &VirtualWatchpoint)
+    {
+        for (physical, size) in watchpoint.ranges.iter() {
+            self.mmu.remove_watchpoint(*physical, *size as u64,
+                                       watchpoint.kind);
+        }
+    }
+
+    /// Translate `addr` like an access of `kind` would without touching the
+    /// TLB or the page tables, `None` if the access would fault
+    pub fn peek_translate(&self, addr: u64, kind: AccessKind) -> Option<u64> {
+        match self.access_context(kind) {
+            Some(context) => {
+                paging::peek_translate(&self.mmu, &context, addr, kind)
+            },
+            None => Some(addr),
+        }
+    }
+
+    /// Split `length` bytes at the virtual address `addr` into physical
+    /// ranges, one for every page touched. `None` if some of it can't be
+    /// accessed as `kind`
+    fn peek_ranges(&self, addr: u64, length: usize, kind: AccessKind)
+        -> Option<Vec<(u64, usize)>>
+    {
+        let mut ranges = Vec::new();
+        let mut done = 0;
+        while done < length {
+            let addr = addr.wrapping_add(done as u64);
+            let size = ((PAGE_SIZE - addr % PAGE_SIZE) as usize)
+                .min(length - done);
+
+            ranges.push((self.peek_translate(addr, kind)?, size));
+            done += size;
+        }
+
+        Some(ranges)
+    }
+
+    /// Copy memory at the virtual address `addr` into `buffer` without any
+    /// side effects, false if some of it can't be accessed as `kind`
+    pub fn peek_virtual(&self, addr: u64, buffer: &mut [u8],
+                        kind: AccessKind) -> bool
+    {
+        let ranges = match self.peek_ranges(addr, buffer.len(), kind) {
+            Some(ranges) => ranges,
+            None => return false,
+        };
+
+        let mut done = 0;
+        for (physical, size) in ranges {
+            if !self.mmu.peek_bytes(physical, &mut buffer[done..][..size]) {
+                return false;
+            }
+
+            done += size;
+        }
+
+        true
+    }
+
+    /// Copy `data` to the virtual address `addr` without any side effects
+    /// besides the write itself. Like a debugger it only needs the pages
+    /// to be readable, false if some of it can't be written
+    pub fn poke_virtual(&mut self, addr: u64, data: &[u8]) -> bool {
+        let ranges = match self.peek_ranges(addr, data.len(),
+                                            AccessKind::Read)
+        {
+            Some(ranges) => ranges,
+            None => return false,
+        };
+
+        let mut done = 0;
+        for (physical, size) in ranges {
+            if !self.mmu.poke_bytes(physical, &data[done..][..size]) {
+                return false;
+            }
+
+            done += size;
+        }
+
+        true
+    }
+
+    /// Translate an access of `size` bytes, the second address is where
+    /// the access continues when it crosses into another page. Both pages
+    /// are translated before anything is accessed so a page fault leaves
+    /// memory untouched
+    fn translate_access(&mut self, addr: u64, size: u64, kind: AccessKind)
+        -> Result<(u64, Option<u64>), Exception>
+    {
+        let physical = self.translate(addr, kind)?;
+        if self.page_table.is_none() || addr % PAGE_SIZE + size <= PAGE_SIZE {
+            return Ok((physical, None));
+        }
+
+        let next_page = (addr | (PAGE_SIZE - 1)).wrapping_add(1);
+        let next_physical = self.translate(next_page, kind)?;
+
+        Ok((physical, Some(next_physical)))
+    }
+
+    /// Physical address of byte `index` of an access split by
+    /// `translate_access`
+    fn split_address(addr: u64, physical: u64, next_physical: u64,
+                     index: u64) -> u64
+    {
+        let first_part = PAGE_SIZE - addr % PAGE_SIZE;
+        if index < first_part {
+            physical + index
+        } else {
+            next_physical + (index - first_part)
+        }
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let (physical, next_physical) =
+            self.translate_access(addr, size, AccessKind::Read)?;
+
+        let fault = Exception::LoadAccessFault(addr);
+        if let Some(next_physical) = next_physical {
+            let mut value = 0;
+            for index in 0..size {
+                let byte_addr = Self::split_address(addr, physical,
+                                                    next_physical, index);
+                let byte = self.mmu.read_u8(byte_addr).ok_or(fault)?;
+                value |= (byte as u64) << (index * 8);
+            }
+
+            return Ok(value);
+        }
+
+        let value = match size {
+            1 => self.mmu.read_u8(physical).map(|value| value as u64),
+            2 => self.mmu.read_u16(physical).map(|value| value as u64),
+            4 => self.mmu.read_u32(physical).map(|value| value as u64),
+            _ => self.mmu.read_u64(physical),
+        };
+
+        value.ok_or(fault)
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64)
+        -> Result<(), Exception>
+    {
+        let (physical, next_physical) =
+            self.translate_access(addr, size, AccessKind::Write)?;
+
+        let fault = Exception::StoreAccessFault(addr);
+        if let Some(next_physical) = next_physical {
+            // NOTE(patrik): Both pages are checked first so a fault leaves
+            // memory untouched
+            let mapped = (0..size).all(|index| {
+                let byte_addr = Self::split_address(addr, physical,
+                                                    next_physical, index);
+                self.mmu.is_mapped(byte_addr, 1)
+            });
+            if !mapped {
+                return Err(fault);
+            }
+
+            for index in 0..size {
+                let byte_addr = Self::split_address(addr, physical,
+                                                    next_physical, index);
+                self.mmu.write_u8(byte_addr, (value >> (index * 8)) as u8);
+            }
+
+            return Ok(());
+        }
+
+        let written = match size {
+            1 => self.mmu.write_u8(physical, value as u8),
+            2 => self.mmu.write_u16(physical, value as u16),
+            4 => self.mmu.write_u32(physical, value as u32),
+            _ => self.mmu.write_u64(physical, value),
+        };
+
+        if !written {
+            return Err(fault);
+        }
+
+        Ok(())
+    }
+
+    fn load_u8(&mut self, addr: u64) -> Result<u8, Exception> {
+        Ok(self.load(addr, 1)? as u8)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> Result<u16, Exception> {
+        self.check_load_alignment(addr, 2)?;
+        Ok(self.load(addr, 2)? as u16)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> Result<u32, Exception> {
+        self.check_load_alignment(addr, 4)?;
+        Ok(self.load(addr, 4)? as u32)
+    }
+
+    fn load_u64(&mut self, addr: u64) -> Result<u64, Exception> {
+        self.check_load_alignment(addr, 8)?;
+        self.load(addr, 8)
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) -> Result<(), Exception> {
+        self.store(addr, 1, value as u64)
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) -> Result<(), Exception> {
+        self.check_store_alignment(addr, 2)?;
+        self.store(addr, 2, value as u64)
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) -> Result<(), Exception> {
+        self.check_store_alignment(addr, 4)?;
+        self.store(addr, 4, value as u64)
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) -> Result<(), Exception> {
+        self.check_store_alignment(addr, 8)?;
+        self.store(addr, 8, value)
+    }
+
+    /// Load the old value of an AMO, it needs write permission so a page
+    /// fault is reported as a store fault
+    fn amo_load_u32(&mut self, addr: u64) -> Result<u32, Exception> {
+        self.check_amo_alignment(addr, 4, true)?;
+        let physical = self.translate(addr, AccessKind::Write)?;
+        self.mmu.read_u32(physical).ok_or(Exception::StoreAccessFault(addr))
+    }
+
+    fn amo_load_u64(&mut self, addr: u64) -> Result<u64, Exception> {
+        self.check_amo_alignment(addr, 8, true)?;
+        let physical = self.translate(addr, AccessKind::Write)?;
+        self.mmu.read_u64(physical).ok_or(Exception::StoreAccessFault(addr))
+    }
+
+    fn amo_store_u32(&mut self, addr: u64, value: u32)
+        -> Result<(), Exception>
+    {
+        let physical = self.translate(addr, AccessKind::Write)?;
+        if !self.mmu.write_u32(physical, value) {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        Ok(())
+    }
+
+    fn amo_store_u64(&mut self, addr: u64, value: u64)
+        -> Result<(), Exception>
+    {
+        let physical = self.translate(addr, AccessKind::Write)?;
+        if !self.mmu.write_u64(physical, value) {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the raw bits of the instruction at `pc`, only the lower 16
+    /// bits are valid for compressed instructions
+    /// Fetch the instruction at `pc`, returns the raw instruction and the
+    /// physical address it was fetched from
+    fn fetch_raw(&mut self, pc: u64) -> Result<(u32, u64), Exception> {
+        let addr = self.translate(pc, AccessKind::Execute)?;
+        if let Some(bytes) = self.mmu.ram_slice(addr, 4) {
+            let inst = u32::from_le_bytes(bytes.try_into().unwrap());
+            return Ok((inst, addr));
+        }
+
+        // NOTE(patrik): The instruction is at the end of RAM, at the end of
+        // a page or in MMIO so only read the upper half if it's not a
+        // compressed instruction
+        let inst = self.mmu.fetch_u16(addr)
+            .ok_or(Exception::InstructionAccessFault(pc))?;
+        if (inst & 0b11) != 0b11 {
+            return Ok((inst as u32, addr));
+        }
+
+        let upper_pc = pc.wrapping_add(2);
+        if self.page_table.is_none() || !upper_pc.is_multiple_of(PAGE_SIZE) {
+            return self.mmu.fetch_u32(addr)
+                .map(|inst| (inst, addr))
+                .ok_or(Exception::InstructionAccessFault(upper_pc));
+        }
+
+        // NOTE(patrik): The upper half is on the next page which can be
+        // mapped anywhere
+        let upper_addr = self.translate(upper_pc, AccessKind::Execute)?;
+        let upper = self.mmu.fetch_u16(upper_addr)
+            .ok_or(Exception::InstructionAccessFault(upper_pc))?;
+
+        Ok((inst as u32 | (upper as u32) << 16, addr))
+    }
+
+    pub fn float_reg(&self, reg: Register) -> u64 {
+        self.float_registers[reg.index()]
+    }
+
+    pub fn set_float_reg(&mut self, reg: Register, value: u64) {
+        self.float_registers[reg.index()] = value;
+        self.set_float_dirty();
+    }
+
+    /// Mark the floating point state as modified in mstatus.FS
+    fn set_float_dirty(&mut self) {
+        let status = self.read_csr(csr::MSTATUS);
+        if status & csr::MSTATUS_FS != csr::MSTATUS_FS {
+            self.write_csr(csr::MSTATUS, status | csr::MSTATUS_FS);
+        }
+    }
+
+    /// Floating point instructions and CSRs are illegal while mstatus.FS
+    /// is off
+    fn check_float_enabled(&self) -> Result<(), Exception> {
+        if self.read_csr(csr::MSTATUS) & csr::MSTATUS_FS == 0 {
+            return Err(Exception::IllegalInstruction(0));
+        }
+
+        Ok(())
+    }
+
+    /// Rounding mode of the rm field of an instruction, 7 uses frm
+    fn rounding_mode(&self, rm: u32) -> Result<RoundingMode, Exception> {
+        let bits = if rm == 0b111 {
+            self.read_csr(csr::FRM)
+        } else {
+            rm as u64
+        };
+
+        RoundingMode::from_bits(bits)
+            .ok_or(Exception::IllegalInstruction(0))
+    }
+
+    fn accrue_float_flags(&mut self, flags: u64) {
+        if flags != 0 {
+            let fflags = self.read_csr(csr::FFLAGS);
+            self.write_csr(csr::FFLAGS, fflags | flags);
+        }
+    }
+
+    fn float_arithmetic<F: Float>(&mut self, rd: Register, rs1: Register,
+                                  rs2: Register, rm: u32,
+                                  operation: fn(F, F, RoundingMode)
+                                      -> (F, u64))
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let b = F::from_register(self.float_reg(rs2));
+        let (value, flags) = operation(a, b, rm);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    /// The fused multiply-adds, the product and the addend can be negated
+    #[allow(clippy::too_many_arguments)]
+    fn float_fused<F: Float>(&mut self, rd: Register, rs1: Register,
+                             rs2: Register, rs3: Register, rm: u32,
+                             negate_product: bool, negate_addend: bool)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let mut a = F::from_register(self.float_reg(rs1));
+        let b = F::from_register(self.float_reg(rs2));
+        let mut c = F::from_register(self.float_reg(rs3));
+        if negate_product {
+            a = -a;
+        }
+        if negate_addend {
+            c = -c;
+        }
+
+        let (value, flags) = float::fused_mul_add(a, b, c, rm);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    fn float_sqrt<F: Float>(&mut self, rd: Register, rs1: Register, rm: u32)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let (value, flags) = float::sqrt(a, rm);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    fn float_sign_injection<F: Float>(&mut self, rd: Register,
+                                      rs1: Register, rs2: Register,
+                                      sign: fn(bool, bool) -> bool)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+
+        let a = self.float_reg(rs1);
+        let b = self.float_reg(rs2);
+        self.set_float_reg(rd, float::sign_injection::<F>(a, b, sign));
+
+        Ok(CoreExit::Success)
+    }
+
+    fn float_min_max<F: Float>(&mut self, rd: Register, rs1: Register,
+                               rs2: Register, max: bool)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let b = F::from_register(self.float_reg(rs2));
+        let (value, flags) = float::min_max(a, b, max);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    fn float_compare<F: Float>(&mut self, rd: Register, rs1: Register,
+                               rs2: Register, accepted: &[cmp::Ordering],
+                               quiet: bool)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let b = F::from_register(self.float_reg(rs2));
+        let (result, flags) = float::compare(a, b, accepted, quiet);
+
+        self.set_reg(rd, result as u64);
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    /// Convert to an integer in `min..=max`, 32-bit results are sign
+    /// extended even when unsigned
+    fn float_to_int<F: Float>(&mut self, rd: Register, rs1: Register,
+                              rm: u32, min: i128, max: i128, word: bool)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let (value, flags) = float::to_int(a, rm, min, max);
+
+        let value = value as u64;
+        if word {
+            self.set_reg(rd, value as u32 as i32 as i64 as u64);
+        } else {
+            self.set_reg(rd, value);
+        }
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    /// Convert the integer in `rs1`, `extend` picks the bits of the
+    /// register that are the integer
+    fn float_from_int<F: Float>(&mut self, rd: Register, rs1: Register,
+                                rm: u32, extend: fn(u64) -> i128)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let value = extend(self.reg(rs1));
+        let (value, flags) = float::from_int::<F>(value, rm);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    /// Convert between single and double precision
+    fn float_convert<F: Float, T: Float>(&mut self, rd: Register,
+                                         rs1: Register, rm: u32)
+        -> Result<CoreExit, Exception>
+    {
+        self.check_float_enabled()?;
+        let rm = self.rounding_mode(rm)?;
+
+        let a = F::from_register(self.float_reg(rs1));
+        let (value, flags) = float::convert::<F, T>(a, rm);
+
+        self.set_float_reg(rd, value.to_register());
+        self.accrue_float_flags(flags);
+
+        Ok(CoreExit::Success)
+    }
+
+    pub fn set_reg(&mut self, reg: Register, value: u64) {
+        if reg != Register::Zero {
+            self.registers[reg.index()] = value;
+        }
+    }
+
+    pub fn reg(&self, reg: Register) -> u64 {
+        self.registers[reg.index()]
+    }
+}
+
+/// Events of `inst` as a mask of `1 << HPM_EVENT_*` bits, taken branches
+/// are only known after executing it
+fn instruction_events(inst: &Instruction) -> u64 {
+    const LOADS: u64 = 1 << csr::HPM_EVENT_LOADS;
+    const STORES: u64 = 1 << csr::HPM_EVENT_STORES;
+    const BRANCHES: u64 = 1 << csr::HPM_EVENT_BRANCHES;
+
+    match inst {
+        Instruction::Lb { .. } | Instruction::Lh { .. } |
+        Instruction::Lw { .. } | Instruction::Ld { .. } |
+        Instruction::Lbu { .. } | Instruction::Lhu { .. } |
+        Instruction::Lwu { .. } | Instruction::Lrw { .. } |
+        Instruction::Lrd { .. } | Instruction::CLw { .. } |
+        Instruction::CLd { .. } | Instruction::CLwsp { .. } |
+        Instruction::CLdsp { .. } | Instruction::Flw { .. } |
+        Instruction::Fld { .. } | Instruction::CFld { .. } |
+        Instruction::CFldsp { .. } => LOADS,
+
+        Instruction::Sb { .. } | Instruction::Sh { .. } |
+        Instruction::Sw { .. } | Instruction::Sd { .. } |
+        Instruction::Scw { .. } | Instruction::Scd { .. } |
+        Instruction::CSw { .. } | Instruction::CSd { .. } |
+        Instruction::CSwsp { .. } | Instruction::CSdsp { .. } |
+        Instruction::Fsw { .. } | Instruction::Fsd { .. } |
+        Instruction::CFsd { .. } | Instruction::CFsdsp { .. } => STORES,
+
+        Instruction::Amoswapw { .. } | Instruction::Amoaddw { .. } |
+        Instruction::Amoxorw { .. } | Instruction::Amoandw { .. } |
+        Instruction::Amoorw { .. } | Instruction::Amominw { .. } |
+        Instruction::Amomaxw { .. } | Instruction::Amominuw { .. } |
+        Instruction::Amomaxuw { .. } | Instruction::Amoswapd { .. } |
+        Instruction::Amoaddd { .. } | Instruction::Amoxord { .. } |
+        Instruction::Amoandd { .. } | Instruction::Amoord { .. } |
+        Instruction::Amomind { .. } | Instruction::Amomaxd { .. } |
+        Instruction::Amominud { .. } |
+        Instruction::Amomaxud { .. } => LOADS | STORES,
+
+        Instruction::Beq { .. } | Instruction::Bne { .. } |
+        Instruction::Blt { .. } | Instruction::Bge { .. } |
+        Instruction::Bltu { .. } | Instruction::Bgeu { .. } |
+        Instruction::CBeqz { .. } | Instruction::CBnez { .. } => BRANCHES,
+
+        _ => 0,
+    }
+}
+
+impl std::fmt::Debug for Core {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "z0 {:016x} ra {:016x}  sp {:016x}  gp {:016x}\n",
+               self.reg(Register::Zero), self.reg(Register::Ra),
+               self.reg(Register::Sp), self.reg(Register::Gp))?;
+        write!(f, "tp {:016x} t0 {:016x}  t1 {:016x}  t2 {:016x}\n",
+               self.reg(Register::Tp), self.reg(Register::T0),
+               self.reg(Register::T1), self.reg(Register::T2))?;
+        write!(f, "s0 {:016x} s1 {:016x}  a0 {:016x}  a1 {:016x}\n",
+               self.reg(Register::S0), self.reg(Register::S1),
+               self.reg(Register::A0), self.reg(Register::A1))?;
+        write!(f, "a2 {:016x} a3 {:016x}  a4 {:016x}  a5 {:016x}\n",
+               self.reg(Register::A2), self.reg(Register::A3),
+               self.reg(Register::A4), self.reg(Register::A5))?;
+        write!(f, "a6 {:016x} a7 {:016x}  s2 {:016x}  s3 {:016x}\n",
+               self.reg(Register::A6), self.reg(Register::A7),
+               self.reg(Register::S2), self.reg(Register::S3))?;
+        write!(f, "s4 {:016x} s5 {:016x}  s6 {:016x}  s7 {:016x}\n",
+               self.reg(Register::S4), self.reg(Register::S5),
+               self.reg(Register::S6), self.reg(Register::S7))?;
+        write!(f, "s8 {:016x} s9 {:016x} s10 {:016x} s11 {:016x}\n",
+               self.reg(Register::S8), self.reg(Register::S9),
+               self.reg(Register::S10), self.reg(Register::S11))?;
+        write!(f, "t3 {:016x} t4 {:016x}  t5 {:016x}  t6 {:016x}\n",
+               self.reg(Register::T3), self.reg(Register::T4),
+               self.reg(Register::T5), self.reg(Register::T6))?;
+
+        write!(f, "pc {:016x}", self.reg(Register::Pc))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM: u64 = 0x8000_0000;
+
+    // Encodings of the instructions the tests run
+    const JAL_RA_2: u32 = 0x002000ef;   // jal ra, .+2
+    const JALR_RA_RA: u32 = 0x000080e7; // jalr ra, 0(ra)
+    const MRET: u32 = 0x30200073;
+    const LD_A0_A1: u32 = 0x0005b503;   // ld a0, 0(a1)
+    const SD_A0_A1: u32 = 0x00a5b023;   // sd a0, 0(a1)
+    const SRAW: u32 = 0x40b5553b;       // sraw a0, a0, a1
+    const REMW: u32 = 0x02b5653b;       // remw a0, a0, a1
+    const REMUW: u32 = 0x02b5753b;      // remuw a0, a0, a1
+
+    /// Core in machine mode with RAM at `RAM` and PC at its start running
+    /// `code`
+    fn create_core(code: &[u32]) -> Core {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x10000);
+        for (index, inst) in code.iter().enumerate() {
+            mmu.poke_bytes(RAM + index as u64 * 4, &inst.to_le_bytes());
+        }
+
+        let mut core = crate::create_core(mmu);
+        core.set_reg(Register::Pc, RAM);
+        core
+    }
+
+    #[test]
+    fn jal_to_misaligned_target_keeps_rd() {
+        let mut core = create_core(&[JAL_RA_2]);
+        core.set_ialign(32);
+        core.set_reg(Register::Ra, 0x1234);
+
+        let exit = core.step();
+        let exception = Exception::InstructionAddressMisaligned(RAM + 2);
+        assert_eq!(exit, CoreExit::Exception(exception));
+        assert_eq!(core.reg(Register::Ra), 0x1234);
+        assert_eq!(core.read_csr(csr::MEPC), RAM);
+        assert_eq!(core.read_csr(csr::MTVAL), RAM + 2);
+    }
+
+    #[test]
+    fn jalr_to_misaligned_target_keeps_base() {
+        let mut core = create_core(&[JALR_RA_RA]);
+        core.set_ialign(32);
+        core.set_reg(Register::Ra, RAM + 0x102);
+
+        let exit = core.step();
+        let exception = Exception::InstructionAddressMisaligned(RAM + 0x102);
+        assert_eq!(exit, CoreExit::Exception(exception));
+        assert_eq!(core.reg(Register::Ra), RAM + 0x102);
+    }
+
+    #[test]
+    fn jalr_clears_lowest_bit() {
+        let mut core = create_core(&[JALR_RA_RA]);
+        core.set_reg(Register::Ra, RAM + 0x101);
+
+        assert_eq!(core.step(), CoreExit::Success);
+        assert_eq!(core.reg(Register::Pc), RAM + 0x100);
+        assert_eq!(core.reg(Register::Ra), RAM + 4);
+    }
+
+    #[test]
+    fn mret_to_misaligned_target_keeps_state() {
+        let mut core = create_core(&[MRET]);
+        core.set_ialign(32);
+        core.write_csr(csr::MEPC, RAM + 0x102);
+
+        // NOTE(patrik): MPP says user mode, a trap taken after MRET had
+        // already switched would save user mode as the previous mode
+        let status = core.read_csr(csr::MSTATUS) & !csr::MSTATUS_MPP;
+        core.write_csr(csr::MSTATUS, status);
+
+        let exit = core.step();
+        assert!(matches!(exit, CoreExit::Exception(
+            Exception::InstructionAddressMisaligned(_))));
+        assert_eq!(core.privilege_level(), PrivilegeLevel::Machine);
+        assert_eq!(core.read_csr(csr::MSTATUS) & csr::MSTATUS_MPP,
+                   csr::MSTATUS_MPP);
+        assert_eq!(core.read_csr(csr::MEPC), RAM);
+    }
+
+    /// Run `ld a0, 0(a1)` from `addr` with `policy`
+    fn load_at(policy: MisalignedPolicy, addr: u64) -> (Core, CoreExit) {
+        let mut core = create_core(&[LD_A0_A1]);
+        core.set_misaligned_policy(policy);
+        core.mmu.poke_bytes(RAM + 0x1000 - 8, &[0x11; 16]);
+        core.set_reg(Register::A1, addr);
+
+        let exit = core.step();
+        (core, exit)
+    }
+
+    #[test]
+    fn misaligned_allowed() {
+        let (core, exit) = load_at(MisalignedPolicy::Allow, RAM + 0xffd);
+        assert_eq!(exit, CoreExit::Success);
+        assert_eq!(core.reg(Register::A0), 0x1111_1111_1111_1111);
+    }
+
+    #[test]
+    fn misaligned_trap() {
+        let addr = RAM + 0xff1;
+        let (core, exit) = load_at(MisalignedPolicy::Trap, addr);
+        let exception = Exception::LoadAddressMisaligned(addr);
+        assert_eq!(exit, CoreExit::Exception(exception));
+        assert_eq!(core.read_csr(csr::MCAUSE), 4);
+        assert_eq!(core.read_csr(csr::MTVAL), addr);
+        assert_eq!(core.reg(Register::A0), 0);
+    }
+
+    #[test]
+    fn misaligned_trap_page_cross() {
+        let policy = MisalignedPolicy::TrapPageCross;
+        let (_, exit) = load_at(policy, RAM + 0xff1);
+        assert_eq!(exit, CoreExit::Success);
+
+        let addr = RAM + 0xffd;
+        let (_, exit) = load_at(policy, addr);
+        let exception = Exception::LoadAddressMisaligned(addr);
+        assert_eq!(exit, CoreExit::Exception(exception));
+    }
+
+    #[test]
+    fn misaligned_store_trap_keeps_memory() {
+        let mut core = create_core(&[SD_A0_A1]);
+        core.set_misaligned_policy(MisalignedPolicy::Trap);
+        core.set_reg(Register::A0, u64::MAX);
+        core.set_reg(Register::A1, RAM + 0x1003);
+
+        let exception = Exception::StoreAddressMisaligned(RAM + 0x1003);
+        assert_eq!(core.step(), CoreExit::Exception(exception));
+        assert_eq!(core.mmu.peek_u64(RAM + 0x1000), Some(0));
+        assert_eq!(core.read_csr(csr::MCAUSE), 6);
+    }
+
+    #[test]
+    fn ialign_16_allows_halfword_targets() {
+        let mut core = create_core(&[JAL_RA_2]);
+
+        assert_eq!(core.step(), CoreExit::Success);
+        assert_eq!(core.reg(Register::Pc), RAM + 2);
+        assert_ne!(core.read_csr(csr::MISA) & csr::MISA_C, 0);
+    }
+
+    #[test]
+    fn ialign_32_clears_misa_c() {
+        let mut core = create_core(&[]);
+        core.set_ialign(32);
+        assert_eq!(core.read_csr(csr::MISA) & csr::MISA_C, 0);
+    }
+
+    /// Core in supervisor mode with a gigapage mapping virtual 0 to `RAM`
+    /// and PC at virtual 4 running `code` after the first instruction
+    fn paged_core(code: &[u32]) -> Core {
+        // NOTE(patrik): MRET drops to supervisor mode and continues at
+        // virtual 4 which is the second instruction
+        let mut program = vec![MRET];
+        program.extend_from_slice(code);
+        let mut core = create_core(&program);
+
+        let root = RAM + 0x1000;
+        core.mmu.poke_u64(root, (RAM >> 12) << 10 | 0xcf);
+        let mode = csr::SATP_MODE_SV39 << csr::SATP_MODE_SHIFT;
+        core.write_csr(csr::SATP, mode | root >> 12);
+
+        let status = core.read_csr(csr::MSTATUS) & !csr::MSTATUS_MPP;
+        core.write_csr(csr::MSTATUS, status | 1 << csr::MSTATUS_MPP_SHIFT);
+        core.write_csr(csr::MEPC, 4);
+
+        assert_eq!(core.step(), CoreExit::Success);
+        assert_eq!(core.privilege_level(), PrivilegeLevel::Supervisor);
+        core
+    }
+
+    #[test]
+    fn watchpoint_on_virtual_range_fires_on_physical_access() {
+        let mut core = paged_core(&[LD_A0_A1]);
+        core.set_reg(Register::A1, 0x2000);
+
+        // NOTE(patrik): The range crosses into the page the load reads
+        let watchpoint = core
+            .add_virtual_watchpoint(0x1ffc, 8, WatchKind::Read)
+            .unwrap();
+
+        let access = match core.step() {
+            CoreExit::Watchpoint(access) => access,
+            exit => panic!("Expected a watchpoint, got {:x?}", exit),
+        };
+
+        assert_eq!(access.addr, RAM + 0x2000);
+        assert_eq!(watchpoint.hit(&access), Some(0x2000));
+
+        core.remove_virtual_watchpoint(&watchpoint);
+        core.set_reg(Register::Pc, 4);
+        assert_eq!(core.step(), CoreExit::Success);
+    }
+
+    #[test]
+    fn watchpoint_on_unmapped_range_is_refused() {
+        let mut core = paged_core(&[]);
+        assert!(core.add_virtual_watchpoint(0x3fff_fffc, 8, WatchKind::Write)
+                .is_none());
+    }
+
+    /// Run `inst` with `a0` and `a1` set, the result in `a0`
+    fn alu(inst: u32, a0: u64, a1: u64) -> u64 {
+        let mut core = create_core(&[inst]);
+        core.set_reg(Register::A0, a0);
+        core.set_reg(Register::A1, a1);
+
+        assert_eq!(core.step(), CoreExit::Success);
+        core.reg(Register::A0)
+    }
+
+    #[test]
+    fn sraw_shifts_in_the_sign_of_the_word() {
+        assert_eq!(alu(SRAW, 0x8000_0000, 4), 0xffff_ffff_f800_0000);
+        assert_eq!(alu(SRAW, 0x7fff_0000, 4), 0x07ff_f000);
+        assert_eq!(alu(SRAW, 0x1_8000_0000, 32 + 31), u64::MAX);
+    }
+
+    #[test]
+    fn remw_by_zero_is_the_dividend() {
+        assert_eq!(alu(REMW, 0x1_0000_0007, 0), 7);
+        assert_eq!(alu(REMW, 0x8000_0000, 0), 0xffff_ffff_8000_0000);
+        assert_eq!(alu(REMUW, 0x1_0000_0007, 0), 7);
+        assert_eq!(alu(REMUW, 0xffff_fffe, 0), 0xffff_ffff_ffff_fffe);
+        assert_eq!(alu(REMW, (-7i64) as u64, 2), (-1i64) as u64);
+        assert_eq!(alu(REMUW, 7, 1 << 32 | 2), 1);
     }
 }
@@ -0,0 +1,201 @@
+//! Addresses and bit fields of the control and status registers the core
+//! itself needs to know about, and the names of the common ones for the
+//! debuggers
+
+// Floating point, fflags and frm are views of fcsr
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16    = 0x002;
+pub const FCSR: u16   = 0x003;
+
+// Supervisor trap setup and handling, sstatus, sie and sip are views of
+// the machine registers
+pub const SSTATUS: u16  = 0x100;
+pub const SIE: u16      = 0x104;
+pub const STVEC: u16    = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SEPC: u16     = 0x141;
+pub const SCAUSE: u16   = 0x142;
+pub const STVAL: u16    = 0x143;
+pub const SIP: u16      = 0x144;
+
+// Supervisor timer compare of Sstc
+pub const STIMECMP: u16 = 0x14d;
+
+// Supervisor address translation and protection
+pub const SATP: u16 = 0x180;
+
+// Machine information
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16   = 0xf12;
+pub const MIMPID: u16    = 0xf13;
+pub const MHARTID: u16   = 0xf14;
+
+// Machine trap setup and handling
+pub const MSTATUS: u16  = 0x300;
+pub const MISA: u16     = 0x301;
+pub const MEDELEG: u16  = 0x302;
+pub const MIDELEG: u16  = 0x303;
+pub const MIE: u16      = 0x304;
+pub const MTVEC: u16    = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MEPC: u16     = 0x341;
+pub const MCAUSE: u16   = 0x342;
+pub const MTVAL: u16    = 0x343;
+pub const MIP: u16      = 0x344;
+pub const MENVCFG: u16  = 0x30a;
+
+// Counters, mhpmevent3-31 follow mcountinhibit and mhpmcounter3-31 follow
+// minstret. The user mode counters at 0xc00 are read-only shadows of the
+// machine ones
+pub const MCOUNTINHIBIT: u16 = 0x320;
+pub const MCYCLE: u16        = 0xb00;
+pub const MINSTRET: u16      = 0xb02;
+pub const CYCLE: u16         = 0xc00;
+pub const TIME: u16          = 0xc01;
+pub const INSTRET: u16       = 0xc02;
+
+/// Number of counters including time and the reserved ones
+pub const COUNTERS: usize = 32;
+
+// Index of the counters in mcountinhibit and the counter-enable registers
+pub const COUNTER_CYCLE: usize   = 0;
+pub const COUNTER_TIME: usize    = 1;
+pub const COUNTER_INSTRET: usize = 2;
+pub const COUNTER_HPM3: usize    = 3;
+
+// Events selected by writing mhpmevent, other values read back as 0
+pub const HPM_EVENT_LOADS: u64          = 1;
+pub const HPM_EVENT_STORES: u64         = 2;
+pub const HPM_EVENT_BRANCHES: u64       = 3;
+pub const HPM_EVENT_TAKEN_BRANCHES: u64 = 4;
+pub const HPM_EVENT_TRAPS: u64          = 5;
+pub const HPM_EVENT_TLB_MISSES: u64     = 6;
+
+const SUPPORTED_EVENTS: &[u64] = &[
+    HPM_EVENT_LOADS, HPM_EVENT_STORES, HPM_EVENT_BRANCHES,
+    HPM_EVENT_TAKEN_BRANCHES, HPM_EVENT_TRAPS, HPM_EVENT_TLB_MISSES,
+];
+
+// mstatus fields
+pub const MSTATUS_SIE: u64  = 1 << 1;
+pub const MSTATUS_MIE: u64  = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64  = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP: u64  = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_FS: u64   = 0b11 << 13;
+pub const MSTATUS_XS: u64   = 0b11 << 15;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64  = 1 << 18;
+pub const MSTATUS_MXR: u64  = 1 << 19;
+pub const MSTATUS_TVM: u64  = 1 << 20;
+pub const MSTATUS_TSR: u64  = 1 << 22;
+pub const MSTATUS_SD: u64   = 1 << 63;
+
+/// Fields of mstatus visible through sstatus, SIE, SPIE, UBE, SPP, VS, FS,
+/// XS, SUM, MXR, UXL and SD
+pub const SSTATUS_MASK: u64 = 0x8000_0003_000d_e762;
+
+// mip bits that are driven by devices instead of written by software
+pub const MIP_DEVICE_MASK: u64 = 1 << 3 | 1 << 7 | 1 << 9 | 1 << 11;
+
+// Supervisor interrupt bits of mip and mie
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_SEIP: u64 = 1 << 9;
+
+/// misa of a hart with MXL=64 and the A, C, D, F, I, M, S and U extentions
+pub const MISA_VALUE: u64 = 2 << 62 | 1 << 0 | 1 << 2 | 1 << 3 | 1 << 5 |
+    1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+/// Bit of the C extention in misa
+pub const MISA_C: u64 = 1 << 2;
+
+/// Mask of the fcsr bits, fflags in the low 5 and frm in the upper 3
+pub const FCSR_MASK: u64 = 0xff;
+pub const FCSR_FFLAGS_MASK: u64 = 0x1f;
+pub const FCSR_FRM_SHIFT: u64 = 5;
+
+// Modes of satp, the rest are unsupported and writes selecting them are
+// ignored
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+/// menvcfg bit enabling stimecmp, STIP then follows stimecmp
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+/// Index of the counter if `csr` is mcycle, minstret or one of the
+/// mhpmcounters
+pub fn machine_counter(csr: u16) -> Option<usize> {
+    let counter = (csr & 0x1f) as usize;
+    if csr & !0x1f == MCYCLE && counter != COUNTER_TIME {
+        Some(counter)
+    } else {
+        None
+    }
+}
+
+/// Index of the counter if `csr` is one of the user mode counters
+pub fn user_counter(csr: u16) -> Option<usize> {
+    if csr & !0x1f == CYCLE {
+        Some((csr & 0x1f) as usize)
+    } else {
+        None
+    }
+}
+
+/// Is `csr` one of mhpmevent3-31
+pub fn is_hpm_event(csr: u16) -> bool {
+    csr & !0x1f == MCOUNTINHIBIT && (csr & 0x1f) as usize >= COUNTER_HPM3
+}
+
+pub fn is_supported_event(event: u64) -> bool {
+    event == 0 || SUPPORTED_EVENTS.contains(&event)
+}
+
+/// Names of the CSRs shown by the debuggers
+pub const NAMES: &[(&str, u16)] = &[
+    ("fflags",     FFLAGS),
+    ("frm",        FRM),
+    ("fcsr",       FCSR),
+
+    ("sstatus",    SSTATUS),
+    ("sie",        SIE),
+    ("stvec",      STVEC),
+    ("scounteren", SCOUNTEREN),
+    ("sscratch",   0x140),
+    ("sepc",       SEPC),
+    ("scause",     SCAUSE),
+    ("stval",      STVAL),
+    ("sip",        SIP),
+    ("stimecmp",   STIMECMP),
+    ("satp",       SATP),
+
+    ("mvendorid",  MVENDORID),
+    ("marchid",    MARCHID),
+    ("mimpid",     MIMPID),
+    ("mhartid",    MHARTID),
+
+    ("mstatus",    MSTATUS),
+    ("misa",       MISA),
+    ("medeleg",    MEDELEG),
+    ("mideleg",    MIDELEG),
+    ("mie",        MIE),
+    ("mtvec",      MTVEC),
+    ("mcounteren", MCOUNTEREN),
+    ("mcountinhibit", MCOUNTINHIBIT),
+    ("mscratch",   0x340),
+    ("mepc",       MEPC),
+    ("mcause",     MCAUSE),
+    ("mtval",      MTVAL),
+    ("mip",        MIP),
+    ("menvcfg",    MENVCFG),
+
+    ("mcycle",     MCYCLE),
+    ("minstret",   MINSTRET),
+    ("cycle",      CYCLE),
+    ("time",       TIME),
+    ("instret",    INSTRET),
+];
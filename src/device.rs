@@ -0,0 +1,198 @@
+//! Interface between the memory bus and the devices mapped on it
+
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+/// Request from the guest to stop the machine
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Shutdown {
+    /// Powered off or finished a test successfully
+    Pass,
+    /// A test failed with the given code
+    Fail(u16),
+    Reboot,
+}
+
+/// Interrupt, power and timer state shared between the devices on the bus
+/// and the harts
+pub struct Interrupts {
+    /// Bits of mip driven by devices, one entry per hart
+    pending: Vec<u64>,
+
+    /// Level of the external interrupt lines going into the interrupt
+    /// controller, indexed by interrupt source id
+    lines: Vec<bool>,
+    /// Incremented every time the level of a line changes, lets the
+    /// interrupt controller skip work when nothing has happened
+    line_changes: u64,
+
+    /// Value of the platform timer, read by the harts through the time CSR
+    time: u64,
+
+    shutdown: Option<Shutdown>,
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+
+            lines: Vec::new(),
+            line_changes: 0,
+
+            time: 0,
+
+            shutdown: None,
+        }
+    }
+
+    /// Raise or lower the mip `bit` of `hart`
+    pub fn set_pending(&mut self, hart: usize, bit: u64, level: bool) {
+        if hart >= self.pending.len() {
+            self.pending.resize(hart + 1, 0);
+        }
+
+        if level {
+            self.pending[hart] |= 1 << bit;
+        } else {
+            self.pending[hart] &= !(1 << bit);
+        }
+    }
+
+    /// The mip bits devices are currently driving for `hart`
+    pub fn pending(&self, hart: usize) -> u64 {
+        self.pending.get(hart).copied().unwrap_or(0)
+    }
+
+    /// Raise or lower external interrupt line `irq`, lines are level
+    /// triggered so a device keeps its line raised until the condition
+    /// has been cleared by the guest
+    pub fn set_line(&mut self, irq: usize, level: bool) {
+        if irq >= self.lines.len() {
+            self.lines.resize(irq + 1, false);
+        }
+
+        if self.lines[irq] != level {
+            self.lines[irq] = level;
+            self.line_changes = self.line_changes.wrapping_add(1);
+        }
+    }
+
+    /// Current level of external interrupt line `irq`
+    pub fn line(&self, irq: usize) -> bool {
+        self.lines.get(irq).copied().unwrap_or(false)
+    }
+
+    pub fn line_changes(&self) -> u64 {
+        self.line_changes
+    }
+
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Ask for the machine to be stopped, the first request wins
+    pub fn request_shutdown(&mut self, shutdown: Shutdown) {
+        if self.shutdown.is_none() {
+            self.shutdown = Some(shutdown);
+        }
+    }
+
+    pub fn shutdown(&self) -> Option<Shutdown> {
+        self.shutdown
+    }
+
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        writer.list_u64(&self.pending);
+        writer.list_bool(&self.lines);
+        writer.u64(self.line_changes);
+        writer.u64(self.time);
+
+        let (tag, code) = match self.shutdown {
+            None => (0, 0),
+            Some(Shutdown::Pass) => (1, 0),
+            Some(Shutdown::Fail(code)) => (2, code),
+            Some(Shutdown::Reboot) => (3, 0),
+        };
+        writer.u8(tag);
+        writer.u32(code as u32);
+    }
+
+    pub fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let pending = reader.list_u64(None)?;
+        let lines = reader.list_bool(None)?;
+        let line_changes = reader.u64()?;
+        let time = reader.u64()?;
+
+        let tag = reader.u8()?;
+        let code = reader.u32()? as u16;
+        let shutdown = match tag {
+            0 => None,
+            1 => Some(Shutdown::Pass),
+            2 => Some(Shutdown::Fail(code)),
+            3 => Some(Shutdown::Reboot),
+
+            _ => {
+                return Err(format!("Snapshot: Bad shutdown request: {}",
+                                   tag));
+            },
+        };
+
+        self.pending = pending;
+        self.lines = lines;
+        self.line_changes = line_changes;
+        self.time = time;
+        self.shutdown = shutdown;
+
+        Ok(())
+    }
+}
+
+/// Guest RAM as seen by devices that access it on their own, accesses
+/// don't go through watchpoints or the access hook
+pub trait Dma {
+    /// Copy RAM at `addr` into `buffer`, false if any of it is not RAM
+    fn read(&self, addr: u64, buffer: &mut [u8]) -> bool;
+    /// Copy `data` into RAM at `addr`, false if any of it is not RAM
+    fn write(&mut self, addr: u64, data: &[u8]) -> bool;
+}
+
+/// A memory mapped device on the bus, `offset` is relative to the base
+/// address the device was mapped at and `size` is the access size in bytes
+pub trait Device {
+    fn read(&mut self, offset: u64, size: usize) -> u64;
+    fn write(&mut self, offset: u64, size: usize, value: u64);
+
+    /// Called once for every instruction executed, lets the device advance
+    /// its state and raise or lower interrupts
+    fn tick(&mut self, _interrupts: &mut Interrupts) {}
+
+    /// Devices reading and writing RAM themselves get `dma` called after
+    /// every `tick`, asked once when the device is mapped
+    fn uses_dma(&self) -> bool {
+        false
+    }
+
+    fn dma(&mut self, _memory: &mut dyn Dma, _interrupts: &mut Interrupts) {}
+
+    /// Save the state of the device for a snapshot, the host side like
+    /// the console is not part of it
+    fn save(&self, _writer: &mut SnapshotWriter) {}
+    /// Restore the state saved by `save`
+    fn restore(&mut self, _reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! Turns decoded instructions back into assembly for the monitor, with the
+//! mnemonics and operand order of the GNU assembler. Jump and branch
+//! targets are printed as addresses instead of offsets
+
+use crate::csr;
+use crate::instruction::Instruction;
+use crate::Register;
+
+/// ABI names of the floating point registers
+pub const FLOAT_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Rounding mode used when the instruction doesn't name one
+const RM_DYNAMIC: u32 = 7;
+
+fn x(reg: Register) -> String {
+    format!("{:?}", reg).to_lowercase()
+}
+
+fn f(reg: Register) -> &'static str {
+    FLOAT_NAMES[reg.index()]
+}
+
+fn csr_name(csr: u16) -> String {
+    csr::NAMES.iter()
+        .find(|(_, number)| *number == csr)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("{:#x}", csr))
+}
+
+/// Operand for a rounding mode, empty for the dynamic mode
+fn rm(rm: u32) -> String {
+    match rm {
+        0 => ", rne".to_string(),
+        1 => ", rtz".to_string(),
+        2 => ", rdn".to_string(),
+        3 => ", rup".to_string(),
+        4 => ", rmm".to_string(),
+        RM_DYNAMIC => String::new(),
+
+        rm => format!(", {}", rm),
+    }
+}
+
+/// The `.aq`, `.rl` or `.aqrl` ordering suffix of an atomic
+fn order(aq: bool, rl: bool) -> &'static str {
+    match (aq, rl) {
+        (false, false) => "",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (true, true) => ".aqrl",
+    }
+}
+
+/// Predecessor or successor set of a FENCE
+fn fence_set(set: i32) -> String {
+    let set: String = "iorw".chars()
+        .enumerate()
+        .filter(|(index, _)| set & (8 >> index) != 0)
+        .map(|(_, name)| name)
+        .collect();
+
+    if set.is_empty() { "0".to_string() } else { set }
+}
+
+fn target(addr: u64, offset: i32) -> String {
+    format!("{:#x}", addr.wrapping_add(offset as i64 as u64))
+}
+
+/// Assembly for `inst` when it's at `addr`
+pub fn disassemble(inst: &Instruction, addr: u64) -> String {
+    use Instruction::*;
+
+    let load = |name: &str, rd: String, rs1: Register, imm: i64| {
+        format!("{} {}, {}({})", name, rd, imm, x(rs1))
+    };
+    let r = |name: &str, rd: Register, rs1: Register, rs2: Register| {
+        format!("{} {}, {}, {}", name, x(rd), x(rs1), x(rs2))
+    };
+    let i = |name: &str, rd: Register, rs1: Register, imm: i32| {
+        format!("{} {}, {}, {}", name, x(rd), x(rs1), imm)
+    };
+    let branch = |name: &str, rs1: Register, rs2: Register, imm: i32| {
+        format!("{} {}, {}, {}", name, x(rs1), x(rs2), target(addr, imm))
+    };
+    let amo = |name: &str, rd: Register, rs1: Register, rs2: Register,
+               aq: bool, rl: bool| {
+        format!("{}{} {}, {}, ({})", name, order(aq, rl), x(rd), x(rs2),
+                x(rs1))
+    };
+    let lr = |name: &str, rd: Register, rs1: Register, aq: bool, rl: bool| {
+        format!("{}{} {}, ({})", name, order(aq, rl), x(rd), x(rs1))
+    };
+    let fr = |name: &str, rd: Register, rs1: Register, rs2: Register| {
+        format!("{} {}, {}, {}", name, f(rd), f(rs1), f(rs2))
+    };
+    let frm = |name: &str, rd: Register, rs1: Register, rs2: Register,
+               mode: u32| {
+        format!("{} {}, {}, {}{}", name, f(rd), f(rs1), f(rs2), rm(mode))
+    };
+    let fma = |name: &str, rd: Register, rs1: Register, rs2: Register,
+               rs3: Register, mode: u32| {
+        format!("{} {}, {}, {}, {}{}", name, f(rd), f(rs1), f(rs2),
+                f(rs3), rm(mode))
+    };
+    let fcmp = |name: &str, rd: Register, rs1: Register, rs2: Register| {
+        format!("{} {}, {}, {}", name, x(rd), f(rs1), f(rs2))
+    };
+    // NOTE(patrik): Conversions to an integer write an integer register
+    // and conversions from one read an integer register
+    let to_x = |name: &str, rd: Register, rs1: Register, mode: u32| {
+        format!("{} {}, {}{}", name, x(rd), f(rs1), rm(mode))
+    };
+    let from_x = |name: &str, rd: Register, rs1: Register, mode: u32| {
+        format!("{} {}, {}{}", name, f(rd), x(rs1), rm(mode))
+    };
+    let ff = |name: &str, rd: Register, rs1: Register, mode: u32| {
+        format!("{} {}, {}{}", name, f(rd), f(rs1), rm(mode))
+    };
+    let csr = |name: &str, rd: Register, source: String, csr: u16| {
+        format!("{} {}, {}, {}", name, x(rd), csr_name(csr), source)
+    };
+    let cr = |name: &str, reg: Register, rs2: Register| {
+        format!("{} {}, {}", name, x(reg), x(rs2))
+    };
+    let ci = |name: &str, reg: Register, imm: i64| {
+        format!("{} {}, {}", name, x(reg), imm)
+    };
+
+    match *inst {
+        Lui { rd, imm } => format!("lui {}, {:#x}", x(rd),
+                                   (imm as u32) >> 12),
+        Auipc { rd, imm } => format!("auipc {}, {:#x}", x(rd),
+                                     (imm as u32) >> 12),
+        Jal { rd, imm } => format!("jal {}, {}", x(rd), target(addr, imm)),
+        Jalr { rd, rs1, imm } => load("jalr", x(rd), rs1, imm as i64),
+
+        Beq  { rs1, rs2, imm } => branch("beq", rs1, rs2, imm),
+        Bne  { rs1, rs2, imm } => branch("bne", rs1, rs2, imm),
+        Blt  { rs1, rs2, imm } => branch("blt", rs1, rs2, imm),
+        Bge  { rs1, rs2, imm } => branch("bge", rs1, rs2, imm),
+        Bltu { rs1, rs2, imm } => branch("bltu", rs1, rs2, imm),
+        Bgeu { rs1, rs2, imm } => branch("bgeu", rs1, rs2, imm),
+
+        Lb  { rd, rs1, imm } => load("lb", x(rd), rs1, imm as i64),
+        Lh  { rd, rs1, imm } => load("lh", x(rd), rs1, imm as i64),
+        Lw  { rd, rs1, imm } => load("lw", x(rd), rs1, imm as i64),
+        Lbu { rd, rs1, imm } => load("lbu", x(rd), rs1, imm as i64),
+        Lhu { rd, rs1, imm } => load("lhu", x(rd), rs1, imm as i64),
+        Lwu { rd, rs1, imm } => load("lwu", x(rd), rs1, imm as i64),
+        Ld  { rd, rs1, imm } => load("ld", x(rd), rs1, imm as i64),
+
+        Sb { rs1, rs2, imm } => load("sb", x(rs2), rs1, imm as i64),
+        Sh { rs1, rs2, imm } => load("sh", x(rs2), rs1, imm as i64),
+        Sw { rs1, rs2, imm } => load("sw", x(rs2), rs1, imm as i64),
+        Sd { rs1, rs2, imm } => load("sd", x(rs2), rs1, imm as i64),
+
+        Addi  { rd, rs1, imm } => i("addi", rd, rs1, imm),
+        Slti  { rd, rs1, imm } => i("slti", rd, rs1, imm),
+        Sltiu { rd, rs1, imm } => i("sltiu", rd, rs1, imm),
+        Xori  { rd, rs1, imm } => i("xori", rd, rs1, imm),
+        Ori   { rd, rs1, imm } => i("ori", rd, rs1, imm),
+        Andi  { rd, rs1, imm } => i("andi", rd, rs1, imm),
+        Slli  { rd, rs1, shamt } => i("slli", rd, rs1, shamt),
+        Srli  { rd, rs1, shamt } => i("srli", rd, rs1, shamt),
+        Srai  { rd, rs1, shamt } => i("srai", rd, rs1, shamt),
+
+        Addiw { rd, rs1, imm } => i("addiw", rd, rs1, imm),
+        Slliw { rd, rs1, shamt } => i("slliw", rd, rs1, shamt),
+        Srliw { rd, rs1, shamt } => i("srliw", rd, rs1, shamt),
+        Sraiw { rd, rs1, shamt } => i("sraiw", rd, rs1, shamt),
+
+        Add  { rd, rs1, rs2 } => r("add", rd, rs1, rs2),
+        Sub  { rd, rs1, rs2 } => r("sub", rd, rs1, rs2),
+        Sll  { rd, rs1, rs2 } => r("sll", rd, rs1, rs2),
+        Slt  { rd, rs1, rs2 } => r("slt", rd, rs1, rs2),
+        Sltu { rd, rs1, rs2 } => r("sltu", rd, rs1, rs2),
+        Xor  { rd, rs1, rs2 } => r("xor", rd, rs1, rs2),
+        Srl  { rd, rs1, rs2 } => r("srl", rd, rs1, rs2),
+        Sra  { rd, rs1, rs2 } => r("sra", rd, rs1, rs2),
+        Or   { rd, rs1, rs2 } => r("or", rd, rs1, rs2),
+        And  { rd, rs1, rs2 } => r("and", rd, rs1, rs2),
+        Mul    { rd, rs1, rs2 } => r("mul", rd, rs1, rs2),
+        Mulh   { rd, rs1, rs2 } => r("mulh", rd, rs1, rs2),
+        Mulhsu { rd, rs1, rs2 } => r("mulhsu", rd, rs1, rs2),
+        Mulhu  { rd, rs1, rs2 } => r("mulhu", rd, rs1, rs2),
+        Div    { rd, rs1, rs2 } => r("div", rd, rs1, rs2),
+        Divu   { rd, rs1, rs2 } => r("divu", rd, rs1, rs2),
+        Rem    { rd, rs1, rs2 } => r("rem", rd, rs1, rs2),
+        Remu   { rd, rs1, rs2 } => r("remu", rd, rs1, rs2),
+
+        Addw { rd, rs1, rs2 } => r("addw", rd, rs1, rs2),
+        Subw { rd, rs1, rs2 } => r("subw", rd, rs1, rs2),
+        Sllw { rd, rs1, rs2 } => r("sllw", rd, rs1, rs2),
+        Srlw { rd, rs1, rs2 } => r("srlw", rd, rs1, rs2),
+        Sraw { rd, rs1, rs2 } => r("sraw", rd, rs1, rs2),
+        Mulw  { rd, rs1, rs2 } => r("mulw", rd, rs1, rs2),
+        Divw  { rd, rs1, rs2 } => r("divw", rd, rs1, rs2),
+        Divuw { rd, rs1, rs2 } => r("divuw", rd, rs1, rs2),
+        Remw  { rd, rs1, rs2 } => r("remw", rd, rs1, rs2),
+        Remuw { rd, rs1, rs2 } => r("remuw", rd, rs1, rs2),
+
+        Fence { imm, .. } => format!("fence {}, {}", fence_set(imm >> 4),
+                                     fence_set(imm)),
+        FenceI => "fence.i".to_string(),
+
+        Ecall => "ecall".to_string(),
+        Ebreak => "ebreak".to_string(),
+        Sret => "sret".to_string(),
+        Mret => "mret".to_string(),
+        Wfi => "wfi".to_string(),
+        SfenceVma { rs1, rs2 } => format!("sfence.vma {}, {}", x(rs1),
+                                          x(rs2)),
+        Csrrw  { rd, rs1, csr: number } => csr("csrrw", rd, x(rs1), number),
+        Csrrs  { rd, rs1, csr: number } => csr("csrrs", rd, x(rs1), number),
+        Csrrc  { rd, rs1, csr: number } => csr("csrrc", rd, x(rs1), number),
+        Csrrwi { rd, uimm, csr: number } =>
+            csr("csrrwi", rd, uimm.to_string(), number),
+        Csrrsi { rd, uimm, csr: number } =>
+            csr("csrrsi", rd, uimm.to_string(), number),
+        Csrrci { rd, uimm, csr: number } =>
+            csr("csrrci", rd, uimm.to_string(), number),
+
+        Lrw { rd, rs1, aq, rl } => lr("lr.w", rd, rs1, aq, rl),
+        Scw { rd, rs1, rs2, aq, rl } => amo("sc.w", rd, rs1, rs2, aq, rl),
+        Amoswapw { rd, rs1, rs2, aq, rl } =>
+            amo("amoswap.w", rd, rs1, rs2, aq, rl),
+        Amoaddw { rd, rs1, rs2, aq, rl } =>
+            amo("amoadd.w", rd, rs1, rs2, aq, rl),
+        Amoxorw { rd, rs1, rs2, aq, rl } =>
+            amo("amoxor.w", rd, rs1, rs2, aq, rl),
+        Amoandw { rd, rs1, rs2, aq, rl } =>
+            amo("amoand.w", rd, rs1, rs2, aq, rl),
+        Amoorw { rd, rs1, rs2, aq, rl } =>
+            amo("amoor.w", rd, rs1, rs2, aq, rl),
+        Amominw { rd, rs1, rs2, aq, rl } =>
+            amo("amomin.w", rd, rs1, rs2, aq, rl),
+        Amomaxw { rd, rs1, rs2, aq, rl } =>
+            amo("amomax.w", rd, rs1, rs2, aq, rl),
+        Amominuw { rd, rs1, rs2, aq, rl } =>
+            amo("amominu.w", rd, rs1, rs2, aq, rl),
+        Amomaxuw { rd, rs1, rs2, aq, rl } =>
+            amo("amomaxu.w", rd, rs1, rs2, aq, rl),
+        Lrd { rd, rs1, aq, rl } => lr("lr.d", rd, rs1, aq, rl),
+        Scd { rd, rs1, rs2, aq, rl } => amo("sc.d", rd, rs1, rs2, aq, rl),
+        Amoswapd { rd, rs1, rs2, aq, rl } =>
+            amo("amoswap.d", rd, rs1, rs2, aq, rl),
+        Amoaddd { rd, rs1, rs2, aq, rl } =>
+            amo("amoadd.d", rd, rs1, rs2, aq, rl),
+        Amoxord { rd, rs1, rs2, aq, rl } =>
+            amo("amoxor.d", rd, rs1, rs2, aq, rl),
+        Amoandd { rd, rs1, rs2, aq, rl } =>
+            amo("amoand.d", rd, rs1, rs2, aq, rl),
+        Amoord { rd, rs1, rs2, aq, rl } =>
+            amo("amoor.d", rd, rs1, rs2, aq, rl),
+        Amomind { rd, rs1, rs2, aq, rl } =>
+            amo("amomin.d", rd, rs1, rs2, aq, rl),
+        Amomaxd { rd, rs1, rs2, aq, rl } =>
+            amo("amomax.d", rd, rs1, rs2, aq, rl),
+        Amominud { rd, rs1, rs2, aq, rl } =>
+            amo("amominu.d", rd, rs1, rs2, aq, rl),
+        Amomaxud { rd, rs1, rs2, aq, rl } =>
+            amo("amomaxu.d", rd, rs1, rs2, aq, rl),
+
+        Flw { rd, rs1, imm } => load("flw", f(rd).into(), rs1, imm as i64),
+        Fsw { rs1, rs2, imm } => load("fsw", f(rs2).into(), rs1, imm as i64),
+        Fmadds { rd, rs1, rs2, rs3, rm } =>
+            fma("fmadd.s", rd, rs1, rs2, rs3, rm),
+        Fmsubs { rd, rs1, rs2, rs3, rm } =>
+            fma("fmsub.s", rd, rs1, rs2, rs3, rm),
+        Fnmsubs { rd, rs1, rs2, rs3, rm } =>
+            fma("fnmsub.s", rd, rs1, rs2, rs3, rm),
+        Fnmadds { rd, rs1, rs2, rs3, rm } =>
+            fma("fnmadd.s", rd, rs1, rs2, rs3, rm),
+        Fadds { rd, rs1, rs2, rm } => frm("fadd.s", rd, rs1, rs2, rm),
+        Fsubs { rd, rs1, rs2, rm } => frm("fsub.s", rd, rs1, rs2, rm),
+        Fmuls { rd, rs1, rs2, rm } => frm("fmul.s", rd, rs1, rs2, rm),
+        Fdivs { rd, rs1, rs2, rm } => frm("fdiv.s", rd, rs1, rs2, rm),
+        Fsqrts { rd, rs1, rm } => ff("fsqrt.s", rd, rs1, rm),
+        Fsgnjs  { rd, rs1, rs2 } => fr("fsgnj.s", rd, rs1, rs2),
+        Fsgnjns { rd, rs1, rs2 } => fr("fsgnjn.s", rd, rs1, rs2),
+        Fsgnjxs { rd, rs1, rs2 } => fr("fsgnjx.s", rd, rs1, rs2),
+        Fmins   { rd, rs1, rs2 } => fr("fmin.s", rd, rs1, rs2),
+        Fmaxs   { rd, rs1, rs2 } => fr("fmax.s", rd, rs1, rs2),
+        Feqs { rd, rs1, rs2 } => fcmp("feq.s", rd, rs1, rs2),
+        Flts { rd, rs1, rs2 } => fcmp("flt.s", rd, rs1, rs2),
+        Fles { rd, rs1, rs2 } => fcmp("fle.s", rd, rs1, rs2),
+        Fclasss { rd, rs1 } => to_x("fclass.s", rd, rs1, RM_DYNAMIC),
+        Fcvtws  { rd, rs1, rm } => to_x("fcvt.w.s", rd, rs1, rm),
+        Fcvtwus { rd, rs1, rm } => to_x("fcvt.wu.s", rd, rs1, rm),
+        Fcvtls  { rd, rs1, rm } => to_x("fcvt.l.s", rd, rs1, rm),
+        Fcvtlus { rd, rs1, rm } => to_x("fcvt.lu.s", rd, rs1, rm),
+        Fcvtsw  { rd, rs1, rm } => from_x("fcvt.s.w", rd, rs1, rm),
+        Fcvtswu { rd, rs1, rm } => from_x("fcvt.s.wu", rd, rs1, rm),
+        Fcvtsl  { rd, rs1, rm } => from_x("fcvt.s.l", rd, rs1, rm),
+        Fcvtslu { rd, rs1, rm } => from_x("fcvt.s.lu", rd, rs1, rm),
+        Fmvxw { rd, rs1 } => to_x("fmv.x.w", rd, rs1, RM_DYNAMIC),
+        Fmvwx { rd, rs1 } => from_x("fmv.w.x", rd, rs1, RM_DYNAMIC),
+
+        Fld { rd, rs1, imm } => load("fld", f(rd).into(), rs1, imm as i64),
+        Fsd { rs1, rs2, imm } => load("fsd", f(rs2).into(), rs1, imm as i64),
+        Fmaddd { rd, rs1, rs2, rs3, rm } =>
+            fma("fmadd.d", rd, rs1, rs2, rs3, rm),
+        Fmsubd { rd, rs1, rs2, rs3, rm } =>
+            fma("fmsub.d", rd, rs1, rs2, rs3, rm),
+        Fnmsubd { rd, rs1, rs2, rs3, rm } =>
+            fma("fnmsub.d", rd, rs1, rs2, rs3, rm),
+        Fnmaddd { rd, rs1, rs2, rs3, rm } =>
+            fma("fnmadd.d", rd, rs1, rs2, rs3, rm),
+        Faddd { rd, rs1, rs2, rm } => frm("fadd.d", rd, rs1, rs2, rm),
+        Fsubd { rd, rs1, rs2, rm } => frm("fsub.d", rd, rs1, rs2, rm),
+        Fmuld { rd, rs1, rs2, rm } => frm("fmul.d", rd, rs1, rs2, rm),
+        Fdivd { rd, rs1, rs2, rm } => frm("fdiv.d", rd, rs1, rs2, rm),
+        Fsqrtd { rd, rs1, rm } => ff("fsqrt.d", rd, rs1, rm),
+        Fsgnjd  { rd, rs1, rs2 } => fr("fsgnj.d", rd, rs1, rs2),
+        Fsgnjnd { rd, rs1, rs2 } => fr("fsgnjn.d", rd, rs1, rs2),
+        Fsgnjxd { rd, rs1, rs2 } => fr("fsgnjx.d", rd, rs1, rs2),
+        Fmind   { rd, rs1, rs2 } => fr("fmin.d", rd, rs1, rs2),
+        Fmaxd   { rd, rs1, rs2 } => fr("fmax.d", rd, rs1, rs2),
+        Feqd { rd, rs1, rs2 } => fcmp("feq.d", rd, rs1, rs2),
+        Fltd { rd, rs1, rs2 } => fcmp("flt.d", rd, rs1, rs2),
+        Fled { rd, rs1, rs2 } => fcmp("fle.d", rd, rs1, rs2),
+        Fclassd { rd, rs1 } => to_x("fclass.d", rd, rs1, RM_DYNAMIC),
+        Fcvtwd  { rd, rs1, rm } => to_x("fcvt.w.d", rd, rs1, rm),
+        Fcvtwud { rd, rs1, rm } => to_x("fcvt.wu.d", rd, rs1, rm),
+        Fcvtld  { rd, rs1, rm } => to_x("fcvt.l.d", rd, rs1, rm),
+        Fcvtlud { rd, rs1, rm } => to_x("fcvt.lu.d", rd, rs1, rm),
+        Fcvtdw  { rd, rs1, rm } => from_x("fcvt.d.w", rd, rs1, rm),
+        Fcvtdwu { rd, rs1, rm } => from_x("fcvt.d.wu", rd, rs1, rm),
+        Fcvtdl  { rd, rs1, rm } => from_x("fcvt.d.l", rd, rs1, rm),
+        Fcvtdlu { rd, rs1, rm } => from_x("fcvt.d.lu", rd, rs1, rm),
+        Fcvtsd { rd, rs1, rm } => ff("fcvt.s.d", rd, rs1, rm),
+        Fcvtds { rd, rs1, rm } => ff("fcvt.d.s", rd, rs1, rm),
+        Fmvxd { rd, rs1 } => to_x("fmv.x.d", rd, rs1, RM_DYNAMIC),
+        Fmvdx { rd, rs1 } => from_x("fmv.d.x", rd, rs1, RM_DYNAMIC),
+
+        // NOTE(patrik): The compressed instructions are printed as
+        // themselves and not as the instructions they expand to
+        Hint => "c.hint".to_string(),
+
+        CAddi4spn { rd, nzuimm } => format!("c.addi4spn {}, sp, {}", x(rd),
+                                            nzuimm),
+        CFld { rd, rs1, uimm } => load("c.fld", f(rd).into(), rs1,
+                                       uimm as i64),
+        CLw { rd, rs1, uimm } => load("c.lw", x(rd), rs1, uimm as i64),
+        CLd { rd, rs1, uimm } => load("c.ld", x(rd), rs1, uimm as i64),
+        CFsd { rs1, rs2, uimm } => load("c.fsd", f(rs2).into(), rs1,
+                                        uimm as i64),
+        CSw { rs1, rs2, uimm } => load("c.sw", x(rs2), rs1, uimm as i64),
+        CSd { rs1, rs2, uimm } => load("c.sd", x(rs2), rs1, uimm as i64),
+
+        CNop => "c.nop".to_string(),
+        CAddi { reg, nzimm } => ci("c.addi", reg, nzimm as i64),
+        CAddiw { reg, imm } => ci("c.addiw", reg, imm as i64),
+        CLi { rd, imm } => ci("c.li", rd, imm as i64),
+        CAddi16sp { nzimm } => format!("c.addi16sp sp, {}", nzimm),
+        CLui { rd, nzimm } => format!("c.lui {}, {:#x}", x(rd),
+                                      ((nzimm as u32) >> 12) & 0xfffff),
+        CAndi { reg, imm } => ci("c.andi", reg, imm as i64),
+        CSub  { reg, rs2 } => cr("c.sub", reg, rs2),
+        CXor  { reg, rs2 } => cr("c.xor", reg, rs2),
+        COr   { reg, rs2 } => cr("c.or", reg, rs2),
+        CAnd  { reg, rs2 } => cr("c.and", reg, rs2),
+        CSubw { reg, rs2 } => cr("c.subw", reg, rs2),
+        CAddw { reg, rs2 } => cr("c.addw", reg, rs2),
+        CJ { imm } => format!("c.j {}", target(addr, imm)),
+        CBeqz { rs1, imm } => format!("c.beqz {}, {}", x(rs1),
+                                      target(addr, imm)),
+        CBnez { rs1, imm } => format!("c.bnez {}, {}", x(rs1),
+                                      target(addr, imm)),
+
+        CSlli { reg, nzuimm } => ci("c.slli", reg, nzuimm as i64),
+        CFldsp { rd, uimm } => load("c.fldsp", f(rd).into(), Register::Sp,
+                                    uimm as i64),
+        CLwsp { rd, uimm } => load("c.lwsp", x(rd), Register::Sp,
+                                   uimm as i64),
+        CLdsp { rd, uimm } => load("c.ldsp", x(rd), Register::Sp,
+                                   uimm as i64),
+        CJr { rs1 } => format!("c.jr {}", x(rs1)),
+        CMv { rd, rs2 } => cr("c.mv", rd, rs2),
+        CEbreak => "c.ebreak".to_string(),
+        CJalr { rs1 } => format!("c.jalr {}", x(rs1)),
+        CAdd { reg, rs2 } => cr("c.add", reg, rs2),
+        CFsdsp { rs2, uimm } => load("c.fsdsp", f(rs2).into(), Register::Sp,
+                                     uimm as i64),
+        CSwsp { rs2, uimm } => load("c.swsp", x(rs2), Register::Sp,
+                                    uimm as i64),
+        CSdsp { rs2, uimm } => load("c.sdsp", x(rs2), Register::Sp,
+                                    uimm as i64),
+
+        Undefined(inst) => format!(".word {:#010x}", inst),
+        UndefinedCompressed(inst) => format!(".half {:#06x}", inst),
+    }
+}
@@ -0,0 +1,201 @@
+//! Loader for statically linked 64-bit little endian RISC-V ELF files
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::mmu::Mmu;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const SHT_SYMTAB: u32 = 2;
+
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+/// A part of the file that is placed in memory
+pub struct Segment {
+    pub addr: u64,
+    /// Bytes from the file, the rest of the segment up to `mem_size` is
+    /// zero
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+}
+
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+
+    /// Address of the program headers once loaded, if they are part of a
+    /// segment. Needed by the C library of Linux programs to find TLS
+    pub program_headers: Option<u64>,
+    pub program_header_count: usize,
+
+    symbols: HashMap<String, u64>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    let bytes = data.get(offset..offset + 2)
+        .expect("ELF: Read past the end of the file");
+    u16::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let bytes = data.get(offset..offset + 4)
+        .expect("ELF: Read past the end of the file");
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let bytes = data.get(offset..offset + 8)
+        .expect("ELF: Read past the end of the file");
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_str(data: &[u8], offset: usize) -> String {
+    let bytes = data.get(offset..).unwrap_or(&[]);
+    let end = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+impl Elf {
+    /// Check if `data` starts with the ELF magic
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Self {
+        if !Self::is_elf(data) {
+            panic!("ELF: Bad magic");
+        }
+
+        if data.get(4) != Some(&ELFCLASS64) ||
+            data.get(5) != Some(&ELFDATA2LSB)
+        {
+            panic!("ELF: Only 64-bit little endian files are supported");
+        }
+
+        let machine = read_u16(data, 18);
+        if machine != EM_RISCV {
+            panic!("ELF: Not a RISC-V file, machine {}", machine);
+        }
+
+        let entry = read_u64(data, 24);
+        let program_headers = read_u64(data, 32) as usize;
+        let section_headers = read_u64(data, 40) as usize;
+        let program_header_count = read_u16(data, 56) as usize;
+        let section_header_count = read_u16(data, 60) as usize;
+
+        let mut segments = Vec::new();
+        let mut program_headers_addr = None;
+        for index in 0..program_header_count {
+            let header = program_headers + index * PROGRAM_HEADER_SIZE;
+            let typ = read_u32(data, header);
+            let offset = read_u64(data, header + 8) as usize;
+            let addr = read_u64(data, header + 24);
+            let file_size = read_u64(data, header + 32) as usize;
+            let mem_size = read_u64(data, header + 40);
+
+            if typ == PT_PHDR {
+                program_headers_addr = Some(addr);
+            }
+
+            if typ != PT_LOAD {
+                continue;
+            }
+
+            // NOTE(patrik): Without a PT_PHDR the headers are found in the
+            // segment that covers them in the file
+            if program_headers_addr.is_none() &&
+                (offset..offset + file_size).contains(&program_headers)
+            {
+                program_headers_addr =
+                    Some(addr + (program_headers - offset) as u64);
+            }
+
+            let data = data.get(offset..offset + file_size)
+                .expect("ELF: Segment is outside of the file")
+                .to_vec();
+
+            segments.push(Segment { addr, data, mem_size });
+        }
+
+        let mut symbols = HashMap::new();
+        for index in 0..section_header_count {
+            let header = section_headers + index * SECTION_HEADER_SIZE;
+            if read_u32(data, header + 4) != SHT_SYMTAB {
+                continue;
+            }
+
+            let offset = read_u64(data, header + 24) as usize;
+            let size = read_u64(data, header + 32) as usize;
+
+            // NOTE(patrik): sh_link of the symbol table is the index of the
+            // string table holding the names
+            let link = read_u32(data, header + 40) as usize;
+            let strings = section_headers + link * SECTION_HEADER_SIZE;
+            let strings = read_u64(data, strings + 24) as usize;
+
+            for symbol in (offset..offset + size).step_by(SYMBOL_SIZE) {
+                let name = read_str(data, strings + read_u32(data, symbol)
+                                    as usize);
+                if name.is_empty() {
+                    continue;
+                }
+
+                let value = read_u64(data, symbol + 8);
+                symbols.entry(name).or_insert(value);
+            }
+        }
+
+        Self {
+            entry,
+            segments,
+
+            program_headers: program_headers_addr,
+            program_header_count,
+
+            symbols,
+        }
+    }
+
+    /// Address of the symbol called `name`
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Lowest address of any segment
+    pub fn base(&self) -> Option<u64> {
+        self.segments.iter().map(|segment| segment.addr).min()
+    }
+
+    /// First address after the end of all the segments
+    pub fn end(&self) -> Option<u64> {
+        self.segments.iter()
+            .map(|segment| segment.addr + segment.mem_size)
+            .max()
+    }
+
+    /// Copy all the segments into RAM
+    pub fn load(&self, mmu: &mut Mmu) {
+        for segment in self.segments.iter() {
+            let zeroes = segment.mem_size
+                .saturating_sub(segment.data.len() as u64) as usize;
+            let bytes = segment.data.iter()
+                .copied()
+                .chain(std::iter::repeat_n(0, zeroes));
+
+            for (index, value) in bytes.enumerate() {
+                let addr = segment.addr + index as u64;
+                if !mmu.poke_u8(addr, value) {
+                    panic!("ELF: Segment at {:#x} is not in RAM", addr);
+                }
+            }
+        }
+    }
+}
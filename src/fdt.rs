@@ -0,0 +1,514 @@
+//! Flattened device tree generation, describes the harts, memory and the
+//! devices on the bus to kernels and firmware. The blob is placed in RAM
+//! and its address passed in a1
+//!
+//!     rest-emu --dtb --dump-dtb machine.dtb program
+//!
+//! `dtc -I dtb -O dts machine.dtb` shows what was generated
+
+use crate::clint::{ CLINT_BASE, CLINT_SIZE };
+use crate::plic::{ PLIC_BASE, PLIC_SIZE };
+use crate::uart::{ UART_BASE, UART_SIZE, UART_IRQ };
+use crate::syscon::{ SYSCON_BASE, SYSCON_SIZE };
+use crate::virtio::{ self, VIRTIO_MMIO_SIZE, VIRTIO_MMIO_IRQ };
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+/// The memory reservation block only has the terminating entry
+const RESERVATION_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// Phandles of the nodes other nodes refer to, the interrupt controllers
+// of the harts come after these
+const PHANDLE_PLIC: u32 = 1;
+const PHANDLE_SYSCON: u32 = 2;
+const PHANDLE_HART_INTC: u32 = 3;
+
+// Interrupt numbers of the local interrupt controller of a hart
+const IRQ_S_EXTERNAL: u32 = 9;
+const IRQ_M_SOFTWARE: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_M_EXTERNAL: u32 = 11;
+
+/// The UART ignores the divisor latch, this is only for drivers that want
+/// to know
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// Values written to the test finisher to power off and reboot
+const SYSCON_POWEROFF: u32 = 0x5555;
+const SYSCON_REBOOT: u32 = 0x7777;
+
+/// Builds up a flattened device tree one node and property at a time
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Pad the structure block to the next 4 byte boundary
+    fn align(&mut self) {
+        let length = self.structure.len().next_multiple_of(4);
+        self.structure.resize(length, 0);
+    }
+
+    /// Offset of `name` in the strings block, names are only stored once
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in self.strings.split(|byte| *byte == 0) {
+            if string == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+
+            offset += string.len() + 1;
+        }
+
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+
+        offset as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        if self.depth == 0 {
+            panic!("FDT: Ending a node that was never started");
+        }
+
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.string_offset(name);
+
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Property without a value, like `interrupt-controller`
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// String list, each string is NUL terminated
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// `reg` of a node in a bus with two address and two size cells
+    pub fn property_reg(&mut self, regions: &[(u64, u64)]) {
+        let cells: Vec<u32> = regions.iter()
+            .flat_map(|(base, size)| {
+                [(base >> 32) as u32, *base as u32,
+                 (size >> 32) as u32, *size as u32]
+            })
+            .collect();
+        self.property_cells("reg", &cells);
+    }
+
+    /// Put the blob together, all nodes have to be ended
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.depth != 0 {
+            panic!("FDT: {} nodes were never ended", self.depth);
+        }
+        self.token(FDT_END);
+
+        let structure_offset = HEADER_SIZE + RESERVATION_SIZE;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for value in header.iter() {
+            blob.extend_from_slice(&value.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; RESERVATION_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+}
+
+/// What the generated device tree describes besides the devices that are
+/// always on the bus
+pub struct MachineDescription {
+    pub harts: usize,
+    /// ISA string of the harts, like `rv64imac_zicsr`
+    pub isa: String,
+    /// RAM regions as base and size
+    pub memory: Vec<(u64, u64)>,
+    /// Frequency of mtime in Hz
+    pub timebase_frequency: u64,
+    /// Number of interrupt sources of the PLIC, including the unused 0
+    pub plic_sources: usize,
+    /// Kernel command line
+    pub bootargs: Option<String>,
+    /// Start and end of the initramfs in RAM
+    pub initrd: Option<(u64, u64)>,
+    /// Number of virtio-mmio slots on the bus
+    pub virtio_slots: usize,
+}
+
+/// Generate the device tree blob for the machine in `description`
+pub fn generate(description: &MachineDescription) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "rest-emu");
+    fdt.property_string("model", "rest-emu");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path",
+                        &format!("/soc/serial@{:x}", UART_BASE));
+    if let Some(bootargs) = description.bootargs.as_ref() {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = description.initrd {
+        fdt.property_cells("linux,initrd-start",
+                           &[(start >> 32) as u32, start as u32]);
+        fdt.property_cells("linux,initrd-end",
+                           &[(end >> 32) as u32, end as u32]);
+    }
+    fdt.end_node();
+
+    for (base, size) in description.memory.iter() {
+        fdt.begin_node(&format!("memory@{:x}", base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg(&[(*base, *size)]);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency",
+                     description.timebase_frequency as u32);
+    for hart in 0..description.harts {
+        write_hart(&mut fdt, hart, &description.isa);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    let hart_interrupts = |numbers: &[u32]| -> Vec<u32> {
+        (0..description.harts as u32)
+            .flat_map(|hart| {
+                numbers.iter()
+                    .flat_map(move |number| [PHANDLE_HART_INTC + hart,
+                                             *number])
+            })
+            .collect()
+    };
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_reg(&[(CLINT_BASE, CLINT_SIZE)]);
+    fdt.property_cells("interrupts-extended",
+                       &hart_interrupts(&[IRQ_M_SOFTWARE, IRQ_M_TIMER]));
+    fdt.end_node();
+
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_reg(&[(PLIC_BASE, PLIC_SIZE)]);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_u32("riscv,ndev", description.plic_sources as u32 - 1);
+    fdt.property_cells("interrupts-extended",
+                       &hart_interrupts(&[IRQ_M_EXTERNAL, IRQ_S_EXTERNAL]));
+    fdt.property_u32("phandle", PHANDLE_PLIC);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_reg(&[(UART_BASE, UART_SIZE)]);
+    fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+    fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+    fdt.property_u32("interrupts", UART_IRQ as u32);
+    fdt.end_node();
+
+    for slot in 0..description.virtio_slots {
+        let base = virtio::slot_base(slot);
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(&[(base, VIRTIO_MMIO_SIZE)]);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", (VIRTIO_MMIO_IRQ + slot) as u32);
+        fdt.end_node();
+    }
+
+    fdt.begin_node(&format!("test@{:x}", SYSCON_BASE));
+    fdt.property_strings("compatible",
+                         &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.property_reg(&[(SYSCON_BASE, SYSCON_SIZE)]);
+    fdt.property_u32("phandle", PHANDLE_SYSCON);
+    fdt.end_node();
+
+    for (name, value) in [("poweroff", SYSCON_POWEROFF),
+                          ("reboot", SYSCON_REBOOT)]
+    {
+        fdt.begin_node(name);
+        fdt.property_string("compatible", &format!("syscon-{}", name));
+        fdt.property_u32("regmap", PHANDLE_SYSCON);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", value);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+fn write_hart(fdt: &mut FdtWriter, hart: usize, isa: &str) {
+    fdt.begin_node(&format!("cpu@{}", hart));
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", hart as u32);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", isa);
+    fdt.property_string("mmu-type", "riscv,sv39");
+
+    // NOTE(patrik): Newer kernels want the base and the extensions apart
+    let mut parts = isa.split('_');
+    let base = parts.next().unwrap_or("");
+    let (base, letters) = base.split_at(base.len().min(4));
+    let letters: Vec<String> = letters.chars()
+        .map(|letter| letter.to_string())
+        .collect();
+    let mut extensions: Vec<&str> = letters.iter()
+        .map(String::as_str)
+        .collect();
+    extensions.extend(parts);
+
+    fdt.property_string("riscv,isa-base", &format!("{}i", base));
+    fdt.property_strings("riscv,isa-extensions", &extensions);
+
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", PHANDLE_HART_INTC + hart as u32);
+    fdt.end_node();
+
+    fdt.end_node();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Walk the structure block of `blob` and return every property as its
+    /// path and value
+    fn properties(blob: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let structure = be32(blob, 8) as usize;
+        let strings = be32(blob, 12) as usize;
+
+        let c_string = |offset: usize| -> String {
+            let end = blob[offset..].iter().position(|byte| *byte == 0)
+                .unwrap();
+            String::from_utf8(blob[offset..offset + end].to_vec()).unwrap()
+        };
+
+        let mut path: Vec<String> = Vec::new();
+        let mut properties = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = be32(blob, offset);
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(offset);
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    path.push(name);
+                },
+                FDT_END_NODE => { path.pop().unwrap(); },
+                FDT_PROP => {
+                    let length = be32(blob, offset) as usize;
+                    let name = c_string(strings + be32(blob, offset + 4)
+                                        as usize);
+                    let value = blob[offset + 8..][..length].to_vec();
+                    offset = (offset + 8 + length).next_multiple_of(4);
+
+                    properties.push((format!("{}/{}", path.join("/"), name),
+                                     value));
+                },
+                FDT_END => break,
+                _ => panic!("Unknown token {} at {:#x}", token, offset - 4),
+            }
+        }
+
+        assert!(path.is_empty());
+        properties
+    }
+
+    fn property<'a>(properties: &'a [(String, Vec<u8>)], path: &str)
+        -> &'a [u8]
+    {
+        properties.iter()
+            .find(|(name, _)| name == path)
+            .map(|(_, value)| &value[..])
+            .unwrap_or_else(|| panic!("No property {}", path))
+    }
+
+    #[test]
+    fn header_and_layout() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("a", 1);
+        fdt.begin_node("node@1");
+        fdt.property_string("a", "xyz");
+        fdt.property_empty("b");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 8) as usize, HEADER_SIZE + RESERVATION_SIZE);
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+
+        // NOTE(patrik): "a" is only stored once
+        let strings = be32(&blob, 12) as usize;
+        assert_eq!(&blob[strings..], b"a\0b\0");
+        assert_eq!(be32(&blob, 32) as usize, 4);
+
+        let properties = properties(&blob);
+        assert_eq!(property(&properties, "/a"), [0, 0, 0, 1]);
+        assert_eq!(property(&properties, "/node@1/a"), b"xyz\0");
+        assert_eq!(property(&properties, "/node@1/b"), b"");
+    }
+
+    #[test]
+    #[should_panic(expected = "never ended")]
+    fn unended_node_is_refused() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.finish();
+    }
+
+    #[test]
+    fn machine_description() {
+        let description = MachineDescription {
+            harts: 2,
+            isa: "rv64imac_zicsr".to_string(),
+            memory: vec![(0x8000_0000, 0x1_0000_0000)],
+            timebase_frequency: 10_000_000,
+            plic_sources: 32,
+            bootargs: Some("console=ttyS0".to_string()),
+            initrd: None,
+            virtio_slots: 1,
+        };
+        let properties = properties(&generate(&description));
+
+        assert_eq!(property(&properties, "/chosen/bootargs"),
+                   b"console=ttyS0\0");
+        assert_eq!(property(&properties, "/memory@80000000/reg"),
+                   [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(property(&properties, "/cpus/cpu@1/riscv,isa"),
+                   b"rv64imac_zicsr\0");
+        assert_eq!(property(&properties, "/cpus/cpu@1/riscv,isa-base"),
+                   b"rv64i\0");
+        assert_eq!(property(&properties,
+                            "/cpus/cpu@0/riscv,isa-extensions"),
+                   b"i\0m\0a\0c\0zicsr\0");
+
+        let plic = format!("/soc/plic@{:x}", PLIC_BASE);
+        assert_eq!(property(&properties, &format!("{}/riscv,ndev", plic)),
+                   31u32.to_be_bytes());
+
+        // NOTE(patrik): Machine and supervisor external interrupts of both
+        // harts
+        let interrupts: Vec<u8> = [3, 11, 3, 9, 4, 11, 4, 9].iter()
+            .flat_map(|cell: &u32| cell.to_be_bytes())
+            .collect();
+        let path = format!("{}/interrupts-extended", plic);
+        assert_eq!(property(&properties, &path), interrupts);
+
+        let virtio = properties.iter()
+            .filter(|(name, _)| name.starts_with("/soc/virtio_mmio@"))
+            .filter(|(name, _)| name.ends_with("/interrupts"))
+            .count();
+        assert_eq!(virtio, 1);
+        assert!(!properties.iter()
+                .any(|(name, _)| name.contains("linux,initrd")));
+    }
+}
@@ -0,0 +1,521 @@
+//! IEEE 754 arithmetic of the F and D extensions on top of the host
+//! floats. The host always rounds to nearest even, the other rounding
+//! modes are done by looking at which side of the rounded result the exact
+//! result is on and stepping to the neighbour when needed
+//!
+//! Values in the floating point registers are kept as raw bits, singles
+//! are NaN-boxed in the upper 32 bits
+
+use std::cmp::Ordering;
+use std::ops::{ Add, Sub, Mul, Div, Neg };
+
+// Bits of fflags
+pub const FLAG_INEXACT: u64        = 1 << 0;
+pub const FLAG_UNDERFLOW: u64      = 1 << 1;
+pub const FLAG_OVERFLOW: u64       = 1 << 2;
+pub const FLAG_DIVIDE_BY_ZERO: u64 = 1 << 3;
+pub const FLAG_INVALID: u64        = 1 << 4;
+
+/// Rounding mode from the rm field of an instruction or from frm
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Static rounding modes, 7 selects frm and the rest are reserved
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+
+            _ => None,
+        }
+    }
+}
+
+/// What the F and D extensions need from `f32` and `f64`
+pub trait Float: Copy + PartialOrd + Add<Output = Self> +
+    Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> +
+    Neg<Output = Self>
+{
+    const ZERO: Self;
+    const MAX: Self;
+    const MIN_POSITIVE: Self;
+    const CANONICAL_NAN: u64;
+    const SIGN_BIT: u64;
+
+    fn from_bits(bits: u64) -> Self;
+    fn to_bits(self) -> u64;
+
+    /// Bits of the value in a register, a single that isn't properly
+    /// NaN-boxed reads as the canonical NaN
+    fn unbox(value: u64) -> u64;
+    /// Register value holding `bits`
+    fn rebox(bits: u64) -> u64;
+
+    fn from_register(value: u64) -> Self {
+        Self::from_bits(Self::unbox(value))
+    }
+
+    /// Register value holding `self`, NaNs are made canonical
+    fn to_register(self) -> u64 {
+        if self.is_nan() {
+            return Self::rebox(Self::CANONICAL_NAN);
+        }
+
+        Self::rebox(self.to_bits())
+    }
+
+    fn is_nan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    /// Is a NaN with the quiet bit clear
+    fn is_signaling(self) -> bool;
+
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+
+    fn to_f64(self) -> f64;
+    /// Round `value` to nearest even
+    fn from_f64(value: f64) -> Self;
+    /// Round `value` to nearest even
+    fn from_i128(value: i128) -> Self;
+}
+
+impl Float for f32 {
+    const ZERO: Self = 0.0;
+    const MAX: Self = f32::MAX;
+    const MIN_POSITIVE: Self = f32::MIN_POSITIVE;
+    const CANONICAL_NAN: u64 = 0x7fc0_0000;
+    const SIGN_BIT: u64 = 1 << 31;
+
+    fn from_bits(bits: u64) -> Self { f32::from_bits(bits as u32) }
+    fn to_bits(self) -> u64 { f32::to_bits(self) as u64 }
+
+    fn unbox(value: u64) -> u64 {
+        if value >> 32 != 0xffff_ffff {
+            return Self::CANONICAL_NAN;
+        }
+
+        value & 0xffff_ffff
+    }
+
+    fn rebox(bits: u64) -> u64 {
+        0xffff_ffff_0000_0000 | bits
+    }
+
+    fn is_nan(self) -> bool { self.is_nan() }
+    fn is_infinite(self) -> bool { self.is_infinite() }
+    fn is_sign_negative(self) -> bool { self.is_sign_negative() }
+
+    fn is_signaling(self) -> bool {
+        self.is_nan() && f32::to_bits(self) & (1 << 22) == 0
+    }
+
+    fn abs(self) -> Self { self.abs() }
+    fn sqrt(self) -> Self { self.sqrt() }
+    fn mul_add(self, a: Self, b: Self) -> Self { self.mul_add(a, b) }
+    fn next_up(self) -> Self { self.next_up() }
+    fn next_down(self) -> Self { self.next_down() }
+
+    fn to_f64(self) -> f64 { self as f64 }
+    fn from_f64(value: f64) -> Self { value as f32 }
+    fn from_i128(value: i128) -> Self { value as f32 }
+}
+
+impl Float for f64 {
+    const ZERO: Self = 0.0;
+    const MAX: Self = f64::MAX;
+    const MIN_POSITIVE: Self = f64::MIN_POSITIVE;
+    const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+    const SIGN_BIT: u64 = 1 << 63;
+
+    fn from_bits(bits: u64) -> Self { f64::from_bits(bits) }
+    fn to_bits(self) -> u64 { f64::to_bits(self) }
+
+    fn unbox(value: u64) -> u64 {
+        value
+    }
+
+    fn rebox(bits: u64) -> u64 {
+        bits
+    }
+
+    fn is_nan(self) -> bool { self.is_nan() }
+    fn is_infinite(self) -> bool { self.is_infinite() }
+    fn is_sign_negative(self) -> bool { self.is_sign_negative() }
+
+    fn is_signaling(self) -> bool {
+        self.is_nan() && f64::to_bits(self) & (1 << 51) == 0
+    }
+
+    fn abs(self) -> Self { self.abs() }
+    fn sqrt(self) -> Self { self.sqrt() }
+    fn mul_add(self, a: Self, b: Self) -> Self { self.mul_add(a, b) }
+    fn next_up(self) -> Self { self.next_up() }
+    fn next_down(self) -> Self { self.next_down() }
+
+    fn to_f64(self) -> f64 { self }
+    fn from_f64(value: f64) -> Self { value }
+    fn from_i128(value: i128) -> Self { value as f64 }
+}
+
+/// Sign of `value` as an ordering against zero, NaN counts as exact
+fn sign<F: Float>(value: F) -> Ordering {
+    value.partial_cmp(&F::ZERO).unwrap_or(Ordering::Equal)
+}
+
+fn invalid_if_signaling<F: Float>(values: &[F]) -> u64 {
+    if values.iter().any(|value| value.is_signaling()) {
+        FLAG_INVALID
+    } else {
+        0
+    }
+}
+
+/// Round `value`, the result rounded to nearest even, to `rm`. `error`
+/// is where the exact result is compared to `value` and `finite` says if
+/// all the inputs were finite so an infinite result is an overflow
+fn round<F: Float>(value: F, error: Ordering, rm: RoundingMode,
+                   finite: bool) -> (F, u64)
+{
+    if value.is_infinite() && finite {
+        let negative = value.is_sign_negative();
+        let largest = match rm {
+            RoundingMode::TowardZero => true,
+            RoundingMode::Down => !negative,
+            RoundingMode::Up => negative,
+            _ => false,
+        };
+
+        let value = match (largest, negative) {
+            (true, false) => F::MAX,
+            (true, true) => -F::MAX,
+            _ => value,
+        };
+
+        return (value, FLAG_OVERFLOW | FLAG_INEXACT);
+    }
+
+    if error == Ordering::Equal {
+        return (value, 0);
+    }
+
+    let value = match rm {
+        RoundingMode::TowardZero => {
+            if !value.is_sign_negative() && error == Ordering::Less {
+                value.next_down()
+            } else if value.is_sign_negative() &&
+                error == Ordering::Greater
+            {
+                value.next_up()
+            } else {
+                value
+            }
+        },
+        RoundingMode::Down if error == Ordering::Less => value.next_down(),
+        RoundingMode::Up if error == Ordering::Greater => value.next_up(),
+
+        // NOTE(patrik): Ties can't be seen from the error so round to
+        // nearest with ties to max magnitude acts like ties to even
+        _ => value,
+    };
+
+    let mut flags = FLAG_INEXACT;
+    if value.is_infinite() {
+        flags |= FLAG_OVERFLOW;
+    } else if value.abs() < F::MIN_POSITIVE {
+        // NOTE(patrik): Tininess is detected after rounding
+        flags |= FLAG_UNDERFLOW;
+    }
+
+    (value, flags)
+}
+
+pub fn add<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u64) {
+    let value = a + b;
+    if value.is_nan() {
+        let flags = if a.is_nan() || b.is_nan() {
+            invalid_if_signaling(&[a, b])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (value, flags);
+    }
+
+    let finite = !a.is_infinite() && !b.is_infinite();
+    if !finite || value.is_infinite() {
+        return round(value, Ordering::Equal, rm, finite);
+    }
+
+    // NOTE(patrik): An exact zero from operands that aren't both +0 is -0
+    // when rounding down
+    if value == F::ZERO && rm == RoundingMode::Down {
+        let positive_zeros = a == F::ZERO && b == F::ZERO &&
+            !a.is_sign_negative() && !b.is_sign_negative();
+        if !positive_zeros {
+            return (-F::ZERO, 0);
+        }
+    }
+
+    // NOTE(patrik): Two-sum gives the exact rounding error of the addition
+    let b_part = value - a;
+    let a_part = value - b_part;
+    let error = (a - a_part) + (b - b_part);
+
+    round(value, sign(error), rm, true)
+}
+
+pub fn sub<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u64) {
+    add(a, -b, rm)
+}
+
+pub fn mul<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u64) {
+    let value = a * b;
+    if value.is_nan() {
+        let flags = if a.is_nan() || b.is_nan() {
+            invalid_if_signaling(&[a, b])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (value, flags);
+    }
+
+    let finite = !a.is_infinite() && !b.is_infinite();
+    if !finite || value.is_infinite() {
+        return round(value, Ordering::Equal, rm, finite);
+    }
+
+    let error = a.mul_add(b, -value);
+    round(value, sign(error), rm, true)
+}
+
+pub fn div<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u64) {
+    let value = a / b;
+    if value.is_nan() {
+        let flags = if a.is_nan() || b.is_nan() {
+            invalid_if_signaling(&[a, b])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (value, flags);
+    }
+
+    if b == F::ZERO && !a.is_infinite() {
+        return (value, FLAG_DIVIDE_BY_ZERO);
+    }
+
+    let finite = !a.is_infinite() && !b.is_infinite();
+    if !finite || value.is_infinite() {
+        return round(value, Ordering::Equal, rm, finite);
+    }
+
+    // NOTE(patrik): The remainder a - value * b is exact, the exact
+    // quotient is on its side of value when b is positive
+    let remainder = (-value).mul_add(b, a);
+    let error = if b.is_sign_negative() {
+        sign(remainder).reverse()
+    } else {
+        sign(remainder)
+    };
+
+    round(value, error, rm, true)
+}
+
+pub fn sqrt<F: Float>(a: F, rm: RoundingMode) -> (F, u64) {
+    let value = a.sqrt();
+    if value.is_nan() {
+        let flags = if a.is_nan() {
+            invalid_if_signaling(&[a])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (value, flags);
+    }
+
+    if a.is_infinite() {
+        return (value, 0);
+    }
+
+    let remainder = (-value).mul_add(value, a);
+    round(value, sign(remainder), rm, true)
+}
+
+/// a * b + c with a single rounding, the rounding error isn't known so
+/// only overflow is reported besides invalid operations
+pub fn fused_mul_add<F: Float>(a: F, b: F, c: F, rm: RoundingMode)
+    -> (F, u64)
+{
+    let value = a.mul_add(b, c);
+
+    let product_invalid = (a.is_infinite() && b == F::ZERO) ||
+        (a == F::ZERO && b.is_infinite());
+    if product_invalid {
+        return (value, FLAG_INVALID);
+    }
+
+    if value.is_nan() {
+        let flags = if a.is_nan() || b.is_nan() || c.is_nan() {
+            invalid_if_signaling(&[a, b, c])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (value, flags);
+    }
+
+    let finite = !a.is_infinite() && !b.is_infinite() && !c.is_infinite();
+    round(value, Ordering::Equal, rm, finite)
+}
+
+/// fmin and fmax, `-0.0` is less than `0.0` and a single NaN input is
+/// ignored
+pub fn min_max<F: Float>(a: F, b: F, max: bool) -> (F, u64) {
+    let flags = invalid_if_signaling(&[a, b]);
+
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => a + b,
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let a_first = if a == b {
+                a.is_sign_negative() != max
+            } else {
+                (a < b) != max
+            };
+
+            if a_first { a } else { b }
+        },
+    };
+
+    (value, flags)
+}
+
+/// feq, flt and fle. Equality only complains about signaling NaNs while
+/// the ordered compares complain about all NaNs
+pub fn compare<F: Float>(a: F, b: F, accepted: &[Ordering],
+                         quiet: bool) -> (bool, u64)
+{
+    if a.is_nan() || b.is_nan() {
+        let flags = if quiet {
+            invalid_if_signaling(&[a, b])
+        } else {
+            FLAG_INVALID
+        };
+
+        return (false, flags);
+    }
+
+    let ordering = a.partial_cmp(&b).unwrap();
+    (accepted.contains(&ordering), 0)
+}
+
+/// Bit of fclass describing `value`
+pub fn classify<F: Float>(value: F) -> u64 {
+    let negative = value.is_sign_negative();
+
+    let bit = if value.is_signaling() {
+        8
+    } else if value.is_nan() {
+        9
+    } else if value.is_infinite() {
+        if negative { 0 } else { 7 }
+    } else if value == F::ZERO {
+        if negative { 3 } else { 4 }
+    } else if value.abs() < F::MIN_POSITIVE {
+        if negative { 2 } else { 5 }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+
+    1 << bit
+}
+
+/// Convert to an integer in `min..=max`, out of range values and NaN
+/// saturate and are invalid
+pub fn to_int<F: Float>(value: F, rm: RoundingMode, min: i128, max: i128)
+    -> (i128, u64)
+{
+    if value.is_nan() {
+        return (max, FLAG_INVALID);
+    }
+
+    let value = value.to_f64();
+    let rounded = match rm {
+        RoundingMode::NearestEven => value.round_ties_even(),
+        RoundingMode::TowardZero => value.trunc(),
+        RoundingMode::Down => value.floor(),
+        RoundingMode::Up => value.ceil(),
+        RoundingMode::NearestMaxMagnitude => value.round(),
+    };
+
+    // NOTE(patrik): Casting saturates and every 64-bit integer fits in an
+    // i128 so the range check is exact
+    let integer = rounded as i128;
+    if integer < min {
+        return (min, FLAG_INVALID);
+    }
+    if integer > max {
+        return (max, FLAG_INVALID);
+    }
+
+    let flags = if rounded != value { FLAG_INEXACT } else { 0 };
+    (integer, flags)
+}
+
+pub fn from_int<F: Float>(value: i128, rm: RoundingMode) -> (F, u64) {
+    let rounded = F::from_i128(value);
+    let error = value.cmp(&(rounded.to_f64() as i128));
+
+    round(rounded, error, rm, true)
+}
+
+/// fcvt.s.d and fcvt.d.s
+pub fn convert<F: Float, T: Float>(value: F, rm: RoundingMode) -> (T, u64) {
+    if value.is_nan() {
+        let nan = T::from_bits(T::CANONICAL_NAN);
+        return (nan, invalid_if_signaling(&[value]));
+    }
+
+    let value = value.to_f64();
+    let rounded = T::from_f64(value);
+    let error = value.partial_cmp(&rounded.to_f64())
+        .unwrap_or(Ordering::Equal);
+
+    round(rounded, error, rm, !value.is_infinite())
+}
+
+/// fsgnj, fsgnjn and fsgnjx, they only move bits around so NaNs are kept
+/// as they are. `sign` picks the sign from the signs of `a` and `b`
+pub fn sign_injection<F: Float>(a: u64, b: u64, sign: fn(bool, bool) -> bool)
+    -> u64
+{
+    let a = F::unbox(a);
+    let b = F::unbox(b);
+
+    let negative = sign(a & F::SIGN_BIT != 0, b & F::SIGN_BIT != 0);
+    let bits = if negative {
+        a | F::SIGN_BIT
+    } else {
+        a & !F::SIGN_BIT
+    };
+
+    F::rebox(bits)
+}
@@ -0,0 +1,633 @@
+//! Coverage guided fuzzing of guest code. The machine runs up to a start
+//! point once and is snapshotted there, then every input is written into
+//! the guest and run from the snapshot with an instruction budget
+//!
+//!     rest-emu fuzz --input buffer,256 --length-register a1 \
+//!         --start fuzz_entry program.elf
+//!
+//! Edges taken by branches and jumps are counted in a map like AFL does,
+//! inputs that reach new edges or hit counts are kept in the corpus.
+//! Exceptions, watchpoints, non-zero exits, panics of the emulator and
+//! running out of the budget are reported as crashes
+
+use std::convert::TryInto;
+use std::fs;
+use std::panic::{ self, AssertUnwindSafe };
+use std::path::{ Path, PathBuf };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::cpu::{ CoreExit, Exception, Register, StopReason };
+use crate::mmu::{ MemoryAccess, WatchKind };
+use crate::machine::{ Machine, Snapshot };
+use crate::clint::TimerSource;
+use crate::elf::Elf;
+use crate::htif::Htif;
+use crate::uart::Uart;
+use crate::rng::Rng;
+use crate::monitor::parse_register;
+use crate::UART_IRQ;
+
+/// Number of entries in the coverage map, must be a power of two
+pub const COVERAGE_MAP_SIZE: usize = 1 << 16;
+
+/// Instructions an input gets to run before it's considered hung
+const DEFAULT_BUDGET: u64 = 1_000_000;
+
+/// Largest input the mutator creates
+const MAX_INPUT_SIZE: usize = 4096;
+
+/// Runs between status lines, must be a power of two
+const STATUS_INTERVAL: u64 = 8192;
+
+const INTERESTING_VALUES: &[u8] = &[0x00, 0x01, 0x7f, 0x80, 0xff];
+
+/// Hit counts of the edges taken by branches and jumps, indexed by a hash
+/// of the address after the branch and the target
+pub struct Coverage {
+    counts: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; COVERAGE_MAP_SIZE],
+        }
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, from: u64, to: u64) {
+        let hash = (from ^ to.rotate_left(17))
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let index = (hash >> 32) as usize & (COVERAGE_MAP_SIZE - 1);
+
+        self.counts[index] = self.counts[index].saturating_add(1);
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+    }
+
+    pub fn counts(&self) -> &[u8] {
+        &self.counts
+    }
+}
+
+/// Put a hit count in a bucket so small changes in loop counts don't count
+/// as new coverage, one bit per bucket
+fn bucket(count: u8) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1 << 0,
+        2 => 1 << 1,
+        3 => 1 << 2,
+        4..=7 => 1 << 3,
+        8..=15 => 1 << 4,
+        16..=31 => 1 << 5,
+        32..=127 => 1 << 6,
+        _ => 1 << 7,
+    }
+}
+
+/// Where the input goes in the guest
+#[derive(Copy, Clone, Debug)]
+pub enum InputTarget {
+    /// A buffer of `size` bytes at `addr`, longer inputs are cut off
+    Memory { addr: u64, size: u64 },
+    /// The first 8 bytes of the input little endian
+    Register(Register),
+}
+
+#[derive(Clone, Debug)]
+pub enum Crash {
+    /// The instruction at `pc` raised an exception
+    Exception { pc: u64, exception: Exception },
+    Watchpoint(MemoryAccess),
+    /// The program exited with a non-zero code
+    Exit(i32),
+    Timeout,
+    /// The emulator panicked
+    Panic(String),
+}
+
+impl Crash {
+    /// Name used to tell crashes apart, only the first input for each is
+    /// kept
+    fn key(&self) -> String {
+        match self {
+            Crash::Exception { pc, exception } => {
+                format!("exception-{}-{:x}", exception.cause(), pc)
+            },
+            Crash::Watchpoint(access) => {
+                format!("watchpoint-{:?}-{:x}", access.kind, access.addr)
+                    .to_lowercase()
+            },
+            Crash::Exit(code) => format!("exit-{}", code),
+            Crash::Timeout => "timeout".to_string(),
+            Crash::Panic(message) => {
+                format!("panic-{}", &input_name(message.as_bytes())[..8])
+            },
+        }
+    }
+}
+
+/// Runs inputs from a snapshot of the machine, the building block for the
+/// mutator loop or a libFuzzer style entry point
+pub struct Fuzzer {
+    machine: Machine,
+    snapshot: Snapshot,
+
+    target: InputTarget,
+    /// Register that gets the length of the input written to memory
+    length_register: Option<Register>,
+    budget: u64,
+}
+
+impl Fuzzer {
+    /// Snapshot `machine` in its current state, every input starts from
+    /// there
+    pub fn new(mut machine: Machine, target: InputTarget,
+               length_register: Option<Register>, budget: u64) -> Self
+    {
+        machine.core.set_coverage(Some(Coverage::new()));
+        machine.core.set_stop_on_exceptions(true);
+        let snapshot = machine.snapshot();
+
+        Self {
+            machine,
+            snapshot,
+
+            target,
+            length_register,
+            budget,
+        }
+    }
+
+    pub fn coverage(&self) -> &Coverage {
+        self.machine.core.coverage()
+            .expect("Fuzzer: Coverage is always enabled")
+    }
+
+    fn inject(&mut self, data: &[u8]) {
+        let core = &mut self.machine.core;
+
+        let length = match self.target {
+            InputTarget::Memory { addr, size } => {
+                let length = data.len().min(size as usize);
+                for (index, value) in data[..length].iter().enumerate() {
+                    if !core.mmu.poke_u8(addr + index as u64, *value) {
+                        panic!("Fuzzer: Input buffer at {:#x} is not in RAM",
+                               addr);
+                    }
+                }
+
+                length
+            },
+
+            InputTarget::Register(reg) => {
+                let mut bytes = [0; 8];
+                let length = data.len().min(8);
+                bytes[..length].copy_from_slice(&data[..length]);
+                core.set_reg(reg, u64::from_le_bytes(bytes));
+
+                length
+            },
+        };
+
+        if let Some(reg) = self.length_register {
+            core.set_reg(reg, length as u64);
+        }
+    }
+
+    fn run(&mut self) -> Option<Crash> {
+        let mut remaining = self.budget;
+        while remaining > 0 {
+            let result = self.machine.run(remaining);
+            remaining -= result.steps;
+
+            match result.reason {
+                StopReason::Exit(CoreExit::Shutdown(shutdown)) => {
+                    return match crate::shutdown_exit_code(shutdown) {
+                        0 => None,
+                        code => Some(Crash::Exit(code)),
+                    };
+                },
+
+                StopReason::Exit(CoreExit::Exception(exception)) => {
+                    let pc = result.last_pc;
+                    return Some(Crash::Exception { pc, exception });
+                },
+                StopReason::Exit(CoreExit::Watchpoint(access)) => {
+                    return Some(Crash::Watchpoint(access));
+                },
+
+                _ => {},
+            }
+        }
+
+        Some(Crash::Timeout)
+    }
+
+    /// Run one input from the snapshot, returns the crash if it caused one.
+    /// The coverage of the run is left in `coverage`
+    pub fn execute(&mut self, data: &[u8]) -> Option<Crash> {
+        self.machine.reset_to(&self.snapshot);
+        if let Some(coverage) = self.machine.core.coverage_mut() {
+            coverage.clear();
+        }
+
+        self.inject(data);
+
+        // NOTE(patrik): The next reset puts the machine back together
+        // after a panic
+        panic::catch_unwind(AssertUnwindSafe(|| self.run()))
+            .unwrap_or_else(|payload| {
+                Some(Crash::Panic(crate::conformance::panic_message(
+                    payload.as_ref())))
+            })
+    }
+}
+
+fn mutate(rng: &mut Rng, input: &mut Vec<u8>, corpus: &[Vec<u8>]) {
+    if input.is_empty() {
+        input.push(rng.next() as u8);
+        return;
+    }
+
+    let index = rng.below(input.len());
+    match rng.below(8) {
+        0 => input[index] ^= 1 << rng.below(8),
+        1 => input[index] = rng.next() as u8,
+        2 => {
+            let delta = rng.below(35) as u8;
+            input[index] = if rng.below(2) == 0 {
+                input[index].wrapping_add(delta + 1)
+            } else {
+                input[index].wrapping_sub(delta + 1)
+            };
+        },
+        3 => {
+            let value = INTERESTING_VALUES[rng.below(INTERESTING_VALUES.len())];
+            input[index] = value;
+        },
+        4 if input.len() < MAX_INPUT_SIZE => {
+            input.insert(index, rng.next() as u8);
+        },
+        5 if input.len() > 1 => {
+            input.remove(index);
+        },
+        6 => {
+            // NOTE(patrik): Copy a chunk of the input over another part
+            let length = rng.below(input.len() - index) + 1;
+            let from = rng.below(input.len() - length + 1);
+            input.copy_within(from..from + length, index);
+        },
+        7 => {
+            // NOTE(patrik): Splice in the tail of another corpus entry
+            let other = &corpus[rng.below(corpus.len())];
+            if !other.is_empty() {
+                let from = rng.below(other.len());
+                input.truncate(index);
+                input.extend_from_slice(&other[from..]);
+                input.truncate(MAX_INPUT_SIZE);
+            }
+        },
+
+        _ => input[index] = !input[index],
+    }
+}
+
+struct Options {
+    target: Option<InputTarget>,
+    length_register: Option<Register>,
+    start: Option<u64>,
+    budget: u64,
+    corpus: Option<PathBuf>,
+    crashes: PathBuf,
+    runs: Option<u64>,
+    seed: u64,
+    watchpoints: Vec<(u64, u64, WatchKind)>,
+}
+
+/// Parse an address as a number or a symbol of the program
+fn parse_address(elf: &Elf, value: &str) -> u64 {
+    match elf.symbol(value) {
+        Some(addr) => addr,
+        None => crate::parse_u64(value),
+    }
+}
+
+fn parse_options(elf: &Elf, args: &[String]) -> Options {
+    let mut options = Options {
+        target: None,
+        length_register: None,
+        start: None,
+        budget: DEFAULT_BUDGET,
+        corpus: None,
+        crashes: PathBuf::from("crashes"),
+        runs: None,
+        seed: SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(1),
+        watchpoints: Vec::new(),
+    };
+
+    let register = |value: &str| {
+        parse_register(value)
+            .unwrap_or_else(|| panic!("Unknown register: {}", value))
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| panic!("{} needs a value", name))
+                .clone()
+        };
+
+        match arg.as_str() {
+            "--input" => {
+                let value = value("--input");
+                let (addr, size) = value.split_once(',')
+                    .unwrap_or_else(|| panic!("--input needs ADDR,SIZE"));
+                options.target = Some(InputTarget::Memory {
+                    addr: parse_address(elf, addr),
+                    size: crate::parse_u64(size),
+                });
+            },
+
+            "--input-register" => {
+                let reg = register(&value("--input-register"));
+                options.target = Some(InputTarget::Register(reg));
+            },
+
+            "--length-register" => {
+                options.length_register =
+                    Some(register(&value("--length-register")));
+            },
+
+            "--start" => {
+                options.start = Some(parse_address(elf, &value("--start")));
+            },
+
+            "--budget" => {
+                options.budget = crate::parse_u64(&value("--budget"));
+            },
+
+            "--corpus" => {
+                options.corpus = Some(PathBuf::from(value("--corpus")));
+            },
+
+            "--crashes" => {
+                options.crashes = PathBuf::from(value("--crashes"));
+            },
+
+            "--runs" => {
+                options.runs = Some(crate::parse_u64(&value("--runs")));
+            },
+
+            "--seed" => options.seed = crate::parse_u64(&value("--seed")),
+
+            "--watch" => {
+                let value = value("--watch");
+                let mut parts = value.split(',');
+                let addr = parse_address(elf, parts.next().unwrap_or(""));
+                let size = parts.next().map(crate::parse_u64).unwrap_or(8);
+                let kind = match parts.next().unwrap_or("w") {
+                    "r" => WatchKind::Read,
+                    "w" => WatchKind::Write,
+                    "rw" => WatchKind::Access,
+                    "x" => WatchKind::Execute,
+
+                    kind => panic!("Unknown watchpoint kind: {}", kind),
+                };
+
+                options.watchpoints.push((addr, size, kind));
+            },
+
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    options
+}
+
+/// Build the machine for `elf` and run it up to `start`
+fn create_machine(elf: &Elf, options: &Options) -> Machine {
+    let memory_map = [crate::default_memory(Some(elf))];
+    let uart = Uart::new(UART_IRQ, None, Box::new(std::io::sink()));
+    let mut mmu = crate::create_mmu(&memory_map,
+                                    TimerSource::Instructions(1), uart,
+                                    None);
+    elf.load(&mut mmu);
+
+    let htif = elf.symbol("tohost").map(|tohost| {
+        Htif::new(tohost, elf.symbol("fromhost"),
+                  Box::new(std::io::sink()))
+    });
+
+    let mut core = crate::create_core(mmu);
+    core.set_environment_traps(true);
+    core.set_reg(Register::Pc, elf.entry);
+    core.set_reg(Register::Ra, crate::RETURN_ADDRESS);
+    let (ram_base, ram_size) = memory_map[0];
+    core.set_reg(Register::Sp, ram_base + ram_size);
+
+    let mut machine = Machine::new(core, htif);
+
+    let start = options.start
+        .filter(|start| *start != machine.core.reg(Register::Pc));
+    if let Some(start) = start {
+        machine.core.add_breakpoint(start);
+        let result = machine.run(options.budget);
+        machine.core.remove_breakpoint(start);
+
+        if result.reason != StopReason::Breakpoint(start) {
+            panic!("fuzz: Stopped with {:x?} before reaching {:#x}",
+                   result.reason, start);
+        }
+    }
+
+    for (addr, size, kind) in options.watchpoints.iter() {
+        machine.core.mmu.add_watchpoint(*addr, *size, *kind);
+    }
+
+    machine
+}
+
+fn load_corpus(directory: &Path) -> Vec<Vec<u8>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    paths.iter()
+        .filter_map(|path| fs::read(path).ok())
+        .map(|mut data| {
+            data.truncate(MAX_INPUT_SIZE);
+            data
+        })
+        .collect()
+}
+
+/// Name for an input file, FNV-1a of the contents
+fn input_name(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+fn save_input(directory: &Path, name: &str, data: &[u8]) {
+    fs::create_dir_all(directory)
+        .unwrap_or_else(|_| panic!("Failed to create {}",
+                                   directory.display()));
+
+    let path = directory.join(name);
+    fs::write(&path, data)
+        .unwrap_or_else(|_| panic!("Failed to write {}", path.display()));
+}
+
+/// Save the input of a crash that hasn't been seen before, `crashes` holds
+/// the keys of the ones seen so far
+fn report_crash(directory: &Path, crashes: &mut Vec<String>, crash: Crash,
+                input: &[u8])
+{
+    let key = crash.key();
+    if crashes.contains(&key) {
+        return;
+    }
+
+    let name = format!("crash-{}-{}", key, input_name(input));
+    save_input(directory, &name, input);
+    println!("New crash {:x?}, saved as {}", crash, name);
+
+    crashes.push(key);
+}
+
+/// Merge the coverage of the last run into `seen`, returns true if it hit
+/// a new edge or hit count bucket
+fn merge_coverage(seen: &mut [u8], coverage: &Coverage) -> bool {
+    let mut new = false;
+
+    // NOTE(patrik): Most of the map is zero so skip it 8 entries at a time
+    let chunks = seen.chunks_exact_mut(8)
+        .zip(coverage.counts().chunks_exact(8));
+    for (seen, counts) in chunks {
+        if u64::from_ne_bytes(counts.try_into().unwrap()) == 0 {
+            continue;
+        }
+
+        for (seen, count) in seen.iter_mut().zip(counts.iter()) {
+            let bucket = bucket(*count);
+            if bucket & !*seen != 0 {
+                *seen |= bucket;
+                new = true;
+            }
+        }
+    }
+
+    new
+}
+
+/// Entry point of the `fuzz` subcommand, returns the process exit code
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let mut args: Vec<String> = args.collect();
+    let path = match args.pop() {
+        Some(path) if !path.starts_with("--") => path,
+        _ => panic!("fuzz: No program given"),
+    };
+
+    let data = fs::read(&path)
+        .unwrap_or_else(|_| panic!("Failed to read {}", path));
+    if !Elf::is_elf(&data) {
+        panic!("{} is not an ELF file", path);
+    }
+    let elf = Elf::parse(&data);
+
+    let options = parse_options(&elf, &args);
+    let target = options.target
+        .unwrap_or_else(|| panic!("fuzz: --input or --input-register is \
+                                   needed"));
+
+    let machine = create_machine(&elf, &options);
+    let mut fuzzer = Fuzzer::new(machine, target, options.length_register,
+                                 options.budget);
+
+    // NOTE(patrik): Panics are reported as crashes so don't print them as
+    // they happen
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut rng = Rng::new(options.seed);
+    let mut seen = vec![0; COVERAGE_MAP_SIZE];
+    let mut crashes: Vec<String> = Vec::new();
+
+    let mut corpus = options.corpus.as_ref()
+        .map(|directory| load_corpus(directory))
+        .unwrap_or_default();
+    if corpus.is_empty() {
+        corpus.push(vec![0; 8]);
+    }
+
+    // NOTE(patrik): Run the initial corpus first so the coverage map
+    // starts out with everything it already reaches
+    for input in corpus.iter() {
+        if let Some(crash) = fuzzer.execute(input) {
+            report_crash(&options.crashes, &mut crashes, crash, input);
+        }
+        merge_coverage(&mut seen, fuzzer.coverage());
+    }
+
+    let start = Instant::now();
+    let mut runs = 0u64;
+    while options.runs.is_none_or(|limit| runs < limit) {
+        let mut input = corpus[rng.below(corpus.len())].clone();
+        for _ in 0..rng.below(4) + 1 {
+            mutate(&mut rng, &mut input, &corpus);
+        }
+
+        let crash = fuzzer.execute(&input);
+        runs += 1;
+
+        if merge_coverage(&mut seen, fuzzer.coverage()) {
+            if let Some(directory) = options.corpus.as_ref() {
+                save_input(directory, &input_name(&input), &input);
+            }
+
+            corpus.push(input.clone());
+        }
+
+        if let Some(crash) = crash {
+            report_crash(&options.crashes, &mut crashes, crash, &input);
+        }
+
+        if runs & (STATUS_INTERVAL - 1) == 0 {
+            let edges = seen.iter().filter(|seen| **seen != 0).count();
+            let rate = runs as f64 / start.elapsed().as_secs_f64();
+            println!("runs: {} corpus: {} edges: {} crashes: {} \
+                      ({:.0} runs/s)", runs, corpus.len(), edges,
+                     crashes.len(), rate);
+        }
+    }
+
+    panic::set_hook(default_hook);
+
+    let edges = seen.iter().filter(|seen| **seen != 0).count();
+    println!("Done, {} runs, {} edges, {} crashes", runs, edges,
+             crashes.len());
+
+    if crashes.is_empty() { 0 } else { 1 }
+}
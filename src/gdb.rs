@@ -0,0 +1,825 @@
+//! GDB remote serial protocol server so a debugger can attach to the guest
+//! over TCP or a Unix socket
+//!
+//!     rest-emu --gdb 1234 program.elf
+//!     riscv64-unknown-elf-gdb -ex "target remote :1234" program.elf
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{ Read, Write };
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ self, Receiver };
+
+use crate::cpu::{ Core, CoreExit, Register, StopReason, VirtualWatchpoint };
+use crate::mmu::{ AccessKind, WatchKind, MemoryAccess };
+use crate::machine::Machine;
+use crate::csr;
+use crate::disasm;
+
+const INTERRUPT: u8 = 0x03;
+
+/// Largest packet GDB may send or expect, advertised in `qSupported`
+const PACKET_SIZE: u64 = 0x1000;
+
+// Register numbers used by GDB, CSRs are numbered 65 + the CSR address
+const PC_REGNUM: u64 = 32;
+const FP_REGNUM_BASE: u64 = 33;
+const FP_COUNT: u64 = 32;
+const CSR_REGNUM_BASE: u64 = 65;
+const CSR_COUNT: u64 = 4096;
+
+/// CSRs GDB expects with the floating point registers instead of the
+/// other CSRs
+const FP_CSRS: [u16; 3] = [csr::FFLAGS, csr::FRM, csr::FCSR];
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+/// Why the target stopped running
+enum Stop {
+    Step,
+    Interrupt,
+    SoftwareBreakpoint,
+    Watchpoint(MemoryAccess),
+    Exited(i32),
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(value: &str) -> Option<Vec<u8>> {
+    if value.len() & 1 != 0 {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(value.get(index..index + 2)?, 16).ok()
+        })
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value, 16).ok()
+}
+
+/// Parse `addr,length` used by the memory and breakpoint packets
+fn parse_range(value: &str) -> Option<(u64, u64)> {
+    let (addr, length) = value.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(length)?))
+}
+
+/// Target description with the general purpose registers, the floating
+/// point registers and the CSRs
+fn target_xml() -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\"?>\n");
+    xml.push_str("<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n");
+    xml.push_str("<target version=\"1.0\">\n");
+    xml.push_str("<architecture>riscv:rv64</architecture>\n");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+    for regnum in 0..=PC_REGNUM {
+        let reg = Register::from(regnum as u32);
+        let name = format!("{:?}", reg).to_lowercase();
+        let typ = match reg {
+            Register::Ra | Register::Pc => "code_ptr",
+            Register::Sp => "data_ptr",
+            _ => "int",
+        };
+
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"64\" \
+                               type=\"{}\" regnum=\"{}\"/>\n",
+                              name, typ, regnum));
+    }
+    xml.push_str("</feature>\n");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+    for (index, name) in disasm::FLOAT_NAMES.iter().enumerate() {
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"64\" \
+                               type=\"ieee_double\" regnum=\"{}\"/>\n",
+                              name, FP_REGNUM_BASE + index as u64));
+    }
+    for (name, csr) in csr::NAMES.iter() {
+        if FP_CSRS.contains(csr) {
+            xml.push_str(&format!("<reg name=\"{}\" bitsize=\"64\" \
+                                   regnum=\"{}\" group=\"float\"/>\n",
+                                  name, CSR_REGNUM_BASE + *csr as u64));
+        }
+    }
+    xml.push_str("</feature>\n");
+
+    xml.push_str("<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for (name, csr) in csr::NAMES.iter() {
+        if FP_CSRS.contains(csr) {
+            continue;
+        }
+
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"64\" \
+                               regnum=\"{}\" group=\"csr\"/>\n",
+                              name, CSR_REGNUM_BASE + *csr as u64));
+    }
+    xml.push_str("</feature>\n");
+
+    xml.push_str("</target>\n");
+
+    xml
+}
+
+/// Wait for GDB to connect to `address`, either `unix:PATH`, `HOST:PORT`
+/// or just a port on localhost
+fn accept(address: &str) -> (Box<dyn Read + Send>, Box<dyn Write>) {
+    if let Some(path) = address.strip_prefix("unix:") {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .unwrap_or_else(|_| panic!("Failed to listen on {}", address));
+
+        eprintln!("Waiting for GDB to connect on {}", address);
+        let (stream, _) = listener.accept()
+            .expect("Failed to accept GDB connection");
+        let reader = stream.try_clone()
+            .expect("Failed to clone GDB connection");
+
+        return (Box::new(reader), Box::new(stream));
+    }
+
+    let address = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("127.0.0.1:{}", address)
+    };
+
+    let listener = TcpListener::bind(&address)
+        .unwrap_or_else(|_| panic!("Failed to listen on {}", address));
+
+    eprintln!("Waiting for GDB to connect on {}", address);
+    let (stream, _) = listener.accept()
+        .expect("Failed to accept GDB connection");
+    let _ = stream.set_nodelay(true);
+    let reader = stream.try_clone()
+        .expect("Failed to clone GDB connection");
+
+    (Box::new(reader), Box::new(stream))
+}
+
+pub struct GdbServer {
+    /// Bytes from GDB, read on a separate thread so the target can check
+    /// for interrupts while running
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+
+    no_ack: bool,
+
+    /// Physical address and original value of the bytes under the EBREAKs
+    /// inserted for software breakpoints, by the virtual address GDB gave
+    sw_breakpoints: HashMap<u64, Vec<(u64, u8)>>,
+    /// Hardware breakpoints and watchpoints added to the MMU
+    watchpoints: Vec<VirtualWatchpoint>,
+}
+
+impl GdbServer {
+    /// Wait for GDB to connect, see `accept` for the address format.
+    /// `stop` is the stop handle of the core, it's set when GDB interrupts
+    /// the target or disconnects
+    pub fn listen(address: &str, stop: Arc<AtomicBool>) -> Self {
+        let (mut reader, output) = accept(address);
+
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut byte = [0; 1];
+            while let Ok(1) = reader.read(&mut byte) {
+                if byte[0] == INTERRUPT {
+                    stop.store(true, Ordering::Relaxed);
+                }
+
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+
+            stop.store(true, Ordering::Relaxed);
+        });
+
+        Self {
+            input,
+            output,
+
+            no_ack: false,
+
+            sw_breakpoints: HashMap::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Wait for the next packet, `None` if GDB disconnected
+    fn recv_packet(&mut self) -> Option<String> {
+        loop {
+            // NOTE(patrik): Acks and interrupts while the target is
+            // stopped are ignored
+            if self.input.recv().ok()? != b'$' {
+                continue;
+            }
+
+            let mut data = Vec::new();
+            loop {
+                let byte = self.input.recv().ok()?;
+                if byte == b'#' {
+                    break;
+                }
+
+                data.push(byte);
+            }
+
+            // NOTE(patrik): The transport is reliable so the checksum is
+            // not checked
+            self.input.recv().ok()?;
+            self.input.recv().ok()?;
+
+            if !self.no_ack {
+                let _ = self.output.write_all(b"+");
+            }
+
+            return Some(String::from_utf8_lossy(&data).to_string());
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| {
+            sum.wrapping_add(byte)
+        });
+
+        let packet = format!("${}#{:02x}", data, checksum);
+        let _ = self.output.write_all(packet.as_bytes());
+        let _ = self.output.flush();
+    }
+
+    /// Serve GDB until it detaches or the program exits, returns the exit
+    /// code of the program if it exited or GDB killed it
+    pub fn run(&mut self, machine: &mut Machine) -> Option<i32> {
+        machine.core.set_ebreak_debug(true);
+
+        while let Some(packet) = self.recv_packet() {
+            if packet.is_empty() || !packet.is_char_boundary(1) {
+                self.send_packet("");
+                continue;
+            }
+
+            let (command, arguments) = packet.split_at(1);
+
+            if let Some(single) = self.resume_action(command, arguments) {
+                if command == "c" || command == "s" {
+                    if let Some(addr) = parse_hex(arguments) {
+                        machine.core.set_reg(Register::Pc, addr);
+                    }
+                }
+
+                let stop = self.resume(machine, single);
+                let reply = Self::stop_reply(&stop);
+                self.send_packet(&reply);
+
+                if let Stop::Exited(code) = stop {
+                    self.cleanup(&mut machine.core);
+                    return Some(code);
+                }
+
+                continue;
+            }
+
+            let reply = match command {
+                "?" => format!("S{:02x}", SIGTRAP),
+
+                "q" => self.query(arguments),
+                "Q" if arguments == "StartNoAckMode" => {
+                    self.send_packet("OK");
+                    self.no_ack = true;
+                    continue;
+                },
+
+                "H" | "T" => "OK".to_string(),
+
+                "g" => self.read_registers(&machine.core),
+                "G" => self.write_registers(&mut machine.core, arguments),
+                "p" => self.read_register(&machine.core, arguments),
+                "P" => self.write_register(&mut machine.core, arguments),
+
+                "m" => self.read_memory(&machine.core, arguments),
+                "M" => self.write_memory(&mut machine.core, arguments),
+
+                "Z" => self.insert_breakpoint(&mut machine.core, arguments),
+                "z" => self.remove_breakpoint(&mut machine.core, arguments),
+
+                "v" => self.v_packet(arguments),
+
+                "D" => {
+                    self.send_packet("OK");
+                    break;
+                },
+
+                "k" => {
+                    self.cleanup(&mut machine.core);
+                    return Some(0);
+                },
+
+                _ => String::new(),
+            };
+
+            self.send_packet(&reply);
+        }
+
+        self.cleanup(&mut machine.core);
+
+        None
+    }
+
+    /// Remove everything the debugger added to the target
+    fn cleanup(&mut self, core: &mut Core) {
+        for (_, original) in self.sw_breakpoints.drain() {
+            for (physical, value) in original {
+                core.mmu.poke_u8(physical, value);
+            }
+        }
+
+        for watchpoint in self.watchpoints.drain(..) {
+            core.remove_virtual_watchpoint(&watchpoint);
+        }
+
+        core.set_ebreak_debug(false);
+    }
+
+    /// Check if the packet resumes the target, returns if it's a single
+    /// step
+    fn resume_action(&self, command: &str, arguments: &str) -> Option<bool> {
+        match command {
+            "c" | "C" => Some(false),
+            "s" | "S" => Some(true),
+
+            // NOTE(patrik): There is only one thread so only the first
+            // action matters
+            "v" => {
+                let action = arguments.strip_prefix("Cont;")?;
+                match action.chars().next()? {
+                    'c' | 'C' => Some(false),
+                    's' | 'S' => Some(true),
+
+                    _ => None,
+                }
+            },
+
+            _ => None,
+        }
+    }
+
+    fn v_packet(&self, arguments: &str) -> String {
+        if arguments == "Cont?" {
+            return "vCont;c;C;s;S".to_string();
+        }
+
+        String::new()
+    }
+
+    fn query(&self, arguments: &str) -> String {
+        if arguments.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;\
+                            hwbreak+;QStartNoAckMode+;vContSupported+",
+                           PACKET_SIZE);
+        }
+
+        if let Some(range) = arguments
+            .strip_prefix("Xfer:features:read:target.xml:")
+        {
+            let (offset, length) = match parse_range(range) {
+                Some(range) => range,
+                None => return "E01".to_string(),
+            };
+
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            let marker = if end == xml.len() { "l" } else { "m" };
+
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+
+        match arguments {
+            "Attached" => "1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            "C" => "QC1".to_string(),
+            "Symbol::" => "OK".to_string(),
+
+            _ => String::new(),
+        }
+    }
+
+    fn register(core: &Core, regnum: u64) -> Option<u64> {
+        if regnum <= PC_REGNUM {
+            return Some(core.reg(Register::from(regnum as u32)));
+        }
+
+        let fp = regnum.wrapping_sub(FP_REGNUM_BASE);
+        if fp < FP_COUNT {
+            return Some(core.float_reg(Register::from(fp as u32)));
+        }
+
+        let csr = regnum.wrapping_sub(CSR_REGNUM_BASE);
+        if csr < CSR_COUNT {
+            return Some(core.read_csr(csr as u16));
+        }
+
+        None
+    }
+
+    fn set_register(core: &mut Core, regnum: u64, value: u64) -> bool {
+        if regnum <= PC_REGNUM {
+            core.set_reg(Register::from(regnum as u32), value);
+            return true;
+        }
+
+        let fp = regnum.wrapping_sub(FP_REGNUM_BASE);
+        if fp < FP_COUNT {
+            core.set_float_reg(Register::from(fp as u32), value);
+            return true;
+        }
+
+        let csr = regnum.wrapping_sub(CSR_REGNUM_BASE);
+        if csr < CSR_COUNT {
+            core.write_csr(csr as u16, value);
+            return true;
+        }
+
+        false
+    }
+
+    /// The `g` packet has the general purpose registers, PC and the
+    /// floating point registers in register number order
+    fn read_registers(&self, core: &Core) -> String {
+        (0..FP_REGNUM_BASE + FP_COUNT)
+            .filter_map(|regnum| Self::register(core, regnum))
+            .map(|value| hex_encode(&value.to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&self, core: &mut Core, arguments: &str) -> String {
+        let bytes = match hex_decode(arguments) {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+
+        for (regnum, value) in bytes.chunks_exact(8).enumerate()
+            .take((FP_REGNUM_BASE + FP_COUNT) as usize)
+        {
+            let value = u64::from_le_bytes(value.try_into().unwrap());
+            Self::set_register(core, regnum as u64, value);
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_register(&self, core: &Core, arguments: &str) -> String {
+        match parse_hex(arguments)
+            .and_then(|regnum| Self::register(core, regnum))
+        {
+            Some(value) => hex_encode(&value.to_le_bytes()),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&self, core: &mut Core, arguments: &str) -> String {
+        let parsed = arguments.split_once('=').and_then(|(regnum, value)| {
+            let bytes = hex_decode(value)?;
+            let mut value = [0; 8];
+            let length = bytes.len().min(8);
+            value[..length].copy_from_slice(&bytes[..length]);
+
+            Some((parse_hex(regnum)?, u64::from_le_bytes(value)))
+        });
+
+        match parsed {
+            Some((regnum, value))
+                if Self::set_register(core, regnum, value) =>
+            {
+                "OK".to_string()
+            },
+
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Find the saved original byte at the physical address `physical` if
+    /// a software breakpoint covers it
+    fn shadowed(&mut self, physical: u64) -> Option<&mut u8> {
+        self.sw_breakpoints.values_mut()
+            .flat_map(|original| original.iter_mut())
+            .find(|(addr, _)| *addr == physical)
+            .map(|(_, value)| value)
+    }
+
+    fn read_memory(&mut self, core: &Core, arguments: &str) -> String {
+        let (addr, length) = match parse_range(arguments) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+
+        // NOTE(patrik): Every byte is sent as two hex digits so more than
+        // this wouldn't fit in a reply
+        if length > PACKET_SIZE / 2 {
+            return "E01".to_string();
+        }
+
+        // NOTE(patrik): Only RAM can be read since reading a device
+        // register might change the state of the device. Addresses are
+        // virtual when the hart has paging on
+        let mut bytes = Vec::with_capacity(length as usize);
+        for addr in addr..addr.wrapping_add(length) {
+            let physical = core.peek_translate(addr, AccessKind::Read);
+            let value = physical.and_then(|physical| {
+                match self.shadowed(physical) {
+                    Some(value) => Some(*value),
+                    None => core.mmu.peek_u8(physical),
+                }
+            });
+
+            match value {
+                Some(value) => bytes.push(value),
+                None if bytes.is_empty() => return "E01".to_string(),
+                None => break,
+            }
+        }
+
+        hex_encode(&bytes)
+    }
+
+    fn write_memory(&mut self, core: &mut Core, arguments: &str) -> String {
+        let parsed = arguments.split_once(':').and_then(|(range, data)| {
+            Some((parse_range(range)?, hex_decode(data)?))
+        });
+
+        let (addr, bytes) = match parsed {
+            Some(((addr, _), bytes)) => (addr, bytes),
+            None => return "E01".to_string(),
+        };
+
+        for (index, value) in bytes.iter().enumerate() {
+            let addr = addr.wrapping_add(index as u64);
+            let physical = match core.peek_translate(addr, AccessKind::Read) {
+                Some(physical) => physical,
+                None => return "E01".to_string(),
+            };
+
+            if let Some(original) = self.shadowed(physical) {
+                *original = *value;
+                continue;
+            }
+
+            if !core.mmu.poke_u8(physical, *value) {
+                return "E01".to_string();
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    /// Parse `TYPE,ADDR,KIND` of the breakpoint packets
+    fn parse_breakpoint(arguments: &str) -> Option<(char, u64, u64)> {
+        let (typ, range) = arguments.split_once(',')?;
+        let (addr, kind) = parse_range(range.split(';').next()?)?;
+
+        Some((typ.chars().next()?, addr, kind))
+    }
+
+    /// The watchpoint kind and size for a `Z`/`z` packet, hardware
+    /// breakpoints only trigger on the instruction at `addr`
+    fn watchpoint(typ: char, kind: u64) -> Option<(WatchKind, u64)> {
+        let watch = match typ {
+            '1' => return Some((WatchKind::Execute, 1)),
+            '2' => WatchKind::Write,
+            '3' => WatchKind::Read,
+            '4' => WatchKind::Access,
+
+            _ => return None,
+        };
+
+        Some((watch, kind))
+    }
+
+    fn insert_breakpoint(&mut self, core: &mut Core,
+                         arguments: &str) -> String
+    {
+        let (typ, addr, kind) = match Self::parse_breakpoint(arguments) {
+            Some(breakpoint) => breakpoint,
+            None => return "E01".to_string(),
+        };
+
+        match typ {
+            '0' => {
+                if self.sw_breakpoints.contains_key(&addr) {
+                    return "OK".to_string();
+                }
+
+                let ebreak = if kind == 2 {
+                    C_EBREAK.to_le_bytes().to_vec()
+                } else {
+                    EBREAK.to_le_bytes().to_vec()
+                };
+
+                // NOTE(patrik): The physical addresses are kept so the
+                // breakpoint can be removed after satp has changed
+                let mut original = Vec::with_capacity(ebreak.len());
+                for index in 0..ebreak.len() as u64 {
+                    let addr = addr.wrapping_add(index);
+                    let byte = core.peek_translate(addr, AccessKind::Read)
+                        .and_then(|physical| {
+                            Some((physical, core.mmu.peek_u8(physical)?))
+                        });
+
+                    match byte {
+                        Some(byte) => original.push(byte),
+                        None => return "E01".to_string(),
+                    }
+                }
+
+                for ((physical, _), value) in original.iter().zip(ebreak) {
+                    core.mmu.poke_u8(*physical, value);
+                }
+
+                self.sw_breakpoints.insert(addr, original);
+            },
+
+            _ => {
+                let (watch, size) = match Self::watchpoint(typ, kind) {
+                    Some(watchpoint) => watchpoint,
+                    None => return String::new(),
+                };
+
+                let exists = self.watchpoints.iter().any(|watchpoint| {
+                    (watchpoint.addr, watchpoint.length, watchpoint.kind) ==
+                        (addr, size, watch)
+                });
+
+                if !exists {
+                    let watchpoint =
+                        match core.add_virtual_watchpoint(addr, size, watch) {
+                            Some(watchpoint) => watchpoint,
+                            None => return "E01".to_string(),
+                        };
+
+                    self.watchpoints.push(watchpoint);
+                }
+            },
+        }
+
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint(&mut self, core: &mut Core,
+                         arguments: &str) -> String
+    {
+        let (typ, addr, kind) = match Self::parse_breakpoint(arguments) {
+            Some(breakpoint) => breakpoint,
+            None => return "E01".to_string(),
+        };
+
+        match typ {
+            '0' => {
+                if let Some(original) = self.sw_breakpoints.remove(&addr) {
+                    for (physical, value) in original {
+                        core.mmu.poke_u8(physical, value);
+                    }
+                }
+            },
+
+            _ => {
+                let (watch, size) = match Self::watchpoint(typ, kind) {
+                    Some(watchpoint) => watchpoint,
+                    None => return String::new(),
+                };
+
+                let index = self.watchpoints.iter().position(|watchpoint| {
+                    (watchpoint.addr, watchpoint.length, watchpoint.kind) ==
+                        (addr, size, watch)
+                });
+
+                if let Some(index) = index {
+                    let watchpoint = self.watchpoints.remove(index);
+                    core.remove_virtual_watchpoint(&watchpoint);
+                }
+            },
+        }
+
+        "OK".to_string()
+    }
+
+    fn resume(&mut self, machine: &mut Machine, single: bool) -> Stop {
+        machine.core.skip_execute_watchpoint();
+
+        // NOTE(patrik): Interrupts that came while the target was stopped
+        // don't count
+        machine.core.stop_handle().store(false, Ordering::Relaxed);
+
+        let limit = if single { 1 } else { u64::MAX };
+        loop {
+            match machine.run(limit).reason {
+                StopReason::Exit(CoreExit::Shutdown(shutdown)) => {
+                    return Stop::Exited(crate::shutdown_exit_code(shutdown));
+                },
+
+                StopReason::Exit(CoreExit::Ebreak) => {
+                    return Stop::SoftwareBreakpoint;
+                },
+
+                StopReason::Exit(CoreExit::Watchpoint(mut access)) => {
+                    // NOTE(patrik): Accesses are observed physically, report
+                    // the address GDB asked to watch
+                    if let Some(addr) = self.watchpoints.iter()
+                        .find_map(|watchpoint| watchpoint.hit(&access))
+                    {
+                        access.addr = addr;
+                    }
+
+                    return Stop::Watchpoint(access);
+                },
+
+                StopReason::StopRequested => return Stop::Interrupt,
+
+                _ => {},
+            }
+
+            if single {
+                return Stop::Step;
+            }
+        }
+    }
+
+    fn stop_reply(stop: &Stop) -> String {
+        match stop {
+            Stop::Step => format!("S{:02x}", SIGTRAP),
+            Stop::Interrupt => format!("S{:02x}", SIGINT),
+            Stop::SoftwareBreakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+
+            Stop::Watchpoint(access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "rwatch",
+                    AccessKind::Write => "watch",
+                    AccessKind::Execute => {
+                        return format!("T{:02x}hwbreak:;", SIGTRAP);
+                    },
+                };
+
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, access.addr)
+            },
+
+            Stop::Exited(code) => format!("W{:02x}", *code as u8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Mmu;
+
+    const RAM: u64 = 0x8000_0000;
+
+    fn create_server() -> GdbServer {
+        let (_, input) = mpsc::channel();
+        GdbServer {
+            input,
+            output: Box::new(std::io::sink()),
+
+            no_ack: false,
+
+            sw_breakpoints: HashMap::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn read_memory_up_to_packet_size() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x10000);
+        mmu.poke_bytes(RAM, &[0xab; 4]);
+        let core = crate::create_core(mmu);
+        let mut server = create_server();
+
+        let arguments = format!("{:x},4", RAM);
+        assert_eq!(server.read_memory(&core, &arguments), "abababab");
+
+        let arguments = format!("{:x},{:x}", RAM, PACKET_SIZE / 2);
+        let reply = server.read_memory(&core, &arguments);
+        assert_eq!(reply.len() as u64, PACKET_SIZE);
+
+        let arguments = format!("{:x},{:x}", RAM, PACKET_SIZE / 2 + 1);
+        assert_eq!(server.read_memory(&core, &arguments), "E01");
+
+        let arguments = format!("{:x},ffffffffffffffff", RAM);
+        assert_eq!(server.read_memory(&core, &arguments), "E01");
+    }
+}
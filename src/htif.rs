@@ -0,0 +1,238 @@
+//! Host-Target Interface used by riscv-tests and the Berkeley proxy kernel,
+//! the guest writes commands to the `tohost` symbol and the host answers
+//! through `fromhost`
+
+use std::io::Write;
+
+use crate::mmu::Mmu;
+use crate::device::Shutdown;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_PUTCHAR: u64 = 1;
+
+// Proxied system calls, numbers from the RISC-V Linux ABI
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+/// Largest amount of data written by a single call, larger writes are
+/// partial
+const MAX_TRANSFER: u64 = 16 * 1024 * 1024;
+
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+
+    output: Box<dyn Write>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>,
+               output: Box<dyn Write>) -> Self
+    {
+        Self {
+            tohost,
+            fromhost,
+
+            output,
+        }
+    }
+
+    pub fn tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    /// Handle a pending command, returns the shutdown requested by the
+    /// guest if it asked to exit
+    pub fn poll(&mut self, mmu: &mut Mmu) -> Option<Shutdown> {
+        // NOTE(patrik): The host side accesses bypass watchpoints and the
+        // access hook since the guest didn't do them
+        let value = mmu.peek_u64(self.tohost).unwrap_or(0);
+        if value == 0 {
+            return None;
+        }
+
+        mmu.poke_u64(self.tohost, 0);
+
+        let device = value >> 56;
+        let command = (value >> 48) & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+
+        match device {
+            DEVICE_SYSCALL => {
+                // NOTE(patrik): The lowest bit set means the payload is an
+                // exit code instead of a pointer to the syscall arguments
+                if payload & 1 != 0 {
+                    return Some(Self::exit(payload >> 1));
+                }
+
+                let exit = self.syscall(mmu, payload);
+                self.respond(mmu, device, command, 1);
+
+                exit
+            },
+
+            DEVICE_CONSOLE if command == CONSOLE_PUTCHAR => {
+                let _ = self.output.write_all(&[payload as u8]);
+                let _ = self.output.flush();
+                self.respond(mmu, device, command, 0);
+
+                None
+            },
+
+            // NOTE(patrik): Reading from the console is not supported, the
+            // request is never answered
+            _ => None,
+        }
+    }
+
+    fn exit(code: u64) -> Shutdown {
+        if code == 0 {
+            Shutdown::Pass
+        } else {
+            Shutdown::Fail(code as u16)
+        }
+    }
+
+    fn respond(&mut self, mmu: &mut Mmu, device: u64, command: u64,
+               payload: u64)
+    {
+        if let Some(fromhost) = self.fromhost {
+            mmu.poke_u64(fromhost, device << 56 | command << 48 | payload);
+        }
+    }
+
+    /// Run the system call described by the eight words at `addr`, the
+    /// result is written back over the first word
+    fn syscall(&mut self, mmu: &mut Mmu, addr: u64) -> Option<Shutdown> {
+        let mut args = [0; 8];
+        for (index, arg) in args.iter_mut().enumerate() {
+            *arg = mmu.peek_u64(addr + index as u64 * 8).unwrap_or(0);
+        }
+
+        let result = match args[0] {
+            SYS_WRITE => {
+                let (fd, buffer, length) = (args[1], args[2], args[3]);
+                let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+                if fd != 1 && fd != 2 {
+                    -EBADF
+                } else if mmu.peek_bytes(buffer, &mut data) {
+                    let _ = self.output.write_all(&data);
+                    let _ = self.output.flush();
+
+                    data.len() as i64
+                } else {
+                    -EFAULT
+                }
+            },
+
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(Self::exit(args[1])),
+
+            _ => -ENOSYS,
+        };
+
+        mmu.poke_u64(addr, result as u64);
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    const RAM: u64 = 0x8000_0000;
+    const TOHOST: u64 = RAM;
+    const FROMHOST: u64 = RAM + 0x40;
+    const ARGS: u64 = RAM + 0x100;
+    const BUFFER: u64 = RAM + 0x200;
+
+    /// Output shared with the test so it can look at what was written
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_htif() -> (Htif, Mmu, Output) {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x1000);
+
+        let output = Output::default();
+        let htif = Htif::new(TOHOST, Some(FROMHOST),
+                             Box::new(output.clone()));
+
+        (htif, mmu, output)
+    }
+
+    /// Run a system call with `args` and return its result
+    fn syscall(htif: &mut Htif, mmu: &mut Mmu, args: &[u64]) -> i64 {
+        for (index, arg) in args.iter().enumerate() {
+            mmu.poke_u64(ARGS + index as u64 * 8, *arg);
+        }
+
+        mmu.poke_u64(TOHOST, ARGS);
+        assert_eq!(htif.poll(mmu), None);
+        assert_eq!(mmu.peek_u64(TOHOST), Some(0));
+        assert_eq!(mmu.peek_u64(FROMHOST), Some(1));
+
+        mmu.peek_u64(ARGS).unwrap() as i64
+    }
+
+    #[test]
+    fn write_goes_to_the_output() {
+        let (mut htif, mut mmu, output) = create_htif();
+        mmu.poke_bytes(BUFFER, b"hello");
+
+        let result = syscall(&mut htif, &mut mmu, &[SYS_WRITE, 1, BUFFER, 5]);
+        assert_eq!(result, 5);
+        assert_eq!(&output.0.borrow()[..], b"hello");
+    }
+
+    #[test]
+    fn write_errors() {
+        let (mut htif, mut mmu, output) = create_htif();
+
+        let result = syscall(&mut htif, &mut mmu, &[SYS_WRITE, 3, BUFFER, 5]);
+        assert_eq!(result, -EBADF);
+
+        // NOTE(patrik): The buffer runs past the end of RAM
+        let args = [SYS_WRITE, 1, RAM + 0xffc, 8];
+        assert_eq!(syscall(&mut htif, &mut mmu, &args), -EFAULT);
+
+        assert_eq!(syscall(&mut htif, &mut mmu, &[1234]), -ENOSYS);
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let (mut htif, mut mmu, _) = create_htif();
+
+        mmu.poke_u64(TOHOST, 1);
+        assert_eq!(htif.poll(&mut mmu), Some(Shutdown::Pass));
+
+        mmu.poke_u64(TOHOST, 3 << 1 | 1);
+        assert_eq!(htif.poll(&mut mmu), Some(Shutdown::Fail(3)));
+
+        mmu.poke_u64(ARGS, SYS_EXIT);
+        mmu.poke_u64(ARGS + 8, 7);
+        mmu.poke_u64(TOHOST, ARGS);
+        assert_eq!(htif.poll(&mut mmu), Some(Shutdown::Fail(7)));
+    }
+}
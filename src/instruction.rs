@@ -100,10 +100,16 @@ pub enum Instruction {
 
     // 0b0001111
     Fence { rd: Register, rs1: Register, imm: i32 },
+    // Zifencei
+    FenceI,
 
     // 0b1110011
     Ecall,
     Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma { rs1: Register, rs2: Register },
     Csrrw  { rd: Register, rs1: Register, csr: u16 },
     Csrrs  { rd: Register, rs1: Register, csr: u16 },
     Csrrc  { rd: Register, rs1: Register, csr: u16 },
@@ -137,6 +143,84 @@ pub enum Instruction {
     Amomaxud { rd: Register, rs1: Register, rs2: Register, aq: bool, rl: bool },
 
 
+    // F Extention, the floating point registers are named by Register
+    // like the integer registers
+    // 0b0000111 and 0b0100111
+    Flw { rd: Register, rs1: Register, imm: i32 },
+    Fsw { rs1: Register, rs2: Register, imm: i32 },
+    // 0b1000011, 0b1000111, 0b1001011, 0b1001111 and 0b1010011
+    Fmadds  { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fmsubs  { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fnmsubs { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fnmadds { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fadds   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fsubs   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fmuls   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fdivs   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fsqrts  { rd: Register, rs1: Register, rm: u32 },
+    Fsgnjs  { rd: Register, rs1: Register, rs2: Register },
+    Fsgnjns { rd: Register, rs1: Register, rs2: Register },
+    Fsgnjxs { rd: Register, rs1: Register, rs2: Register },
+    Fmins   { rd: Register, rs1: Register, rs2: Register },
+    Fmaxs   { rd: Register, rs1: Register, rs2: Register },
+    Feqs    { rd: Register, rs1: Register, rs2: Register },
+    Flts    { rd: Register, rs1: Register, rs2: Register },
+    Fles    { rd: Register, rs1: Register, rs2: Register },
+    Fclasss { rd: Register, rs1: Register },
+    Fcvtws  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtwus { rd: Register, rs1: Register, rm: u32 },
+    Fcvtls  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtlus { rd: Register, rs1: Register, rm: u32 },
+    Fcvtsw  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtswu { rd: Register, rs1: Register, rm: u32 },
+    Fcvtsl  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtslu { rd: Register, rs1: Register, rm: u32 },
+    Fmvxw  { rd: Register, rs1: Register },
+    Fmvwx  { rd: Register, rs1: Register },
+
+    // D Extention
+    Fld { rd: Register, rs1: Register, imm: i32 },
+    Fsd { rs1: Register, rs2: Register, imm: i32 },
+    Fmaddd  { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fmsubd  { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fnmsubd { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Fnmaddd { rd: Register, rs1: Register, rs2: Register,
+              rs3: Register, rm: u32 },
+    Faddd   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fsubd   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fmuld   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fdivd   { rd: Register, rs1: Register, rs2: Register, rm: u32 },
+    Fsqrtd  { rd: Register, rs1: Register, rm: u32 },
+    Fsgnjd  { rd: Register, rs1: Register, rs2: Register },
+    Fsgnjnd { rd: Register, rs1: Register, rs2: Register },
+    Fsgnjxd { rd: Register, rs1: Register, rs2: Register },
+    Fmind   { rd: Register, rs1: Register, rs2: Register },
+    Fmaxd   { rd: Register, rs1: Register, rs2: Register },
+    Feqd    { rd: Register, rs1: Register, rs2: Register },
+    Fltd    { rd: Register, rs1: Register, rs2: Register },
+    Fled    { rd: Register, rs1: Register, rs2: Register },
+    Fclassd { rd: Register, rs1: Register },
+    Fcvtwd  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtwud { rd: Register, rs1: Register, rm: u32 },
+    Fcvtld  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtlud { rd: Register, rs1: Register, rm: u32 },
+    Fcvtdw  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtdwu { rd: Register, rs1: Register, rm: u32 },
+    Fcvtdl  { rd: Register, rs1: Register, rm: u32 },
+    Fcvtdlu { rd: Register, rs1: Register, rm: u32 },
+    Fcvtsd { rd: Register, rs1: Register, rm: u32 },
+    Fcvtds { rd: Register, rs1: Register, rm: u32 },
+    Fmvxd  { rd: Register, rs1: Register },
+    Fmvdx  { rd: Register, rs1: Register },
+
+
     // C Extention
     Hint,
 
@@ -361,7 +445,7 @@ impl Instruction {
                         let reg = Register::from((inst >> 7) & 0b11111);
 
                         if reg == Register::Zero {
-                            panic!("Reserved instruction");
+                            Instruction::UndefinedCompressed(inst)
                         } else {
                             Instruction::CAddiw { reg, imm }
                         }
@@ -395,7 +479,7 @@ impl Instruction {
                             let nzimm = ((nzimm as i32) << 22) >> 22;
 
                             return if nzimm == 0 {
-                                panic!("Reserved Instruction");
+                                Instruction::UndefinedCompressed(inst)
                             } else {
                                 Instruction::CAddi16sp { nzimm }
                             };
@@ -410,7 +494,7 @@ impl Instruction {
                                 let nzimm = ((nzimm as i32) << 14) >> 14;
 
                                 return if nzimm == 0 {
-                                    panic!("Reserved Instruction");
+                                    Instruction::UndefinedCompressed(inst)
                                 } else {
                                     Instruction::CLui { rd, nzimm }
                                 };
@@ -464,8 +548,8 @@ impl Instruction {
                                         Instruction::CSubw { reg, rs2 },
                                     (1, 0b01) =>
                                         Instruction::CAddw { reg, rs2 },
-                                    (1, 0b10) => panic!("Reserved instruction"),
-                                    (1, 0b11) => panic!("Reserved instruction"),
+                                    (1, 0b10) | (1, 0b11) =>
+                                        Instruction::UndefinedCompressed(inst),
 
                                     _ => unreachable!(),
                                 }
@@ -575,7 +659,7 @@ impl Instruction {
                         let rd = Register::from((inst >> 7) & 0b11111);
 
                         if rd == Register::Zero {
-                            panic!("Reserved instruction");
+                            return Instruction::UndefinedCompressed(inst);
                         }
 
                         Instruction::CLwsp { rd, uimm }
@@ -592,7 +676,7 @@ impl Instruction {
                         let rd = Register::from((inst >> 7) & 0b11111);
 
                         if rd == Register::Zero {
-                            panic!("Reserved Instruction");
+                            return Instruction::UndefinedCompressed(inst);
                         }
 
                         Instruction::CLdsp { rd, uimm }
@@ -771,10 +855,107 @@ impl Instruction {
                 };
             },
 
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => {
+                Self::decode_r4(original_inst, opcode)
+            },
+
+            0b1010011 => Self::decode_op_fp(original_inst),
+
             _ => Instruction::Undefined(original_inst)
         }
     }
 
+    /// Fused multiply-add, rs3 and the format are in the funct7 bits
+    fn decode_r4(original_inst: u32, opcode: u32) -> Self {
+        let inst = RType::from(original_inst);
+        let rd = inst.rd;
+        let rs1 = inst.rs1;
+        let rs2 = inst.rs2;
+        let rs3 = Register::from(inst.funct7 >> 2);
+        let rm = inst.funct3;
+
+        return match (opcode, inst.funct7 & 0b11) {
+            (0b1000011, 0b00) => Instruction::Fmadds  { rd, rs1, rs2, rs3, rm },
+            (0b1000111, 0b00) => Instruction::Fmsubs  { rd, rs1, rs2, rs3, rm },
+            (0b1001011, 0b00) => Instruction::Fnmsubs { rd, rs1, rs2, rs3, rm },
+            (0b1001111, 0b00) => Instruction::Fnmadds { rd, rs1, rs2, rs3, rm },
+
+            (0b1000011, 0b01) => Instruction::Fmaddd  { rd, rs1, rs2, rs3, rm },
+            (0b1000111, 0b01) => Instruction::Fmsubd  { rd, rs1, rs2, rs3, rm },
+            (0b1001011, 0b01) => Instruction::Fnmsubd { rd, rs1, rs2, rs3, rm },
+            (0b1001111, 0b01) => Instruction::Fnmaddd { rd, rs1, rs2, rs3, rm },
+
+            _ => Instruction::Undefined(original_inst)
+        };
+    }
+
+    /// The rest of the F and D extentions, funct7 holds the operation and
+    /// the format and rs2 selects the variant of some of them
+    fn decode_op_fp(original_inst: u32) -> Self {
+        let inst = RType::from(original_inst);
+        let rd = inst.rd;
+        let rs1 = inst.rs1;
+        let rs2 = inst.rs2;
+        let rm = inst.funct3;
+        let variant = rs2.index();
+
+        return match (inst.funct7, inst.funct3, variant) {
+            (0b0000000, _, _) => Instruction::Fadds { rd, rs1, rs2, rm },
+            (0b0000100, _, _) => Instruction::Fsubs { rd, rs1, rs2, rm },
+            (0b0001000, _, _) => Instruction::Fmuls { rd, rs1, rs2, rm },
+            (0b0001100, _, _) => Instruction::Fdivs { rd, rs1, rs2, rm },
+            (0b0101100, _, 0) => Instruction::Fsqrts { rd, rs1, rm },
+            (0b0010000, 0b000, _) => Instruction::Fsgnjs  { rd, rs1, rs2 },
+            (0b0010000, 0b001, _) => Instruction::Fsgnjns { rd, rs1, rs2 },
+            (0b0010000, 0b010, _) => Instruction::Fsgnjxs { rd, rs1, rs2 },
+            (0b0010100, 0b000, _) => Instruction::Fmins { rd, rs1, rs2 },
+            (0b0010100, 0b001, _) => Instruction::Fmaxs { rd, rs1, rs2 },
+            (0b1100000, _, 0) => Instruction::Fcvtws  { rd, rs1, rm },
+            (0b1100000, _, 1) => Instruction::Fcvtwus { rd, rs1, rm },
+            (0b1100000, _, 2) => Instruction::Fcvtls  { rd, rs1, rm },
+            (0b1100000, _, 3) => Instruction::Fcvtlus { rd, rs1, rm },
+            (0b1110000, 0b000, 0) => Instruction::Fmvxw { rd, rs1 },
+            (0b1010000, 0b010, _) => Instruction::Feqs { rd, rs1, rs2 },
+            (0b1010000, 0b001, _) => Instruction::Flts { rd, rs1, rs2 },
+            (0b1010000, 0b000, _) => Instruction::Fles { rd, rs1, rs2 },
+            (0b1110000, 0b001, 0) => Instruction::Fclasss { rd, rs1 },
+            (0b1101000, _, 0) => Instruction::Fcvtsw  { rd, rs1, rm },
+            (0b1101000, _, 1) => Instruction::Fcvtswu { rd, rs1, rm },
+            (0b1101000, _, 2) => Instruction::Fcvtsl  { rd, rs1, rm },
+            (0b1101000, _, 3) => Instruction::Fcvtslu { rd, rs1, rm },
+            (0b1111000, 0b000, 0) => Instruction::Fmvwx { rd, rs1 },
+
+            (0b0000001, _, _) => Instruction::Faddd { rd, rs1, rs2, rm },
+            (0b0000101, _, _) => Instruction::Fsubd { rd, rs1, rs2, rm },
+            (0b0001001, _, _) => Instruction::Fmuld { rd, rs1, rs2, rm },
+            (0b0001101, _, _) => Instruction::Fdivd { rd, rs1, rs2, rm },
+            (0b0101101, _, 0) => Instruction::Fsqrtd { rd, rs1, rm },
+            (0b0010001, 0b000, _) => Instruction::Fsgnjd  { rd, rs1, rs2 },
+            (0b0010001, 0b001, _) => Instruction::Fsgnjnd { rd, rs1, rs2 },
+            (0b0010001, 0b010, _) => Instruction::Fsgnjxd { rd, rs1, rs2 },
+            (0b0010101, 0b000, _) => Instruction::Fmind { rd, rs1, rs2 },
+            (0b0010101, 0b001, _) => Instruction::Fmaxd { rd, rs1, rs2 },
+            (0b0100000, _, 1) => Instruction::Fcvtsd { rd, rs1, rm },
+            (0b0100001, _, 0) => Instruction::Fcvtds { rd, rs1, rm },
+            (0b1100001, _, 0) => Instruction::Fcvtwd  { rd, rs1, rm },
+            (0b1100001, _, 1) => Instruction::Fcvtwud { rd, rs1, rm },
+            (0b1100001, _, 2) => Instruction::Fcvtld  { rd, rs1, rm },
+            (0b1100001, _, 3) => Instruction::Fcvtlud { rd, rs1, rm },
+            (0b1110001, 0b000, 0) => Instruction::Fmvxd { rd, rs1 },
+            (0b1010001, 0b010, _) => Instruction::Feqd { rd, rs1, rs2 },
+            (0b1010001, 0b001, _) => Instruction::Fltd { rd, rs1, rs2 },
+            (0b1010001, 0b000, _) => Instruction::Fled { rd, rs1, rs2 },
+            (0b1110001, 0b001, 0) => Instruction::Fclassd { rd, rs1 },
+            (0b1101001, _, 0) => Instruction::Fcvtdw  { rd, rs1, rm },
+            (0b1101001, _, 1) => Instruction::Fcvtdwu { rd, rs1, rm },
+            (0b1101001, _, 2) => Instruction::Fcvtdl  { rd, rs1, rm },
+            (0b1101001, _, 3) => Instruction::Fcvtdlu { rd, rs1, rm },
+            (0b1111001, 0b000, 0) => Instruction::Fmvdx { rd, rs1 },
+
+            _ => Instruction::Undefined(original_inst)
+        };
+    }
+
     fn decode_i(original_inst: u32, opcode: u32) -> Self {
         let inst = IType::from(original_inst);
         let rd = inst.rd;
@@ -856,9 +1037,19 @@ impl Instruction {
                 }
             }
 
+            0b0000111 => {
+                return match inst.funct3 {
+                    0b010 => Instruction::Flw { rd, rs1, imm },
+                    0b011 => Instruction::Fld { rd, rs1, imm },
+
+                    _ => Instruction::Undefined(original_inst),
+                };
+            }
+
             0b0001111 => {
                 return match inst.funct3 {
                     0b000 => Instruction::Fence { rd, rs1, imm },
+                    0b001 => Instruction::FenceI,
 
                     _ => Instruction::Undefined(original_inst),
                 };
@@ -873,6 +1064,17 @@ impl Instruction {
                         return match imm & 0b111111111111 {
                             0 => Instruction::Ecall,
                             1 => Instruction::Ebreak,
+                            0b000100000010 => Instruction::Sret,
+                            0b001100000010 => Instruction::Mret,
+                            0b000100000101 => Instruction::Wfi,
+
+                            _ if imm >> 5 & 0b1111111 == 0b0001001 &&
+                                rd == Register::Zero =>
+                            {
+                                let rs2 = (imm & 0b11111) as u32;
+                                let rs2 = Register::from(rs2);
+                                Instruction::SfenceVma { rs1, rs2 }
+                            },
 
                             _ => Instruction::Undefined(original_inst),
                         }
@@ -911,6 +1113,14 @@ impl Instruction {
                 };
             }
 
+            0b0100111 => {
+                return match inst.funct3 {
+                    0b010 => Instruction::Fsw { rs1, rs2, imm },
+                    0b011 => Instruction::Fsd { rs1, rs2, imm },
+                    _ => Instruction::Undefined(original_inst)
+                };
+            }
+
             _ => Instruction::Undefined(original_inst)
         };
 
@@ -1133,7 +1343,7 @@ static TYPE_MAPPING_TABLE: [Option<Type>; 128] = [
     None,          // 0b0000100
     None,          // 0b0000101
     None,          // 0b0000110
-    None,          // 0b0000111
+    Some(Type::I), // 0b0000111
     None,          // 0b0001000
     None,          // 0b0001001
     None,          // 0b0001010
@@ -1165,7 +1375,7 @@ static TYPE_MAPPING_TABLE: [Option<Type>; 128] = [
     None,          // 0b0100100
     None,          // 0b0100101
     None,          // 0b0100110
-    None,          // 0b0100111
+    Some(Type::S), // 0b0100111
     None,          // 0b0101000
     None,          // 0b0101001
     None,          // 0b0101010
@@ -1193,23 +1403,23 @@ static TYPE_MAPPING_TABLE: [Option<Type>; 128] = [
     None,          // 0b1000000
     None,          // 0b1000001
     None,          // 0b1000010
-    None,          // 0b1000011
+    Some(Type::R), // 0b1000011
     None,          // 0b1000100
     None,          // 0b1000101
     None,          // 0b1000110
-    None,          // 0b1000111
+    Some(Type::R), // 0b1000111
     None,          // 0b1001000
     None,          // 0b1001001
     None,          // 0b1001010
-    None,          // 0b1001011
+    Some(Type::R), // 0b1001011
     None,          // 0b1001100
     None,          // 0b1001101
     None,          // 0b1001110
-    None,          // 0b1001111
+    Some(Type::R), // 0b1001111
     None,          // 0b1010000
     None,          // 0b1010001
     None,          // 0b1010010
-    None,          // 0b1010011
+    Some(Type::R), // 0b1010011
     None,          // 0b1010100
     None,          // 0b1010101
     None,          // 0b1010110
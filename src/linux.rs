@@ -0,0 +1,1063 @@
+//! User-mode emulation of Linux, runs statically linked riscv64 Linux
+//! programs without a kernel. The program runs in user mode, its ECALLs
+//! are handled as system calls against the host and the initial stack is
+//! laid out the way the kernel does it
+//!
+//!     rest-emu --linux program [args...]
+//!
+//! The same system calls serve programs built against newlib for the
+//! riscv-pk proxy kernel, those get no environment and the old path based
+//! calls pk still has. Files can be limited to a directory on the host
+//!
+//!     rest-emu --pk --sandbox dir program [args...]
+//!
+//! There is no address translation so the program can see all of RAM and
+//! memory given back with munmap is never reused
+
+use std::ffi::{ OsStr, OsString };
+use std::fs::{ self, DirBuilder, File, Metadata, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt,
+};
+use std::path::{ Component, Path, PathBuf };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::cpu::{ Core, Exception, Register, PrivilegeLevel };
+use crate::device::Shutdown;
+use crate::elf::{ Elf, PROGRAM_HEADER_SIZE };
+use crate::machine::EcallHandler;
+use crate::mmu::{ Mmu, PAGE_SIZE };
+use crate::csr;
+
+/// RAM used when none is given on the command line, the stack is at the
+/// end of it and mmap allocations go below the stack
+pub const DEFAULT_MEMORY: (u64, u64) = (0, 0x1_0000_0000);
+
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Largest amount of data moved by a single read or write, larger requests
+/// are partial
+const MAX_TRANSFER: u64 = 16 * 1024 * 1024;
+
+const PATH_MAX: u64 = 4096;
+
+// System call numbers from the generic Linux ABI RISC-V uses
+const SYS_GETCWD: u64 = 17;
+const SYS_FCNTL: u64 = 25;
+const SYS_IOCTL: u64 = 29;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_LINKAT: u64 = 37;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+
+// Older calls without a directory file descriptor that riscv-pk and newlib
+// still use
+const SYS_OPEN: u64 = 1024;
+const SYS_LINK: u64 = 1025;
+const SYS_UNLINK: u64 = 1026;
+const SYS_MKDIR: u64 = 1030;
+const SYS_ACCESS: u64 = 1033;
+const SYS_STAT: u64 = 1038;
+const SYS_LSTAT: u64 = 1039;
+
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const ENODEV: i64 = 19;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ERANGE: i64 = 34;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// mstatus.FS when the floating point state is on but untouched
+const FS_INITIAL: u64 = 1 << 13;
+
+/// One bit per single letter extension, IMAFDC
+const HWCAP: u64 = extension(b'I') | extension(b'M') | extension(b'A') |
+    extension(b'F') | extension(b'D') | extension(b'C');
+
+/// Size of `struct stat` and of the structures filled by rt_sigaction
+/// and uname
+const STAT_SIZE: usize = 128;
+const SIGACTION_SIZE: usize = 24;
+const UTSNAME_FIELD_SIZE: usize = 65;
+
+const S_IFCHR: u64 = 0o020000;
+
+/// User and group ids the program runs as
+const ID: u64 = 0;
+
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGSEGV: i32 = 11;
+
+enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl Descriptor {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i64> {
+        let result = match self {
+            Descriptor::Stdin => io::stdin().read(buffer),
+            Descriptor::File(file) => file.read(buffer),
+
+            _ => return Err(EBADF),
+        };
+
+        result.map_err(host_error)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, i64> {
+        let result = match self {
+            Descriptor::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            },
+            Descriptor::Stderr => io::stderr().write_all(data),
+            Descriptor::File(file) => {
+                return file.write(data).map_err(host_error);
+            },
+
+            Descriptor::Stdin => return Err(EBADF),
+        };
+
+        result.map(|_| data.len()).map_err(host_error)
+    }
+
+    fn try_clone(&self) -> Result<Self, i64> {
+        Ok(match self {
+            Descriptor::Stdin => Descriptor::Stdin,
+            Descriptor::Stdout => Descriptor::Stdout,
+            Descriptor::Stderr => Descriptor::Stderr,
+            Descriptor::File(file) => {
+                Descriptor::File(file.try_clone().map_err(host_error)?)
+            },
+        })
+    }
+}
+
+/// What paths from the program refer to on the host
+pub enum FileAccess {
+    /// The paths are used as they are
+    Host,
+
+    /// The directory is the root of the program, paths can't leave it.
+    /// The directory has to be canonical
+    Sandbox(PathBuf),
+
+    /// No files can be opened
+    Denied,
+}
+
+/// State of the emulated process
+pub struct Linux {
+    /// Open files indexed by file descriptor
+    files: Vec<Option<Descriptor>>,
+
+    file_access: FileAccess,
+
+    /// End of the heap, where it started and the highest it has been
+    brk: u64,
+    brk_start: u64,
+    brk_max: u64,
+
+    /// Lowest address handed out by mmap, allocations grow down towards
+    /// the heap
+    mmap_bottom: u64,
+
+    /// When the program started, the monotonic clocks count from here
+    start: Instant,
+}
+
+impl Linux {
+    /// Set up `core` to run `elf` as a Linux process with `args` as argv
+    /// and `environment` as its variables, the stack is placed at the end
+    /// of the RAM region `memory`
+    pub fn new(core: &mut Core, elf: &Elf, memory: (u64, u64),
+               args: &[String], environment: &[(OsString, OsString)])
+        -> Self
+    {
+        let (base, size) = memory;
+        let stack_top = base + size;
+        let brk = page_align(elf.end().unwrap_or(base));
+
+        if stack_top < brk + STACK_SIZE {
+            panic!("Linux: Not enough memory for the stack");
+        }
+
+        let sp = setup_stack(&mut core.mmu, elf, stack_top, args,
+                             environment);
+        core.set_reg(Register::Sp, sp);
+        core.set_reg(Register::Pc, elf.entry);
+        core.set_reg(Register::Ra, 0);
+        // NOTE(patrik): a0 is the function the dynamic linker wants called
+        // at exit, there is none
+        core.set_reg(Register::A0, 0);
+
+        // NOTE(patrik): Linux lets user mode read cycle, time and instret
+        core.write_csr(csr::MCOUNTEREN, 0b111);
+        core.write_csr(csr::SCOUNTEREN, 0b111);
+        core.set_privilege_level(PrivilegeLevel::User);
+
+        // NOTE(patrik): The floating point unit starts out on and clean
+        // like the kernel leaves it for a new process
+        let status = core.read_csr(csr::MSTATUS);
+        core.write_csr(csr::MSTATUS, status | FS_INITIAL);
+
+        // NOTE(patrik): ECALLs come to the host and there is no kernel to
+        // take the exceptions, they kill the program instead
+        core.set_environment_traps(false);
+        core.set_stop_on_exceptions(true);
+
+        let files = vec![
+            Some(Descriptor::Stdin),
+            Some(Descriptor::Stdout),
+            Some(Descriptor::Stderr),
+        ];
+
+        Self {
+            files,
+            file_access: FileAccess::Host,
+
+            brk,
+            brk_start: brk,
+            brk_max: brk,
+
+            mmap_bottom: stack_top - STACK_SIZE,
+
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_file_access(&mut self, file_access: FileAccess) {
+        self.file_access = file_access;
+    }
+
+    fn descriptor(&mut self, fd: u64) -> Result<&mut Descriptor, i64> {
+        self.files.get_mut(fd as usize)
+            .and_then(|descriptor| descriptor.as_mut())
+            .ok_or(EBADF)
+    }
+
+    /// Put `descriptor` in the lowest free slot at or above `minimum`
+    fn add_descriptor(&mut self, descriptor: Descriptor,
+                      minimum: usize) -> u64
+    {
+        let fd = (minimum..self.files.len())
+            .find(|fd| self.files[*fd].is_none())
+            .unwrap_or_else(|| self.files.len().max(minimum));
+
+        if fd >= self.files.len() {
+            self.files.resize_with(fd + 1, || None);
+        }
+        self.files[fd] = Some(descriptor);
+
+        fd as u64
+    }
+
+    /// Path on the host for the guest path at `addr`, relative paths are
+    /// relative to the working directory of the emulator or the root of
+    /// the sandbox
+    fn host_path(&self, mmu: &Mmu, dirfd: u64, addr: u64)
+        -> Result<PathBuf, i64>
+    {
+        let path = PathBuf::from(OsStr::from_bytes(&read_string(mmu, addr)?));
+
+        // NOTE(patrik): Directory file descriptors are not supported
+        if path.is_relative() && dirfd as i64 != AT_FDCWD {
+            return Err(EBADF);
+        }
+
+        match &self.file_access {
+            FileAccess::Host => Ok(path),
+            FileAccess::Sandbox(root) => sandbox_path(root, &path),
+            FileAccess::Denied => Err(EACCES),
+        }
+    }
+
+    fn syscall(&mut self, mmu: &mut Mmu, number: u64, args: [u64; 6])
+        -> Result<u64, i64>
+    {
+        let cwd = AT_FDCWD as u64;
+        match number {
+            // NOTE(patrik): The old calls are the new ones relative to the
+            // working directory
+            SYS_OPEN => {
+                self.syscall(mmu, SYS_OPENAT,
+                             [cwd, args[0], args[1], args[2], 0, 0])
+            },
+            SYS_LINK => {
+                self.syscall(mmu, SYS_LINKAT,
+                             [cwd, args[0], cwd, args[1], 0, 0])
+            },
+            SYS_UNLINK => {
+                self.syscall(mmu, SYS_UNLINKAT, [cwd, args[0], 0, 0, 0, 0])
+            },
+            SYS_MKDIR => {
+                self.syscall(mmu, SYS_MKDIRAT,
+                             [cwd, args[0], args[1], 0, 0, 0])
+            },
+            SYS_ACCESS => {
+                self.syscall(mmu, SYS_FACCESSAT,
+                             [cwd, args[0], args[1], 0, 0, 0])
+            },
+            SYS_STAT | SYS_LSTAT => {
+                let flags = if number == SYS_LSTAT {
+                    AT_SYMLINK_NOFOLLOW
+                } else {
+                    0
+                };
+
+                self.syscall(mmu, SYS_NEWFSTATAT,
+                             [cwd, args[0], args[1], flags, 0, 0])
+            },
+
+            SYS_READ => {
+                let (fd, buffer, count) = (args[0], args[1], args[2]);
+                let mut data = vec![0; count.min(MAX_TRANSFER) as usize];
+                let length = self.descriptor(fd)?.read(&mut data)?;
+                write_bytes(mmu, buffer, &data[..length])?;
+
+                Ok(length as u64)
+            },
+
+            SYS_WRITE => {
+                let (fd, buffer, count) = (args[0], args[1], args[2]);
+                let data = read_bytes(mmu, buffer, count.min(MAX_TRANSFER))?;
+                let length = self.descriptor(fd)?.write(&data)?;
+
+                Ok(length as u64)
+            },
+
+            SYS_READV | SYS_WRITEV => {
+                let (fd, iov, count) = (args[0], args[1], args[2]);
+                let mut total = 0;
+                for index in 0..count {
+                    let entry = iov.wrapping_add(index * 16);
+                    let base = mmu.peek_u64(entry).ok_or(EFAULT)?;
+                    let length = mmu.peek_u64(entry + 8).ok_or(EFAULT)?;
+                    let length = length.min(MAX_TRANSFER);
+
+                    let done = if number == SYS_READV {
+                        let mut data = vec![0; length as usize];
+                        let done = self.descriptor(fd)?.read(&mut data)?;
+                        write_bytes(mmu, base, &data[..done])?;
+                        done
+                    } else {
+                        let data = read_bytes(mmu, base, length)?;
+                        self.descriptor(fd)?.write(&data)?
+                    };
+
+                    total += done as u64;
+                    if done as u64 != length {
+                        break;
+                    }
+                }
+
+                Ok(total)
+            },
+
+            SYS_OPENAT => {
+                let (dirfd, path, flags, mode) =
+                    (args[0], args[1], args[2], args[3]);
+                let path = self.host_path(mmu, dirfd, path)?;
+
+                let access = flags & O_ACCMODE;
+                let mut options = OpenOptions::new();
+                options.read(access != O_WRONLY)
+                    .write(access != O_RDONLY)
+                    .append(flags & O_APPEND != 0)
+                    .truncate(flags & O_TRUNC != 0)
+                    .mode(mode as u32);
+
+                if flags & O_CREAT != 0 {
+                    if flags & O_EXCL != 0 {
+                        options.create_new(true);
+                    } else {
+                        options.create(true);
+                    }
+                }
+
+                let file = options.open(path).map_err(host_error)?;
+                Ok(self.add_descriptor(Descriptor::File(file), 0))
+            },
+
+            SYS_CLOSE => {
+                let descriptor = self.files.get_mut(args[0] as usize)
+                    .and_then(|descriptor| descriptor.take());
+
+                descriptor.map(|_| 0).ok_or(EBADF)
+            },
+
+            SYS_LSEEK => {
+                let (fd, offset, whence) = (args[0], args[1], args[2]);
+                let position = match whence {
+                    SEEK_SET => SeekFrom::Start(offset),
+                    SEEK_CUR => SeekFrom::Current(offset as i64),
+                    SEEK_END => SeekFrom::End(offset as i64),
+
+                    _ => return Err(EINVAL),
+                };
+
+                match self.descriptor(fd)? {
+                    Descriptor::File(file) => {
+                        file.seek(position).map_err(host_error)
+                    },
+
+                    _ => Err(ESPIPE),
+                }
+            },
+
+            SYS_FSTAT => {
+                let stat = self.fstat(args[0])?;
+                write_bytes(mmu, args[1], &stat)?;
+
+                Ok(0)
+            },
+
+            SYS_NEWFSTATAT => {
+                let (dirfd, path, buffer, flags) =
+                    (args[0], args[1], args[2], args[3]);
+
+                let empty = mmu.peek_u8(path).ok_or(EFAULT)? == 0;
+                let stat = if empty && flags & AT_EMPTY_PATH != 0 {
+                    self.fstat(dirfd)?
+                } else {
+                    let path = self.host_path(mmu, dirfd, path)?;
+                    let metadata = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+                        fs::symlink_metadata(path)
+                    } else {
+                        fs::metadata(path)
+                    };
+
+                    stat_bytes(Some(&metadata.map_err(host_error)?))
+                };
+                write_bytes(mmu, buffer, &stat)?;
+
+                Ok(0)
+            },
+
+            SYS_FACCESSAT => {
+                let path = self.host_path(mmu, args[0], args[1])?;
+                fs::metadata(path).map(|_| 0).map_err(host_error)
+            },
+
+            SYS_MKDIRAT => {
+                let path = self.host_path(mmu, args[0], args[1])?;
+                DirBuilder::new().mode(args[2] as u32).create(path)
+                    .map(|_| 0).map_err(host_error)
+            },
+
+            SYS_UNLINKAT => {
+                let path = self.host_path(mmu, args[0], args[1])?;
+                let result = if args[2] & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(path)
+                } else {
+                    fs::remove_file(path)
+                };
+
+                result.map(|_| 0).map_err(host_error)
+            },
+
+            SYS_LINKAT => {
+                let old = self.host_path(mmu, args[0], args[1])?;
+                let new = self.host_path(mmu, args[2], args[3])?;
+                fs::hard_link(old, new).map(|_| 0).map_err(host_error)
+            },
+
+            SYS_GETCWD => {
+                let (buffer, size) = (args[0], args[1]);
+                let cwd = match self.file_access {
+                    FileAccess::Host => {
+                        std::env::current_dir().map_err(host_error)?
+                    },
+
+                    _ => PathBuf::from("/"),
+                };
+
+                let mut path = cwd.as_os_str().as_bytes().to_vec();
+                path.push(0);
+                if path.len() as u64 > size {
+                    return Err(ERANGE);
+                }
+                write_bytes(mmu, buffer, &path)?;
+
+                Ok(path.len() as u64)
+            },
+
+            // NOTE(patrik): Nothing is a terminal, C libraries use this to
+            // pick the buffering of stdout
+            SYS_IOCTL => {
+                self.descriptor(args[0])?;
+                Err(ENOTTY)
+            },
+
+            SYS_FCNTL => {
+                let (fd, command, argument) = (args[0], args[1], args[2]);
+                let descriptor = self.descriptor(fd)?;
+
+                match command {
+                    F_DUPFD | F_DUPFD_CLOEXEC => {
+                        let descriptor = descriptor.try_clone()?;
+                        Ok(self.add_descriptor(descriptor,
+                                               argument as usize))
+                    },
+
+                    F_GETFD | F_SETFD | F_GETFL | F_SETFL => Ok(0),
+
+                    _ => Err(EINVAL),
+                }
+            },
+
+            SYS_BRK => Ok(self.brk(mmu, args[0])),
+
+            SYS_MMAP => self.mmap(mmu, args),
+
+            // NOTE(patrik): Memory is never protected and freed memory is
+            // not reused so these have nothing to do
+            SYS_MUNMAP | SYS_MPROTECT | SYS_MADVISE => Ok(0),
+
+            SYS_CLOCK_GETTIME => {
+                let (clock, buffer) = (args[0], args[1]);
+                let time = if clock == CLOCK_REALTIME {
+                    SystemTime::now().duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                } else {
+                    self.start.elapsed()
+                };
+
+                write_u64(mmu, buffer, time.as_secs())?;
+                write_u64(mmu, buffer + 8, time.subsec_nanos() as u64)?;
+
+                Ok(0)
+            },
+
+            SYS_GETTIMEOFDAY => {
+                let buffer = args[0];
+                if buffer != 0 {
+                    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+
+                    write_u64(mmu, buffer, time.as_secs())?;
+                    write_u64(mmu, buffer + 8, time.subsec_micros() as u64)?;
+                }
+
+                Ok(0)
+            },
+
+            SYS_UNAME => {
+                let fields: [&[u8]; 6] = [
+                    b"Linux", b"rest-emu", b"6.1.0", b"#1", b"riscv64", b"",
+                ];
+
+                let mut utsname = vec![0; fields.len() * UTSNAME_FIELD_SIZE];
+                for (index, field) in fields.iter().enumerate() {
+                    let offset = index * UTSNAME_FIELD_SIZE;
+                    utsname[offset..offset + field.len()]
+                        .copy_from_slice(field);
+                }
+                write_bytes(mmu, args[0], &utsname)?;
+
+                Ok(0)
+            },
+
+            SYS_GETRANDOM => {
+                let (buffer, length) = (args[0], args[1]);
+                let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+                random_bytes(&mut data);
+                write_bytes(mmu, buffer, &data)?;
+
+                Ok(data.len() as u64)
+            },
+
+            SYS_PRLIMIT64 => {
+                let (resource, old) = (args[1], args[3]);
+                if old != 0 {
+                    let limit = if resource == RLIMIT_STACK {
+                        STACK_SIZE
+                    } else {
+                        RLIM_INFINITY
+                    };
+
+                    write_u64(mmu, old, limit)?;
+                    write_u64(mmu, old + 8, RLIM_INFINITY)?;
+                }
+
+                Ok(0)
+            },
+
+            // NOTE(patrik): Signals are never delivered, the old action
+            // and mask read back as the defaults
+            SYS_RT_SIGACTION => {
+                if args[2] != 0 {
+                    write_bytes(mmu, args[2], &[0; SIGACTION_SIZE])?;
+                }
+
+                Ok(0)
+            },
+
+            SYS_RT_SIGPROCMASK => {
+                if args[2] != 0 {
+                    write_u64(mmu, args[2], 0)?;
+                }
+
+                Ok(0)
+            },
+
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => {
+                Ok(std::process::id() as u64)
+            },
+            SYS_GETPPID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(ID),
+
+            // NOTE(patrik): There is only one thread so there is never
+            // anyone to wait for
+            SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD => Ok(0),
+
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn fstat(&mut self, fd: u64) -> Result<Vec<u8>, i64> {
+        match self.descriptor(fd)? {
+            Descriptor::File(file) => {
+                let metadata = file.metadata().map_err(host_error)?;
+                Ok(stat_bytes(Some(&metadata)))
+            },
+
+            _ => Ok(stat_bytes(None)),
+        }
+    }
+
+    /// Move the end of the heap to `addr`, an address that can't be used
+    /// leaves it where it is. Returns the new end
+    fn brk(&mut self, mmu: &mut Mmu, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > self.mmap_bottom {
+            return self.brk;
+        }
+
+        // NOTE(patrik): Memory the heap grows into again after shrinking
+        // has to read as zero
+        if addr > self.brk && self.brk < self.brk_max {
+            let end = addr.min(self.brk_max);
+            if zero(mmu, self.brk, end - self.brk).is_err() {
+                return self.brk;
+            }
+        }
+
+        self.brk = addr;
+        self.brk_max = self.brk_max.max(addr);
+
+        addr
+    }
+
+    fn mmap(&mut self, mmu: &mut Mmu, args: [u64; 6]) -> Result<u64, i64> {
+        let (addr, length, flags, fd, offset) =
+            (args[0], args[1], args[3], args[4], args[5]);
+
+        if length == 0 || offset & (PAGE_SIZE - 1) != 0 {
+            return Err(EINVAL);
+        }
+
+        let size = page_align(length);
+        let addr = if flags & MAP_FIXED != 0 {
+            if addr & (PAGE_SIZE - 1) != 0 {
+                return Err(EINVAL);
+            }
+
+            zero(mmu, addr, size).map_err(|_| ENOMEM)?;
+            addr
+        } else {
+            // NOTE(patrik): New memory is always below all the earlier
+            // allocations so it's still zero
+            let addr = self.mmap_bottom.checked_sub(size)
+                .filter(|addr| *addr >= self.brk)
+                .ok_or(ENOMEM)?;
+            self.mmap_bottom = addr;
+
+            addr
+        };
+
+        // NOTE(patrik): Files are copied in, writes to a shared mapping
+        // never reach the file
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = match self.descriptor(fd)? {
+                Descriptor::File(file) => file,
+                _ => return Err(ENODEV),
+            };
+
+            let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+            let mut done = 0;
+            while done < data.len() {
+                let count = file.read_at(&mut data[done..],
+                                         offset + done as u64)
+                    .map_err(host_error)?;
+                if count == 0 {
+                    break;
+                }
+
+                done += count;
+            }
+
+            write_bytes(mmu, addr, &data[..done])?;
+        }
+
+        Ok(addr)
+    }
+}
+
+impl EcallHandler for Linux {
+    fn ecall(&mut self, core: &mut Core) -> Option<Shutdown> {
+        let number = core.reg(Register::A7);
+        let args = [
+            core.reg(Register::A0), core.reg(Register::A1),
+            core.reg(Register::A2), core.reg(Register::A3),
+            core.reg(Register::A4), core.reg(Register::A5),
+        ];
+
+        if number == SYS_EXIT || number == SYS_EXIT_GROUP {
+            let _ = io::stdout().flush();
+
+            // NOTE(patrik): Only the low 8 bits of the status reach the
+            // parent
+            let code = args[0] as u8;
+            return Some(if code == 0 {
+                Shutdown::Pass
+            } else {
+                Shutdown::Fail(code as u16)
+            });
+        }
+
+        let result = match self.syscall(&mut core.mmu, number, args) {
+            Ok(value) => value,
+            Err(errno) => -errno as u64,
+        };
+        core.set_reg(Register::A0, result);
+
+        None
+    }
+}
+
+const fn extension(letter: u8) -> u64 {
+    1 << (letter - b'A')
+}
+
+/// Exit code a shell reports for a program killed by the signal Linux
+/// sends for `exception`
+pub fn signal_exit_code(exception: Exception) -> i32 {
+    let signal = match exception {
+        Exception::IllegalInstruction(_) => SIGILL,
+        Exception::Breakpoint(_) => SIGTRAP,
+        Exception::InstructionAddressMisaligned(_) |
+        Exception::LoadAddressMisaligned(_) |
+        Exception::StoreAddressMisaligned(_) => SIGBUS,
+
+        _ => SIGSEGV,
+    };
+
+    128 + signal
+}
+
+fn page_align(value: u64) -> u64 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Linux errno for an error from the host, the host is expected to be
+/// Linux as well so the numbers are the same
+fn host_error(error: io::Error) -> i64 {
+    error.raw_os_error().map(i64::from).unwrap_or(EIO)
+}
+
+/// Host path of `path` in the sandbox at `root`. The program sees `root` as
+/// `/` and can't get out of it with `..` or with symbolic links
+fn sandbox_path(root: &Path, path: &Path) -> Result<PathBuf, i64> {
+    let mut inside = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => inside.push(name),
+            // NOTE(patrik): `..` at the root stays at the root like it does
+            // on Linux
+            Component::ParentDir => { inside.pop(); },
+
+            _ => {},
+        }
+    }
+
+    // NOTE(patrik): A symbolic link in the sandbox can point anywhere, the
+    // part of the path that exists has to stay in the sandbox once the
+    // links are followed. A dangling link fails to canonicalize so nothing
+    // can be created through it
+    let path = root.join(inside);
+    let existing = path.ancestors()
+        .find(|ancestor| fs::symlink_metadata(ancestor).is_ok())
+        .unwrap_or(root);
+    let existing = existing.canonicalize().map_err(host_error)?;
+    if !existing.starts_with(root) {
+        return Err(EACCES);
+    }
+
+    Ok(path)
+}
+
+fn read_string(mmu: &Mmu, addr: u64) -> Result<Vec<u8>, i64> {
+    let mut string = Vec::new();
+    for index in 0..PATH_MAX {
+        match mmu.peek_u8(addr.wrapping_add(index)).ok_or(EFAULT)? {
+            0 => return Ok(string),
+            value => string.push(value),
+        }
+    }
+
+    Err(ENAMETOOLONG)
+}
+
+fn read_bytes(mmu: &Mmu, addr: u64, length: u64) -> Result<Vec<u8>, i64> {
+    let mut data = vec![0; length as usize];
+    if !mmu.peek_bytes(addr, &mut data) {
+        return Err(EFAULT);
+    }
+
+    Ok(data)
+}
+
+fn write_bytes(mmu: &mut Mmu, addr: u64, data: &[u8]) -> Result<(), i64> {
+    if !mmu.poke_bytes(addr, data) {
+        return Err(EFAULT);
+    }
+
+    Ok(())
+}
+
+fn write_u64(mmu: &mut Mmu, addr: u64, value: u64) -> Result<(), i64> {
+    write_bytes(mmu, addr, &value.to_le_bytes())
+}
+
+fn zero(mmu: &mut Mmu, addr: u64, length: u64) -> Result<(), i64> {
+    let page = [0; PAGE_SIZE as usize];
+    for offset in (0..length).step_by(PAGE_SIZE as usize) {
+        let size = (length - offset).min(PAGE_SIZE) as usize;
+        write_bytes(mmu, addr + offset, &page[..size])?;
+    }
+
+    Ok(())
+}
+
+fn random_bytes(buffer: &mut [u8]) {
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(buffer))
+        .unwrap_or_else(|_| panic!("Linux: Failed to read /dev/urandom"));
+}
+
+/// `struct stat` for a file with `metadata`, `None` for the terminal
+fn stat_bytes(metadata: Option<&Metadata>) -> Vec<u8> {
+    let mut stat = vec![0; STAT_SIZE];
+    let mut put = |offset: usize, size: usize, value: u64| {
+        stat[offset..offset + size]
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+    };
+
+    match metadata {
+        Some(metadata) => {
+            put(0, 8, metadata.dev());
+            put(8, 8, metadata.ino());
+            put(16, 4, metadata.mode() as u64);
+            put(20, 4, metadata.nlink());
+            put(24, 4, metadata.uid() as u64);
+            put(28, 4, metadata.gid() as u64);
+            put(32, 8, metadata.rdev());
+            put(48, 8, metadata.size());
+            put(56, 4, metadata.blksize());
+            put(64, 8, metadata.blocks());
+            put(72, 8, metadata.atime() as u64);
+            put(80, 8, metadata.atime_nsec() as u64);
+            put(88, 8, metadata.mtime() as u64);
+            put(96, 8, metadata.mtime_nsec() as u64);
+            put(104, 8, metadata.ctime() as u64);
+            put(112, 8, metadata.ctime_nsec() as u64);
+        },
+
+        None => {
+            put(16, 4, S_IFCHR | 0o620);
+            put(20, 4, 1);
+            put(56, 4, 1024);
+        },
+    }
+
+    stat
+}
+
+fn push(mmu: &mut Mmu, sp: &mut u64, bytes: &[u8]) -> u64 {
+    *sp -= bytes.len() as u64;
+    if !mmu.poke_bytes(*sp, bytes) {
+        panic!("Linux: Stack at {:#x} is not in RAM", *sp);
+    }
+
+    *sp
+}
+
+fn push_string(mmu: &mut Mmu, sp: &mut u64, string: &[u8]) -> u64 {
+    push(mmu, sp, &[0]);
+    push(mmu, sp, string)
+}
+
+/// Lay out argc, argv, envp and the auxiliary vector below `stack_top`
+/// the way the kernel does it, returns the stack pointer the program
+/// starts with
+fn setup_stack(mmu: &mut Mmu, elf: &Elf, stack_top: u64,
+               args: &[String], environment: &[(OsString, OsString)]) -> u64
+{
+    let mut sp = stack_top;
+
+    let mut random = [0; 16];
+    random_bytes(&mut random);
+    let random = push(mmu, &mut sp, &random);
+
+    let execfn = push_string(mmu, &mut sp, args[0].as_bytes());
+
+    let argv: Vec<u64> = args.iter()
+        .map(|arg| push_string(mmu, &mut sp, arg.as_bytes()))
+        .collect();
+
+    let envp: Vec<u64> = environment.iter()
+        .map(|(key, value)| {
+            let mut variable = key.as_bytes().to_vec();
+            variable.push(b'=');
+            variable.extend_from_slice(value.as_bytes());
+
+            push_string(mmu, &mut sp, &variable)
+        })
+        .collect();
+
+    let mut auxv = vec![
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_header_count as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, elf.entry),
+        (AT_UID, ID),
+        (AT_EUID, ID),
+        (AT_GID, ID),
+        (AT_EGID, ID),
+        (AT_HWCAP, HWCAP),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+    ];
+    if let Some(program_headers) = elf.program_headers {
+        auxv.push((AT_PHDR, program_headers));
+    }
+
+    let mut words = vec![args.len() as u64];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    sp = (sp - words.len() as u64 * 8) & !15;
+    for (index, word) in words.iter().enumerate() {
+        mmu.poke_u64(sp + index as u64 * 8, *word);
+    }
+
+    sp
+}
@@ -0,0 +1,186 @@
+//! The whole machine, a core with everything on its bus and the host side
+//! devices that are serviced between instructions
+
+use crate::cpu::{ Core, CoreExit, CoreSnapshot, Register };
+use crate::cpu::{ RunResult, StopReason };
+use crate::device::Shutdown;
+use crate::mmu::{ MemorySnapshot, MemoryAccess, AccessKind, WatchKind };
+use crate::htif::Htif;
+use crate::semihosting::Semihosting;
+
+/// In-memory copy of the state of the machine, made by
+/// `Machine::snapshot` and restored with `Machine::reset_to`
+pub struct Snapshot {
+    core: CoreSnapshot,
+    memory: MemorySnapshot,
+}
+
+/// Handles ECALLs from the guest on the host side, in place of the kernel
+/// or firmware the guest would otherwise need
+pub trait EcallHandler {
+    /// Handle the ECALL the core just executed, PC is already past it.
+    /// Returns the shutdown if the guest asked to exit
+    fn ecall(&mut self, core: &mut Core) -> Option<Shutdown>;
+
+    /// Whether the handler keeps state outside of the core and memory,
+    /// like open files, that a snapshot would lose
+    fn has_state(&self) -> bool {
+        true
+    }
+}
+
+pub struct Machine {
+    pub core: Core,
+    htif: Option<Htif>,
+    ecall_handler: Option<Box<dyn EcallHandler>>,
+    semihosting: Option<Semihosting>,
+}
+
+impl Machine {
+    pub fn new(mut core: Core, htif: Option<Htif>) -> Self {
+        // NOTE(patrik): HTIF is only looked at when the guest writes
+        // tohost and returning from the entry point stops the core
+        if let Some(htif) = htif.as_ref() {
+            core.mmu.add_watchpoint(htif.tohost(), 8, WatchKind::Write);
+        }
+        core.add_breakpoint(crate::RETURN_ADDRESS);
+
+        Self {
+            core,
+            htif,
+            ecall_handler: None,
+            semihosting: None,
+        }
+    }
+
+    /// Handle ECALLs with `handler` instead of stopping with
+    /// `CoreExit::Ecall`, only ECALLs that don't trap into the guest reach
+    /// it
+    pub fn set_ecall_handler(&mut self,
+                             handler: Option<Box<dyn EcallHandler>>)
+    {
+        self.ecall_handler = handler;
+    }
+
+    /// Service semihosting calls from the guest, without it the EBREAKs
+    /// of a call are plain EBREAKs
+    pub fn set_semihosting(&mut self, semihosting: Option<Semihosting>) {
+        self.core.set_semihosting(semihosting.is_some());
+        self.semihosting = semihosting;
+    }
+
+    /// Snapshots hold the core, memory and devices only, fails if an
+    /// ECALL handler or semihosting keeps state of its own
+    pub fn check_snapshot(&self) -> Result<(), String> {
+        let handler = self.ecall_handler.as_ref()
+            .is_some_and(|handler| handler.has_state());
+        if handler || self.semihosting.is_some() {
+            return Err("Snapshots don't hold the state of the Linux, pk \
+                        or semihosting handlers".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn is_tohost(&self, access: &MemoryAccess) -> bool {
+        match self.htif.as_ref() {
+            Some(htif) => {
+                access.kind != AccessKind::Execute &&
+                    access.addr < htif.tohost() + 8 &&
+                    htif.tohost() < access.addr + access.size as u64
+            },
+
+            None => false,
+        }
+    }
+
+    /// Run the core at most `limit` steps, see `Core::run`. The program
+    /// being done by asking for a shutdown through a device, HTIF, the
+    /// ECALL handler or semihosting or by returning from the entry point
+    /// stops with `CoreExit::Shutdown`
+    pub fn run(&mut self, limit: u64) -> RunResult {
+        let mut total = RunResult {
+            reason: StopReason::Limit,
+            steps: 0,
+            retired: 0,
+            last_pc: self.core.reg(Register::Pc),
+        };
+
+        loop {
+            let result = self.core.run(limit - total.steps);
+            total.reason = result.reason;
+            total.steps += result.steps;
+            total.retired += result.retired;
+            total.last_pc = result.last_pc;
+
+            if self.core.reg(Register::Pc) == crate::RETURN_ADDRESS {
+                total.reason = StopReason::Exit(
+                    CoreExit::Shutdown(Shutdown::Pass));
+                break;
+            }
+
+            let shutdown = match result.reason {
+                StopReason::Exit(CoreExit::Watchpoint(access))
+                    if self.is_tohost(&access) =>
+                {
+                    let htif = self.htif.as_mut()
+                        .expect("Machine: tohost written without HTIF");
+                    htif.poll(&mut self.core.mmu)
+                },
+
+                StopReason::Exit(CoreExit::Ecall) => {
+                    match self.ecall_handler.as_mut() {
+                        Some(handler) => handler.ecall(&mut self.core),
+                        None => break,
+                    }
+                },
+
+                StopReason::Exit(CoreExit::Semihosting) => {
+                    match self.semihosting.as_mut() {
+                        Some(semihosting) => semihosting.call(&mut self.core),
+                        None => break,
+                    }
+                },
+
+                _ => break,
+            };
+
+            if let Some(shutdown) = shutdown {
+                total.reason = StopReason::Exit(CoreExit::Shutdown(shutdown));
+                break;
+            }
+
+            // NOTE(patrik): The write to tohost and handled calls are not
+            // stops the caller wants to see so carry on, unless it was the
+            // last step
+            total.reason = StopReason::Limit;
+            if total.steps >= limit {
+                break;
+            }
+
+            let pc = self.core.reg(Register::Pc);
+            if self.core.is_breakpoint(pc) {
+                total.reason = StopReason::Breakpoint(pc);
+                break;
+            }
+        }
+
+        total
+    }
+
+    /// Take a snapshot to go back to with `reset_to`, from now on RAM
+    /// pages are tracked so resetting only copies the ones written
+    pub fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            core: self.core.snapshot(),
+            memory: self.core.mmu.snapshot(),
+        }
+    }
+
+    /// Put the machine back in the state of `snapshot`, fast when it's the
+    /// last snapshot taken or reset to
+    pub fn reset_to(&mut self, snapshot: &Snapshot) {
+        self.core.reset_to(&snapshot.core);
+        self.core.mmu.reset_to(&snapshot.memory);
+    }
+}
@@ -4,20 +4,105 @@
 // TODO(patrik):
 //   - For rust to compile for RV64 we need to have the GC extentions
 //     implement: G = I M A F D Zicsr Zifencei
-//                    x x x     xxxxx
+//                    x x x x x xxxxx xxxxxxxx
 //   - Implement the M extentions (done)
-//   - Implement the A extentions (almost done)
-//   - Implement the F extentions
-//   - Implement the D extentions
-//   - Implement the Zifencei extentions
+//   - Implement the A extentions (done)
+//   - Implement the F extentions (done)
+//   - Implement the D extentions (done)
+//   - Implement the Zifencei extentions (done)
 
 mod instruction;
 mod mmu;
 mod cpu;
+mod csr;
+mod bench;
+mod device;
+mod clint;
+mod plic;
+mod uart;
+mod syscon;
+mod elf;
+mod htif;
+mod conformance;
+mod gdb;
+mod monitor;
+mod disasm;
+mod snapshot;
+mod machine;
+mod fuzz;
+mod linux;
+mod semihosting;
+mod sbi;
+mod fdt;
+mod float;
+mod paging;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_rng;
+mod rng;
+
+use std::fs::File;
+use std::io::{ IsTerminal, Write };
+use std::sync::mpsc::Receiver;
 
 use mmu::Mmu;
-use cpu::{ Core, Register };
+use cpu::{ Core, CoreExit, Register, MisalignedPolicy, StopReason };
 use cpu::{ CoreState, CoreStateFunctions, PrivilegeLevel };
+use clint::{ Clint, TimerSource, CLINT_BASE, CLINT_SIZE };
+use plic::{ Plic, PLIC_BASE, PLIC_SIZE };
+use uart::{ Uart, RawTerminal, UART_BASE, UART_SIZE, UART_IRQ };
+use syscon::{ Syscon, SYSCON_BASE, SYSCON_SIZE };
+use device::Shutdown;
+use elf::Elf;
+use htif::Htif;
+use gdb::GdbServer;
+use machine::Machine;
+use monitor::Monitor;
+use linux::{ FileAccess, Linux };
+use semihosting::Semihosting;
+use sbi::Sbi;
+use machine::EcallHandler;
+use fdt::MachineDescription;
+use virtio::{ VirtioMmio, VirtioDevice, VIRTIO_MMIO_SIZE, VIRTIO_MMIO_IRQ };
+use virtio::VIRTIO_MMIO_SLOTS;
+use virtio_blk::{ VirtioBlk, DiskMode };
+use virtio_console::{ VirtioConsole, PortBackend };
+use virtio_rng::VirtioRng;
+
+// NOTE(patrik): JALR clears the lowest bit of the target so the magic
+// return address needs to be even
+const RETURN_ADDRESS: u64 = 0xffff1336;
+
+/// Where RAM starts on most RISC-V boards, programs linked at or above it
+/// get RAM there by default
+const DRAM_BASE: u64 = 0x8000_0000;
+const DRAM_DEFAULT_SIZE: u64 = 128 * 1024 * 1024;
+/// RAM of the `virt` machine when none is given, enough for a kernel and a
+/// small initramfs
+const VIRT_DEFAULT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Offset from the start of RAM of a kernel Image without a valid header,
+/// it is also where OpenSBI fw_jump jumps to by default
+const KERNEL_DEFAULT_OFFSET: u64 = 0x20_0000;
+/// Offset of `text_offset` and the second magic in the header of an Image
+const KERNEL_TEXT_OFFSET: usize = 8;
+const KERNEL_MAGIC_OFFSET: usize = 0x38;
+const KERNEL_MAGIC: &[u8; 4] = b"RSC\x05";
+/// Largest distance between the kernel and the initramfs, QEMU puts it
+/// halfway into RAM but at most this far from the kernel
+const INITRD_MAX_DISTANCE: u64 = 512 * 1024 * 1024;
+
+/// Exit code used when the guest asks for a reboot, there is nothing to
+/// reboot into so the emulator stops and lets the caller decide
+const REBOOT_EXIT_CODE: i32 = 128;
+
+/// Number of interrupt sources of the PLIC including the reserved source 0
+const PLIC_SOURCES: usize = 96;
+
+/// mtime frequency told to the guest when it counts instructions, there is
+/// no real frequency so the core is assumed to run 10 million a second
+const INSTRUCTIONS_PER_SECOND: u64 = 10_000_000;
 
 fn load_binary_program(mmu: &mut Mmu) {
     use std::fs::File;
@@ -46,22 +131,245 @@ fn custom_read_csr(core_state: &CoreState, csr: u16) -> u64 {
 fn custom_write_privilege_level(core_state: &mut CoreState,
                                 privilege_level: PrivilegeLevel)
 {
+    core_state.privilege_level = privilege_level;
 }
 
 fn custom_read_privilege_level(core_state: &CoreState) -> PrivilegeLevel {
     core_state.privilege_level
 }
 
-fn main() {
-    let mut mmu = Mmu::new(1 * 1024 * 1024);
-    load_binary_program(&mut mmu);
+fn parse_misaligned_policy(value: &str) -> MisalignedPolicy {
+    match value {
+        "allow" => MisalignedPolicy::Allow,
+        "trap" => MisalignedPolicy::Trap,
+        "trap-page-cross" => MisalignedPolicy::TrapPageCross,
+
+        _ => panic!("Unknown misaligned policy: {}", value),
+    }
+}
+
+/// Parse a number written in decimal or in hex with a 0x prefix
+fn parse_u64(value: &str) -> u64 {
+    let result = if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        value.parse()
+    };
+
+    result.unwrap_or_else(|_| panic!("Failed to parse number: {}", value))
+}
+
+/// Parse a memory region written as `base,size`
+fn parse_memory_region(value: &str) -> (u64, u64) {
+    let mut parts = value.split(',');
+    let base = parts.next()
+        .unwrap_or_else(|| panic!("Memory region is missing base: {}", value));
+    let size = parts.next()
+        .unwrap_or_else(|| panic!("Memory region is missing size: {}", value));
+
+    (parse_u64(base), parse_u64(size))
+}
+
+/// Parse a disk written as `FILE` or `FILE,MODE` with the mode `ro`, `rw`
+/// or `cow`
+fn parse_drive(value: &str) -> (String, DiskMode) {
+    let (path, mode) = match value.rsplit_once(',') {
+        Some((path, "ro")) => (path, DiskMode::ReadOnly),
+        Some((path, "rw")) => (path, DiskMode::ReadWrite),
+        Some((path, "cow")) => (path, DiskMode::CopyOnWrite),
+        Some((_, mode)) => panic!("Unknown disk mode: {}", mode),
+        None => (value, DiskMode::ReadWrite),
+    };
+
+    (path.to_string(), mode)
+}
+
+/// Create the port of the virtio console written as `BACKEND` or
+/// `BACKEND,NAME` where the backend is `stdio`, `file:PATH` or
+/// `unix:PATH`. A stdio port takes `stdin`
+fn create_console_port(value: &str, index: usize,
+                       stdin: &mut Option<Receiver<u8>>)
+    -> (String, PortBackend)
+{
+    let (backend, name) = match value.split_once(',') {
+        Some((backend, name)) => (backend, name.to_string()),
+        None => (value, format!("port{}", index)),
+    };
+
+    let backend = if backend == "stdio" {
+        PortBackend::Stdio(stdin.take())
+    } else if let Some(path) = backend.strip_prefix("file:") {
+        let file = File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create {}", path));
+        PortBackend::File(file)
+    } else if let Some(path) = backend.strip_prefix("unix:") {
+        PortBackend::unix_socket(path)
+    } else {
+        panic!("Unknown console port: {}", value);
+    };
+
+    (name, backend)
+}
+
+/// Parse a timer source written as `instructions:N` or `wall:FREQUENCY`
+fn parse_timer_source(value: &str) -> TimerSource {
+    let mut parts = value.split(':');
+    let kind = parts.next().unwrap_or("");
+    let argument = parts.next()
+        .unwrap_or_else(|| panic!("Timer source is missing a value: {}",
+                                  value));
+
+    match kind {
+        "instructions" => TimerSource::Instructions(parse_u64(argument)),
+        "wall" => TimerSource::WallClock(parse_u64(argument)),
+
+        _ => panic!("Unknown timer source: {}", value),
+    }
+}
+
+/// Read the whole file at `path`
+fn read_file(path: &str) -> Vec<u8> {
+    std::fs::read(path)
+        .unwrap_or_else(|_| panic!("Failed to read {}", path))
+}
+
+/// Copy `data` into RAM at `addr`
+fn load_raw(mmu: &mut Mmu, addr: u64, data: &[u8], path: &str) {
+    if !mmu.poke_bytes(addr, data) {
+        panic!("{} doesn't fit in RAM at {:#x}", path, addr);
+    }
+}
+
+/// Where a kernel Image wants to be relative to the start of RAM, read
+/// from its header
+fn kernel_offset(image: &[u8]) -> u64 {
+    let magic = image.get(KERNEL_MAGIC_OFFSET..KERNEL_MAGIC_OFFSET + 4);
+    if magic != Some(KERNEL_MAGIC.as_slice()) {
+        return KERNEL_DEFAULT_OFFSET;
+    }
+
+    let mut text_offset = [0; 8];
+    text_offset.copy_from_slice(
+        &image[KERNEL_TEXT_OFFSET..KERNEL_TEXT_OFFSET + 8]);
+    u64::from_le_bytes(text_offset)
+}
+
+/// Load the firmware, kernel and initramfs of the `virt` machine into
+/// `ram`, returns the entry of the firmware and where the initramfs is
+fn load_virt_images(mmu: &mut Mmu, ram: (u64, u64), firmware: &str,
+                    kernel: Option<&str>, initrd: Option<&str>)
+    -> (u64, Option<(u64, u64)>)
+{
+    let (ram_base, ram_size) = ram;
+
+    // NOTE(patrik): OpenSBI builds both fw_jump.elf and fw_jump.bin, the
+    // binary goes at the start of RAM where it is linked
+    let data = read_file(firmware);
+    let entry = if Elf::is_elf(&data) {
+        let elf = Elf::parse(&data);
+        elf.load(mmu);
+        elf.entry
+    } else {
+        load_raw(mmu, ram_base, &data, firmware);
+        ram_base
+    };
+
+    let mut kernel_address = ram_base + KERNEL_DEFAULT_OFFSET;
+    if let Some(path) = kernel {
+        let data = read_file(path);
+        if Elf::is_elf(&data) {
+            panic!("{} is an ELF file, the kernel has to be an Image",
+                   path);
+        }
+
+        kernel_address = ram_base + kernel_offset(&data);
+        load_raw(mmu, kernel_address, &data, path);
+    }
+
+    let initrd = initrd.map(|path| {
+        let data = read_file(path);
+        let start = kernel_address +
+            (ram_size / 2).min(INITRD_MAX_DISTANCE);
+        let start = start & !(mmu::PAGE_SIZE - 1);
+        load_raw(mmu, start, &data, path);
+
+        (start, start + data.len() as u64)
+    });
+
+    (entry, initrd)
+}
+
+/// Process exit code for a shutdown requested by the guest
+fn shutdown_exit_code(shutdown: Shutdown) -> i32 {
+    match shutdown {
+        Shutdown::Pass => 0,
+        // NOTE(patrik): A failure always has to give a non-zero exit code
+        // even if the guest reported code 0
+        Shutdown::Fail(code) => (code as i32).max(1),
+        Shutdown::Reboot => REBOOT_EXIT_CODE,
+    }
+}
+
+/// RAM region used when none is given on the command line
+fn default_memory(elf: Option<&Elf>) -> (u64, u64) {
+    let base = elf.and_then(|elf| elf.base()).unwrap_or(0);
+    if base >= DRAM_BASE {
+        (DRAM_BASE, DRAM_DEFAULT_SIZE)
+    } else {
+        (0, 1024 * 1024)
+    }
+}
+
+/// Frequency of mtime the guest is told about in the device tree
+fn timebase_frequency(timer_source: TimerSource) -> u64 {
+    match timer_source {
+        TimerSource::Instructions(divider) => {
+            INSTRUCTIONS_PER_SECOND / divider.max(1)
+        },
+        TimerSource::WallClock(frequency) => frequency,
+    }
+}
+
+/// Create the memory bus with RAM at `memory_map` and all the devices of
+/// the machine mapped. With `virtio` all the virtio-mmio slots are mapped
+/// with the devices in the first ones
+fn create_mmu(memory_map: &[(u64, u64)], timer_source: TimerSource,
+              uart: Uart, virtio: Option<Vec<Box<dyn VirtioDevice>>>) -> Mmu
+{
+    let mut mmu = Mmu::new();
+    for (base, size) in memory_map.iter() {
+        mmu.add_ram(*base, *size);
+    }
+
+    let clint = Clint::new(1, timer_source);
+    mmu.map_device(CLINT_BASE, CLINT_SIZE, Box::new(clint));
+
+    let plic = Plic::new(1, PLIC_SOURCES);
+    mmu.map_device(PLIC_BASE, PLIC_SIZE, Box::new(plic));
 
-    let entry = std::fs::read_to_string("test/rust-test.entry")
-        .expect("Failed to find a entry file");
-    let entry = entry.trim_end_matches('\n');
-    let entry = u64::from_str_radix(&entry[2..], 16)
-        .expect("Failed to parse entry to int");
+    mmu.map_device(UART_BASE, UART_SIZE, Box::new(uart));
+
+    mmu.map_device(SYSCON_BASE, SYSCON_SIZE, Box::new(Syscon::new()));
+
+    if let Some(devices) = virtio {
+        if devices.len() > VIRTIO_MMIO_SLOTS {
+            panic!("Only {} virtio devices fit on the bus",
+                   VIRTIO_MMIO_SLOTS);
+        }
+
+        let mut devices = devices.into_iter();
+        for slot in 0..VIRTIO_MMIO_SLOTS {
+            let transport = VirtioMmio::new(VIRTIO_MMIO_IRQ + slot,
+                                            devices.next());
+            mmu.map_device(virtio::slot_base(slot), VIRTIO_MMIO_SIZE,
+                           Box::new(transport));
+        }
+    }
+
+    mmu
+}
 
+fn create_core(mmu: Mmu) -> Core {
     let core_state_funcs = CoreStateFunctions {
         write_csr: custom_write_csr,
         read_csr: custom_read_csr,
@@ -72,37 +380,511 @@ fn main() {
 
     let core_state = CoreState::new(core_state_funcs);
 
-    let mut core = Core::new(core_state, mmu);
+    Core::new(core_state, mmu)
+}
+
+fn main() {
+    let mut misaligned_policy = MisalignedPolicy::Allow;
+    let mut ialign = 16;
+    let mut trace = false;
+    let mut trace_memory = false;
+    let mut bench = None;
+    let mut memory_map = Vec::new();
+    let mut timer_source = TimerSource::Instructions(1);
+    let mut uart_output = None;
+    let mut program = None;
+    let mut gdb = None;
+    let mut monitor = false;
+    let mut restore = None;
+    let mut linux = false;
+    let mut pk = false;
+    let mut sandbox = None;
+    let mut semihosting = false;
+    let mut sbi = false;
+    let mut dtb = false;
+    let mut dump_dtb = None;
+    let mut bootargs = None;
+    let mut virt = false;
+    let mut firmware = None;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut drives = Vec::new();
+    let mut console_ports = Vec::new();
+    let mut rng_seed = None;
+    let mut guest_args = Vec::new();
+
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("test") => {
+            args.next();
+            std::process::exit(conformance::run(args));
+        },
+
+        Some("fuzz") => {
+            args.next();
+            std::process::exit(fuzz::run(args));
+        },
+
+        _ => {},
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--misaligned" => {
+                let value = args.next()
+                    .expect("--misaligned needs a policy");
+                misaligned_policy = parse_misaligned_policy(&value);
+            },
+
+            "--ialign" => {
+                let value = args.next()
+                    .expect("--ialign needs a value");
+                ialign = value.parse()
+                    .expect("Failed to parse IALIGN");
+            },
+
+            "--trace" => trace = true,
+            "--trace-memory" => trace_memory = true,
+
+            "--timer" => {
+                let value = args.next()
+                    .expect("--timer needs a source");
+                timer_source = parse_timer_source(&value);
+            },
+
+            "--uart-output" => {
+                let value = args.next()
+                    .expect("--uart-output needs a file");
+                uart_output = Some(value);
+            },
+
+            "--gdb" => {
+                let value = args.next()
+                    .expect("--gdb needs an address");
+                gdb = Some(value);
+            },
+
+            "--monitor" => monitor = true,
+            "--linux" => linux = true,
+
+            "--pk" => {
+                linux = true;
+                pk = true;
+            },
+
+            "--semihosting" => semihosting = true,
+            "--sbi" => sbi = true,
+            "--dtb" => dtb = true,
+
+            "--dump-dtb" => {
+                let value = args.next()
+                    .expect("--dump-dtb needs a file");
+                dump_dtb = Some(value);
+            },
+
+            "--bootargs" => {
+                let value = args.next()
+                    .expect("--bootargs needs a command line");
+                bootargs = Some(value);
+            },
+
+            "--machine" => {
+                let value = args.next()
+                    .expect("--machine needs a name");
+                virt = match value.as_str() {
+                    "virt" => true,
+                    _ => panic!("Unknown machine: {}", value),
+                };
+            },
+
+            "--firmware" => {
+                let value = args.next()
+                    .expect("--firmware needs a file");
+                firmware = Some(value);
+            },
+
+            "--kernel" => {
+                let value = args.next()
+                    .expect("--kernel needs a file");
+                kernel = Some(value);
+            },
+
+            "--initrd" => {
+                let value = args.next()
+                    .expect("--initrd needs a file");
+                initrd = Some(value);
+            },
+
+            "--drive" => {
+                let value = args.next()
+                    .expect("--drive needs a disk image");
+                drives.push(parse_drive(&value));
+            },
+
+            "--console-port" => {
+                let value = args.next()
+                    .expect("--console-port needs a backend");
+                console_ports.push(value);
+            },
+
+            "--rng" => {
+                let value = args.next()
+                    .expect("--rng needs a seed");
+                rng_seed = Some(parse_u64(&value));
+            },
+
+            "--sandbox" => {
+                let value = args.next()
+                    .expect("--sandbox needs a directory");
+                let root = std::fs::canonicalize(&value)
+                    .ok()
+                    .filter(|root| root.is_dir())
+                    .unwrap_or_else(|| {
+                        panic!("Sandbox {} is not a directory", value)
+                    });
+                sandbox = Some(root);
+            },
+
+            "--restore" => {
+                let value = args.next()
+                    .expect("--restore needs a snapshot");
+                restore = Some(value);
+            },
+
+            "--memory" => {
+                let value = args.next()
+                    .expect("--memory needs a region");
+                memory_map.push(parse_memory_region(&value));
+            },
+
+            "--bench" => {
+                let value = args.next()
+                    .expect("--bench needs an instruction count");
+                let instructions: u64 = value.parse()
+                    .expect("Failed to parse instruction count");
+                bench = Some(instructions);
+            },
+
+            _ if !arg.starts_with("--") && program.is_none() => {
+                // NOTE(patrik): Everything after a Linux program or one
+                // using semihosting is passed on to it
+                if linux || semihosting {
+                    guest_args.push(arg.clone());
+                    guest_args.extend(args.by_ref());
+                }
+
+                program = Some(arg);
+            },
+
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    let elf = program.map(|path| {
+        let data = std::fs::read(&path)
+            .unwrap_or_else(|_| panic!("Failed to read {}", path));
+        if !Elf::is_elf(&data) {
+            panic!("{} is not an ELF file", path);
+        }
+
+        Elf::parse(&data)
+    });
+
+    if linux && elf.is_none() {
+        panic!("--linux and --pk need an ELF program");
+    }
+
+    if sbi && (elf.is_none() || linux) {
+        panic!("--sbi needs an ELF kernel and can't be used with --linux");
+    }
+
+    if dtb && linux {
+        panic!("--dtb can't be used with --linux");
+    }
+
+    if sandbox.is_some() && !linux {
+        panic!("--sandbox needs --linux or --pk");
+    }
+
+    if virt && (firmware.is_none() || elf.is_some() || linux || sbi) {
+        panic!("--machine virt needs --firmware and no program");
+    }
+
+    let virtio_options = !drives.is_empty() || !console_ports.is_empty() ||
+        rng_seed.is_some();
+    if !virt && (firmware.is_some() || kernel.is_some() ||
+                 initrd.is_some() || virtio_options)
+    {
+        panic!("--firmware, --kernel, --initrd and the virtio devices need \
+                --machine virt");
+    }
+
+    let stdio_port = console_ports.iter()
+        .any(|port| port.split(',').next() == Some("stdio"));
+
+    if memory_map.is_empty() {
+        if linux {
+            memory_map.push(linux::DEFAULT_MEMORY);
+        } else if virt {
+            memory_map.push((DRAM_BASE, VIRT_DEFAULT_SIZE));
+        } else {
+            memory_map.push(default_memory(elf.as_ref()));
+        }
+    }
+
+    // NOTE(patrik): Only take over the terminal when the console is
+    // actually connected to it, the monitor needs stdin for itself and a
+    // Linux program or semihosting reads stdin directly. A virtio console
+    // port on stdio takes the input from the UART
+    let console = (uart_output.is_none() || stdio_port) && !monitor &&
+        !linux && !semihosting;
+    let raw_terminal = if console && std::io::stdin().is_terminal() {
+        Some(RawTerminal::enable())
+    } else {
+        None
+    };
+
+    let mut stdin_input = if console {
+        let saved_terminal = raw_terminal.as_ref()
+            .and_then(|terminal| terminal.saved());
+        Some(uart::stdin_input(saved_terminal))
+    } else {
+        None
+    };
+    let uart_input = if stdio_port { None } else { stdin_input.take() };
+
+    let uart_output: Box<dyn Write> = match uart_output {
+        Some(path) => {
+            let file = File::create(&path)
+                .unwrap_or_else(|_| panic!("Failed to create {}", path));
+            Box::new(file)
+        },
+
+        None => Box::new(std::io::stdout()),
+    };
+
+    let uart = Uart::new(UART_IRQ, uart_input, uart_output);
+    let virtio_slots = if virt { VIRTIO_MMIO_SLOTS } else { 0 };
+    let virtio = if virt {
+        let mut devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+        for (path, mode) in drives.iter() {
+            devices.push(Box::new(VirtioBlk::open(path, *mode)));
+        }
+
+        if !console_ports.is_empty() {
+            let ports = console_ports.iter()
+                .enumerate()
+                .map(|(index, port)| {
+                    create_console_port(port, index, &mut stdin_input)
+                })
+                .collect();
+            devices.push(Box::new(VirtioConsole::new(ports)));
+        }
+
+        if let Some(seed) = rng_seed {
+            devices.push(Box::new(VirtioRng::new(seed)));
+        }
+
+        Some(devices)
+    } else {
+        None
+    };
+    let mut mmu = create_mmu(&memory_map, timer_source, uart, virtio);
+
+    if let Some(instructions) = bench {
+        let mut core = create_core(mmu);
+        core.set_trace(trace);
+        bench::run(&mut core, instructions);
+        return;
+    }
+
+    let mut htif = None;
+    let mut initrd_range = None;
+    let entry = if let Some(firmware) = firmware.as_ref() {
+        let (entry, range) = load_virt_images(&mut mmu, memory_map[0],
+                                              firmware, kernel.as_deref(),
+                                              initrd.as_deref());
+        initrd_range = range;
+        entry
+    } else if let Some(elf) = elf.as_ref() {
+        elf.load(&mut mmu);
+
+        if let Some(tohost) = elf.symbol("tohost") {
+            let fromhost = elf.symbol("fromhost");
+            htif = Some(Htif::new(tohost, fromhost,
+                                  Box::new(std::io::stdout())));
+        }
+
+        elf.entry
+    } else {
+        load_binary_program(&mut mmu);
+
+        let entry = std::fs::read_to_string("test/rust-test.entry")
+            .expect("Failed to find a entry file");
+        let entry = entry.trim_end_matches('\n');
+        u64::from_str_radix(&entry[2..], 16)
+            .expect("Failed to parse entry to int")
+    };
+
+    if trace_memory {
+        mmu.set_access_hook(Some(Box::new(|access| {
+            println!("Memory: {:x?}", access);
+        })));
+    }
+
+    let mut core = create_core(mmu);
+    core.set_trace(trace);
+    // NOTE(patrik): ELF programs and firmware are full guests with their
+    // own trap handlers
+    core.set_environment_traps(elf.is_some() || virt);
+    core.set_misaligned_policy(misaligned_policy);
+    core.set_ialign(ialign);
     core.set_reg(Register::Pc, entry);
-    core.set_reg(Register::Ra, 0xffff1337);
-    core.set_reg(Register::Sp, 1 * 1024 * 1024);
+    core.set_reg(Register::Ra, RETURN_ADDRESS);
+    let (ram_base, ram_size) = memory_map[0];
+    core.set_reg(Register::Sp, ram_base + ram_size);
 
-    core.set_reg(Register::A0, 123);
+    // NOTE(patrik): Firmware gets the hart id in a0 like on real boards
+    core.set_reg(Register::A0, if virt { 0 } else { 123 });
     // core.set_reg(Register::A1, 321);
 
+    let load_dtb = dtb || sbi || virt;
+    let mut dtb_address = 0;
+    if load_dtb || dump_dtb.is_some() {
+        let description = MachineDescription {
+            harts: 1,
+            isa: cpu::ISA.to_string(),
+            memory: memory_map.clone(),
+            timebase_frequency: timebase_frequency(timer_source),
+            plic_sources: PLIC_SOURCES,
+            bootargs,
+            initrd: initrd_range,
+            virtio_slots,
+        };
+        let blob = fdt::generate(&description);
+
+        if let Some(path) = dump_dtb {
+            std::fs::write(&path, &blob)
+                .unwrap_or_else(|_| panic!("Failed to write {}", path));
+        }
+
+        // NOTE(patrik): The device tree goes at the end of RAM with the
+        // stack right below it
+        if load_dtb {
+            dtb_address = (ram_base + ram_size)
+                .checked_sub(blob.len() as u64)
+                .map(|address| address & !(mmu::PAGE_SIZE - 1))
+                .filter(|address| *address >= ram_base)
+                .expect("Not enough RAM for the device tree");
+            if initrd_range.is_some_and(|(_, end)| end > dtb_address) {
+                panic!("Not enough RAM for the initramfs and the device \
+                        tree");
+            }
+            if !core.mmu.poke_bytes(dtb_address, &blob) {
+                panic!("Failed to write the device tree to RAM");
+            }
+
+            core.set_reg(Register::Sp, dtb_address);
+            core.set_reg(Register::A1, dtb_address);
+        }
+    }
+
     core.write_csr(0xfff, 0b111);
 
-    loop {
-        let res = core.step();
-        println!("Exit: {:#?}", res);
+    let is_linux = linux;
+    let ecall_handler: Option<Box<dyn EcallHandler>> = if linux {
+        let elf = elf.as_ref().unwrap();
 
-        // TODO(patrik):
-        // check_devices_for_interrupts();
-        // check_interrupts();
-        // send_interrupt_to_core();
+        // NOTE(patrik): riscv-pk gives the program no environment and
+        // without a sandbox it gets no files either
+        let environment: Vec<_> = if pk {
+            Vec::new()
+        } else {
+            std::env::vars_os().collect()
+        };
 
-        // core.trap(pc);
+        let mut linux = Linux::new(&mut core, elf, memory_map[0],
+                                   &guest_args, &environment);
+        linux.set_file_access(match sandbox {
+            Some(root) => FileAccess::Sandbox(root),
+            None if pk => FileAccess::Denied,
+            None => FileAccess::Host,
+        });
+
+        Some(Box::new(linux))
+    } else if sbi {
+        let entry = elf.as_ref().unwrap().entry;
+        Some(Box::new(Sbi::new(&mut core, entry, dtb_address)))
+    } else {
+        None
+    };
 
-        if core.reg(Register::Pc) == 0xffff1337 {
-            break;
+    let mut machine = Machine::new(core, htif);
+    machine.set_ecall_handler(ecall_handler);
+    if semihosting {
+        machine.set_semihosting(Some(Semihosting::new(&guest_args)));
+    }
+
+    if let Some(path) = restore {
+        if let Err(error) = machine.check_snapshot() {
+            panic!("{}", error);
         }
+        snapshot::restore_file(&mut machine.core, &path);
     }
 
-    println!("{:#x?}", core);
+    let mut exit_code = None;
+    if let Some(address) = gdb {
+        let stop = machine.core.stop_handle();
+        let mut server = GdbServer::listen(&address, stop);
+        exit_code = server.run(&mut machine);
+    } else if monitor {
+        let mut monitor = Monitor::new(elf.as_ref());
+        exit_code = Some(monitor.run(&mut machine));
+    }
+
+    // NOTE(patrik): Keep running if GDB detached before the program was
+    // done
+    let exit_code = match exit_code {
+        Some(exit_code) => exit_code,
+        None => loop {
+            let result = machine.run(u64::MAX);
+            match result.reason {
+                StopReason::Exit(CoreExit::Shutdown(shutdown)) => {
+                    break shutdown_exit_code(shutdown);
+                },
+
+                StopReason::Exit(CoreExit::Exception(exception))
+                    if is_linux =>
+                {
+                    eprintln!("Linux: Program killed by {:x?} at {:#x}",
+                              exception, result.last_pc);
+                    break linux::signal_exit_code(exception);
+                },
 
-    let value = core.read_csr(0xfff);
-    println!("CSR Reg: {:#b}", value);
+                reason => {
+                    if trace {
+                        println!("Exit: {:#?}", reason);
+                    }
+                },
+            }
+        },
+    };
+
+    // NOTE(patrik): The dump is only useful for checking the result of
+    // the test program
+    if elf.is_none() && !virt {
+        let core = &mut machine.core;
+        println!("{:#x?}", core);
+
+        let value = core.read_csr(0xfff);
+        println!("CSR Reg: {:#b}", value);
+
+        let value = core.mmu.read_u32(0x36c);
+        println!("Value: {:?}", value);
+    }
 
-    let value = core.mmu.read_u32(0x36c);
-    println!("Value: {}", value);
+    // NOTE(patrik): process::exit doesn't run destructors so the terminal
+    // has to be restored first
+    drop(raw_terminal);
+    std::process::exit(exit_code);
 }
@@ -1,65 +1,941 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::device::{ Device, Dma, Interrupts };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+/// Size of a page, used for page crossing checks and as the allocation
+/// granularity of guest memory
+pub const PAGE_SIZE: u64 = 4096;
+
+type Page = [u8; PAGE_SIZE as usize];
+
+/// Id of the next in-memory snapshot, unique across all MMUs
+static NEXT_SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A region of guest physical memory, pages are allocated the first time
+/// they are written and unallocated pages read as zero
+struct Region {
+    base: u64,
+    size: u64,
+
+    pages: Vec<Option<Box<Page>>>,
+
+    /// Pages written since the last in-memory snapshot or reset, `dirty`
+    /// is indexed by page and `dirty_pages` lists the same pages
+    dirty: Vec<bool>,
+    dirty_pages: Vec<usize>,
+}
+
+impl Region {
+    fn new(base: u64, size: u64) -> Self {
+        let count = (size / PAGE_SIZE) as usize;
+
+        let mut pages = Vec::with_capacity(count);
+        pages.resize_with(count, || None);
+
+        Self {
+            base,
+            size,
+
+            pages,
+
+            dirty: vec![false; count],
+            dirty_pages: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    fn contains(&self, addr: u64) -> bool {
+        addr.wrapping_sub(self.base) < self.size
+    }
+
+    #[inline(always)]
+    fn page_index(&self, addr: u64) -> usize {
+        ((addr - self.base) / PAGE_SIZE) as usize
+    }
+
+    #[inline(always)]
+    fn page(&self, addr: u64) -> Option<&Page> {
+        self.pages[self.page_index(addr)].as_deref()
+    }
+
+    #[inline(always)]
+    fn page_mut(&mut self, addr: u64) -> &mut Page {
+        let index = self.page_index(addr);
+        if !self.dirty[index] {
+            self.dirty[index] = true;
+            self.dirty_pages.push(index);
+        }
+
+        self.pages[index]
+            .get_or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))
+    }
+
+    fn clear_dirty(&mut self) {
+        for index in self.dirty_pages.drain(..) {
+            self.dirty[index] = false;
+        }
+    }
+
+    /// Put page `index` back to the contents in `saved`
+    fn restore_page(&mut self, index: usize, saved: &Option<Box<Page>>) {
+        match (self.pages[index].as_mut(), saved) {
+            (Some(page), Some(saved)) => page.copy_from_slice(&saved[..]),
+            (None, Some(saved)) => self.pages[index] = Some(saved.clone()),
+            (_, None) => self.pages[index] = None,
+        }
+    }
+}
+
+/// Copy of RAM and the state of the devices kept in memory, restoring it
+/// with `Mmu::reset_to` only copies back the pages written since
+pub struct MemorySnapshot {
+    id: u64,
+    regions: Vec<Vec<Option<Box<Page>>>>,
+    devices: Vec<u8>,
+}
+
+/// Kind of guest access a watchpoint triggers on
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes
+    Access,
+    /// Instruction fetches
+    Execute,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A guest memory access, `value` is the value read, written or the raw
+/// instruction fetched
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MemoryAccess {
+    pub addr: u64,
+    pub size: usize,
+    pub value: u64,
+    pub kind: AccessKind,
+}
+
+/// Callback that gets to see every guest memory access
+pub type AccessHook = Box<dyn FnMut(&MemoryAccess)>;
+
+struct Watchpoint {
+    base: u64,
+    size: u64,
+    kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u64, size: usize, kind: AccessKind) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => kind != AccessKind::Execute,
+            WatchKind::Execute => kind == AccessKind::Execute,
+        };
+
+        // NOTE(patrik): Execute watchpoints only trigger on instructions
+        // that start inside the range, not ones that overlap it
+        if kind == AccessKind::Execute {
+            return kind_matches && addr.wrapping_sub(self.base) < self.size;
+        }
+
+        kind_matches && addr < self.base.wrapping_add(self.size) &&
+            self.base < addr.wrapping_add(size as u64)
+    }
+}
+
+struct MappedDevice {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+    /// The device wants `Device::dma` called
+    dma: bool,
+}
+
+/// The RAM regions of the bus handed to devices doing DMA
+struct DmaView<'a> {
+    regions: &'a mut [Region],
+}
+
+impl Dma for DmaView<'_> {
+    fn read(&self, addr: u64, buffer: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buffer.len() {
+            let addr = addr.wrapping_add(done as u64);
+            let region = match self.regions.iter()
+                .find(|region| region.contains(addr))
+            {
+                Some(region) => region,
+                None => return false,
+            };
+
+            let offset = (addr % PAGE_SIZE) as usize;
+            let length = (PAGE_SIZE as usize - offset)
+                .min(buffer.len() - done);
+            let chunk = &mut buffer[done..done + length];
+            match region.page(addr) {
+                Some(page) => {
+                    chunk.copy_from_slice(&page[offset..offset + length]);
+                },
+
+                None => chunk.iter_mut().for_each(|value| *value = 0),
+            }
+
+            done += length;
+        }
+
+        true
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = addr.wrapping_add(done as u64);
+            let region = match self.regions.iter_mut()
+                .find(|region| region.contains(addr))
+            {
+                Some(region) => region,
+                None => return false,
+            };
+
+            let offset = (addr % PAGE_SIZE) as usize;
+            let length = (PAGE_SIZE as usize - offset)
+                .min(data.len() - done);
+            region.page_mut(addr)[offset..offset + length]
+                .copy_from_slice(&data[done..done + length]);
+
+            done += length;
+        }
+
+        true
+    }
+}
+
+/// The memory bus of the machine, made up of RAM regions at arbitrary
+/// page aligned base addresses, accesses that stay inside a page of RAM
+/// take the fast path and everything else goes through the slower MMIO
+/// path
 pub struct Mmu {
-    ram: Vec<u8>,
+    regions: Vec<Region>,
+
+    devices: Vec<MappedDevice>,
+    interrupts: Interrupts,
+
+    watchpoints: Vec<Watchpoint>,
+    /// First access that hit a watchpoint since the last time it was taken
+    watchpoint_hit: Option<MemoryAccess>,
+
+    access_hook: Option<AccessHook>,
+
+    /// In-memory snapshot the dirty pages are relative to
+    snapshot_id: Option<u64>,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mmu {
-    pub fn new(size: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            ram: vec![0; size],
+            regions: Vec::new(),
+
+            devices: Vec::new(),
+            interrupts: Interrupts::new(),
+
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+
+            access_hook: None,
+
+            snapshot_id: None,
+        }
+    }
+
+    /// Add a RAM region of `size` bytes at `base`, no memory is allocated
+    /// until the guest writes to it
+    pub fn add_ram(&mut self, base: u64, size: u64) {
+        if (base | size) & (PAGE_SIZE - 1) != 0 || size == 0 {
+            panic!("add_ram: region {:#x} size {:#x} is not page aligned",
+                   base, size);
+        }
+
+        let end = base.checked_add(size - 1)
+            .expect("add_ram: region wraps the address space");
+
+        for region in self.regions.iter() {
+            let region_end = region.base + (region.size - 1);
+            if base <= region_end && region.base <= end {
+                panic!("add_ram: region {:#x}-{:#x} overlaps {:#x}-{:#x}",
+                       base, end, region.base, region_end);
+            }
+        }
+
+        self.regions.push(Region::new(base, size));
+    }
+
+    /// Map `device` at `base`, accesses inside RAM never reach devices
+    pub fn map_device(&mut self, base: u64, size: u64,
+                      device: Box<dyn Device>)
+    {
+        let dma = device.uses_dma();
+        self.devices.push(MappedDevice { base, size, device, dma });
+    }
+
+    /// Advance all the devices by one instruction
+    pub fn tick(&mut self) {
+        for mapped in self.devices.iter_mut() {
+            mapped.device.tick(&mut self.interrupts);
+
+            if mapped.dma {
+                let mut memory = DmaView { regions: &mut self.regions };
+                mapped.device.dma(&mut memory, &mut self.interrupts);
+            }
+        }
+    }
+
+    pub fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    /// Save RAM, the interrupt state and the state of all the devices,
+    /// only the pages that have been allocated are saved
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u64(self.regions.len() as u64);
+        for region in self.regions.iter() {
+            writer.u64(region.base);
+            writer.u64(region.size);
+
+            let count = region.pages.iter().filter(|page| page.is_some())
+                .count();
+            writer.u64(count as u64);
+
+            for (index, page) in region.pages.iter().enumerate() {
+                if let Some(page) = page {
+                    writer.u64(index as u64);
+                    writer.bytes(&page[..]);
+                }
+            }
+        }
+
+        self.save_devices(writer);
+    }
+
+    fn save_devices(&self, writer: &mut SnapshotWriter) {
+        self.interrupts.save(writer);
+
+        writer.u64(self.devices.len() as u64);
+        for mapped in self.devices.iter() {
+            writer.u64(mapped.base);
+            writer.u64(mapped.size);
+            mapped.device.save(writer);
+        }
+    }
+
+    /// Read RAM saved by `save` into new pages without touching the
+    /// current ones, they're put in place with `set_ram`
+    pub fn load_ram(&self, reader: &mut SnapshotReader)
+        -> Result<Vec<Vec<Option<Box<Page>>>>, String>
+    {
+        reader.length(Some(self.regions.len()))?;
+
+        let mut regions = Vec::with_capacity(self.regions.len());
+        for region in self.regions.iter() {
+            let (base, size) = (reader.u64()?, reader.u64()?);
+            if base != region.base || size != region.size {
+                return Err(format!("Snapshot: RAM at {:#x} size {:#x} \
+                                    doesn't match {:#x} size {:#x}",
+                                   base, size, region.base, region.size));
+            }
+
+            let mut pages = vec![None; region.pages.len()];
+            let count = reader.length(None)?;
+            for _ in 0..count {
+                let index = reader.u64()? as usize;
+                let data = reader.bytes(PAGE_SIZE as usize)?;
+
+                let page = pages.get_mut(index)
+                    .ok_or_else(|| {
+                        format!("Snapshot: Bad page index: {}", index)
+                    })?;
+
+                let mut new_page = Box::new([0; PAGE_SIZE as usize]);
+                new_page.copy_from_slice(data);
+                *page = Some(new_page);
+            }
+
+            regions.push(pages);
+        }
+
+        Ok(regions)
+    }
+
+    /// Replace RAM with the pages read by `load_ram`
+    pub fn set_ram(&mut self, regions: Vec<Vec<Option<Box<Page>>>>) {
+        for (region, pages) in self.regions.iter_mut().zip(regions) {
+            region.pages = pages;
+            region.clear_dirty();
+        }
+
+        // NOTE(patrik): The pages no longer match any in-memory snapshot,
+        // the next reset has to copy all of them
+        self.snapshot_id = None;
+        self.watchpoint_hit = None;
+    }
+
+    /// Serialized state of the interrupts and the devices, put back with
+    /// `set_device_state`
+    pub fn device_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        self.save_devices(&mut writer);
+        writer.finish()
+    }
+
+    pub fn set_device_state(&mut self, state: &[u8]) {
+        // NOTE(patrik): The device state was saved by this machine, it
+        // can't fail to restore
+        let mut reader = SnapshotReader::new(state);
+        self.restore_devices(&mut reader)
+            .and_then(|_| reader.finish())
+            .expect("Bad device state");
+    }
+
+    /// Restore the interrupts and the devices saved by `save`, they can
+    /// be partly restored if it fails
+    pub fn restore_devices(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        self.interrupts.restore(reader)?;
+
+        reader.length(Some(self.devices.len()))?;
+        for mapped in self.devices.iter_mut() {
+            let (base, size) = (reader.u64()?, reader.u64()?);
+            if base != mapped.base || size != mapped.size {
+                return Err(format!("Snapshot: Device at {:#x} size {:#x} \
+                                    doesn't match {:#x} size {:#x}",
+                                   base, size, mapped.base, mapped.size));
+            }
+
+            mapped.device.restore(reader)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take an in-memory snapshot of RAM and the devices and start
+    /// tracking the pages written from now on
+    pub fn snapshot(&mut self) -> MemorySnapshot {
+        let id = NEXT_SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
+
+        let regions = self.regions.iter_mut()
+            .map(|region| {
+                region.clear_dirty();
+                region.pages.clone()
+            })
+            .collect();
+
+        let devices = self.device_state();
+        self.snapshot_id = Some(id);
+
+        MemorySnapshot {
+            id,
+            regions,
+            devices,
         }
     }
 
-    pub fn write_u8(&mut self, addr: u64, value: u8) {
-        self.ram[addr as usize] = value;
+    /// Go back to the state in `snapshot`, only the pages written since
+    /// the last snapshot or reset are copied if that was `snapshot`
+    pub fn reset_to(&mut self, snapshot: &MemorySnapshot) {
+        if snapshot.regions.len() != self.regions.len() {
+            panic!("reset_to: Snapshot is from a different memory map");
+        }
+
+        let only_dirty = self.snapshot_id == Some(snapshot.id);
+        for (region, saved) in self.regions.iter_mut()
+            .zip(snapshot.regions.iter())
+        {
+            if saved.len() != region.pages.len() {
+                panic!("reset_to: Snapshot is from a different memory map");
+            }
+
+            if only_dirty {
+                while let Some(index) = region.dirty_pages.pop() {
+                    region.dirty[index] = false;
+                    region.restore_page(index, &saved[index]);
+                }
+            } else {
+                for (index, page) in saved.iter().enumerate() {
+                    region.restore_page(index, page);
+                }
+                region.clear_dirty();
+            }
+        }
+
+        self.set_device_state(&snapshot.devices);
+
+        self.snapshot_id = Some(snapshot.id);
+        self.watchpoint_hit = None;
+    }
+
+    pub fn add_watchpoint(&mut self, base: u64, size: u64, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { base, size, kind });
+    }
+
+    /// Remove a watchpoint added with the same arguments, returns false if
+    /// there was no such watchpoint
+    pub fn remove_watchpoint(&mut self, base: u64, size: u64,
+                             kind: WatchKind) -> bool
+    {
+        let index = self.watchpoints.iter().position(|watchpoint| {
+            watchpoint.base == base && watchpoint.size == size &&
+                watchpoint.kind == kind
+        });
+
+        match index {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    pub fn take_watchpoint_hit(&mut self) -> Option<MemoryAccess> {
+        self.watchpoint_hit.take()
+    }
+
+    /// Set the callback called for every guest access, `None` removes it
+    pub fn set_access_hook(&mut self, hook: Option<AccessHook>) {
+        self.access_hook = hook;
+    }
+
+    /// Check a guest access against the watchpoints and pass it to the
+    /// access hook. Loads and stores are observed by the read and write
+    /// functions, instruction fetches are observed by the core
+    #[inline(always)]
+    pub fn observe(&mut self, addr: u64, size: usize, value: u64,
+                   kind: AccessKind)
+    {
+        if self.watchpoints.is_empty() && self.access_hook.is_none() {
+            return;
+        }
+
+        let access = MemoryAccess { addr, size, value, kind };
+
+        if let Some(hook) = self.access_hook.as_mut() {
+            hook(&access);
+        }
+
+        if self.watchpoint_hit.is_none() && self.watchpoints.iter()
+            .any(|watchpoint| watchpoint.matches(addr, size, kind))
+        {
+            self.watchpoint_hit = Some(access);
+        }
+    }
+
+    /// Read a byte of RAM without any side effects, `None` if `addr` is
+    /// not RAM. Used by debuggers that must not touch devices
+    pub fn peek_u8(&self, addr: u64) -> Option<u8> {
+        let region = self.region(addr)?;
+        let offset = (addr % PAGE_SIZE) as usize;
+
+        Some(region.page(addr).map(|page| page[offset]).unwrap_or(0))
+    }
+
+    /// Read 8 bytes of RAM without any side effects
+    pub fn peek_u64(&self, addr: u64) -> Option<u64> {
+        let mut bytes = [0; 8];
+        for (index, value) in bytes.iter_mut().enumerate() {
+            *value = self.peek_u8(addr.wrapping_add(index as u64))?;
+        }
+
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// Read RAM into `buffer` without any side effects, returns false if
+    /// any of it is not RAM
+    pub fn peek_bytes(&self, addr: u64, buffer: &mut [u8]) -> bool {
+        for (index, value) in buffer.iter_mut().enumerate() {
+            match self.peek_u8(addr.wrapping_add(index as u64)) {
+                Some(byte) => *value = byte,
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Write `bytes` to RAM without going through watchpoints, returns
+    /// false if any of it is not RAM
+    pub fn poke_bytes(&mut self, addr: u64, bytes: &[u8]) -> bool {
+        bytes.iter().enumerate().all(|(index, value)| {
+            self.poke_u8(addr.wrapping_add(index as u64), *value)
+        })
+    }
+
+    /// Write 8 bytes of RAM without going through watchpoints, returns
+    /// false if any of it is not RAM
+    pub fn poke_u64(&mut self, addr: u64, value: u64) -> bool {
+        value.to_le_bytes().iter().enumerate().all(|(index, value)| {
+            self.poke_u8(addr.wrapping_add(index as u64), *value)
+        })
+    }
+
+    /// Write a byte of RAM without going through watchpoints, returns
+    /// false if `addr` is not RAM
+    pub fn poke_u8(&mut self, addr: u64, value: u8) -> bool {
+        match self.region_mut(addr) {
+            Some(region) => {
+                let offset = (addr % PAGE_SIZE) as usize;
+                region.page_mut(addr)[offset] = value;
+
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    #[inline(always)]
+    fn region(&self, addr: u64) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(addr))
+    }
+
+    #[inline(always)]
+    fn region_mut(&mut self, addr: u64) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| region.contains(addr))
+    }
+
+    #[inline(always)]
+    fn crosses_page(addr: u64, size: usize) -> bool {
+        (addr % PAGE_SIZE) + size as u64 > PAGE_SIZE
+    }
+
+    /// Get the RAM backing `addr..addr + size` if it has been allocated,
+    /// used by the instruction fetch to get all the bytes of an
+    /// instruction with one lookup
+    #[inline(always)]
+    pub fn ram_slice(&self, addr: u64, size: usize) -> Option<&[u8]> {
+        if Self::crosses_page(addr, size) {
+            return None;
+        }
+
+        let page = self.region(addr)?.page(addr)?;
+        let offset = (addr % PAGE_SIZE) as usize;
+        Some(&page[offset..offset + size])
+    }
+
+    /// Read `buffer` from `addr`, returns false if some of it isn't mapped
+    #[inline(always)]
+    fn read(&mut self, addr: u64, buffer: &mut [u8]) -> bool {
+        let size = buffer.len();
+
+        if Self::crosses_page(addr, size) {
+            // NOTE(patrik): The access might cross into another region or
+            // into MMIO so do it one byte at the time
+            return buffer.iter_mut().enumerate().all(|(index, value)| {
+                let addr = addr.wrapping_add(index as u64);
+                self.read(addr, std::slice::from_mut(value))
+            });
+        }
+
+        if let Some(region) = self.region(addr) {
+            let offset = (addr % PAGE_SIZE) as usize;
+            match region.page(addr) {
+                Some(page) => {
+                    buffer.copy_from_slice(&page[offset..offset + size]);
+                },
+
+                None => buffer.iter_mut().for_each(|value| *value = 0),
+            }
+
+            return true;
+        }
+
+        match self.read_mmio(addr, size) {
+            Some(value) => {
+                buffer.copy_from_slice(&value.to_le_bytes()[..size]);
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    /// Write `buffer` to `addr`, returns false if some of it isn't mapped
+    #[inline(always)]
+    fn write(&mut self, addr: u64, buffer: &[u8]) -> bool {
+        let size = buffer.len();
+
+        if Self::crosses_page(addr, size) {
+            // NOTE(patrik): Check every byte first so a write running into
+            // unmapped memory doesn't change anything
+            let mapped = (0..size as u64).all(|index| {
+                self.is_mapped(addr.wrapping_add(index), 1)
+            });
+            if !mapped {
+                return false;
+            }
+
+            for (index, value) in buffer.iter().enumerate() {
+                self.write(addr.wrapping_add(index as u64), &[*value]);
+            }
+
+            return true;
+        }
+
+        if let Some(region) = self.region_mut(addr) {
+            let offset = (addr % PAGE_SIZE) as usize;
+            let page = region.page_mut(addr);
+            page[offset..offset + size].copy_from_slice(buffer);
+
+            return true;
+        }
+
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(buffer);
+        self.write_mmio(addr, size, u64::from_le_bytes(value))
+    }
+
+    /// Is `addr..addr + size` backed by RAM or a single device
+    pub fn is_mapped(&mut self, addr: u64, size: usize) -> bool {
+        self.region(addr).is_some() || self.device(addr, size).is_some()
+    }
+
+    fn device(&mut self, addr: u64, size: usize)
+        -> Option<(&mut dyn Device, u64)>
+    {
+        for mapped in self.devices.iter_mut() {
+            let offset = addr.wrapping_sub(mapped.base);
+            if offset < mapped.size && offset + size as u64 <= mapped.size {
+                return Some((mapped.device.as_mut(), offset));
+            }
+        }
+
+        None
+    }
+
+    fn read_mmio(&mut self, addr: u64, size: usize) -> Option<u64> {
+        let (device, offset) = self.device(addr, size)?;
+        Some(device.read(offset, size))
     }
 
-    pub fn read_u8(&self, addr: u64) -> u8{
-        self.ram[addr as usize]
+    fn write_mmio(&mut self, addr: u64, size: usize, value: u64) -> bool {
+        match self.device(addr, size) {
+            Some((device, offset)) => {
+                device.write(offset, size, value);
+                true
+            },
+
+            None => false,
+        }
     }
 
-    pub fn write_u16(&mut self, addr: u64, value: u16) {
-        self.write_u8(addr, (value & 0xff) as u8);
-        self.write_u8(addr + 1, ((value >> 8) & 0xff) as u8);
+    // NOTE(patrik): The accessors below return None or false when the
+    // address isn't mapped, the core turns that into an access fault
+
+    /// Read an instruction parcel, not observed as a load
+    pub fn fetch_u16(&mut self, addr: u64) -> Option<u16> {
+        let mut bytes = [0; 2];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        Some(u16::from_le_bytes(bytes))
     }
 
-    pub fn read_u16(&self, addr: u64) -> u16 {
-        self.read_u8(addr) as u16 | (self.read_u8(addr + 1) as u16) << 8
+    /// Read a full instruction, not observed as a load
+    pub fn fetch_u32(&mut self, addr: u64) -> Option<u32> {
+        let mut bytes = [0; 4];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        Some(u32::from_le_bytes(bytes))
     }
 
-    pub fn write_u32(&mut self, addr: u64, value: u32) {
-        self.write_u8(addr + 0, ((value >> 0) & 0xff) as u8);
-        self.write_u8(addr + 1, ((value >> 8) & 0xff) as u8);
-        self.write_u8(addr + 2, ((value >> 16) & 0xff) as u8);
-        self.write_u8(addr + 3, ((value >> 24) & 0xff) as u8);
+    pub fn write_u8(&mut self, addr: u64, value: u8) -> bool {
+        self.observe(addr, 1, value as u64, AccessKind::Write);
+        self.write(addr, &[value])
     }
 
-    pub fn read_u32(&self, addr: u64) -> u32 {
-        self.read_u8(addr) as u32 |
-            (self.read_u8(addr + 1) as u32) << 8 |
-            (self.read_u8(addr + 2) as u32) << 16 |
-            (self.read_u8(addr + 3) as u32) << 24
+    pub fn read_u8(&mut self, addr: u64) -> Option<u8> {
+        let mut bytes = [0; 1];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        self.observe(addr, 1, bytes[0] as u64, AccessKind::Read);
+        Some(bytes[0])
     }
 
-    pub fn write_u64(&mut self, addr: u64, value: u64) {
-        self.write_u8(addr + 0, ((value >> 0) & 0xff) as u8);
-        self.write_u8(addr + 1, ((value >> 8) & 0xff) as u8);
-        self.write_u8(addr + 2, ((value >> 16) & 0xff) as u8);
-        self.write_u8(addr + 3, ((value >> 24) & 0xff) as u8);
+    pub fn write_u16(&mut self, addr: u64, value: u16) -> bool {
+        self.observe(addr, 2, value as u64, AccessKind::Write);
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn read_u16(&mut self, addr: u64) -> Option<u16> {
+        let mut bytes = [0; 2];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        let value = u16::from_le_bytes(bytes);
+        self.observe(addr, 2, value as u64, AccessKind::Read);
+        Some(value)
+    }
 
-        self.write_u8(addr + 4, ((value >> 32) & 0xff) as u8);
-        self.write_u8(addr + 5, ((value >> 40) & 0xff) as u8);
-        self.write_u8(addr + 6, ((value >> 48) & 0xff) as u8);
-        self.write_u8(addr + 7, ((value >> 56) & 0xff) as u8);
+    pub fn write_u32(&mut self, addr: u64, value: u32) -> bool {
+        self.observe(addr, 4, value as u64, AccessKind::Write);
+        self.write(addr, &value.to_le_bytes())
     }
 
-    pub fn read_u64(&self, addr: u64) -> u64 {
-        self.read_u8(addr) as u64 |
-            (self.read_u8(addr + 1) as u64) << 8 |
-            (self.read_u8(addr + 2) as u64) << 16 |
-            (self.read_u8(addr + 3) as u64) << 24 |
-            (self.read_u8(addr + 4) as u64) << 32 |
-            (self.read_u8(addr + 5) as u64) << 40 |
-            (self.read_u8(addr + 6) as u64) << 48 |
-            (self.read_u8(addr + 7) as u64) << 56
+    pub fn read_u32(&mut self, addr: u64) -> Option<u32> {
+        let mut bytes = [0; 4];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        let value = u32::from_le_bytes(bytes);
+        self.observe(addr, 4, value as u64, AccessKind::Read);
+        Some(value)
+    }
+
+    pub fn write_u64(&mut self, addr: u64, value: u64) -> bool {
+        self.observe(addr, 8, value, AccessKind::Write);
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn read_u64(&mut self, addr: u64) -> Option<u64> {
+        let mut bytes = [0; 8];
+        if !self.read(addr, &mut bytes) {
+            return None;
+        }
+
+        let value = u64::from_le_bytes(bytes);
+        self.observe(addr, 8, value, AccessKind::Read);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM: u64 = 0x8000_0000;
+
+    fn allocated(mmu: &Mmu) -> usize {
+        mmu.regions.iter()
+            .flat_map(|region| region.pages.iter())
+            .filter(|page| page.is_some())
+            .count()
+    }
+
+    #[test]
+    fn pages_are_allocated_on_first_write() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 1 << 30);
+
+        assert_eq!(mmu.read_u64(RAM + 0x1234_5678), Some(0));
+        assert_eq!(allocated(&mmu), 0);
+
+        assert!(mmu.write_u32(RAM + 0x1234_5678, 0xdeadbeef));
+        assert_eq!(allocated(&mmu), 1);
+        assert_eq!(mmu.read_u32(RAM + 0x1234_5678), Some(0xdeadbeef));
+        assert_eq!(mmu.read_u32(RAM + 0x1234_5678 + PAGE_SIZE), Some(0));
+    }
+
+    #[test]
+    fn unmapped_accesses_fail() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x2000);
+
+        assert_eq!(mmu.read_u8(RAM - 1), None);
+        assert_eq!(mmu.read_u8(RAM + 0x2000), None);
+        assert!(!mmu.write_u8(RAM + 0x2000, 1));
+
+        // NOTE(patrik): A write running off the end of RAM writes nothing
+        assert!(!mmu.write_u64(RAM + 0x1ffc, u64::MAX));
+        assert_eq!(mmu.read_u32(RAM + 0x1ffc), Some(0));
+        assert_eq!(allocated(&mmu), 0);
+    }
+
+    #[test]
+    fn accesses_cross_into_the_next_region() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x1000);
+        mmu.add_ram(RAM + 0x1000, 0x1000);
+
+        assert!(mmu.write_u64(RAM + 0xffc, 0x1122_3344_5566_7788));
+        assert_eq!(mmu.read_u64(RAM + 0xffc), Some(0x1122_3344_5566_7788));
+        assert_eq!(mmu.read_u32(RAM + 0x1000), Some(0x1122_3344));
+        assert_eq!(allocated(&mmu), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlapping_ram_is_refused() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x2000);
+        mmu.add_ram(RAM + 0x1000, 0x2000);
+    }
+
+    #[test]
+    fn reset_restores_the_dirty_pages() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x10000);
+        mmu.write_u64(RAM, 1);
+
+        let snapshot = mmu.snapshot();
+        mmu.write_u64(RAM, 2);
+        mmu.write_u64(RAM + 0x5000, 3);
+        assert_eq!(mmu.regions[0].dirty_pages, [0, 5]);
+
+        mmu.reset_to(&snapshot);
+        assert_eq!(mmu.read_u64(RAM), Some(1));
+        assert_eq!(mmu.read_u64(RAM + 0x5000), Some(0));
+        assert!(mmu.regions[0].dirty_pages.is_empty());
+        assert!(mmu.regions[0].dirty.iter().all(|dirty| !dirty));
+        assert_eq!(allocated(&mmu), 1);
+
+        // NOTE(patrik): Resetting again only has the new writes to undo
+        mmu.write_u64(RAM + 0x8, 4);
+        mmu.reset_to(&snapshot);
+        assert_eq!(mmu.read_u64(RAM + 0x8), Some(0));
+        assert_eq!(mmu.read_u64(RAM), Some(1));
+    }
+
+    #[test]
+    fn reset_to_an_older_snapshot_restores_everything() {
+        let mut mmu = Mmu::new();
+        mmu.add_ram(RAM, 0x10000);
+
+        let first = mmu.snapshot();
+        mmu.write_u64(RAM, 1);
+        let second = mmu.snapshot();
+        mmu.write_u64(RAM + 0x1000, 2);
+
+        // NOTE(patrik): The dirty pages are relative to `second` so only
+        // copying them would leave the write before it in place
+        mmu.reset_to(&first);
+        assert_eq!(mmu.read_u64(RAM), Some(0));
+        assert_eq!(mmu.read_u64(RAM + 0x1000), Some(0));
+        assert_eq!(allocated(&mmu), 0);
+
+        mmu.reset_to(&second);
+        assert_eq!(mmu.read_u64(RAM), Some(1));
+        assert_eq!(mmu.read_u64(RAM + 0x1000), Some(0));
     }
 }
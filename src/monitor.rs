@@ -0,0 +1,520 @@
+//! Interactive monitor for poking at the guest without a cross GDB, reads
+//! commands from stdin until the program exits or the user quits
+
+use std::io::{ BufRead, Write };
+
+use crate::cpu::{ Core, CoreExit, Register, StopReason, VirtualWatchpoint };
+use crate::mmu::{ AccessKind, WatchKind };
+use crate::instruction::Instruction;
+use crate::elf::Elf;
+use crate::machine::{ Machine, Snapshot };
+use crate::snapshot;
+use crate::csr;
+use crate::disasm;
+
+/// Instructions shown before PC when disassembling around it
+const DISAS_BEFORE: u64 = 3;
+
+const HELP: &str = "\
+step [N]              Execute N instructions (default 1)
+continue              Run until a breakpoint or the program exits
+until ADDR            Run until PC is ADDR
+break ADDR            Set a breakpoint
+delete ADDR           Remove a breakpoint
+breakpoints           List the breakpoints
+watch ADDR [LENGTH] [r|w|rw|x]
+                      Stop on accesses to memory (default 8 bytes, w)
+unwatch ADDR [LENGTH] [r|w|rw|x]
+                      Remove a watchpoint
+watchpoints           List the watchpoints
+regs                  Print all the registers
+reg NAME [VALUE]      Print or set a register by ABI name (a0, sp, pc...)
+csr NAME [VALUE]      Print or set a CSR by name or number
+csrs                  Print all the named CSRs
+mem ADDR [LENGTH]     Dump memory (default 64 bytes)
+write ADDR VALUE [SIZE]
+                      Write SIZE bytes of VALUE to memory (default 8)
+disas [ADDR] [N]      Disassemble N instructions (default around PC)
+save FILE             Save a snapshot of the machine
+restore FILE          Restore a snapshot of the machine
+checkpoint            Keep a snapshot of the machine in memory
+reset                 Go back to the checkpoint
+quit                  Stop the emulator
+
+ADDR and VALUE can be numbers in decimal or hex with 0x or symbols";
+
+/// Number of bytes shown per line of a memory dump
+const DUMP_LINE: u64 = 16;
+
+pub struct Monitor<'a> {
+    /// Program being run, used to look up symbols
+    elf: Option<&'a Elf>,
+
+    breakpoints: Vec<u64>,
+    watchpoints: Vec<VirtualWatchpoint>,
+
+    checkpoint: Option<Snapshot>,
+}
+
+/// Parse a register by ABI name or as x0-x31
+pub fn parse_register(name: &str) -> Option<Register> {
+    if let Some(index) = name.strip_prefix('x') {
+        let index: u32 = index.parse().ok()?;
+        return if index < 32 { Some(Register::from(index)) } else { None };
+    }
+
+    if name == "fp" {
+        return Some(Register::S0);
+    }
+
+    (0..=32u32)
+        .map(Register::from)
+        .find(|reg| format!("{:?}", reg).to_lowercase() == name)
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(elf: Option<&'a Elf>) -> Self {
+        Self {
+            elf,
+
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+
+            checkpoint: None,
+        }
+    }
+
+    /// Parse a number in decimal, hex with a 0x prefix or a symbol
+    fn parse_value(&self, value: &str) -> Option<u64> {
+        if let Some(hex) = value.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16).ok();
+        }
+
+        if let Ok(value) = value.parse::<u64>() {
+            return Some(value);
+        }
+
+        if let Ok(value) = value.parse::<i64>() {
+            return Some(value as u64);
+        }
+
+        self.elf.and_then(|elf| elf.symbol(value))
+    }
+
+    fn parse_csr(&self, name: &str) -> Option<u16> {
+        let from_name = csr::NAMES.iter()
+            .find(|(csr_name, _)| *csr_name == name)
+            .map(|(_, csr)| *csr);
+
+        from_name
+            .or_else(|| self.parse_value(name).map(|csr| csr as u16))
+            .filter(|csr| *csr < 4096)
+    }
+
+    /// Read a byte at the virtual address `addr` as the hart would see it
+    fn peek_u8(core: &Core, addr: u64, kind: AccessKind) -> Option<u8> {
+        let mut byte = [0; 1];
+        if !core.peek_virtual(addr, &mut byte, kind) {
+            return None;
+        }
+
+        Some(byte[0])
+    }
+
+    /// Decode the instruction at `addr`, returns the instruction and its
+    /// size in bytes
+    fn decode(core: &Core, addr: u64) -> Option<(Instruction, u64)> {
+        let mut bytes = [0; 4];
+        if !core.peek_virtual(addr, &mut bytes[..2], AccessKind::Execute) {
+            return None;
+        }
+
+        let low = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if low & 0b11 != 0b11 {
+            return Some((Instruction::decode_compressed(low), 2));
+        }
+
+        if !core.peek_virtual(addr + 2, &mut bytes[2..], AccessKind::Execute) {
+            return None;
+        }
+
+        Some((Instruction::decode(u32::from_le_bytes(bytes)), 4))
+    }
+
+    /// Address to disassemble from to show a few instructions before
+    /// `pc`. Compressed instructions make the start ambiguous, the
+    /// earliest address that decodes into a run of instructions ending
+    /// exactly at `pc` is used
+    fn start_before(core: &Core, pc: u64) -> u64 {
+        let lowest = pc.saturating_sub(DISAS_BEFORE * 4);
+        (lowest..pc).step_by(2)
+            .find(|start| {
+                let mut addr = *start;
+                while addr < pc {
+                    match Self::decode(core, addr) {
+                        Some((_, size)) => addr += size,
+                        None => return false,
+                    }
+                }
+
+                addr == pc
+            })
+            .unwrap_or(pc)
+    }
+
+    /// Parse argument `index` if it was given
+    fn arg(&self, args: &[&str], index: usize)
+        -> Result<Option<u64>, String>
+    {
+        match args.get(index) {
+            Some(arg) => self.parse_value(arg)
+                .map(Some)
+                .ok_or_else(|| format!("Bad value: {}", arg)),
+
+            None => Ok(None),
+        }
+    }
+
+    /// Parse the arguments of watch and unwatch
+    fn watchpoint(&self, args: &[&str])
+        -> Result<(u64, u64, WatchKind), String>
+    {
+        let addr = self.arg(args, 0)?.ok_or("watch needs an address")?;
+        let length = self.arg(args, 1)?.unwrap_or(8);
+        let kind = match args.get(2).copied().unwrap_or("w") {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "rw" | "a" => WatchKind::Access,
+            "x" => WatchKind::Execute,
+
+            kind => return Err(format!("Unknown watchpoint kind: {}", kind)),
+        };
+
+        Ok((addr, length, kind))
+    }
+
+    fn print_location(&self, core: &Core) {
+        let pc = core.reg(Register::Pc);
+        match Self::decode(core, pc) {
+            Some((inst, _)) => {
+                println!("{:#018x}: {}", pc, disasm::disassemble(&inst, pc));
+            },
+            None => println!("{:#018x}: <not mapped>", pc),
+        }
+    }
+
+    /// Run `count` instructions, until PC is `until`, a breakpoint or
+    /// watchpoint is hit or the program exits. The first instruction is
+    /// always executed so it's possible to continue from a breakpoint
+    fn run_until(&self, machine: &mut Machine, count: Option<u64>,
+                 until: Option<u64>) -> Option<i32>
+    {
+        machine.core.skip_execute_watchpoint();
+
+        let temporary = until.filter(|until| !self.breakpoints.contains(until));
+        if let Some(until) = temporary {
+            machine.core.add_breakpoint(until);
+        }
+
+        let mut remaining = count.unwrap_or(u64::MAX);
+        let exit_code = loop {
+            let result = machine.run(remaining);
+            remaining -= result.steps;
+
+            match result.reason {
+                StopReason::Exit(CoreExit::Shutdown(shutdown)) => {
+                    let code = crate::shutdown_exit_code(shutdown);
+                    println!("Program exited with code {}", code);
+                    break Some(code);
+                },
+                StopReason::Exit(CoreExit::Exception(exception)) => {
+                    println!("Exception: {:x?}", exception);
+                },
+                StopReason::Exit(CoreExit::Watchpoint(mut access)) => {
+                    if let Some(addr) = self.watchpoints.iter()
+                        .find_map(|watchpoint| watchpoint.hit(&access))
+                    {
+                        access.addr = addr;
+                    }
+
+                    println!("Watchpoint: {:x?}", access);
+                    break None;
+                },
+                StopReason::Breakpoint(pc) => {
+                    if Some(pc) != until {
+                        println!("Breakpoint at {:#x}", pc);
+                    }
+                    break None;
+                },
+
+                StopReason::Limit | StopReason::StopRequested => break None,
+                StopReason::Exit(_) => {},
+            }
+
+            if remaining == 0 {
+                break None;
+            }
+        };
+
+        if let Some(until) = temporary {
+            machine.core.remove_breakpoint(until);
+        }
+
+        if exit_code.is_none() {
+            self.print_location(&machine.core);
+        }
+
+        exit_code
+    }
+
+    fn dump_memory(&self, core: &Core, addr: u64, length: u64) {
+        for line in (0..length).step_by(DUMP_LINE as usize) {
+            let line_addr = addr.wrapping_add(line);
+            let bytes: Vec<Option<u8>> = (0..DUMP_LINE.min(length - line))
+                .map(|index| line_addr.wrapping_add(index))
+                .map(|addr| Self::peek_u8(core, addr, AccessKind::Read))
+                .collect();
+
+            let hex: Vec<String> = bytes.iter()
+                .map(|byte| match byte {
+                    Some(byte) => format!("{:02x}", byte),
+                    None => "??".to_string(),
+                })
+                .collect();
+            let ascii: String = bytes.iter()
+                .map(|byte| match byte {
+                    Some(byte) if byte.is_ascii_graphic() => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+
+            println!("{:#018x}: {:<48}|{}|",
+                     line_addr, hex.join(" "), ascii);
+        }
+    }
+
+    /// Run a single command line, returns the exit code when the monitor
+    /// should stop
+    fn command(&mut self, machine: &mut Machine,
+               line: &str) -> Result<Option<i32>, String>
+    {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let core = &mut machine.core;
+
+        match command {
+            "help" | "h" | "?" => println!("{}", HELP),
+
+            "step" | "s" | "si" => {
+                let count = self.arg(&args, 0)?.unwrap_or(1);
+                if count > 0 {
+                    let count = Some(count);
+                    return Ok(self.run_until(machine, count, None));
+                }
+            },
+
+            "continue" | "c" => {
+                return Ok(self.run_until(machine, None, None));
+            },
+
+            "until" | "u" => {
+                let addr = self.arg(&args, 0)?
+                    .ok_or("until needs an address")?;
+                return Ok(self.run_until(machine, None, Some(addr)));
+            },
+
+            "break" | "b" => {
+                let addr = self.arg(&args, 0)?
+                    .ok_or("break needs an address")?;
+                if !self.breakpoints.contains(&addr) {
+                    core.add_breakpoint(addr);
+                    self.breakpoints.push(addr);
+                }
+            },
+
+            "delete" | "d" => {
+                let addr = self.arg(&args, 0)?
+                    .ok_or("delete needs an address")?;
+                core.remove_breakpoint(addr);
+                self.breakpoints.retain(|breakpoint| *breakpoint != addr);
+            },
+
+            "breakpoints" => {
+                for breakpoint in self.breakpoints.iter() {
+                    println!("{:#x}", breakpoint);
+                }
+            },
+
+            "watch" => {
+                let (addr, length, kind) = self.watchpoint(&args)?;
+                let exists = self.watchpoints.iter().any(|watchpoint| {
+                    (watchpoint.addr, watchpoint.length, watchpoint.kind) ==
+                        (addr, length, kind)
+                });
+
+                if !exists {
+                    let watchpoint = core
+                        .add_virtual_watchpoint(addr, length, kind)
+                        .ok_or(format!("{:#x} is not mapped", addr))?;
+                    self.watchpoints.push(watchpoint);
+                }
+            },
+
+            "unwatch" => {
+                let (addr, length, kind) = self.watchpoint(&args)?;
+                let index = self.watchpoints.iter().position(|watchpoint| {
+                    (watchpoint.addr, watchpoint.length, watchpoint.kind) ==
+                        (addr, length, kind)
+                }).ok_or("No such watchpoint")?;
+
+                let watchpoint = self.watchpoints.remove(index);
+                core.remove_virtual_watchpoint(&watchpoint);
+            },
+
+            "watchpoints" => {
+                for watchpoint in self.watchpoints.iter() {
+                    println!("{:#x} {} {:?}", watchpoint.addr,
+                             watchpoint.length, watchpoint.kind);
+                }
+            },
+
+            "regs" => println!("{:#x?}", core),
+
+            "reg" | "r" => {
+                let name = args.first().ok_or("reg needs a register")?;
+                let reg = parse_register(name)
+                    .ok_or_else(|| format!("Unknown register: {}", name))?;
+
+                match self.arg(&args, 1)? {
+                    Some(value) => core.set_reg(reg, value),
+                    None => println!("{} = {:#x}", name, core.reg(reg)),
+                }
+            },
+
+            "csr" => {
+                let name = args.first().ok_or("csr needs a CSR")?;
+                let csr = self.parse_csr(name)
+                    .ok_or_else(|| format!("Unknown CSR: {}", name))?;
+
+                match self.arg(&args, 1)? {
+                    Some(value) => core.write_csr(csr, value),
+                    None => println!("{} = {:#x}", name, core.read_csr(csr)),
+                }
+            },
+
+            "csrs" => {
+                for (name, csr) in csr::NAMES.iter() {
+                    println!("{:<12} {:#x}", name, core.read_csr(*csr));
+                }
+            },
+
+            "mem" | "x" => {
+                let addr = self.arg(&args, 0)?
+                    .ok_or("mem needs an address")?;
+                let length = self.arg(&args, 1)?.unwrap_or(64);
+                self.dump_memory(core, addr, length);
+            },
+
+            "write" | "w" => {
+                let addr = self.arg(&args, 0)?
+                    .ok_or("write needs an address")?;
+                let data = self.arg(&args, 1)?
+                    .ok_or("write needs a value")?;
+                let size = self.arg(&args, 2)?.unwrap_or(8).min(8);
+
+                let bytes = &data.to_le_bytes()[..size as usize];
+                if !core.poke_virtual(addr, bytes) {
+                    return Err(format!("{:#x} is not mapped RAM", addr));
+                }
+            },
+
+            "disas" | "disassemble" => {
+                let pc = core.reg(Register::Pc);
+                let mut addr = match self.arg(&args, 0)? {
+                    Some(addr) => addr,
+                    None => Self::start_before(core, pc),
+                };
+                let count = self.arg(&args, 1)?.unwrap_or(10);
+
+                for _ in 0..count {
+                    match Self::decode(core, addr) {
+                        Some((inst, size)) => {
+                            let marker = if addr == pc { "=>" } else { "  " };
+                            println!("{} {:#018x}: {}", marker, addr,
+                                     disasm::disassemble(&inst, addr));
+                            addr += size;
+                        },
+
+                        None => {
+                            println!("   {:#018x}: <not mapped>", addr);
+                            break;
+                        },
+                    }
+                }
+            },
+
+            "save" => {
+                let path = args.first().ok_or("save needs a file")?;
+                machine.check_snapshot()?;
+                std::fs::write(path, snapshot::save(&machine.core))
+                    .map_err(|error| format!("Failed to save: {}", error))?;
+            },
+
+            "restore" => {
+                let path = args.first().ok_or("restore needs a file")?;
+                let data = std::fs::read(path)
+                    .map_err(|error| format!("Failed to read: {}", error))?;
+                machine.check_snapshot()?;
+                snapshot::restore(&mut machine.core, &data)?;
+                self.print_location(&machine.core);
+            },
+
+            "checkpoint" => self.checkpoint = Some(machine.snapshot()),
+
+            "reset" => {
+                let checkpoint = self.checkpoint.as_ref()
+                    .ok_or("No checkpoint, take one with checkpoint")?;
+                machine.reset_to(checkpoint);
+                self.print_location(&machine.core);
+            },
+
+            "quit" | "q" => return Ok(Some(0)),
+
+            _ => return Err(format!("Unknown command: {}, try help",
+                                    command)),
+        }
+
+        Ok(None)
+    }
+
+    /// Read commands from stdin until the program exits or the user quits,
+    /// returns the exit code
+    pub fn run(&mut self, machine: &mut Machine) -> i32 {
+        println!("rest-emu monitor, type help for the commands");
+        machine.core.set_stop_on_exceptions(true);
+        self.print_location(&machine.core);
+
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(rest-emu) ");
+            let _ = std::io::stdout().flush();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => return 0,
+            };
+
+            match self.command(machine, &line) {
+                Ok(Some(exit_code)) => return exit_code,
+                Ok(None) => {},
+                Err(error) => println!("{}", error),
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Sv39 address translation, the page table walk and a TLB caching the
+//! leaf entries it finds. The walk sets the accessed and dirty bits itself
+//! like hardware implementing Svadu instead of raising page faults for
+//! software to set them
+//!
+//! Page tables have to be in RAM, a walk touching anything else is a page
+//! fault
+
+use crate::mmu::{ Mmu, AccessKind, PAGE_SIZE };
+
+// Bits of a page table entry
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+/// Bits used by Svpbmt and Svnapot, neither is implemented so they have to
+/// be zero
+const PTE_RESERVED_SHIFT: u64 = 54;
+
+const LEVELS: u64 = 3;
+const VPN_BITS: u64 = 9;
+const VPN_MASK: u64 = (1 << (VPN_BITS * LEVELS)) - 1;
+const PTE_SIZE: u64 = 8;
+
+/// Number of entries of the direct mapped TLB
+const TLB_ENTRIES: usize = 256;
+
+/// What decides if an access is allowed besides the page table entry
+#[derive(Copy, Clone, Debug)]
+pub struct AccessContext {
+    /// Physical address of the root page table
+    pub root: u64,
+    /// The effective privilege level of the access is user mode
+    pub user: bool,
+    /// mstatus.SUM, supervisor mode may load and store to user pages
+    pub sum: bool,
+    /// mstatus.MXR, loads from executable pages are allowed
+    pub mxr: bool,
+}
+
+/// Result of a successful translation
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+    pub address: u64,
+    /// The TLB didn't have the page and the page tables were walked
+    pub miss: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TlbEntry {
+    /// Virtual page number, the tag of the entry
+    vpn: u64,
+    /// Physical page number of the 4 KiB page, superpages are cached one
+    /// 4 KiB page at the time
+    ppn: u64,
+    /// The leaf entry, after setting the accessed and dirty bits
+    pte: u64,
+}
+
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; TLB_ENTRIES],
+        }
+    }
+
+    /// Forget all cached translations, after SFENCE.VMA or a write to satp
+    pub fn flush(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+
+    /// Translate the virtual address `addr`, `None` is a page fault
+    pub fn translate(&mut self, mmu: &mut Mmu, context: &AccessContext,
+                     addr: u64, kind: AccessKind) -> Option<Translation>
+    {
+        if !is_canonical(addr) {
+            return None;
+        }
+
+        let vpn = (addr / PAGE_SIZE) & VPN_MASK;
+        let index = vpn as usize % TLB_ENTRIES;
+        let offset = addr % PAGE_SIZE;
+
+        if let Some(entry) = self.entries[index] {
+            // NOTE(patrik): A store to a clean page has to walk again to
+            // set the dirty bit
+            let clean = kind == AccessKind::Write && entry.pte & PTE_D == 0;
+            if entry.vpn == vpn && !clean {
+                if !is_allowed(entry.pte, context, kind) {
+                    return None;
+                }
+
+                return Some(Translation {
+                    address: entry.ppn * PAGE_SIZE + offset,
+                    miss: false,
+                });
+            }
+        }
+
+        let entry = walk(mmu, context, vpn, kind)?;
+        self.entries[index] = Some(entry);
+
+        Some(Translation {
+            address: entry.ppn * PAGE_SIZE + offset,
+            miss: true,
+        })
+    }
+}
+
+/// Translate `addr` without the TLB and without setting the accessed and
+/// dirty bits, so a debugger looking at memory doesn't change anything
+pub fn peek_translate(mmu: &Mmu, context: &AccessContext, addr: u64,
+                      kind: AccessKind) -> Option<u64>
+{
+    if !is_canonical(addr) {
+        return None;
+    }
+
+    let vpn = (addr / PAGE_SIZE) & VPN_MASK;
+    let (_, entry) = find_leaf(mmu, context, vpn, kind)?;
+
+    Some(entry.ppn * PAGE_SIZE + addr % PAGE_SIZE)
+}
+
+/// Bits 63-39 have to be copies of bit 38
+fn is_canonical(addr: u64) -> bool {
+    ((addr as i64) << 25 >> 25) as u64 == addr
+}
+
+/// Check the permissions of the leaf entry `pte` for an access
+fn is_allowed(pte: u64, context: &AccessContext, kind: AccessKind) -> bool {
+    let user_page = pte & PTE_U != 0;
+    let privilege_ok = match kind {
+        // NOTE(patrik): Supervisor mode never executes user pages, SUM
+        // only covers loads and stores
+        AccessKind::Execute => user_page == context.user,
+        _ => if context.user { user_page } else { !user_page || context.sum },
+    };
+
+    let permission_ok = match kind {
+        AccessKind::Read => {
+            pte & PTE_R != 0 || (context.mxr && pte & PTE_X != 0)
+        },
+        AccessKind::Write => pte & PTE_W != 0,
+        AccessKind::Execute => pte & PTE_X != 0,
+    };
+
+    privilege_ok && permission_ok
+}
+
+/// Walk the page tables for the virtual page `vpn` and set the accessed
+/// and dirty bits of the leaf entry
+fn walk(mmu: &mut Mmu, context: &AccessContext, vpn: u64, kind: AccessKind)
+    -> Option<TlbEntry>
+{
+    let (pte_addr, mut entry) = find_leaf(mmu, context, vpn, kind)?;
+
+    let mut update = PTE_A;
+    if kind == AccessKind::Write {
+        update |= PTE_D;
+    }
+
+    if entry.pte & update != update {
+        entry.pte |= update;
+        if !mmu.poke_u64(pte_addr, entry.pte) {
+            return None;
+        }
+    }
+
+    Some(entry)
+}
+
+/// Find the leaf entry for the virtual page `vpn` and check the access is
+/// allowed, returns the address of the entry as well
+fn find_leaf(mmu: &Mmu, context: &AccessContext, vpn: u64,
+             kind: AccessKind) -> Option<(u64, TlbEntry)>
+{
+    let mut table = context.root;
+
+    for level in (0..LEVELS).rev() {
+        let index = (vpn >> (level * VPN_BITS)) & ((1 << VPN_BITS) - 1);
+        let pte_addr = table + index * PTE_SIZE;
+        let pte = mmu.peek_u64(pte_addr)?;
+
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) ||
+            pte >> PTE_RESERVED_SHIFT != 0
+        {
+            return None;
+        }
+
+        let ppn = (pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+
+        if pte & (PTE_R | PTE_X) == 0 {
+            // NOTE(patrik): The accessed, dirty and user bits are reserved
+            // in pointers to the next level
+            if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                return None;
+            }
+
+            table = ppn * PAGE_SIZE;
+            continue;
+        }
+
+        // NOTE(patrik): A superpage has to be aligned to its size
+        let low_mask = (1 << (level * VPN_BITS)) - 1;
+        if ppn & low_mask != 0 {
+            return None;
+        }
+
+        if !is_allowed(pte, context, kind) {
+            return None;
+        }
+
+        return Some((pte_addr, TlbEntry {
+            vpn,
+            ppn: ppn | (vpn & low_mask),
+            pte,
+        }));
+    }
+
+    None
+}
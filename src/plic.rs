@@ -0,0 +1,403 @@
+//! Platform-Level Interrupt Controller, routes the external interrupt
+//! lines of the devices to MEIP/SEIP of the harts using the same register
+//! layout as the SiFive PLIC
+
+use crate::device::{ Device, Interrupts };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x0400_0000;
+
+const PRIORITY_BASE: u64  = 0x000000;
+const PENDING_BASE: u64   = 0x001000;
+const ENABLE_BASE: u64    = 0x002000;
+const ENABLE_STRIDE: u64  = 0x80;
+const CONTEXT_BASE: u64   = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+const SEIP_BIT: u64 = 9;
+const MEIP_BIT: u64 = 11;
+
+/// Per context state, context `2 * hart` is the machine mode context of a
+/// hart and `2 * hart + 1` the supervisor mode context
+struct Context {
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+pub struct Plic {
+    /// Number of interrupt sources including the reserved source 0
+    sources: usize,
+
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    /// Set between a source being claimed and completed, the gateway
+    /// doesn't forward new requests from the source in the meantime
+    in_service: Vec<bool>,
+
+    contexts: Vec<Context>,
+
+    /// Value of `Interrupts::line_changes` at the last update
+    line_changes: u64,
+    /// Set when a register write, claim or completion might have changed
+    /// the state of the outputs
+    dirty: bool,
+}
+
+impl Plic {
+    pub fn new(harts: usize, sources: usize) -> Self {
+        let words = sources.div_ceil(32);
+
+        let contexts = (0..harts * 2)
+            .map(|_| Context { enable: vec![0; words], threshold: 0 })
+            .collect();
+
+        Self {
+            sources,
+
+            priority: vec![0; sources],
+            pending: vec![false; sources],
+            in_service: vec![false; sources],
+
+            contexts,
+
+            line_changes: 0,
+            dirty: true,
+        }
+    }
+
+    fn enabled(&self, context: usize, source: usize) -> bool {
+        let enable = &self.contexts[context].enable;
+        (enable[source / 32] >> (source % 32)) & 1 != 0
+    }
+
+    /// The highest priority pending source enabled for `context` with a
+    /// priority above the threshold, ties go to the lowest id
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let threshold = self.contexts[context].threshold;
+
+        let mut best: Option<(usize, u32)> = None;
+        for source in 1..self.sources {
+            let priority = self.priority[source];
+            if !self.pending[source] || priority <= threshold ||
+                !self.enabled(context, source)
+            {
+                continue;
+            }
+
+            match best {
+                Some((_, best_priority)) if best_priority >= priority => {},
+                _ => best = Some((source, priority)),
+            }
+        }
+
+        best.map(|(source, _)| source)
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.in_service[source] = true;
+
+                source as u32
+            },
+
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source == 0 || source >= self.sources {
+            return;
+        }
+
+        // NOTE(patrik): Completions for sources that are not enabled for
+        // the context are silently ignored
+        if self.enabled(context, source) {
+            self.in_service[source] = false;
+        }
+    }
+
+    fn read_u32(&mut self, offset: u64) -> u32 {
+        self.dirty = true;
+
+        if offset < PENDING_BASE {
+            let source = ((offset - PRIORITY_BASE) / 4) as usize;
+            return self.priority.get(source).copied().unwrap_or(0);
+        }
+
+        if offset < ENABLE_BASE {
+            let word = ((offset - PENDING_BASE) / 4) as usize;
+            let mut value = 0;
+            for bit in 0..32 {
+                let source = word * 32 + bit;
+                if self.pending.get(source).copied().unwrap_or(false) {
+                    value |= 1 << bit;
+                }
+            }
+
+            return value;
+        }
+
+        if offset < CONTEXT_BASE {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+
+            return self.contexts.get(context)
+                .and_then(|context| context.enable.get(word).copied())
+                .unwrap_or(0);
+        }
+
+        let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+        if context >= self.contexts.len() {
+            return 0;
+        }
+
+        match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+            0 => self.contexts[context].threshold,
+            4 => self.claim(context),
+
+            _ => 0,
+        }
+    }
+
+    fn write_u32(&mut self, offset: u64, value: u32) {
+        self.dirty = true;
+
+        if offset < PENDING_BASE {
+            let source = ((offset - PRIORITY_BASE) / 4) as usize;
+            if source != 0 && source < self.sources {
+                self.priority[source] = value & 0b111;
+            }
+
+            return;
+        }
+
+        if offset < ENABLE_BASE {
+            // NOTE(patrik): The pending bits are read only
+            return;
+        }
+
+        if offset < CONTEXT_BASE {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+
+            let sources = self.sources;
+            if let Some(context) = self.contexts.get_mut(context) {
+                if let Some(enable) = context.enable.get_mut(word) {
+                    // NOTE(patrik): Source 0 doesn't exist so it can never
+                    // be enabled
+                    let mut value = value;
+                    if word == 0 {
+                        value &= !1;
+                    }
+                    for bit in 0..32 {
+                        if word * 32 + bit >= sources {
+                            value &= !(1 << bit);
+                        }
+                    }
+
+                    *enable = value;
+                }
+            }
+
+            return;
+        }
+
+        let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+        if context >= self.contexts.len() {
+            return;
+        }
+
+        match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+            0 => self.contexts[context].threshold = value & 0b111,
+            4 => self.complete(context, value),
+
+            _ => {},
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, size: usize) -> u64 {
+        // NOTE(patrik): All the registers are 32-bit, wider accesses are
+        // split into 32-bit ones
+        let mut value = 0;
+        for index in 0..(size as u64).div_ceil(4) {
+            let word = self.read_u32(offset + index * 4) as u64;
+            value |= word << (index * 32);
+        }
+
+        value
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) {
+        for index in 0..(size as u64).div_ceil(4) {
+            let word = (value >> (index * 32)) as u32;
+            self.write_u32(offset + index * 4, word);
+        }
+    }
+
+    fn tick(&mut self, interrupts: &mut Interrupts) {
+        if !self.dirty && interrupts.line_changes() == self.line_changes {
+            return;
+        }
+        self.dirty = false;
+        self.line_changes = interrupts.line_changes();
+
+        for source in 1..self.sources {
+            if interrupts.line(source) && !self.in_service[source] {
+                self.pending[source] = true;
+            }
+        }
+
+        for context in 0..self.contexts.len() {
+            let hart = context / 2;
+            let bit = if context % 2 == 0 { MEIP_BIT } else { SEIP_BIT };
+
+            let level = self.best_source(context).is_some();
+            interrupts.set_pending(hart, bit, level);
+        }
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        let priority: Vec<u64> = self.priority.iter()
+            .map(|priority| *priority as u64)
+            .collect();
+        writer.list_u64(&priority);
+        writer.list_bool(&self.pending);
+        writer.list_bool(&self.in_service);
+
+        writer.u64(self.contexts.len() as u64);
+        for context in self.contexts.iter() {
+            let enable: Vec<u64> = context.enable.iter()
+                .map(|enable| *enable as u64)
+                .collect();
+            writer.list_u64(&enable);
+            writer.u32(context.threshold);
+        }
+
+        writer.u64(self.line_changes);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let priority = reader.list_u64(Some(self.sources))?;
+        let pending = reader.list_bool(Some(self.sources))?;
+        let in_service = reader.list_bool(Some(self.sources))?;
+
+        reader.length(Some(self.contexts.len()))?;
+        let mut contexts = Vec::with_capacity(self.contexts.len());
+        for context in self.contexts.iter() {
+            let enable = reader.list_u64(Some(context.enable.len()))?;
+            let threshold = reader.u32()?;
+            contexts.push((enable, threshold));
+        }
+
+        let line_changes = reader.u64()?;
+
+        self.priority = priority.iter()
+            .map(|priority| *priority as u32)
+            .collect();
+        self.pending = pending;
+        self.in_service = in_service;
+        for (context, (enable, threshold)) in self.contexts.iter_mut()
+            .zip(contexts)
+        {
+            context.enable = enable.iter()
+                .map(|enable| *enable as u32)
+                .collect();
+            context.threshold = threshold;
+        }
+        self.line_changes = line_changes;
+        self.dirty = true;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: u64 = CONTEXT_BASE + 4;
+    const MEIP: u64 = 1 << MEIP_BIT;
+
+    /// PLIC for one hart with sources 1 and 2 enabled for machine mode,
+    /// source 2 with the higher priority
+    fn create_plic() -> (Plic, Interrupts) {
+        let mut plic = Plic::new(1, 8);
+        plic.write(PRIORITY_BASE + 4, 4, 1);
+        plic.write(PRIORITY_BASE + 8, 4, 2);
+        plic.write(ENABLE_BASE, 4, 0b110);
+
+        (plic, Interrupts::new())
+    }
+
+    #[test]
+    fn claim_takes_the_highest_priority_source() {
+        let (mut plic, mut interrupts) = create_plic();
+        interrupts.set_line(1, true);
+        interrupts.set_line(2, true);
+        plic.tick(&mut interrupts);
+        assert_eq!(interrupts.pending(0) & MEIP, MEIP);
+
+        assert_eq!(plic.read(CLAIM, 4), 2);
+        assert_eq!(plic.read(CLAIM, 4), 1);
+        assert_eq!(plic.read(CLAIM, 4), 0);
+
+        plic.tick(&mut interrupts);
+        assert_eq!(interrupts.pending(0) & MEIP, 0);
+    }
+
+    #[test]
+    fn source_pends_again_only_after_completion() {
+        let (mut plic, mut interrupts) = create_plic();
+        interrupts.set_line(1, true);
+        plic.tick(&mut interrupts);
+        assert_eq!(plic.read(CLAIM, 4), 1);
+
+        // NOTE(patrik): The line is still high but the source is in service
+        plic.tick(&mut interrupts);
+        assert_eq!(interrupts.pending(0) & MEIP, 0);
+        assert_eq!(plic.read(PENDING_BASE, 4), 0);
+
+        plic.write(CLAIM, 4, 1);
+        plic.tick(&mut interrupts);
+        assert_eq!(interrupts.pending(0) & MEIP, MEIP);
+        assert_eq!(plic.read(PENDING_BASE, 4), 0b10);
+    }
+
+    #[test]
+    fn completion_of_a_disabled_source_is_ignored() {
+        let (mut plic, mut interrupts) = create_plic();
+        interrupts.set_line(1, true);
+        plic.tick(&mut interrupts);
+        assert_eq!(plic.read(CLAIM, 4), 1);
+
+        plic.write(ENABLE_BASE, 4, 0b100);
+        plic.write(CLAIM, 4, 1);
+        plic.write(ENABLE_BASE, 4, 0b110);
+        plic.tick(&mut interrupts);
+        assert_eq!(plic.read(PENDING_BASE, 4), 0);
+    }
+
+    #[test]
+    fn threshold_masks_lower_priorities() {
+        let (mut plic, mut interrupts) = create_plic();
+        plic.write(CONTEXT_BASE, 4, 1);
+        interrupts.set_line(1, true);
+        plic.tick(&mut interrupts);
+
+        assert_eq!(interrupts.pending(0) & MEIP, 0);
+        assert_eq!(plic.read(CLAIM, 4), 0);
+
+        interrupts.set_line(2, true);
+        plic.tick(&mut interrupts);
+        assert_eq!(interrupts.pending(0) & MEIP, MEIP);
+        assert_eq!(plic.read(CLAIM, 4), 2);
+    }
+}
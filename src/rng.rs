@@ -0,0 +1,40 @@
+//! Small deterministic random number generator, the same seed always
+//! gives the same numbers so runs can be repeated
+
+/// xorshift64*, fast and good enough for picking mutations and feeding
+/// the guest entropy that doesn't have to be secure
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed.max(1),
+        }
+    }
+
+    /// State to save, `Rng::new` with it continues where this left off
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Random number below `limit`, which must not be zero
+    pub fn below(&mut self, limit: usize) -> usize {
+        (self.next() % limit as u64) as usize
+    }
+
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let value = self.next().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Built-in SBI firmware, S-mode kernels run straight from an ELF without
+//! OpenSBI. The kernel starts in supervisor mode at its entry point and
+//! its ECALLs are serviced on the host
+//!
+//!     rest-emu --sbi kernel.elf
+//!
+//! The base, TIME, IPI, RFENCE, HSM, SRST and DBCN extensions are
+//! implemented along with the legacy ones. The timer is stimecmp of Sstc
+//! and there is only one hart
+
+use std::io::{ self, Write };
+
+use crate::cpu::{ Core, Register, PrivilegeLevel };
+use crate::device::Shutdown;
+use crate::machine::EcallHandler;
+use crate::mmu::AccessKind;
+use crate::csr;
+
+// Extension ids
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_CLEAR_IPI: u64 = 0x03;
+const EXT_LEGACY_SEND_IPI: u64 = 0x04;
+const EXT_LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const EXT_LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const EXT_LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x54494d45;
+const EXT_IPI: u64 = 0x735049;
+const EXT_RFENCE: u64 = 0x52464e43;
+const EXT_HSM: u64 = 0x48534d;
+const EXT_SRST: u64 = 0x53525354;
+const EXT_DBCN: u64 = 0x4442434e;
+
+const EXTENSIONS: &[u64] = &[
+    EXT_LEGACY_SET_TIMER, EXT_LEGACY_CONSOLE_PUTCHAR,
+    EXT_LEGACY_CONSOLE_GETCHAR, EXT_LEGACY_CLEAR_IPI, EXT_LEGACY_SEND_IPI,
+    EXT_LEGACY_REMOTE_FENCE_I, EXT_LEGACY_REMOTE_SFENCE_VMA,
+    EXT_LEGACY_REMOTE_SFENCE_VMA_ASID, EXT_LEGACY_SHUTDOWN,
+    EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN,
+];
+
+// Functions of the base extension
+const BASE_GET_SPEC_VERSION: u64 = 0;
+const BASE_GET_IMPL_ID: u64 = 1;
+const BASE_GET_IMPL_VERSION: u64 = 2;
+const BASE_PROBE_EXTENSION: u64 = 3;
+const BASE_GET_MVENDORID: u64 = 4;
+const BASE_GET_MARCHID: u64 = 5;
+const BASE_GET_MIMPID: u64 = 6;
+
+// Functions of the HSM extension
+const HSM_HART_START: u64 = 0;
+const HSM_HART_STOP: u64 = 1;
+const HSM_HART_GET_STATUS: u64 = 2;
+const HSM_HART_SUSPEND: u64 = 3;
+
+const HSM_STATUS_STARTED: u64 = 0;
+const HSM_SUSPEND_RETENTIVE: u64 = 0;
+
+// Reset types of the SRST extension
+const SRST_SHUTDOWN: u64 = 0;
+const SRST_COLD_REBOOT: u64 = 1;
+const SRST_WARM_REBOOT: u64 = 2;
+
+// Functions of the DBCN extension
+const DBCN_CONSOLE_WRITE: u64 = 0;
+const DBCN_CONSOLE_READ: u64 = 1;
+const DBCN_CONSOLE_WRITE_BYTE: u64 = 2;
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// SBI 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+
+/// Not one of the registered implementation ids
+const IMPL_ID: u64 = 0x7265_7374;
+const IMPL_VERSION: u64 = 1;
+
+/// Hart mask base meaning all harts
+const ALL_HARTS: u64 = u64::MAX;
+
+/// Exceptions handled by the kernel, everything but ECALLs from supervisor
+/// and machine mode as there is no machine mode software to take them
+const DELEGATED_EXCEPTIONS: u64 = 0xffff & !(1 << 9 | 1 << 11);
+
+/// Largest amount of data written by a single console write
+const MAX_CONSOLE_WRITE: u64 = 64 * 1024;
+
+/// SBI implementation, there is no state beyond the CSRs of the core
+pub struct Sbi {
+    hart_id: u64,
+}
+
+impl Sbi {
+    /// Set up `core` to start the S-mode kernel at `entry` with `a1`
+    /// pointing at the device tree
+    pub fn new(core: &mut Core, entry: u64, dtb: u64) -> Self {
+        let hart_id = core.read_csr(csr::MHARTID);
+
+        core.set_reg(Register::Pc, entry);
+        core.set_reg(Register::A0, hart_id);
+        core.set_reg(Register::A1, dtb);
+
+        // NOTE(patrik): Give the kernel everything OpenSBI does, the
+        // counters and the timer through stimecmp
+        core.write_csr(csr::MIDELEG,
+                       csr::MIP_SSIP | csr::MIP_STIP | csr::MIP_SEIP);
+        core.write_csr(csr::MEDELEG, DELEGATED_EXCEPTIONS);
+        core.write_csr(csr::MCOUNTEREN, u32::MAX as u64);
+        core.write_csr(csr::STIMECMP, u64::MAX);
+        core.write_csr(csr::MENVCFG, csr::MENVCFG_STCE);
+        core.set_privilege_level(PrivilegeLevel::Supervisor);
+
+        core.set_environment_traps(true);
+        core.set_firmware_ecalls(true);
+
+        Self {
+            hart_id,
+        }
+    }
+
+    /// Is this hart in the hart mask, harts that don't exist in it are an
+    /// error
+    fn has_hart(&self, mask: u64, base: u64) -> Result<bool, i64> {
+        if base == ALL_HARTS {
+            return Ok(true);
+        }
+
+        let others = if self.hart_id >= base && self.hart_id - base < 64 {
+            mask & !(1 << (self.hart_id - base))
+        } else {
+            mask
+        };
+        if others != 0 {
+            return Err(SBI_ERR_INVALID_PARAM);
+        }
+
+        Ok(mask != others)
+    }
+
+    fn call(&mut self, core: &mut Core, extension: u64, function: u64,
+            args: [u64; 6]) -> Result<u64, i64>
+    {
+        match (extension, function) {
+            (EXT_BASE, BASE_GET_SPEC_VERSION) => Ok(SPEC_VERSION),
+            (EXT_BASE, BASE_GET_IMPL_ID) => Ok(IMPL_ID),
+            (EXT_BASE, BASE_GET_IMPL_VERSION) => Ok(IMPL_VERSION),
+            (EXT_BASE, BASE_PROBE_EXTENSION) => {
+                Ok(EXTENSIONS.contains(&args[0]) as u64)
+            },
+            (EXT_BASE, BASE_GET_MVENDORID) => {
+                Ok(core.read_csr(csr::MVENDORID))
+            },
+            (EXT_BASE, BASE_GET_MARCHID) => Ok(core.read_csr(csr::MARCHID)),
+            (EXT_BASE, BASE_GET_MIMPID) => Ok(core.read_csr(csr::MIMPID)),
+
+            (EXT_TIME, 0) => {
+                core.write_csr(csr::STIMECMP, args[0]);
+                Ok(0)
+            },
+
+            (EXT_IPI, 0) => {
+                if self.has_hart(args[0], args[1])? {
+                    let mip = core.read_csr(csr::MIP);
+                    core.write_csr(csr::MIP, mip | csr::MIP_SSIP);
+                }
+
+                Ok(0)
+            },
+
+            // NOTE(patrik): Instructions are fetched from memory every time
+            // so FENCE.I has nothing to do, the other fences flush the whole
+            // TLB no matter the address range or address space
+            (EXT_RFENCE, 0) => {
+                self.has_hart(args[0], args[1])?;
+                Ok(0)
+            },
+            (EXT_RFENCE, 1..=6) => {
+                if self.has_hart(args[0], args[1])? {
+                    core.flush_tlb();
+                }
+
+                Ok(0)
+            },
+
+            (EXT_HSM, HSM_HART_START) => {
+                if args[0] == self.hart_id {
+                    Err(SBI_ERR_ALREADY_AVAILABLE)
+                } else {
+                    Err(SBI_ERR_INVALID_PARAM)
+                }
+            },
+            (EXT_HSM, HSM_HART_STOP) => Err(SBI_ERR_FAILED),
+            (EXT_HSM, HSM_HART_GET_STATUS) => {
+                if args[0] == self.hart_id {
+                    Ok(HSM_STATUS_STARTED)
+                } else {
+                    Err(SBI_ERR_INVALID_PARAM)
+                }
+            },
+            // NOTE(patrik): A retentive suspend resumes right away like a
+            // WFI that is woken up, the state is never lost
+            (EXT_HSM, HSM_HART_SUSPEND) => {
+                if args[0] == HSM_SUSPEND_RETENTIVE {
+                    Ok(0)
+                } else {
+                    Err(SBI_ERR_NOT_SUPPORTED)
+                }
+            },
+
+            (EXT_DBCN, DBCN_CONSOLE_WRITE) => {
+                let (length, addr) = (args[0], args[1]);
+                let length = length.min(MAX_CONSOLE_WRITE);
+
+                let mut data = vec![0; length as usize];
+                if args[2] != 0 || !core.mmu.peek_bytes(addr, &mut data) {
+                    return Err(SBI_ERR_INVALID_ADDRESS);
+                }
+                console_write(&data);
+
+                Ok(length)
+            },
+            // NOTE(patrik): Input goes to the UART, the console never has
+            // anything to read
+            (EXT_DBCN, DBCN_CONSOLE_READ) => Ok(0),
+            (EXT_DBCN, DBCN_CONSOLE_WRITE_BYTE) => {
+                console_write(&[args[0] as u8]);
+                Ok(0)
+            },
+
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /// Legacy extensions return a single value in a0
+    fn legacy_call(&mut self, core: &mut Core, extension: u64,
+                   args: [u64; 6]) -> u64
+    {
+        match extension {
+            EXT_LEGACY_SET_TIMER => core.write_csr(csr::STIMECMP, args[0]),
+            EXT_LEGACY_CONSOLE_PUTCHAR => console_write(&[args[0] as u8]),
+            EXT_LEGACY_CONSOLE_GETCHAR => return u64::MAX,
+            EXT_LEGACY_CLEAR_IPI => {
+                let mip = core.read_csr(csr::MIP);
+                core.write_csr(csr::MIP, mip & !csr::MIP_SSIP);
+            },
+            EXT_LEGACY_SEND_IPI => {
+                // NOTE(patrik): The legacy calls pass the hart mask by its
+                // virtual address
+                let mut bytes = [0; 8];
+                let mask = if core.peek_virtual(args[0], &mut bytes,
+                                                AccessKind::Read)
+                {
+                    u64::from_le_bytes(bytes)
+                } else {
+                    0
+                };
+                if mask & (1 << self.hart_id) != 0 {
+                    let mip = core.read_csr(csr::MIP);
+                    core.write_csr(csr::MIP, mip | csr::MIP_SSIP);
+                }
+            },
+            EXT_LEGACY_REMOTE_FENCE_I => {},
+            EXT_LEGACY_REMOTE_SFENCE_VMA |
+            EXT_LEGACY_REMOTE_SFENCE_VMA_ASID => core.flush_tlb(),
+
+            _ => return SBI_ERR_NOT_SUPPORTED as u64,
+        }
+
+        SBI_SUCCESS as u64
+    }
+}
+
+impl EcallHandler for Sbi {
+    /// The timer and IPIs live in the CSRs and the CLINT, all in the
+    /// snapshot
+    fn has_state(&self) -> bool {
+        false
+    }
+
+    fn ecall(&mut self, core: &mut Core) -> Option<Shutdown> {
+        let extension = core.reg(Register::A7);
+        let function = core.reg(Register::A6);
+        let args = [
+            core.reg(Register::A0), core.reg(Register::A1),
+            core.reg(Register::A2), core.reg(Register::A3),
+            core.reg(Register::A4), core.reg(Register::A5),
+        ];
+
+        match (extension, function) {
+            (EXT_SRST, 0) => {
+                let _ = io::stdout().flush();
+                match args[0] {
+                    SRST_SHUTDOWN if args[1] == 0 => {
+                        return Some(Shutdown::Pass);
+                    },
+                    SRST_SHUTDOWN => return Some(Shutdown::Fail(1)),
+                    SRST_COLD_REBOOT | SRST_WARM_REBOOT => {
+                        return Some(Shutdown::Reboot);
+                    },
+
+                    _ => {},
+                }
+            },
+
+            (EXT_LEGACY_SHUTDOWN, _) => {
+                let _ = io::stdout().flush();
+                return Some(Shutdown::Pass);
+            },
+
+            _ => {},
+        }
+
+        if extension < EXT_BASE {
+            let value = self.legacy_call(core, extension, args);
+            core.set_reg(Register::A0, value);
+            return None;
+        }
+
+        let (error, value) = match self.call(core, extension, function, args)
+        {
+            Ok(value) => (SBI_SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+        core.set_reg(Register::A0, error as u64);
+        core.set_reg(Register::A1, value);
+
+        None
+    }
+}
+
+fn console_write(data: &[u8]) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(data).and_then(|_| stdout.flush());
+}
@@ -0,0 +1,433 @@
+//! RISC-V semihosting, the guest asks the host to do I/O for it with the
+//! ARM semihosting operations. A call is an EBREAK between
+//! `slli zero, zero, 0x1f` and `srai zero, zero, 7` with the operation in
+//! a0 and a pointer to its parameters in a1, the result goes in a0
+//!
+//!     rest-emu --semihosting program [args...]
+//!
+//! Files are opened relative to the working directory of the emulator and
+//! `:tt` is the console
+
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+use crate::cpu::{ Core, Register };
+use crate::device::Shutdown;
+use crate::mmu::Mmu;
+
+// Operation numbers
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_TMPNAM: u64 = 0x0d;
+const SYS_REMOVE: u64 = 0x0e;
+const SYS_RENAME: u64 = 0x0f;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_SYSTEM: u64 = 0x12;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+const SYS_ELAPSED: u64 = 0x30;
+const SYS_TICKFREQ: u64 = 0x31;
+
+/// Exit reason of a program that returned normally, the subcode is the
+/// exit status
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+/// Ticks counted by SYS_ELAPSED per second
+const TICK_FREQUENCY: u64 = 1_000_000;
+
+/// Largest amount of data moved by a single read or write, larger requests
+/// are partial
+const MAX_TRANSFER: u64 = 16 * 1024 * 1024;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl Handle {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, i64> {
+        let result = match self {
+            Handle::Stdin => io::stdin().read(buffer),
+            Handle::File(file) => file.read(buffer),
+
+            _ => return Err(EBADF),
+        };
+
+        result.map_err(host_error)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, i64> {
+        let result = match self {
+            Handle::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            },
+            Handle::Stderr => io::stderr().write_all(data),
+            Handle::File(file) => {
+                return file.write(data).map_err(host_error);
+            },
+
+            Handle::Stdin => return Err(EBADF),
+        };
+
+        result.map(|_| data.len()).map_err(host_error)
+    }
+}
+
+/// Host side of semihosting, the open files and what the guest needs to
+/// know about how it was started
+pub struct Semihosting {
+    /// Open files indexed by handle
+    files: Vec<Option<Handle>>,
+
+    /// Error of the last operation that failed, read with SYS_ERRNO
+    errno: i64,
+
+    /// What SYS_GET_CMDLINE returns, the program and its arguments
+    command_line: String,
+
+    /// When the program started, SYS_CLOCK and SYS_ELAPSED count from here
+    start: Instant,
+}
+
+impl Semihosting {
+    /// `args` is the program followed by its arguments
+    pub fn new(args: &[String]) -> Self {
+        Self {
+            files: Vec::new(),
+            errno: 0,
+            command_line: args.join(" "),
+            start: Instant::now(),
+        }
+    }
+
+    /// Handle the semihosting call the core just made. Returns the
+    /// shutdown if the guest asked to exit
+    pub fn call(&mut self, core: &mut Core) -> Option<Shutdown> {
+        let operation = core.reg(Register::A0);
+        let parameter = core.reg(Register::A1);
+
+        if operation == SYS_EXIT || operation == SYS_EXIT_EXTENDED {
+            let _ = io::stdout().flush();
+
+            // NOTE(patrik): On RV64 both take a block with the reason and
+            // the subcode, a bad block is treated as a failure
+            let reason = core.mmu.peek_u64(parameter);
+            let code = core.mmu.peek_u64(parameter.wrapping_add(8));
+
+            return Some(match (reason, code) {
+                (Some(ADP_STOPPED_APPLICATION_EXIT), Some(0)) => {
+                    Shutdown::Pass
+                },
+                (Some(ADP_STOPPED_APPLICATION_EXIT), Some(code)) => {
+                    Shutdown::Fail(code as u16)
+                },
+
+                _ => Shutdown::Fail(1),
+            });
+        }
+
+        let result = match self.operation(&mut core.mmu, operation,
+                                          parameter)
+        {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                u64::MAX
+            },
+        };
+        core.set_reg(Register::A0, result);
+
+        None
+    }
+
+    fn handle(&mut self, handle: u64) -> Result<&mut Handle, i64> {
+        self.files.get_mut(handle as usize)
+            .and_then(|handle| handle.as_mut())
+            .ok_or(EBADF)
+    }
+
+    fn add_handle(&mut self, handle: Handle) -> u64 {
+        let index = self.files.iter().position(|handle| handle.is_none())
+            .unwrap_or_else(|| {
+                self.files.push(None);
+                self.files.len() - 1
+            });
+        self.files[index] = Some(handle);
+
+        index as u64
+    }
+
+    fn operation(&mut self, mmu: &mut Mmu, operation: u64, parameter: u64)
+        -> Result<u64, i64>
+    {
+        let field = |index: u64| {
+            mmu.peek_u64(parameter.wrapping_add(index * 8)).ok_or(EFAULT)
+        };
+
+        match operation {
+            SYS_OPEN => {
+                let (name, mode, length) = (field(0)?, field(1)?, field(2)?);
+                let name = read_name(mmu, name, length)?;
+                if mode > 11 {
+                    return Err(EINVAL);
+                }
+
+                // NOTE(patrik): Modes are the ones of fopen in order, r,
+                // w and a each with b, + and +b
+                let plus = mode & 2 != 0;
+                let handle = match (name.as_str(), mode / 4) {
+                    (":tt", 0) => Handle::Stdin,
+                    (":tt", 1) => Handle::Stdout,
+                    (":tt", _) => Handle::Stderr,
+
+                    (_, kind) => {
+                        let mut options = OpenOptions::new();
+                        match kind {
+                            0 => options.read(true).write(plus),
+                            1 => {
+                                options.write(true).read(plus)
+                                    .create(true).truncate(true)
+                            },
+                            _ => {
+                                options.append(true).read(plus)
+                                    .create(true)
+                            },
+                        };
+
+                        Handle::File(options.open(name).map_err(host_error)?)
+                    },
+                };
+
+                Ok(self.add_handle(handle))
+            },
+
+            SYS_CLOSE => {
+                let handle = field(0)?;
+                let handle = self.files.get_mut(handle as usize)
+                    .and_then(|handle| handle.take());
+
+                handle.map(|_| 0).ok_or(EBADF)
+            },
+
+            SYS_WRITEC => {
+                let value = mmu.peek_u8(parameter).ok_or(EFAULT)?;
+                Handle::Stdout.write(&[value])?;
+
+                Ok(0)
+            },
+
+            SYS_WRITE0 => {
+                let mut string = Vec::new();
+                loop {
+                    let addr = parameter.wrapping_add(string.len() as u64);
+                    match mmu.peek_u8(addr).ok_or(EFAULT)? {
+                        0 => break,
+                        value => string.push(value),
+                    }
+                }
+                Handle::Stdout.write(&string)?;
+
+                Ok(0)
+            },
+
+            // NOTE(patrik): Both return how many bytes were not
+            // transferred, an error transfers nothing
+            SYS_WRITE => {
+                let (handle, buffer, length) =
+                    (field(0)?, field(1)?, field(2)?);
+                let data = read_bytes(mmu, buffer,
+                                      length.min(MAX_TRANSFER))?;
+
+                match self.handle(handle).and_then(|file| file.write(&data)) {
+                    Ok(done) => Ok(length - done as u64),
+                    Err(errno) => {
+                        self.errno = errno;
+                        Ok(length)
+                    },
+                }
+            },
+
+            SYS_READ => {
+                let (handle, buffer, length) =
+                    (field(0)?, field(1)?, field(2)?);
+                let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+
+                match self.handle(handle).and_then(|file| file.read(&mut data))
+                {
+                    Ok(done) => {
+                        write_bytes(mmu, buffer, &data[..done])?;
+                        Ok(length - done as u64)
+                    },
+                    Err(errno) => {
+                        self.errno = errno;
+                        Ok(length)
+                    },
+                }
+            },
+
+            SYS_READC => {
+                let mut value = [0];
+                match Handle::Stdin.read(&mut value)? {
+                    0 => Err(EIO),
+                    _ => Ok(value[0] as u64),
+                }
+            },
+
+            SYS_ISERROR => Ok(((field(0)? as i64) < 0) as u64),
+
+            SYS_ISTTY => {
+                match self.handle(field(0)?)? {
+                    Handle::File(_) => Ok(0),
+                    _ => Ok(1),
+                }
+            },
+
+            SYS_SEEK => {
+                let (handle, position) = (field(0)?, field(1)?);
+                match self.handle(handle)? {
+                    Handle::File(file) => {
+                        file.seek(SeekFrom::Start(position))
+                            .map(|_| 0).map_err(host_error)
+                    },
+
+                    _ => Err(EINVAL),
+                }
+            },
+
+            SYS_FLEN => {
+                match self.handle(field(0)?)? {
+                    Handle::File(file) => {
+                        file.metadata().map(|metadata| metadata.len())
+                            .map_err(host_error)
+                    },
+
+                    _ => Err(EINVAL),
+                }
+            },
+
+            SYS_TMPNAM => {
+                let (buffer, id, length) = (field(0)?, field(1)?, field(2)?);
+                let name = std::env::temp_dir()
+                    .join(format!("rest-emu-{}-{}", std::process::id(), id));
+
+                let mut name = name.to_string_lossy().into_owned().into_bytes();
+                name.push(0);
+                if name.len() as u64 > length {
+                    return Err(EINVAL);
+                }
+                write_bytes(mmu, buffer, &name)?;
+
+                Ok(0)
+            },
+
+            SYS_REMOVE => {
+                let name = read_name(mmu, field(0)?, field(1)?)?;
+                fs::remove_file(name).map(|_| 0).map_err(host_error)
+            },
+
+            SYS_RENAME => {
+                let old = read_name(mmu, field(0)?, field(1)?)?;
+                let new = read_name(mmu, field(2)?, field(3)?)?;
+                fs::rename(old, new).map(|_| 0).map_err(host_error)
+            },
+
+            SYS_CLOCK => Ok(self.start.elapsed().as_millis() as u64 / 10),
+
+            SYS_TIME => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+
+                Ok(time.as_secs())
+            },
+
+            // NOTE(patrik): The guest doesn't get to run commands on the
+            // host
+            SYS_SYSTEM => Err(ENOSYS),
+
+            SYS_ERRNO => Ok(self.errno as u64),
+
+            SYS_GET_CMDLINE => {
+                let (buffer, length) = (field(0)?, field(1)?);
+
+                let mut command_line = self.command_line.as_bytes().to_vec();
+                command_line.push(0);
+                if command_line.len() as u64 > length {
+                    return Err(EINVAL);
+                }
+                write_bytes(mmu, buffer, &command_line)?;
+
+                let length = (command_line.len() - 1) as u64;
+                write_bytes(mmu, parameter + 8, &length.to_le_bytes())?;
+
+                Ok(0)
+            },
+
+            // NOTE(patrik): All zero means unknown, the C library falls
+            // back to the symbols of its linker script
+            SYS_HEAPINFO => {
+                write_bytes(mmu, field(0)?, &[0; 32])?;
+                Ok(0)
+            },
+
+            SYS_ELAPSED => {
+                let ticks = self.start.elapsed().as_micros() as u64;
+                write_bytes(mmu, parameter, &ticks.to_le_bytes())?;
+
+                Ok(0)
+            },
+
+            SYS_TICKFREQ => Ok(TICK_FREQUENCY),
+
+            _ => Err(ENOSYS),
+        }
+    }
+}
+
+/// Error number for an error from the host
+fn host_error(error: io::Error) -> i64 {
+    error.raw_os_error().map(i64::from).unwrap_or(EIO)
+}
+
+fn read_bytes(mmu: &Mmu, addr: u64, length: u64) -> Result<Vec<u8>, i64> {
+    let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+    if !mmu.peek_bytes(addr, &mut data) {
+        return Err(EFAULT);
+    }
+
+    Ok(data)
+}
+
+fn read_name(mmu: &Mmu, addr: u64, length: u64) -> Result<String, i64> {
+    String::from_utf8(read_bytes(mmu, addr, length)?).map_err(|_| EINVAL)
+}
+
+fn write_bytes(mmu: &mut Mmu, addr: u64, data: &[u8]) -> Result<(), i64> {
+    if !mmu.poke_bytes(addr, data) {
+        return Err(EFAULT);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,293 @@
+//! Saving and restoring the complete state of the machine, the core, RAM
+//! and the devices. A snapshot can only be restored into a machine created
+//! with the same memory map and devices
+//!
+//! The format is a header followed by the state of each part in order,
+//! all numbers are little endian
+
+use std::convert::TryInto;
+
+use crate::cpu::Core;
+
+const MAGIC: &[u8; 8] = b"RESTSNAP";
+
+/// Bumped every time the layout of the state changes, snapshots from other
+/// versions are rejected
+const VERSION: u32 = 4;
+
+/// Builds up the serialized state
+#[derive(Default)]
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Write an optional value as a presence flag followed by the value
+    pub fn option_u64(&mut self, value: Option<u64>) {
+        self.bool(value.is_some());
+        self.u64(value.unwrap_or(0));
+    }
+
+    /// Write a length prefixed list of values
+    pub fn list_u64(&mut self, values: &[u64]) {
+        self.u64(values.len() as u64);
+        for value in values.iter() {
+            self.u64(*value);
+        }
+    }
+
+    /// Write a length prefixed list of flags
+    pub fn list_bool(&mut self, values: &[bool]) {
+        self.u64(values.len() as u64);
+        for value in values.iter() {
+            self.bool(*value);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back the state written by `SnapshotWriter`, fails if the data
+/// ends early or doesn't match what the machine expects
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+        }
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("Snapshot: Unexpected end of data")?;
+
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+
+            value => Err(format!("Snapshot: Bad flag value: {}", value)),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn option_u64(&mut self) -> Result<Option<u64>, String> {
+        let present = self.bool()?;
+        let value = self.u64()?;
+
+        Ok(if present { Some(value) } else { None })
+    }
+
+    /// Read a length prefixed list, `expected` is the length the machine
+    /// needs if it's fixed
+    pub fn list_u64(&mut self, expected: Option<usize>)
+        -> Result<Vec<u64>, String>
+    {
+        let length = self.length(expected)?;
+        (0..length).map(|_| self.u64()).collect()
+    }
+
+    pub fn list_bool(&mut self, expected: Option<usize>)
+        -> Result<Vec<bool>, String>
+    {
+        let length = self.length(expected)?;
+        (0..length).map(|_| self.bool()).collect()
+    }
+
+    /// Read a length and check it against `expected`
+    pub fn length(&mut self, expected: Option<usize>)
+        -> Result<usize, String>
+    {
+        let length = self.u64()?;
+        if length > (self.data.len() - self.offset) as u64 {
+            return Err(format!("Snapshot: Bad length: {}", length));
+        }
+
+        let length = length as usize;
+        if let Some(expected) = expected {
+            if length != expected {
+                return Err(format!("Snapshot: Expected {} entries but \
+                                    got {}", expected, length));
+            }
+        }
+
+        Ok(length)
+    }
+
+    /// Check that all the data has been read
+    pub fn finish(&self) -> Result<(), String> {
+        if self.offset != self.data.len() {
+            return Err(format!("Snapshot: {} bytes of trailing data",
+                               self.data.len() - self.offset));
+        }
+
+        Ok(())
+    }
+}
+
+/// Serialize the state of the whole machine
+pub fn save(core: &Core) -> Vec<u8> {
+    let mut writer = SnapshotWriter::new();
+    writer.bytes(MAGIC);
+    writer.u32(VERSION);
+
+    core.save(&mut writer);
+    core.mmu.save(&mut writer);
+
+    writer.finish()
+}
+
+/// Replace the state of the whole machine with a snapshot made by `save`,
+/// the machine is left as it was if the snapshot can't be used
+pub fn restore(core: &mut Core, data: &[u8]) -> Result<(), String> {
+    let mut reader = SnapshotReader::new(data);
+    if data.len() < MAGIC.len() || reader.bytes(MAGIC.len())? != MAGIC {
+        return Err("Snapshot: Not a snapshot".to_string());
+    }
+
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!("Snapshot: Unsupported version {}, expected {}",
+                           version, VERSION));
+    }
+
+    // NOTE(patrik): The core and RAM are read into new state first, the
+    // devices can only be restored in place so their old state is kept to
+    // go back to if the rest of the snapshot turns out to be bad
+    let core_state = Core::load_snapshot(&mut reader)?;
+    let ram = core.mmu.load_ram(&mut reader)?;
+
+    let devices = core.mmu.device_state();
+    let result = core.mmu.restore_devices(&mut reader)
+        .and_then(|_| reader.finish());
+    if let Err(error) = result {
+        core.mmu.set_device_state(&devices);
+        return Err(error);
+    }
+
+    core.mmu.set_ram(ram);
+    core.reset_to(&core_state);
+
+    Ok(())
+}
+
+pub fn restore_file(core: &mut Core, path: &str) {
+    let data = std::fs::read(path)
+        .unwrap_or_else(|_| panic!("Failed to read snapshot {}", path));
+    if let Err(error) = restore(core, &data) {
+        panic!("{}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clint::TimerSource;
+    use crate::cpu::Register;
+    use crate::uart::{ Uart, UART_IRQ };
+
+    const RAM: u64 = 0x8000_0000;
+
+    fn create_core() -> Core {
+        let uart = Uart::new(UART_IRQ, None, Box::new(std::io::sink()));
+        let mmu = crate::create_mmu(&[(RAM, 0x10_0000)],
+                                    TimerSource::Instructions(1), uart, None);
+        crate::create_core(mmu)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut core = create_core();
+        core.set_reg(Register::A0, 0x1234);
+        core.mmu.poke_u64(RAM + 0x1000, 0xbeef);
+        let data = save(&core);
+
+        core.set_reg(Register::A0, 0);
+        core.mmu.poke_u64(RAM + 0x1000, 0);
+        core.mmu.poke_u64(RAM + 0x2000, 1);
+
+        restore(&mut core, &data).unwrap();
+        assert_eq!(core.reg(Register::A0), 0x1234);
+        assert_eq!(core.mmu.peek_u64(RAM + 0x1000), Some(0xbeef));
+        assert_eq!(core.mmu.peek_u64(RAM + 0x2000), Some(0));
+    }
+
+    #[test]
+    fn truncated_leaves_machine_alone() {
+        let mut core = create_core();
+        core.set_reg(Register::A0, 0x1234);
+        core.mmu.poke_u64(RAM + 0x1000, 0xbeef);
+
+        // NOTE(patrik): An in-memory snapshot makes the MMU track dirty
+        // pages, a failed restore must not lose RAM through that either
+        let _checkpoint = core.mmu.snapshot();
+
+        let mut data = save(&core);
+        data.pop();
+
+        core.set_reg(Register::A0, 0x5678);
+        core.mmu.poke_u64(RAM + 0x1000, 0xcafe);
+
+        assert!(restore(&mut core, &data).is_err());
+        assert_eq!(core.reg(Register::A0), 0x5678);
+        assert_eq!(core.mmu.peek_u64(RAM + 0x1000), Some(0xcafe));
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut core = create_core();
+        assert!(restore(&mut core, b"RESTSNA").is_err());
+        assert!(restore(&mut core, b"NOTASNAPSHOT").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! SiFive test finisher, lets the guest stop the machine with a pass or
+//! fail code or ask for a reboot. QEMU calls it `sifive_test` and Linux
+//! drives it through the `syscon-poweroff` and `syscon-reboot` drivers
+
+use crate::device::{ Device, Interrupts, Shutdown };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+pub const SYSCON_BASE: u64 = 0x0010_0000;
+pub const SYSCON_SIZE: u64 = 0x1000;
+
+// Values of the low 16 bits of a write, the upper 16 bits hold the code of
+// a failure
+const FINISHER_FAIL: u64  = 0x3333;
+const FINISHER_PASS: u64  = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+pub struct Syscon {
+    request: Option<Shutdown>,
+}
+
+impl Default for Syscon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self {
+            request: None,
+        }
+    }
+}
+
+impl Device for Syscon {
+    fn read(&mut self, _offset: u64, _size: usize) -> u64 {
+        0
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, value: u64) {
+        if offset != 0 {
+            return;
+        }
+
+        let code = (value >> 16) as u16;
+        let request = match value & 0xffff {
+            FINISHER_FAIL => Shutdown::Fail(code),
+            FINISHER_PASS => Shutdown::Pass,
+            FINISHER_RESET => Shutdown::Reboot,
+
+            // NOTE(patrik): Unknown values are ignored like on the real
+            // device
+            _ => return,
+        };
+
+        self.request = Some(request);
+    }
+
+    fn tick(&mut self, interrupts: &mut Interrupts) {
+        if let Some(request) = self.request.take() {
+            interrupts.request_shutdown(request);
+        }
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        let value = match self.request {
+            None => 0,
+            Some(Shutdown::Pass) => FINISHER_PASS,
+            Some(Shutdown::Fail(code)) => FINISHER_FAIL | (code as u64) << 16,
+            Some(Shutdown::Reboot) => FINISHER_RESET,
+        };
+
+        writer.u64(value);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let value = reader.u64()?;
+
+        self.request = None;
+        if value != 0 {
+            self.write(0, 4, value);
+        }
+
+        Ok(())
+    }
+}
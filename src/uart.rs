@@ -0,0 +1,414 @@
+//! NS16550A compatible UART used as the console of the machine
+
+use std::collections::VecDeque;
+use std::io::{ Read, Write };
+use std::process::{ Command, Stdio };
+use std::sync::mpsc::{ self, Receiver, TryRecvError };
+
+use crate::device::{ Device, Interrupts };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_IRQ: usize = 10;
+
+const FIFO_SIZE: usize = 16;
+
+/// Number of instructions between checks for new input from the host
+const INPUT_POLL_TICKS: u64 = 256;
+
+// Register offsets
+const RBR_THR: u64 = 0;
+const IER: u64     = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64     = 3;
+const MCR: u64     = 4;
+const LSR: u64     = 5;
+const MSR: u64     = 6;
+const SCR: u64     = 7;
+
+// Interrupt enable bits
+const IER_RDA: u8  = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_MASK: u8 = 0b1111;
+
+// Interrupt identification values
+const IIR_NONE: u8  = 0x01;
+const IIR_THRE: u8  = 0x02;
+const IIR_RDA: u8   = 0x04;
+const IIR_FIFO: u8  = 0xc0;
+
+const FCR_ENABLE: u8   = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DR: u8   = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// Byte written by the host to start an escape sequence on the console,
+/// Ctrl-A followed by `x` quits the emulator
+const ESCAPE: u8 = 0x01;
+
+/// Puts the host terminal in raw mode so every key goes straight to the
+/// guest, the old mode is restored when dropped
+pub struct RawTerminal {
+    saved: Option<String>,
+}
+
+impl RawTerminal {
+    pub fn enable() -> Self {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout).trim().to_string()
+            });
+
+        if saved.is_some() {
+            let _ = Command::new("stty")
+                .args(["raw", "-echo"])
+                .stdin(Stdio::inherit())
+                .status();
+        }
+
+        Self { saved }
+    }
+
+    /// The terminal mode to go back to, `None` if stdin is not a terminal
+    pub fn saved(&self) -> Option<String> {
+        self.saved.clone()
+    }
+
+    fn restore(saved: &str) {
+        let _ = Command::new("stty")
+            .arg(saved)
+            .stdin(Stdio::inherit())
+            .status();
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = self.saved.as_ref() {
+            Self::restore(saved);
+        }
+    }
+}
+
+/// Read the host stdin on a separate thread so the guest never blocks on
+/// it, `saved_terminal` is the terminal mode to restore if the user quits
+/// with the escape sequence
+pub fn stdin_input(saved_terminal: Option<String>) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut escape = false;
+
+        for byte in stdin.lock().bytes() {
+            let byte = match byte {
+                Ok(byte) => byte,
+                Err(_) => break,
+            };
+
+            if escape {
+                escape = false;
+                if byte == b'x' {
+                    if let Some(saved) = saved_terminal.as_ref() {
+                        RawTerminal::restore(saved);
+                    }
+
+                    eprintln!("\r\nrest-emu: Quit from console");
+                    std::process::exit(0);
+                }
+
+                if byte != ESCAPE {
+                    continue;
+                }
+            } else if byte == ESCAPE {
+                escape = true;
+                continue;
+            }
+
+            if sender.send(byte).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+pub struct Uart {
+    irq: usize,
+
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fcr: u8,
+    dll: u8,
+    dlm: u8,
+
+    /// The transmitter holding register empty interrupt is pending, it is
+    /// cleared by reading IIR or writing THR
+    thre_pending: bool,
+
+    ticks: u64,
+}
+
+impl Uart {
+    pub fn new(irq: usize, input: Option<Receiver<u8>>,
+               output: Box<dyn Write>) -> Self
+    {
+        Self {
+            irq,
+
+            input,
+            output,
+
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            tx: VecDeque::with_capacity(FIFO_SIZE),
+
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fcr: 0,
+            dll: 0,
+            dlm: 0,
+
+            thre_pending: false,
+
+            ticks: 0,
+        }
+    }
+
+    fn rx_limit(&self) -> usize {
+        if self.fcr & FCR_ENABLE != 0 { FIFO_SIZE } else { 1 }
+    }
+
+    fn flush_tx(&mut self) {
+        if self.tx.is_empty() {
+            return;
+        }
+
+        let (first, second) = self.tx.as_slices();
+        let _ = self.output.write_all(first);
+        let _ = self.output.write_all(second);
+        let _ = self.output.flush();
+        self.tx.clear();
+
+        self.thre_pending = true;
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        let id = if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        };
+
+        if self.fcr & FCR_ENABLE != 0 { id | IIR_FIFO } else { id }
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = 0;
+        if !self.rx.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.tx.is_empty() {
+            lsr |= LSR_THRE | LSR_TEMT;
+        }
+
+        lsr
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, _size: usize) -> u64 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let value = match offset {
+            RBR_THR if dlab => self.dll,
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+
+            IER if dlab => self.dlm,
+            IER => self.ier,
+
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id & 0x0f == IIR_THRE {
+                    self.thre_pending = false;
+                }
+
+                id
+            },
+
+            LCR => self.lcr,
+            MCR => self.mcr,
+
+            LSR => self.line_status(),
+
+            // NOTE(patrik): Report CTS, DSR and DCD as always asserted
+            MSR => 0xb0,
+            SCR => self.scr,
+
+            _ => 0,
+        };
+
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, value: u64) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            RBR_THR if dlab => self.dll = value,
+            RBR_THR => {
+                self.thre_pending = false;
+                self.tx.push_back(value);
+
+                // NOTE(patrik): Data is sent as soon as it's written, the
+                // FIFO only holds it until the next tick
+                if self.tx.len() >= FIFO_SIZE {
+                    self.flush_tx();
+                }
+            },
+
+            IER if dlab => self.dlm = value,
+            IER => {
+                // NOTE(patrik): Enabling the THRE interrupt while the
+                // transmitter is empty raises it right away
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 &&
+                    self.tx.is_empty()
+                {
+                    self.thre_pending = true;
+                }
+
+                self.ier = value & IER_MASK;
+            },
+
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                if value & FCR_CLEAR_TX != 0 {
+                    self.tx.clear();
+                }
+
+                self.fcr = value & !(FCR_CLEAR_RX | FCR_CLEAR_TX);
+            },
+
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, interrupts: &mut Interrupts) {
+        self.flush_tx();
+
+        self.ticks += 1;
+        if self.ticks >= INPUT_POLL_TICKS {
+            self.ticks = 0;
+
+            while self.rx.len() < self.rx_limit() {
+                let input = match self.input.as_ref() {
+                    Some(input) => input,
+                    None => break,
+                };
+
+                match input.try_recv() {
+                    Ok(byte) => self.rx.push_back(byte),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.input = None;
+                        break;
+                    },
+                }
+            }
+        }
+
+        let level = self.interrupt_id() & 0x0f != IIR_NONE;
+        interrupts.set_line(self.irq, level);
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for fifo in [&self.rx, &self.tx] {
+            writer.u64(fifo.len() as u64);
+            for byte in fifo.iter() {
+                writer.u8(*byte);
+            }
+        }
+
+        for register in [self.ier, self.lcr, self.mcr, self.scr, self.fcr,
+                         self.dll, self.dlm]
+        {
+            writer.u8(register);
+        }
+
+        writer.bool(self.thre_pending);
+        writer.u64(self.ticks);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let mut fifos = [&[][..], &[][..]];
+        for fifo in fifos.iter_mut() {
+            let length = reader.length(None)?;
+            if length > FIFO_SIZE {
+                return Err(format!("Snapshot: UART FIFO holds {} bytes",
+                                   length));
+            }
+
+            *fifo = reader.bytes(length)?;
+        }
+
+        let mut registers = [0; 7];
+        for register in registers.iter_mut() {
+            *register = reader.u8()?;
+        }
+
+        let thre_pending = reader.bool()?;
+        let ticks = reader.u64()?;
+
+        let [rx, tx] = fifos;
+        self.rx.clear();
+        self.rx.extend(rx);
+        self.tx.clear();
+        self.tx.extend(tx);
+
+        let [ier, lcr, mcr, scr, fcr, dll, dlm] = registers;
+        self.ier = ier;
+        self.lcr = lcr;
+        self.mcr = mcr;
+        self.scr = scr;
+        self.fcr = fcr;
+        self.dll = dll;
+        self.dlm = dlm;
+
+        self.thre_pending = thre_pending;
+        self.ticks = ticks;
+
+        Ok(())
+    }
+}
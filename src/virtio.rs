@@ -0,0 +1,752 @@
+//! virtio-mmio transport of the `virt` machine and the split virtqueues
+//! the devices behind it use. The devices only implement `VirtioDevice`,
+//! the transport handles the registers, feature negotiation and the
+//! interrupt line
+//!
+//! A slot without a device reports device ID 0 so the guest driver skips
+//! it, like the unused slots QEMU creates
+
+use std::convert::TryInto;
+
+use crate::device::{ Device, Dma, Interrupts };
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+
+/// First slot, the others follow every `VIRTIO_MMIO_SIZE` bytes
+pub const VIRTIO_MMIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_MMIO_SLOTS: usize = 8;
+/// Interrupt line of the first slot, slot `n` uses `VIRTIO_MMIO_IRQ + n`
+pub const VIRTIO_MMIO_IRQ: usize = 1;
+
+// Registers of the transport
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+/// "virt" in little endian
+const MAGIC_VALUE: u64 = 0x7472_6976;
+/// Version 2 is the non-legacy interface from virtio 1.0
+const VERSION: u64 = 2;
+/// "REST" in little endian
+const VENDOR_ID: u64 = 0x5453_4552;
+
+/// The device follows virtio 1.0 and not the legacy interface
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits set by the driver
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+/// Bit of the interrupt status telling the driver buffers were used
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+
+/// Largest queue the driver may set up
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+// Flags of a descriptor
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_SIZE: u64 = 16;
+
+/// Address of the registers of slot `slot`
+pub fn slot_base(slot: usize) -> u64 {
+    VIRTIO_MMIO_BASE + slot as u64 * VIRTIO_MMIO_SIZE
+}
+
+/// A device behind the virtio-mmio transport
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+    /// Device specific feature bits, the transport adds its own
+    fn features(&self) -> u64;
+    fn queue_count(&self) -> usize;
+    /// The driver settled on `features`, a subset of what was offered
+    fn features_accepted(&mut self, _features: u64) {}
+
+    /// Byte of the device configuration space at `offset`
+    fn read_config(&self, offset: u64) -> u8;
+    fn write_config(&mut self, _offset: u64, _value: u8) {}
+
+    /// The driver made buffers available in queue `index`, returns true
+    /// if any were used
+    fn notify(&mut self, index: usize, queues: &mut [Queue],
+              memory: &mut dyn Dma) -> bool;
+
+    /// Called every tick while the driver is running, for devices with
+    /// input coming from the host. Returns true if any buffers were used
+    fn poll(&mut self, _queues: &mut [Queue], _memory: &mut dyn Dma)
+        -> bool
+    {
+        false
+    }
+
+    /// The driver reset the device
+    fn reset(&mut self) {}
+
+    fn save(&self, _writer: &mut SnapshotWriter) {}
+    fn restore(&mut self, _reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        Ok(())
+    }
+}
+
+/// Buffers of a descriptor chain taken from a queue, as address and length
+pub struct Chain {
+    /// Index of the first descriptor, given back when the chain is used
+    pub head: u16,
+    pub readable: Vec<(u64, u32)>,
+    pub writable: Vec<(u64, u32)>,
+}
+
+impl Chain {
+    /// Total size of the buffers the device may write
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, length)| *length as usize).sum()
+    }
+
+    /// Read all the readable buffers, `None` if any of them is not in RAM
+    /// or there is more than `limit` bytes
+    pub fn read_all(&self, memory: &dyn Dma, limit: usize)
+        -> Option<Vec<u8>>
+    {
+        // NOTE(patrik): The lengths come from the guest so check them
+        // before allocating anything
+        let total: u64 = self.readable.iter()
+            .map(|(_, length)| *length as u64)
+            .sum();
+        if total > limit as u64 {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(total as usize);
+        for (addr, length) in self.readable.iter() {
+            let start = data.len();
+            data.resize(start + *length as usize, 0);
+            if !memory.read(*addr, &mut data[start..]) {
+                return None;
+            }
+        }
+
+        Some(data)
+    }
+
+    /// Write `data` to the writable buffers starting `offset` bytes in,
+    /// returns how much fit
+    pub fn write_at(&self, memory: &mut dyn Dma, offset: usize,
+                    data: &[u8]) -> usize
+    {
+        let mut skip = offset;
+        let mut written = 0;
+        for (addr, length) in self.writable.iter() {
+            let length = *length as usize;
+            if skip >= length {
+                skip -= length;
+                continue;
+            }
+
+            let count = (length - skip).min(data.len() - written);
+            let chunk = &data[written..written + count];
+            if !memory.write(*addr + skip as u64, chunk) {
+                break;
+            }
+
+            skip = 0;
+            written += count;
+            if written == data.len() {
+                break;
+            }
+        }
+
+        written
+    }
+}
+
+/// A split virtqueue set up by the driver
+#[derive(Default)]
+pub struct Queue {
+    size: u16,
+    ready: bool,
+    /// Guest physical addresses of the descriptor table, the available
+    /// ring (driver area) and the used ring (device area)
+    desc: u64,
+    driver: u64,
+    device: u64,
+
+    /// Next entry of the available ring to take
+    last_avail: u16,
+    /// Next entry of the used ring to fill
+    used: u16,
+}
+
+impl Queue {
+    fn read_u16(memory: &dyn Dma, addr: u64) -> Option<u16> {
+        let mut bytes = [0; 2];
+        if memory.read(addr, &mut bytes) {
+            Some(u16::from_le_bytes(bytes))
+        } else {
+            None
+        }
+    }
+
+    /// Take the next chain the driver made available, `None` if there is
+    /// nothing or the chain is broken
+    pub fn pop(&mut self, memory: &dyn Dma) -> Option<Chain> {
+        if !self.ready || self.size == 0 {
+            return None;
+        }
+
+        let avail = Self::read_u16(memory, self.driver + 2)?;
+        if avail == self.last_avail {
+            return None;
+        }
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = Self::read_u16(memory, self.driver + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        // NOTE(patrik): A chain can't be longer than the queue, stopping
+        // there keeps a looping chain from hanging the emulator
+        let mut index = head;
+        for _ in 0..self.size {
+            if index >= self.size {
+                return None;
+            }
+
+            let mut desc = [0; DESC_SIZE as usize];
+            let addr = self.desc + index as u64 * DESC_SIZE;
+            if !memory.read(addr, &mut desc) {
+                return None;
+            }
+
+            let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap());
+            let length = u32::from_le_bytes(desc[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push((addr, length));
+            } else {
+                chain.readable.push((addr, length));
+            }
+
+            if flags & DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = next;
+        }
+
+        None
+    }
+
+    /// Give chain `head` back to the driver with `length` bytes written
+    pub fn push(&mut self, memory: &mut dyn Dma, head: u16, length: u32) {
+        let slot = (self.used % self.size) as u64;
+
+        let mut element = [0; 8];
+        element[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        element[4..8].copy_from_slice(&length.to_le_bytes());
+        memory.write(self.device + 4 + slot * 8, &element);
+
+        self.used = self.used.wrapping_add(1);
+        memory.write(self.device + 2, &self.used.to_le_bytes());
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u32(self.size as u32);
+        writer.bool(self.ready);
+        writer.u64(self.desc);
+        writer.u64(self.driver);
+        writer.u64(self.device);
+        writer.u32(self.last_avail as u32);
+        writer.u32(self.used as u32);
+    }
+
+    /// Read a queue saved by `save`
+    fn load(reader: &mut SnapshotReader) -> Result<Self, String> {
+        Ok(Self {
+            size: reader.u32()? as u16,
+            ready: reader.bool()?,
+            desc: reader.u64()?,
+            driver: reader.u64()?,
+            device: reader.u64()?,
+            last_avail: reader.u32()? as u16,
+            used: reader.u32()? as u16,
+        })
+    }
+}
+
+/// Set the low or high half of `value`
+fn set_half(value: &mut u64, high: bool, half: u64) {
+    if high {
+        *value = (*value & 0xffff_ffff) | half << 32;
+    } else {
+        *value = (*value & !0xffff_ffff) | (half & 0xffff_ffff);
+    }
+}
+
+/// The virtio-mmio registers of one slot and the device behind them
+pub struct VirtioMmio {
+    irq: usize,
+    device: Option<Box<dyn VirtioDevice>>,
+    queues: Vec<Queue>,
+
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+
+    /// Queues notified since the last tick, one bit per queue
+    notified: u64,
+}
+
+impl VirtioMmio {
+    /// A slot with `device` behind it, or nothing with `None`
+    pub fn new(irq: usize, device: Option<Box<dyn VirtioDevice>>) -> Self {
+        let count = device.as_ref().map(|device| device.queue_count())
+            .unwrap_or(0);
+        let mut queues = Vec::with_capacity(count);
+        queues.resize_with(count, Queue::default);
+
+        Self {
+            irq,
+            device,
+            queues,
+
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+
+            notified: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.as_ref()
+            .map(|device| device.features() | VIRTIO_F_VERSION_1)
+            .unwrap_or(0)
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        for queue in self.queues.iter_mut() {
+            *queue = Queue::default();
+        }
+
+        self.status = 0;
+        self.driver_features = 0;
+        self.interrupt_status = 0;
+        self.notified = 0;
+
+        if let Some(device) = self.device.as_mut() {
+            device.reset();
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn read(&mut self, offset: u64, size: usize) -> u64 {
+        if offset >= REG_CONFIG {
+            let device = match self.device.as_ref() {
+                Some(device) => device,
+                None => return 0,
+            };
+
+            let mut value = 0;
+            for index in 0..size as u64 {
+                let byte = device.read_config(offset - REG_CONFIG + index);
+                value |= (byte as u64) << (index * 8);
+            }
+
+            return value;
+        }
+
+        // NOTE(patrik): Registers below the configuration space are 32 bits
+        // wide and only accessed as such
+        match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => {
+                self.device.as_ref()
+                    .map(|device| device.device_id() as u64)
+                    .unwrap_or(0)
+            },
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => {
+                match self.device_features_sel {
+                    0 => self.device_features() & 0xffff_ffff,
+                    1 => self.device_features() >> 32,
+                    _ => 0,
+                }
+            },
+            REG_QUEUE_NUM_MAX if (self.queue_sel as usize) <
+                self.queues.len() => QUEUE_SIZE_MAX as u64,
+            REG_QUEUE_READY => {
+                self.queue().map(|queue| queue.ready as u64).unwrap_or(0)
+            },
+            REG_INTERRUPT_STATUS => self.interrupt_status as u64,
+            REG_STATUS => self.status as u64,
+            REG_CONFIG_GENERATION => self.config_generation as u64,
+
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) {
+        if offset >= REG_CONFIG {
+            if let Some(device) = self.device.as_mut() {
+                for index in 0..size as u64 {
+                    device.write_config(offset - REG_CONFIG + index,
+                                        (value >> (index * 8)) as u8);
+                }
+            }
+
+            return;
+        }
+
+        let value = value as u32;
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_DRIVER_FEATURES => {
+                // NOTE(patrik): Features the device doesn't offer are
+                // ignored instead of failing negotiation
+                let mut features = self.driver_features;
+                match self.driver_features_sel {
+                    0 => set_half(&mut features, false, value as u64),
+                    1 => set_half(&mut features, true, value as u64),
+                    _ => {},
+                }
+                self.driver_features = features & self.device_features();
+            },
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    queue.size = (value as u16).min(QUEUE_SIZE_MAX);
+                }
+            },
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            },
+            REG_QUEUE_NOTIFY if (value as usize) <
+                self.queues.len().min(64) => self.notified |= 1 << value,
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS => {
+                if value == 0 {
+                    self.reset();
+                    return;
+                }
+
+                // NOTE(patrik): The features are final once the driver
+                // sets FEATURES_OK
+                let features_ok = value & !self.status & STATUS_FEATURES_OK;
+                self.status = value;
+                if let Some(device) = self.device.as_mut()
+                    .filter(|_| features_ok != 0)
+                {
+                    device.features_accepted(self.driver_features);
+                }
+            },
+            REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH => {
+                let high = offset == REG_QUEUE_DESC_HIGH;
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.desc, high, value as u64);
+                }
+            },
+            REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH => {
+                let high = offset == REG_QUEUE_DRIVER_HIGH;
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.driver, high, value as u64);
+                }
+            },
+            REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH => {
+                let high = offset == REG_QUEUE_DEVICE_HIGH;
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.device, high, value as u64);
+                }
+            },
+
+            _ => {},
+        }
+    }
+
+    fn uses_dma(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn dma(&mut self, memory: &mut dyn Dma, interrupts: &mut Interrupts) {
+        let device = match self.device.as_mut() {
+            Some(device) => device,
+            None => return,
+        };
+
+        if self.status & STATUS_DRIVER_OK != 0 {
+            let mut used = false;
+            while self.notified != 0 {
+                let index = self.notified.trailing_zeros() as usize;
+                self.notified &= !(1 << index);
+                used |= device.notify(index, &mut self.queues, memory);
+            }
+
+            used |= device.poll(&mut self.queues, memory);
+            if used {
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+        }
+
+        interrupts.set_line(self.irq, self.interrupt_status != 0);
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u32(self.status);
+        writer.u32(self.device_features_sel);
+        writer.u32(self.driver_features_sel);
+        writer.u64(self.driver_features);
+        writer.u32(self.queue_sel);
+        writer.u32(self.interrupt_status);
+        writer.u32(self.config_generation);
+        writer.u64(self.notified);
+
+        writer.u64(self.queues.len() as u64);
+        for queue in self.queues.iter() {
+            queue.save(writer);
+        }
+
+        if let Some(device) = self.device.as_ref() {
+            device.save(writer);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let status = reader.u32()?;
+        let device_features_sel = reader.u32()?;
+        let driver_features_sel = reader.u32()?;
+        let driver_features = reader.u64()?;
+        let queue_sel = reader.u32()?;
+        let interrupt_status = reader.u32()?;
+        let config_generation = reader.u32()?;
+        let notified = reader.u64()?;
+
+        let count = reader.length(Some(self.queues.len()))?;
+        let queues = (0..count)
+            .map(|_| Queue::load(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // NOTE(patrik): The device comes last in the snapshot, the
+        // transport is only changed once it restored fine
+        if let Some(device) = self.device.as_mut() {
+            device.restore(reader)?;
+        }
+
+        self.status = status;
+        self.device_features_sel = device_features_sel;
+        self.driver_features_sel = driver_features_sel;
+        self.driver_features = driver_features;
+        self.queue_sel = queue_sel;
+        self.interrupt_status = interrupt_status;
+        self.config_generation = config_generation;
+        self.notified = notified;
+        self.queues = queues;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM: u64 = 0x8000_0000;
+    const DESC: u64 = RAM;
+    const DRIVER: u64 = RAM + 0x100;
+    const DEVICE: u64 = RAM + 0x200;
+    const QUEUE_SIZE: u16 = 4;
+
+    /// RAM as seen by a device doing DMA
+    struct Memory(Vec<u8>);
+
+    impl Dma for Memory {
+        fn read(&self, addr: u64, buffer: &mut [u8]) -> bool {
+            let offset = addr.wrapping_sub(RAM) as usize;
+            match self.0.get(offset..offset.wrapping_add(buffer.len())) {
+                Some(data) => { buffer.copy_from_slice(data); true },
+                None => false,
+            }
+        }
+
+        fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+            let offset = addr.wrapping_sub(RAM) as usize;
+            match self.0.get_mut(offset..offset.wrapping_add(data.len())) {
+                Some(buffer) => { buffer.copy_from_slice(data); true },
+                None => false,
+            }
+        }
+    }
+
+    impl Memory {
+        fn descriptor(&mut self, index: u16, addr: u64, length: u32,
+                      flags: u16, next: u16)
+        {
+            let mut desc = Vec::new();
+            desc.extend_from_slice(&addr.to_le_bytes());
+            desc.extend_from_slice(&length.to_le_bytes());
+            desc.extend_from_slice(&flags.to_le_bytes());
+            desc.extend_from_slice(&next.to_le_bytes());
+            self.write(DESC + index as u64 * DESC_SIZE, &desc);
+        }
+
+        /// Make the chain starting at `head` available
+        fn make_available(&mut self, head: u16) {
+            let mut index = [0; 2];
+            self.read(DRIVER + 2, &mut index);
+            let index = u16::from_le_bytes(index);
+
+            let slot = (index % QUEUE_SIZE) as u64;
+            self.write(DRIVER + 4 + slot * 2, &head.to_le_bytes());
+            self.write(DRIVER + 2, &index.wrapping_add(1).to_le_bytes());
+        }
+    }
+
+    fn create_queue() -> (Queue, Memory) {
+        let queue = Queue {
+            size: QUEUE_SIZE,
+            ready: true,
+            desc: DESC,
+            driver: DRIVER,
+            device: DEVICE,
+            ..Default::default()
+        };
+
+        (queue, Memory(vec![0; 0x1000]))
+    }
+
+    #[test]
+    fn pop_splits_readable_and_writable() {
+        let (mut queue, mut memory) = create_queue();
+        memory.descriptor(2, RAM + 0x800, 16, DESC_F_NEXT, 3);
+        memory.descriptor(3, RAM + 0x900, 32, DESC_F_WRITE, 0);
+        memory.make_available(2);
+
+        let chain = queue.pop(&memory).unwrap();
+        assert_eq!(chain.head, 2);
+        assert_eq!(chain.readable, [(RAM + 0x800, 16)]);
+        assert_eq!(chain.writable, [(RAM + 0x900, 32)]);
+        assert!(queue.pop(&memory).is_none());
+
+        queue.push(&mut memory, chain.head, 5);
+        let mut used = [0; 12];
+        memory.read(DEVICE + 2, &mut used[..2]);
+        memory.read(DEVICE + 4, &mut used[4..]);
+        assert_eq!(used, [1, 0, 0, 0, 2, 0, 0, 0, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn broken_chains_are_dropped() {
+        let (mut queue, mut memory) = create_queue();
+
+        // NOTE(patrik): A chain looping back on itself
+        memory.descriptor(0, RAM + 0x800, 16, DESC_F_NEXT, 1);
+        memory.descriptor(1, RAM + 0x800, 16, DESC_F_NEXT, 0);
+        memory.make_available(0);
+        assert!(queue.pop(&memory).is_none());
+
+        // NOTE(patrik): Next descriptor outside the table
+        memory.descriptor(2, RAM + 0x800, 16, DESC_F_NEXT, QUEUE_SIZE);
+        memory.make_available(2);
+        assert!(queue.pop(&memory).is_none());
+
+        memory.make_available(QUEUE_SIZE);
+        assert!(queue.pop(&memory).is_none());
+
+        // NOTE(patrik): The queue keeps going after a broken chain
+        memory.descriptor(3, RAM + 0x800, 16, 0, 0);
+        memory.make_available(3);
+        assert_eq!(queue.pop(&memory).unwrap().head, 3);
+    }
+
+    #[test]
+    fn read_all_checks_the_limit_and_the_buffers() {
+        let (_, mut memory) = create_queue();
+        memory.write(RAM + 0x800, b"abcd");
+        memory.write(RAM + 0x900, b"efgh");
+
+        let chain = Chain {
+            head: 0,
+            readable: vec![(RAM + 0x800, 4), (RAM + 0x900, 4)],
+            writable: Vec::new(),
+        };
+        assert_eq!(chain.read_all(&memory, 8).unwrap(), b"abcdefgh");
+        assert!(chain.read_all(&memory, 7).is_none());
+
+        let huge = Chain {
+            head: 0,
+            readable: vec![(RAM, u32::MAX); 4],
+            writable: Vec::new(),
+        };
+        assert!(huge.read_all(&memory, 1 << 20).is_none());
+
+        let outside = Chain {
+            head: 0,
+            readable: vec![(RAM + 0xffc, 8)],
+            writable: Vec::new(),
+        };
+        assert!(outside.read_all(&memory, 1 << 20).is_none());
+    }
+
+    #[test]
+    fn write_at_spans_buffers() {
+        let (_, mut memory) = create_queue();
+        let chain = Chain {
+            head: 0,
+            readable: Vec::new(),
+            writable: vec![(RAM + 0x800, 2), (RAM + 0x900, 4)],
+        };
+        assert_eq!(chain.writable_len(), 6);
+
+        assert_eq!(chain.write_at(&mut memory, 1, b"xyz123"), 5);
+        let mut data = [0; 5];
+        memory.read(RAM + 0x800, &mut data[..2]);
+        memory.read(RAM + 0x900, &mut data[2..]);
+        assert_eq!(&data, b"\0xyz1");
+    }
+}
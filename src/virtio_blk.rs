@@ -0,0 +1,279 @@
+//! virtio-blk device backed by a disk image on the host. The image can be
+//! used read-only, read-write, or copy-on-write where the writes of the
+//! guest are kept in memory and the image is never changed
+//!
+//! Requests are handled right away when the driver notifies the queue,
+//! the guest never sees a request in flight
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Seek, SeekFrom, Write };
+
+use crate::device::Dma;
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+use crate::virtio::{ Chain, Queue, VirtioDevice };
+
+const DEVICE_ID_BLOCK: u32 = 2;
+
+// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Status written in the last byte of a request
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Sectors are always 512 bytes, whatever the block size of the image
+const SECTOR_SIZE: u64 = 512;
+/// Type, reserved and sector fields in front of every request
+const HEADER_SIZE: usize = 16;
+/// Length of the serial returned by `VIRTIO_BLK_T_GET_ID`
+const ID_SIZE: usize = 20;
+/// Most data written by one request, anything bigger fails with IOERR
+const WRITE_MAX: usize = 4 * 1024 * 1024;
+
+type Sector = [u8; SECTOR_SIZE as usize];
+
+/// How the guest may use the image
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DiskMode {
+    ReadOnly,
+    ReadWrite,
+    /// Writes go to an overlay in memory, the image is only read
+    CopyOnWrite,
+}
+
+pub struct VirtioBlk {
+    file: File,
+    mode: DiskMode,
+    /// Size of the image in sectors, a partial sector at the end is not
+    /// visible to the guest
+    capacity: u64,
+    /// Sectors written by the guest in copy-on-write mode
+    overlay: HashMap<u64, Box<Sector>>,
+    /// Serial reported to the guest, the name of the image
+    id: Vec<u8>,
+}
+
+impl VirtioBlk {
+    pub fn open(path: &str, mode: DiskMode) -> Self {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)
+            .unwrap_or_else(|_| panic!("Failed to open disk image {}", path));
+        let size = file.metadata()
+            .unwrap_or_else(|_| panic!("Failed to get size of {}", path))
+            .len();
+
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let mut id = name.as_bytes().to_vec();
+        id.truncate(ID_SIZE);
+
+        Self {
+            file,
+            mode,
+            capacity: size / SECTOR_SIZE,
+            overlay: HashMap::new(),
+            id,
+        }
+    }
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut Sector)
+        -> Option<()>
+    {
+        if let Some(data) = self.overlay.get(&sector) {
+            buffer.copy_from_slice(&data[..]);
+            return Some(());
+        }
+
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE)).ok()?;
+        self.file.read_exact(buffer).ok()
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &Sector) -> Option<()> {
+        match self.mode {
+            DiskMode::ReadOnly => None,
+            DiskMode::ReadWrite => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE)).ok()?;
+                self.file.write_all(buffer).ok()
+            },
+            DiskMode::CopyOnWrite => {
+                self.overlay.insert(sector, Box::new(*buffer));
+                Some(())
+            },
+        }
+    }
+
+    /// Check that `length` bytes starting at `sector` are on the disk and
+    /// a whole number of sectors
+    fn in_range(&self, sector: u64, length: usize) -> bool {
+        let count = length as u64 / SECTOR_SIZE;
+        (length as u64).is_multiple_of(SECTOR_SIZE) &&
+            sector.checked_add(count)
+                .is_some_and(|end| end <= self.capacity)
+    }
+
+    /// Handle one request, returns the number of bytes written to the
+    /// buffers of the guest
+    fn request(&mut self, chain: &Chain, memory: &mut dyn Dma) -> u32 {
+        // NOTE(patrik): The status byte is the last writable byte, a
+        // request without one can't be answered at all
+        let status_offset = match chain.writable_len().checked_sub(1) {
+            Some(offset) => offset,
+            None => return 0,
+        };
+
+        let limit = HEADER_SIZE + WRITE_MAX;
+        let readable = match chain.read_all(memory, limit) {
+            Some(readable) if readable.len() >= HEADER_SIZE => readable,
+            _ => {
+                chain.write_at(memory, status_offset, &[VIRTIO_BLK_S_IOERR]);
+                return 1;
+            },
+        };
+
+        let kind = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        let data = &readable[HEADER_SIZE..];
+
+        let mut written = 0;
+        let status = match kind {
+            VIRTIO_BLK_T_IN => {
+                if self.in_range(sector, status_offset) {
+                    let mut buffer = [0; SECTOR_SIZE as usize];
+                    let mut ok = true;
+                    for index in 0..status_offset / SECTOR_SIZE as usize {
+                        if self.read_sector(sector + index as u64,
+                                            &mut buffer).is_none()
+                        {
+                            ok = false;
+                            break;
+                        }
+
+                        let offset = index * SECTOR_SIZE as usize;
+                        written += chain.write_at(memory, offset, &buffer);
+                    }
+
+                    if ok { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                }
+            },
+
+            VIRTIO_BLK_T_OUT => {
+                let ok = self.in_range(sector, data.len()) &&
+                    data.chunks(SECTOR_SIZE as usize)
+                        .enumerate()
+                        .all(|(index, chunk)| {
+                            let buffer = chunk.try_into().unwrap();
+                            self.write_sector(sector + index as u64,
+                                              buffer).is_some()
+                        });
+
+                if ok { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }
+            },
+
+            VIRTIO_BLK_T_FLUSH => {
+                let ok = self.mode != DiskMode::ReadWrite ||
+                    self.file.sync_data().is_ok();
+
+                if ok { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }
+            },
+
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; ID_SIZE];
+                id[..self.id.len()].copy_from_slice(&self.id);
+                let length = status_offset.min(ID_SIZE);
+                written += chain.write_at(memory, 0, &id[..length]);
+
+                VIRTIO_BLK_S_OK
+            },
+
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+
+        written += chain.write_at(memory, status_offset, &[status]);
+        written as u32
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_FLUSH;
+        if self.mode == DiskMode::ReadOnly {
+            features |= VIRTIO_BLK_F_RO;
+        }
+
+        features
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // NOTE(patrik): Only the capacity is there, the fields after it
+        // belong to features that are not offered
+        match offset {
+            0..=7 => (self.capacity >> (offset * 8)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Queue],
+              memory: &mut dyn Dma) -> bool
+    {
+        let queue = &mut queues[index];
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            let length = self.request(&chain, memory);
+            queue.push(memory, chain.head, length);
+            used = true;
+        }
+
+        used
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        // NOTE(patrik): The overlay is part of the state of the guest, a
+        // read-write image is on the host and not saved
+        let mut sectors: Vec<_> = self.overlay.keys().copied().collect();
+        sectors.sort_unstable();
+
+        writer.u64(sectors.len() as u64);
+        for sector in sectors {
+            writer.u64(sector);
+            writer.bytes(&self.overlay[&sector][..]);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let mut overlay = HashMap::new();
+        let count = reader.length(None)?;
+        for _ in 0..count {
+            let sector = reader.u64()?;
+            let data = reader.bytes(SECTOR_SIZE as usize)?;
+            overlay.insert(sector, Box::new(data.try_into().unwrap()));
+        }
+
+        self.overlay = overlay;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+//! virtio-console device with one or more ports. Port 0 is a console, hvc0
+//! in Linux, and the others are named ports showing up as
+//! `/dev/virtio-ports/NAME`. The host side of a port is stdio, a file the
+//! output is written to or a Unix socket taking one client at the time
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{ ErrorKind, Read, Write };
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::sync::mpsc::{ Receiver, TryRecvError };
+
+use crate::device::Dma;
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+use crate::virtio::{ Queue, VirtioDevice };
+
+const DEVICE_ID_CONSOLE: u32 = 3;
+
+/// Feature bit for more than one port and the control queues
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// Events of the control messages
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Port id, event and value of a control message
+const CONTROL_SIZE: usize = 8;
+
+// Queues of the control messages, the queues of port 0 come before them
+// and the queues of the other ports after
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+/// Number of instructions between checks for new input from the host
+const INPUT_POLL_TICKS: u64 = 256;
+/// Most input buffered for a port, the host isn't read while it's full
+const INPUT_MAX: usize = 64 * 1024;
+/// Most output taken from one buffer chain, bigger chains are dropped
+const OUTPUT_MAX: usize = 64 * 1024;
+
+/// Receive queue of `port`, the transmit queue is the one after it
+fn rx_queue(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 + port * 2 }
+}
+
+/// Port and direction of a data queue, `None` for the control queues
+fn queue_port(index: usize) -> Option<(usize, bool)> {
+    match index {
+        0 | 1 => Some((0, index == 1)),
+        CONTROL_RX | CONTROL_TX => None,
+        _ => Some(((index - 2) / 2, index % 2 == 1)),
+    }
+}
+
+fn control_message(port: usize, event: u16, value: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(CONTROL_SIZE);
+    message.extend_from_slice(&(port as u32).to_le_bytes());
+    message.extend_from_slice(&event.to_le_bytes());
+    message.extend_from_slice(&value.to_le_bytes());
+    message
+}
+
+/// Host side of a port
+pub enum PortBackend {
+    /// Input from the reader thread of stdin, output to stdout
+    Stdio(Option<Receiver<u8>>),
+    /// Output written to a file, there is no input
+    File(File),
+    /// A Unix socket listening for a client
+    Socket {
+        listener: UnixListener,
+        client: Option<UnixStream>,
+    },
+}
+
+impl PortBackend {
+    /// Listen on a Unix socket at `path`, a socket left there by an
+    /// earlier run is replaced
+    pub fn unix_socket(path: &str) -> Self {
+        let stale = std::fs::symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.file_type().is_socket());
+        if stale {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let listener = UnixListener::bind(path)
+            .unwrap_or_else(|_| panic!("Failed to listen on {}", path));
+        listener.set_nonblocking(true)
+            .expect("Failed to make the socket non-blocking");
+
+        PortBackend::Socket { listener, client: None }
+    }
+
+    /// The host side is there to talk to
+    fn connected(&self) -> bool {
+        match self {
+            PortBackend::Socket { client, .. } => client.is_some(),
+            _ => true,
+        }
+    }
+
+    /// Take a new client if there is none, returns true if one connected
+    fn accept(&mut self) -> bool {
+        if let PortBackend::Socket { listener, client: client @ None } =
+            self
+        {
+            if let Ok((stream, _)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    *client = Some(stream);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Read what the host has sent into `input`, returns false if the
+    /// client went away
+    fn read(&mut self, input: &mut VecDeque<u8>) -> bool {
+        match self {
+            PortBackend::Stdio(receiver) => {
+                while input.len() < INPUT_MAX {
+                    let byte = match receiver.as_ref() {
+                        Some(receiver) => receiver.try_recv(),
+                        None => break,
+                    };
+
+                    match byte {
+                        Ok(byte) => input.push_back(byte),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            *receiver = None;
+                            break;
+                        },
+                    }
+                }
+            },
+
+            PortBackend::File(_) => {},
+
+            PortBackend::Socket { client, .. } => {
+                let stream = match client.as_mut() {
+                    Some(stream) => stream,
+                    None => return true,
+                };
+
+                let mut buffer = [0; 1024];
+                while input.len() < INPUT_MAX {
+                    match stream.read(&mut buffer) {
+                        Ok(0) => {
+                            *client = None;
+                            return false;
+                        },
+                        Ok(count) => input.extend(&buffer[..count]),
+                        Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                            break;
+                        },
+                        Err(_) => {
+                            *client = None;
+                            return false;
+                        },
+                    }
+                }
+            },
+        }
+
+        true
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match self {
+            PortBackend::Stdio(_) => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(data);
+                let _ = stdout.flush();
+            },
+
+            PortBackend::File(file) => {
+                let _ = file.write_all(data);
+            },
+
+            // NOTE(patrik): Output is dropped while no client is connected
+            // or the client isn't keeping up, like on a real serial line
+            PortBackend::Socket { client, .. } => {
+                if let Some(stream) = client.as_mut() {
+                    let _ = stream.write_all(data);
+                }
+            },
+        }
+    }
+}
+
+struct Port {
+    /// Name given to the guest, not used for the console port
+    name: String,
+    backend: PortBackend,
+    /// Input from the host not given to the guest yet
+    input: VecDeque<u8>,
+    /// The driver has set up the port
+    ready: bool,
+    /// The guest has the port open
+    guest_open: bool,
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// The driver accepted `VIRTIO_CONSOLE_F_MULTIPORT`, without it only
+    /// port 0 is used
+    multiport: bool,
+    /// Control messages for the driver waiting for a buffer
+    control: VecDeque<Vec<u8>>,
+    ticks: u64,
+}
+
+impl VirtioConsole {
+    /// A console with a port for each of `ports`, given as name and host
+    /// side
+    pub fn new(ports: Vec<(String, PortBackend)>) -> Self {
+        if ports.is_empty() {
+            panic!("virtio-console needs at least one port");
+        }
+
+        let ports = ports.into_iter()
+            .map(|(name, backend)| Port {
+                name,
+                backend,
+                input: VecDeque::new(),
+                ready: false,
+                guest_open: false,
+            })
+            .collect();
+
+        Self {
+            ports,
+            multiport: false,
+            control: VecDeque::new(),
+            ticks: 0,
+        }
+    }
+
+    /// The guest wants input on `port`
+    fn accepts_input(&self, port: usize) -> bool {
+        if self.multiport {
+            self.ports[port].guest_open
+        } else {
+            port == 0
+        }
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+
+        let id = id as usize;
+        if event == VIRTIO_CONSOLE_DEVICE_READY {
+            if value == 1 {
+                for port in 0..self.ports.len() {
+                    self.control.push_back(
+                        control_message(port, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            }
+
+            return;
+        }
+
+        let port = match self.ports.get_mut(id) {
+            Some(port) => port,
+            None => return,
+        };
+
+        match event {
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                port.ready = true;
+
+                if id == 0 {
+                    self.control.push_back(
+                        control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                } else {
+                    let mut message =
+                        control_message(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    message.extend_from_slice(port.name.as_bytes());
+                    self.control.push_back(message);
+                }
+
+                if port.backend.connected() {
+                    self.control.push_back(
+                        control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+                }
+            },
+
+            VIRTIO_CONSOLE_PORT_OPEN => port.guest_open = value == 1,
+
+            _ => {},
+        }
+    }
+
+    /// Give pending control messages to the driver, returns true if any
+    /// buffers were used
+    fn deliver_control(&mut self, queues: &mut [Queue],
+                       memory: &mut dyn Dma) -> bool
+    {
+        let mut used = false;
+        while let Some(message) = self.control.front() {
+            let chain = match queues[CONTROL_RX].pop(memory) {
+                Some(chain) => chain,
+                None => break,
+            };
+
+            let length = chain.write_at(memory, 0, message);
+            queues[CONTROL_RX].push(memory, chain.head, length as u32);
+            self.control.pop_front();
+            used = true;
+        }
+
+        used
+    }
+
+    /// Give buffered input of `port` to the driver
+    fn deliver_input(&mut self, port: usize, queues: &mut [Queue],
+                     memory: &mut dyn Dma) -> bool
+    {
+        if !self.accepts_input(port) {
+            return false;
+        }
+
+        let queue = &mut queues[rx_queue(port)];
+        let input = &mut self.ports[port].input;
+
+        let mut used = false;
+        while !input.is_empty() {
+            let chain = match queue.pop(memory) {
+                Some(chain) => chain,
+                None => break,
+            };
+
+            let count = chain.writable_len().min(input.len());
+            let data: Vec<u8> = input.drain(..count).collect();
+            let length = chain.write_at(memory, 0, &data);
+            queue.push(memory, chain.head, length as u32);
+            used = true;
+        }
+
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn queue_count(&self) -> usize {
+        rx_queue(self.ports.len() - 1) + 2
+    }
+
+    fn features_accepted(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // NOTE(patrik): The columns and rows are left at zero, the size of
+        // the console is unknown
+        match offset {
+            4..=7 => ((self.ports.len() as u32) >> ((offset - 4) * 8)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Queue],
+              memory: &mut dyn Dma) -> bool
+    {
+        let mut used = false;
+        match (index, queue_port(index)) {
+            (CONTROL_TX, _) if self.multiport => {
+                while let Some(chain) = queues[CONTROL_TX].pop(memory) {
+                    let message = chain.read_all(memory, CONTROL_SIZE);
+                    if let Some(message) = message {
+                        self.handle_control(&message);
+                    }
+
+                    queues[CONTROL_TX].push(memory, chain.head, 0);
+                    used = true;
+                }
+
+                used |= self.deliver_control(queues, memory);
+            },
+
+            (CONTROL_RX, _) if self.multiport => {
+                used |= self.deliver_control(queues, memory);
+            },
+
+            (_, Some((port, true))) if port < self.ports.len() => {
+                while let Some(chain) = queues[index].pop(memory) {
+                    if let Some(data) = chain.read_all(memory, OUTPUT_MAX) {
+                        self.ports[port].backend.write(&data);
+                    }
+
+                    queues[index].push(memory, chain.head, 0);
+                    used = true;
+                }
+            },
+
+            (_, Some((port, false))) if port < self.ports.len() => {
+                used |= self.deliver_input(port, queues, memory);
+            },
+
+            _ => {},
+        }
+
+        used
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], memory: &mut dyn Dma) -> bool {
+        self.ticks += 1;
+        if self.ticks < INPUT_POLL_TICKS {
+            return false;
+        }
+        self.ticks = 0;
+
+        let mut used = false;
+        for index in 0..self.ports.len() {
+            let port = &mut self.ports[index];
+
+            // NOTE(patrik): The guest is told when a client connects or
+            // goes away so programs on the port see hangups
+            let mut open = None;
+            if port.backend.accept() {
+                open = Some(1);
+            }
+            if !port.backend.read(&mut port.input) {
+                port.input.clear();
+                open = Some(0);
+            }
+
+            if let Some(value) = open.filter(|_| port.ready) {
+                self.control.push_back(
+                    control_message(index, VIRTIO_CONSOLE_PORT_OPEN, value));
+            }
+
+            used |= self.deliver_input(index, queues, memory);
+        }
+
+        if self.multiport {
+            used |= self.deliver_control(queues, memory);
+        }
+
+        used
+    }
+
+    fn reset(&mut self) {
+        for port in self.ports.iter_mut() {
+            port.input.clear();
+            port.ready = false;
+            port.guest_open = false;
+        }
+
+        self.multiport = false;
+        self.control.clear();
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.bool(self.multiport);
+        writer.u64(self.ticks);
+
+        writer.u64(self.control.len() as u64);
+        for message in self.control.iter() {
+            writer.u64(message.len() as u64);
+            writer.bytes(message);
+        }
+
+        for port in self.ports.iter() {
+            let (first, second) = port.input.as_slices();
+            writer.u64(port.input.len() as u64);
+            writer.bytes(first);
+            writer.bytes(second);
+            writer.bool(port.ready);
+            writer.bool(port.guest_open);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        let multiport = reader.bool()?;
+        let ticks = reader.u64()?;
+
+        let mut control = VecDeque::new();
+        let count = reader.length(None)?;
+        for _ in 0..count {
+            let length = reader.length(None)?;
+            control.push_back(reader.bytes(length)?.to_vec());
+        }
+
+        let mut ports = Vec::with_capacity(self.ports.len());
+        for _ in 0..self.ports.len() {
+            let length = reader.length(None)?;
+            let input = reader.bytes(length)?;
+            ports.push((input, reader.bool()?, reader.bool()?));
+        }
+
+        self.multiport = multiport;
+        self.ticks = ticks;
+        self.control = control;
+        for (port, (input, ready, guest_open)) in self.ports.iter_mut()
+            .zip(ports)
+        {
+            port.input = input.iter().copied().collect();
+            port.ready = ready;
+            port.guest_open = guest_open;
+        }
+
+        Ok(())
+    }
+}
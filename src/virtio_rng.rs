@@ -0,0 +1,72 @@
+//! virtio-rng entropy device. The bytes come from a seeded deterministic
+//! generator instead of the host so a run can be repeated exactly
+
+use crate::device::Dma;
+use crate::rng::Rng;
+use crate::snapshot::{ SnapshotWriter, SnapshotReader };
+use crate::virtio::{ Queue, VirtioDevice };
+
+const DEVICE_ID_ENTROPY: u32 = 4;
+
+/// Most bytes given for one request, the driver asks for far less
+const REQUEST_MAX: usize = 64 * 1024;
+
+pub struct VirtioRng {
+    rng: Rng,
+}
+
+impl VirtioRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_ENTROPY
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        0
+    }
+
+    fn notify(&mut self, index: usize, queues: &mut [Queue],
+              memory: &mut dyn Dma) -> bool
+    {
+        let queue = &mut queues[index];
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            let mut data = vec![0; chain.writable_len().min(REQUEST_MAX)];
+            self.rng.fill(&mut data);
+
+            let length = chain.write_at(memory, 0, &data);
+            queue.push(memory, chain.head, length as u32);
+            used = true;
+        }
+
+        used
+    }
+
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u64(self.rng.state());
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader)
+        -> Result<(), String>
+    {
+        self.rng = Rng::new(reader.u64()?);
+
+        Ok(())
+    }
+}